@@ -0,0 +1,214 @@
+use crate::{
+    cache::{CacheBackend, CacheConnection},
+    config::RelayerConfig,
+    cursor::CursorKey,
+    health::Health,
+    http::{Routes, serve},
+    includer::Includer,
+    leader::lease_from_config,
+    lifecycle::state_store,
+    metrics::{Metrics, rpc_client},
+    pending::pending_store,
+    quarantine::Quarantine,
+    queue::{QueueTrait, file::FileQueue},
+    shutdown::Shutdown,
+    signing::{MessageSigner, MessageVerifier},
+    source_verifier::SourceVerifier,
+    subscriber::{DEPOSIT_EVENT_NAME, ProviderType, Subscriber},
+};
+use alloy::providers::{Provider, ProviderBuilder};
+use eyre::{Result, eyre};
+use std::{sync::Arc, time::Duration};
+use tokio::{net::TcpListener, time::timeout};
+use tracing::{debug, info, warn};
+
+/// How long `run_all` lets the includer mint what is still queued after the
+/// subscriber has stopped.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Provider for the source chain in `config`, counting its requests in
+/// `metrics`.
+pub fn source_provider(config: &RelayerConfig, metrics: &Metrics) -> Result<ProviderType> {
+    let client = rpc_client(config.source()?.rpc_url.clone(), metrics, "source");
+    Ok(ProviderBuilder::new().on_client(client))
+}
+
+/// Cursor key of the deposit route in `config`, resolving the chain id from
+/// the source provider.
+pub async fn cursor_key(config: &RelayerConfig) -> Result<CursorKey> {
+    let chain_id = source_provider(config, &Metrics::new())?
+        .get_chain_id()
+        .await?;
+    Ok(CursorKey::new(
+        chain_id,
+        config.source_contract()?,
+        DEPOSIT_EVENT_NAME,
+    ))
+}
+
+/// A subscriber configured from `config`: signing, start block, leader
+/// lease, and the cursor and deposit state stores on `stores`. It records
+/// into `metrics`.
+pub async fn build_subscriber<C: QueueTrait>(
+    config: &RelayerConfig,
+    queue_connection: C,
+    stores: CacheBackend,
+    metrics: &Metrics,
+) -> Result<Subscriber<C, CacheConnection>> {
+    let signer = MessageSigner::from_config(config);
+    if signer.is_none() && config.signing.require_signatures {
+        return Err(eyre!(
+            "signing.require_signatures is set but neither signing.hmac_key nor signing.signing_key is"
+        ));
+    }
+    let cache_connection = CacheConnection::open(stores, config).await?;
+    let states = state_store(stores, config).await?;
+    let sub = Subscriber::from_config(config, queue_connection, cache_connection, metrics.clone())
+        .await?;
+    debug!("Loaded deposit_address: {:?}", sub.contract_address);
+    let chain_id = sub.provider.get_chain_id().await?;
+    let route = CursorKey::new(chain_id, sub.contract_address, DEPOSIT_EVENT_NAME);
+    let leader_lease = lease_from_config(&route.lease_key(), config).await?;
+
+    Ok(sub
+        .with_signer(signer)
+        .with_start_block(config.start_block)
+        .with_chain_id(chain_id)
+        .with_leader_lease(leader_lease)
+        .with_state_store(Some(states)))
+}
+
+/// An includer configured from `config`: message and source verification,
+/// the per-wallet leader lease, and the pending mint and deposit state
+/// stores on `stores`. It records into `metrics`.
+pub async fn build_includer<C: QueueTrait>(
+    config: &RelayerConfig,
+    queue_connection: C,
+    stores: CacheBackend,
+    metrics: &Metrics,
+) -> Result<Includer<C>> {
+    let verifier = MessageVerifier::from_config(config);
+    if verifier.is_none() && config.signing.require_signatures {
+        return Err(eyre!(
+            "signing.require_signatures is set but neither signing.hmac_key nor signing.signer_address is"
+        ));
+    }
+    if verifier.is_none() {
+        warn!(
+            "No signing.hmac_key or signing.signer_address set, queue messages are not authenticated"
+        );
+    }
+
+    let mut incl = Includer::from_config(config, queue_connection, metrics.clone())?
+        .with_verifier(verifier)
+        .with_max_message_age(config.signing.max_message_age);
+
+    if config.verifier.enabled {
+        let source_verifier =
+            SourceVerifier::new(source_provider(config, metrics)?, config.source_contract()?)
+                .with_min_confirmations(config.verifier.min_confirmations);
+        incl = incl.with_source_verifier(source_verifier, Quarantine::from_config(config)?);
+    }
+
+    // Only one includer may submit for a wallet, so the lease is per wallet.
+    let lease_key = format!("relayer:includer:{:#x}:leader", incl.wallet);
+    let lease = lease_from_config(&lease_key, config).await?;
+
+    // Pending mints are tracked so a standby can finish them on failover,
+    // and a restart can finish those abandoned at shutdown. Failover
+    // depends on them; a single includer can run without.
+    match pending_store(stores, config).await {
+        Ok(pending) => incl = incl.with_pending_store(Some(pending)),
+        Err(e) if lease.is_none() => warn!("In-flight mints are not journaled: {}", e),
+        Err(e) => return Err(e.into()),
+    }
+    incl = incl.with_leader_lease(lease);
+
+    // Deposits recorded as minted are acked on redelivery instead of being
+    // minted again, so the includer does not run without the store.
+    let states = state_store(stores, config).await?;
+    Ok(incl.with_state_store(Some(states)))
+}
+
+/// Serves `routes` on `health.addr` until `shutdown`, if it is set. The
+/// binaries serve `/metrics`, `/healthz` and `/readyz`.
+pub async fn serve_http(
+    config: &RelayerConfig,
+    routes: impl Routes,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let Some(addr) = config.health.addr else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(addr).await?;
+    tokio::spawn(serve(listener, Arc::new(routes), shutdown));
+    Ok(())
+}
+
+/// Runs a subscriber and an includer as tasks of this process, connected by
+/// a `FileQueue` in `queue_dir`. A file rather than a channel, so a crash
+/// cannot lose what was published but not minted. Cursors and deposit
+/// states are kept in SQLite unless `cache.backend` says otherwise, so
+/// neither a broker nor Redis is needed.
+///
+/// On `shutdown` the subscriber stops first, finishing its scan. The
+/// includer then mints what is still queued, for up to `DRAIN_TIMEOUT`,
+/// before it stops too. The queue is durable, so deposits the cursor has
+/// passed but that were not minted are delivered again on restart.
+pub async fn run_all(config: &RelayerConfig, shutdown: Shutdown) -> Result<()> {
+    let stores = CacheBackend::from_config_or(config, CacheBackend::Sqlite);
+    let metrics = Metrics::new();
+    let queue = FileQueue::open_with(&config.queue_dir, config.file_queue.clone())?
+        .with_metrics(metrics.clone());
+    let stop_subscriber = Shutdown::new().with_grace(shutdown.grace());
+    let stop_includer = Shutdown::new().with_grace(shutdown.grace());
+    let health = Health::from_config(config);
+    let mut sub = build_subscriber(config, queue.clone(), stores, &metrics)
+        .await?
+        .with_shutdown(stop_subscriber.clone())
+        .with_health(health.clone());
+    let mut incl = build_includer(config, queue.clone(), stores, &metrics)
+        .await?
+        .with_shutdown(stop_includer.clone())
+        .with_health(health.clone());
+    serve_http(config, (metrics, health), shutdown.clone()).await?;
+    let mut subscriber = tokio::spawn(async move { sub.run().await });
+    let mut includer = tokio::spawn(async move { incl.run().await });
+
+    // The run loops only return once stopped, so anything else is a panic.
+    let stopped = tokio::select! {
+        _ = shutdown.wait() => None,
+        res = &mut subscriber => Some(("Subscriber", res)),
+        res = &mut includer => Some(("Includer", res)),
+    };
+    if let Some((name, res)) = stopped {
+        subscriber.abort();
+        includer.abort();
+        return Err(eyre!("{name} stopped unexpectedly: {res:?}"));
+    }
+
+    info!("Shutting down, stopping the subscriber");
+    stop_subscriber.trigger();
+    subscriber.await?;
+
+    info!("Draining {} queued deposits", queue.pending_len());
+    if timeout(DRAIN_TIMEOUT, drained(&queue)).await.is_err() {
+        warn!(
+            "{} deposits were published but not minted within {:?}; they are minted after restart",
+            queue.pending_len(),
+            DRAIN_TIMEOUT
+        );
+    }
+    stop_includer.trigger();
+    // Wakes an includer waiting for a delivery.
+    queue.close();
+    includer.await?;
+    info!("Shut down");
+    Ok(())
+}
+
+async fn drained(queue: &FileQueue) {
+    while queue.pending_len() > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
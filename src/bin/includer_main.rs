@@ -1,28 +1,40 @@
-use alloy::transports::http::reqwest::Url;
 use dotenv::dotenv;
 use eyre::Result;
-use relayer::includer;
-use relayer::queue;
-use relayer::utils::{get_dst_contract_addr, setup_logging};
-use std::env;
-
-const ADDRESS_PATH: &str = "../project_eth/data/deployments.json";
+use relayer::app::{build_includer, serve_http};
+use relayer::cache::CacheBackend;
+use relayer::config::RelayerConfig;
+use relayer::health::Health;
+use relayer::metrics::Metrics;
+use relayer::queue::QueueConnection;
+use relayer::shutdown::Shutdown;
+use relayer::utils::setup_logging;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
-    setup_logging();
-
-    let dst_rpc = env::var("DST_RPC").expect("DST_RPC not set");
-    let rpc_url_dst: Url = dst_rpc.parse()?;
-    let dst_contract_address = get_dst_contract_addr(ADDRESS_PATH)?;
-    let queue_connection = queue::get_queue_connection(false).await?;
-
-    let mut incl =
-        includer::Includer::new(&rpc_url_dst, dst_contract_address, queue_connection.clone())
-            .await?;
-
-    let _res = incl.run().await;
+    let config = RelayerConfig::load()?;
+    setup_logging(&config);
+    // Fail on missing settings before connecting to anything.
+    config.destination()?;
+    config.private_key()?;
 
+    let metrics = Metrics::new();
+    let queue_connection = QueueConnection::from_config(&config)
+        .await?
+        .with_metrics(metrics.clone());
+    let shutdown = Shutdown::from_config(&config);
+    shutdown.trigger_on_signals();
+    let health = Health::from_config(&config);
+    let mut incl = build_includer(
+        &config,
+        queue_connection,
+        CacheBackend::from_config(&config),
+        &metrics,
+    )
+    .await?
+    .with_shutdown(shutdown.clone())
+    .with_health(health.clone());
+    serve_http(&config, (metrics, health), shutdown).await?;
+    incl.run().await;
     Ok(())
 }
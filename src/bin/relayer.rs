@@ -0,0 +1,213 @@
+use dotenv::dotenv;
+use eyre::Result;
+use relayer::app::{build_includer, build_subscriber, cursor_key, run_all, serve_http};
+use relayer::cache::CacheTrait;
+use relayer::cache::{CacheBackend, CacheConnection};
+use relayer::cli::{Command, USAGE, config_problems, exit, exit_code};
+use relayer::config::RelayerConfig;
+use relayer::cursor::write_cursor;
+use relayer::health::Health;
+use relayer::lifecycle::state_store_from_config;
+use relayer::metrics::Metrics;
+use relayer::queue::{DeadLetterQueue, QueueBackend, QueueConnection};
+use relayer::shutdown::Shutdown;
+use relayer::utils::setup_logging;
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv().ok();
+
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(Command::Help) => {
+            println!("{USAGE}");
+            return ExitCode::from(exit::OK);
+        }
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(exit::USAGE);
+        }
+    };
+    match run(command).await {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+async fn run(command: Command) -> Result<u8> {
+    let config = RelayerConfig::load()?;
+    setup_logging(&config);
+    match command {
+        Command::Help => println!("{USAGE}"),
+        Command::Subscribe => {
+            let queue_connection = QueueConnection::from_config(&config).await?;
+            let (metrics, health, shutdown) = (
+                Metrics::new(),
+                Health::from_config(&config),
+                on_signals(&config),
+            );
+            let mut sub = build_subscriber(
+                &config,
+                queue_connection,
+                CacheBackend::from_config(&config),
+                &metrics,
+            )
+            .await?
+            .with_shutdown(shutdown.clone())
+            .with_health(health.clone());
+            serve_http(&config, (metrics, health), shutdown).await?;
+            sub.run().await;
+        }
+        Command::Include => {
+            // Fail on missing settings before connecting to anything.
+            config.destination()?;
+            config.private_key()?;
+            let (metrics, health, shutdown) = (
+                Metrics::new(),
+                Health::from_config(&config),
+                on_signals(&config),
+            );
+            let queue_connection = QueueConnection::from_config(&config)
+                .await?
+                .with_metrics(metrics.clone());
+            let mut incl = build_includer(
+                &config,
+                queue_connection,
+                CacheBackend::from_config(&config),
+                &metrics,
+            )
+            .await?
+            .with_shutdown(shutdown.clone())
+            .with_health(health.clone());
+            serve_http(&config, (metrics, health), shutdown).await?;
+            incl.run().await;
+        }
+        Command::RunAll => {
+            config.destination()?;
+            config.private_key()?;
+            run_all(&config, on_signals(&config)).await?;
+        }
+        Command::Backfill { from, to } => {
+            let queue_connection = QueueConnection::from_config(&config).await?;
+            let metrics = Metrics::new();
+            let mut sub = build_subscriber(
+                &config,
+                queue_connection,
+                CacheBackend::from_config(&config),
+                &metrics,
+            )
+            .await?;
+            let published = sub.backfill(from, to).await?;
+            println!("Published {published} deposits from blocks {from}..={to}");
+        }
+        Command::CursorGet => {
+            let key = cursor_key(&config).await?.to_string();
+            let mut cache = CacheConnection::from_config(&config).await?;
+            match cache.get_offset(&key).await? {
+                None => {
+                    eprintln!("No cursor stored for {key}");
+                    return Ok(exit::NOT_FOUND);
+                }
+                Some(block) => println!("{block}"),
+            }
+        }
+        Command::CursorSet { block } => {
+            // Not fenced: stop the subscriber first, or its next write
+            // replaces this one.
+            let key = cursor_key(&config).await?.to_string();
+            let mut cache = CacheConnection::from_config(&config).await?;
+            write_cursor(&mut cache, &key, block, None).await?;
+            println!("{key} set to {block}");
+        }
+        Command::DlqList { limit } => {
+            let mut queue_connection = QueueConnection::from_config(&config).await?;
+            let letters = queue_connection.dead_letters(limit).await?;
+            if letters.is_empty() {
+                eprintln!("No dead letters");
+            }
+            for letter in letters {
+                println!("{}\t{}", letter.id, String::from_utf8_lossy(&letter.data));
+            }
+        }
+        Command::DlqReplay { id } => {
+            let mut queue_connection = QueueConnection::from_config(&config).await?;
+            if !queue_connection.replay(&id).await? {
+                eprintln!("No dead letter with id {id}");
+                return Ok(exit::NOT_FOUND);
+            }
+            println!("Replayed {id}");
+        }
+        Command::DepositStatus { id } => {
+            let mut states = state_store_from_config(&config).await?;
+            let Some(record) = states.get(&id).await? else {
+                eprintln!("No deposit with id {id}");
+                return Ok(exit::NOT_FOUND);
+            };
+            if let Some(state) = record.state() {
+                println!("{id}: {state}");
+            }
+            for transition in &record.history {
+                println!(
+                    "  {} {:<10} {}",
+                    transition.at, transition.by, transition.state
+                );
+            }
+        }
+        Command::ConfigCheck => {
+            let cache = CacheBackend::from_config(&config);
+            let queue = config.queue_backend;
+            print_config(&config, cache, queue);
+            let problems = config_problems(&config, cache, queue);
+            if !problems.is_empty() {
+                for problem in &problems {
+                    eprintln!("- {problem}");
+                }
+                return Ok(exit::CONFIG);
+            }
+            println!("Configuration is valid");
+        }
+    }
+    Ok(exit::OK)
+}
+
+/// Shutdown triggered by SIGINT or SIGTERM.
+fn on_signals(config: &RelayerConfig) -> Shutdown {
+    let shutdown = Shutdown::from_config(config);
+    shutdown.trigger_on_signals();
+    shutdown
+}
+
+/// Prints the settings in effect. The private key is shown as its address.
+fn print_config(config: &RelayerConfig, cache: CacheBackend, queue: QueueBackend) {
+    let show = |value: Option<String>| value.unwrap_or_else(|| String::from("-"));
+    println!(
+        "source:       {}",
+        show(config.source.as_ref().map(|c| c.rpc_url.to_string()))
+    );
+    println!(
+        "  contract:   {}",
+        show(config.source_contract().ok().map(|a| a.to_string()))
+    );
+    println!(
+        "destination:  {}",
+        show(config.destination.as_ref().map(|c| c.rpc_url.to_string()))
+    );
+    println!(
+        "  contract:   {}",
+        show(config.destination_contract().ok().map(|a| a.to_string()))
+    );
+    println!(
+        "wallet:       {}",
+        show(config.private_key.as_ref().map(|k| k.address().to_string()))
+    );
+    println!("cache:        {cache:?}");
+    match queue {
+        QueueBackend::File => println!("queue:        {queue:?} ({})", config.queue_dir.display()),
+        _ => println!("queue:        {queue:?} ({})", config.amqp_addr),
+    }
+    println!("token data:   {}", config.token_data_path.display());
+}
@@ -1,40 +1,36 @@
-use alloy::providers::ProviderBuilder;
-use alloy::transports::http::reqwest::Url;
 use dotenv::dotenv;
 use eyre::Result;
-use relayer::queue;
-use relayer::subscriber::{ProviderType, RedisCache, Subscriber};
-use relayer::utils::{get_src_contract_addr, setup_logging};
-use std::env;
-use tracing::debug;
-
-const ADDRESS_PATH: &str = "../project_eth/data/deployments.json";
+use relayer::app::{build_subscriber, serve_http};
+use relayer::cache::CacheBackend;
+use relayer::config::RelayerConfig;
+use relayer::health::Health;
+use relayer::metrics::Metrics;
+use relayer::queue::QueueConnection;
+use relayer::shutdown::Shutdown;
+use relayer::utils::setup_logging;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
-    setup_logging();
-
-    let src_rpc = env::var("SRC_RPC").expect("SRC_RPC not set");
-    let rpc_url: Url = src_rpc.parse()?;
-    let src_contract_address = get_src_contract_addr(ADDRESS_PATH)?;
-    debug!("Loaded deposit_address: {:?}", src_contract_address);
-
-    let queue_connection = queue::get_queue_connection(false).await?;
-    let db_url = env::var("DB_URL").expect("DB_URL not set");
+    let config = RelayerConfig::load()?;
+    setup_logging(&config);
+    config.source_contract()?;
 
-    let redis_connection = RedisCache::new(db_url).await?;
-    let provider: ProviderType = ProviderBuilder::new().on_http(rpc_url.clone());
-
-    let mut sub = Subscriber::new(
-        src_contract_address,
+    let queue_connection = QueueConnection::from_config(&config).await?;
+    let shutdown = Shutdown::from_config(&config);
+    shutdown.trigger_on_signals();
+    let metrics = Metrics::new();
+    let health = Health::from_config(&config);
+    let mut sub = build_subscriber(
+        &config,
         queue_connection,
-        redis_connection,
-        provider,
+        CacheBackend::from_config(&config),
+        &metrics,
     )
-    .await
-    .unwrap();
-
-    let _res = sub.run().await;
+    .await?
+    .with_shutdown(shutdown.clone())
+    .with_health(health.clone());
+    serve_http(&config, (metrics, health), shutdown).await?;
+    sub.run().await;
     Ok(())
 }
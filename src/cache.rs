@@ -0,0 +1,231 @@
+use crate::config::RelayerConfig;
+use crate::errors::RelayerError;
+use async_trait::async_trait;
+use std::str::FromStr;
+
+pub mod memory;
+pub mod redis;
+pub mod sqlite;
+
+use self::redis::RedisCache;
+use memory::InMemoryCache;
+use sqlite::SqliteCache;
+
+#[cfg_attr(test, mockall::automock)]
+#[async_trait]
+pub trait CacheTrait: Send {
+    /// The stored offset, or `None` if `key` was never written.
+    async fn get_offset(&mut self, key: &str) -> Result<Option<u64>, RelayerError>;
+    /// The stored offset, `0` if `key` was never written.
+    async fn get_last_offset(&mut self, key: &str) -> Result<u64, RelayerError> {
+        Ok(self.get_offset(key).await?.unwrap_or(0))
+    }
+    async fn set_last_offset(&mut self, key: &str, value: u64) -> Result<(), RelayerError>;
+    async fn delete_offset(&mut self, key: &str) -> Result<(), RelayerError>;
+    /// Writes the offset only if no write with a newer fencing token has
+    /// happened for this key, so a deposed leader cannot move the cursor.
+    async fn set_fenced_offset(
+        &mut self,
+        key: &str,
+        value: u64,
+        token: u64,
+    ) -> Result<(), RelayerError>;
+}
+
+/// Cursor store used by the binaries, selected with `cache.backend`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheBackend {
+    Redis,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for CacheBackend {
+    type Err = RelayerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "redis" => Ok(CacheBackend::Redis),
+            "sqlite" => Ok(CacheBackend::Sqlite),
+            "memory" | "in-memory" => Ok(CacheBackend::Memory),
+            other => Err(RelayerError::Other(format!(
+                "Unknown cache backend: {other}"
+            ))),
+        }
+    }
+}
+
+impl CacheBackend {
+    pub fn from_config(config: &RelayerConfig) -> Self {
+        Self::from_config_or(config, CacheBackend::Redis)
+    }
+
+    /// `cache.backend`, or `default` when it is not set.
+    pub fn from_config_or(config: &RelayerConfig, default: CacheBackend) -> Self {
+        config.cache_backend.unwrap_or(default)
+    }
+}
+
+/// Any of the cache backends, so callers can pick one at runtime without
+/// becoming generic over it.
+pub enum CacheConnection {
+    Redis(RedisCache),
+    Sqlite(SqliteCache),
+    Memory(InMemoryCache),
+}
+
+impl CacheConnection {
+    /// Connects to the backend from `cache.backend` (default redis).
+    pub async fn from_config(config: &RelayerConfig) -> Result<Self, RelayerError> {
+        Self::open(CacheBackend::from_config(config), config).await
+    }
+
+    /// Redis uses `redis.url`, SQLite opens `cache.sqlite_path`.
+    pub async fn open(backend: CacheBackend, config: &RelayerConfig) -> Result<Self, RelayerError> {
+        match backend {
+            CacheBackend::Redis => {
+                let db_url = config.redis_url()?.to_string();
+                Ok(CacheConnection::Redis(RedisCache::new(db_url).await?))
+            }
+            CacheBackend::Sqlite => Ok(CacheConnection::Sqlite(SqliteCache::open(
+                &config.sqlite_path,
+            )?)),
+            CacheBackend::Memory => Ok(CacheConnection::Memory(InMemoryCache::new())),
+        }
+    }
+}
+
+#[async_trait]
+impl CacheTrait for CacheConnection {
+    async fn get_offset(&mut self, key: &str) -> Result<Option<u64>, RelayerError> {
+        match self {
+            CacheConnection::Redis(cache) => cache.get_offset(key).await,
+            CacheConnection::Sqlite(cache) => cache.get_offset(key).await,
+            CacheConnection::Memory(cache) => cache.get_offset(key).await,
+        }
+    }
+
+    async fn set_last_offset(&mut self, key: &str, value: u64) -> Result<(), RelayerError> {
+        match self {
+            CacheConnection::Redis(cache) => cache.set_last_offset(key, value).await,
+            CacheConnection::Sqlite(cache) => cache.set_last_offset(key, value).await,
+            CacheConnection::Memory(cache) => cache.set_last_offset(key, value).await,
+        }
+    }
+
+    async fn delete_offset(&mut self, key: &str) -> Result<(), RelayerError> {
+        match self {
+            CacheConnection::Redis(cache) => cache.delete_offset(key).await,
+            CacheConnection::Sqlite(cache) => cache.delete_offset(key).await,
+            CacheConnection::Memory(cache) => cache.delete_offset(key).await,
+        }
+    }
+
+    async fn set_fenced_offset(
+        &mut self,
+        key: &str,
+        value: u64,
+        token: u64,
+    ) -> Result<(), RelayerError> {
+        match self {
+            CacheConnection::Redis(cache) => cache.set_fenced_offset(key, value, token).await,
+            CacheConnection::Sqlite(cache) => cache.set_fenced_offset(key, value, token).await,
+            CacheConnection::Memory(cache) => cache.set_fenced_offset(key, value, token).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Behaviour every `CacheTrait` backend must have. Keys are prefixed so
+    /// runs against a shared Redis do not collide.
+    async fn check_cache_behaviour<R: CacheTrait>(cache: &mut R, prefix: &str) {
+        let a = format!("{prefix}:a");
+        let b = format!("{prefix}:b");
+
+        assert_eq!(cache.get_last_offset(&a).await.unwrap(), 0);
+        assert_eq!(cache.get_offset(&a).await.unwrap(), None);
+
+        cache.set_last_offset(&a, 0).await.unwrap();
+        assert_eq!(cache.get_offset(&a).await.unwrap(), Some(0));
+
+        cache.set_last_offset(&a, 10).await.unwrap();
+        assert_eq!(cache.get_last_offset(&a).await.unwrap(), 10);
+
+        cache.set_last_offset(&a, 7).await.unwrap();
+        assert_eq!(cache.get_last_offset(&a).await.unwrap(), 7);
+
+        cache.set_last_offset(&b, 1 << 40).await.unwrap();
+        assert_eq!(cache.get_last_offset(&b).await.unwrap(), 1 << 40);
+        assert_eq!(cache.get_last_offset(&a).await.unwrap(), 7);
+
+        cache.delete_offset(&a).await.unwrap();
+        assert_eq!(cache.get_offset(&a).await.unwrap(), None);
+        assert_eq!(cache.get_last_offset(&b).await.unwrap(), 1 << 40);
+        cache.delete_offset(&b).await.unwrap();
+
+        cache.set_fenced_offset(&a, 20, 2).await.unwrap();
+        cache.set_fenced_offset(&a, 21, 2).await.unwrap();
+        assert_eq!(cache.get_last_offset(&a).await.unwrap(), 21);
+        let err = cache.set_fenced_offset(&a, 99, 1).await.unwrap_err();
+        assert!(matches!(
+            err,
+            RelayerError::FencedOut {
+                token: 1,
+                current: 2
+            }
+        ));
+        assert_eq!(cache.get_last_offset(&a).await.unwrap(), 21);
+        cache.set_fenced_offset(&a, 30, 3).await.unwrap();
+        assert_eq!(cache.get_last_offset(&a).await.unwrap(), 30);
+        cache.delete_offset(&a).await.unwrap();
+    }
+
+    #[test]
+    fn test_cache_backend_from_str() {
+        assert_eq!(
+            "Redis".parse::<CacheBackend>().unwrap(),
+            CacheBackend::Redis
+        );
+        assert_eq!(
+            "sqlite".parse::<CacheBackend>().unwrap(),
+            CacheBackend::Sqlite
+        );
+        assert_eq!(
+            "in-memory".parse::<CacheBackend>().unwrap(),
+            CacheBackend::Memory
+        );
+        assert!("etcd".parse::<CacheBackend>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_cache_behaviour() {
+        let mut cache = InMemoryCache::new();
+        check_cache_behaviour(&mut cache, "memory").await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_cache_behaviour() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = SqliteCache::open(dir.path().join("cursor.db")).unwrap();
+        check_cache_behaviour(&mut cache, "sqlite").await;
+    }
+
+    #[tokio::test]
+    async fn test_cache_connection_behaviour() {
+        let mut cache = CacheConnection::Memory(InMemoryCache::new());
+        check_cache_behaviour(&mut cache, "connection").await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at DB_URL"]
+    async fn test_redis_cache_behaviour() {
+        dotenv::dotenv().ok();
+        let db_url = std::env::var("DB_URL").unwrap_or_else(|_| "redis://127.0.0.1/".into());
+        let mut cache = RedisCache::new(db_url).await.unwrap();
+        let prefix = format!("test_relayer:{}", std::process::id());
+        check_cache_behaviour(&mut cache, &prefix).await;
+    }
+}
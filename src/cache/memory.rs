@@ -0,0 +1,76 @@
+use crate::cache::CacheTrait;
+use crate::errors::RelayerError;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct State {
+    offsets: HashMap<String, u64>,
+    fences: HashMap<String, u64>,
+}
+
+/// Process-local cursor store. Clones share the same maps, and nothing
+/// survives a restart.
+#[derive(Clone, Default)]
+pub struct InMemoryCache {
+    state: Arc<Mutex<State>>,
+}
+
+impl InMemoryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("in-memory cache lock poisoned")
+    }
+}
+
+#[async_trait]
+impl CacheTrait for InMemoryCache {
+    async fn get_offset(&mut self, key: &str) -> Result<Option<u64>, RelayerError> {
+        Ok(self.lock().offsets.get(key).copied())
+    }
+
+    async fn set_last_offset(&mut self, key: &str, value: u64) -> Result<(), RelayerError> {
+        self.lock().offsets.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    async fn delete_offset(&mut self, key: &str) -> Result<(), RelayerError> {
+        let mut state = self.lock();
+        state.offsets.remove(key);
+        state.fences.remove(key);
+        Ok(())
+    }
+
+    async fn set_fenced_offset(
+        &mut self,
+        key: &str,
+        value: u64,
+        token: u64,
+    ) -> Result<(), RelayerError> {
+        let mut state = self.lock();
+        let current = state.fences.get(key).copied().unwrap_or(0);
+        if token < current {
+            return Err(RelayerError::FencedOut { token, current });
+        }
+        state.fences.insert(key.to_string(), token);
+        state.offsets.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_clones_share_offsets() {
+        let mut a = InMemoryCache::new();
+        let mut b = a.clone();
+        a.set_last_offset("from_block", 3).await.unwrap();
+        assert_eq!(b.get_last_offset("from_block").await.unwrap(), 3);
+    }
+}
@@ -0,0 +1,87 @@
+use crate::cache::CacheTrait;
+use crate::config::RelayerConfig;
+use crate::errors::RelayerError;
+use async_trait::async_trait;
+use redis::{AsyncCommands, Client, aio::MultiplexedConnection}; // make connection pool at some point
+
+/// Sets the cursor and records the writer's fencing token next to it,
+/// unless a newer token is already recorded.
+const FENCED_SET_SCRIPT: &str = r"
+local current = tonumber(redis.call('GET', KEYS[2]) or '0')
+if tonumber(ARGV[2]) < current then
+    return current
+end
+redis.call('SET', KEYS[2], ARGV[2])
+redis.call('SET', KEYS[1], ARGV[1])
+return -1
+";
+
+pub struct RedisCache {
+    connection: MultiplexedConnection,
+}
+
+impl RedisCache {
+    pub async fn from_config(config: &RelayerConfig) -> Result<Self, RelayerError> {
+        Self::new(config.redis_url()?.to_string()).await
+    }
+
+    pub async fn new(db_url: String) -> Result<Self, RelayerError> {
+        let client = Client::open(db_url).map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        let connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(RedisCache { connection })
+    }
+}
+
+#[async_trait]
+impl CacheTrait for RedisCache {
+    async fn get_offset(&mut self, key: &str) -> Result<Option<u64>, RelayerError> {
+        self.connection
+            .get(key)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))
+    }
+
+    async fn set_last_offset(&mut self, key: &str, value: u64) -> Result<(), RelayerError> {
+        let _res: () = self
+            .connection
+            .set(key, value)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn delete_offset(&mut self, key: &str) -> Result<(), RelayerError> {
+        let _res: () = self
+            .connection
+            .del(&[key.to_string(), format!("{key}:fence")])
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn set_fenced_offset(
+        &mut self,
+        key: &str,
+        value: u64,
+        token: u64,
+    ) -> Result<(), RelayerError> {
+        let current: i64 = redis::Script::new(FENCED_SET_SCRIPT)
+            .key(key)
+            .key(format!("{key}:fence"))
+            .arg(value)
+            .arg(token)
+            .invoke_async(&mut self.connection)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        if current >= 0 {
+            return Err(RelayerError::FencedOut {
+                token,
+                current: current as u64,
+            });
+        }
+        Ok(())
+    }
+}
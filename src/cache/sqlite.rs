@@ -0,0 +1,229 @@
+use crate::cache::CacheTrait;
+use crate::errors::RelayerError;
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) fn sqlite_err(e: rusqlite::Error) -> RelayerError {
+    RelayerError::SqliteError(e.to_string())
+}
+
+/// Connection shared by one store's clones. Every SQLite store opens its
+/// file through `open_sqlite`, since they share it by default.
+#[derive(Clone)]
+pub(crate) struct SqliteConnection(Arc<Mutex<Connection>>);
+
+/// Opens `path` with a busy timeout, in WAL mode with full syncs. Fails if
+/// WAL could not be enabled, e.g. on a network filesystem.
+pub(crate) fn open_sqlite(path: impl AsRef<Path>) -> Result<SqliteConnection, RelayerError> {
+    let connection = Connection::open(path).map_err(sqlite_err)?;
+    connection.busy_timeout(BUSY_TIMEOUT).map_err(sqlite_err)?;
+    let mode: String = connection
+        .query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))
+        .map_err(sqlite_err)?;
+    if !mode.eq_ignore_ascii_case("wal") {
+        return Err(RelayerError::SqliteError(format!(
+            "Could not enable WAL mode, journal mode is {mode}"
+        )));
+    }
+    connection
+        .execute_batch("PRAGMA synchronous = FULL;")
+        .map_err(sqlite_err)?;
+    Ok(SqliteConnection(Arc::new(Mutex::new(connection))))
+}
+
+impl SqliteConnection {
+    /// Locks the connection on the calling thread, for setup and tests.
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.0.lock().expect("sqlite connection lock poisoned")
+    }
+
+    /// Runs `f` on the connection from tokio's blocking pool.
+    pub(crate) async fn with_connection<T, F>(&self, f: F) -> Result<T, RelayerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, RelayerError> + Send + 'static,
+    {
+        let connection = self.0.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().expect("sqlite connection lock poisoned");
+            f(&mut connection)
+        })
+        .await
+        .map_err(|e| RelayerError::Other(e.to_string()))?
+    }
+}
+
+/// Cursor store in a local SQLite file, opened in WAL mode. Each update runs
+/// in its own immediate transaction, so a crash leaves either the old or
+/// the new value.
+#[derive(Clone)]
+pub struct SqliteCache {
+    connection: SqliteConnection,
+}
+
+impl SqliteCache {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RelayerError> {
+        let shared = open_sqlite(path)?;
+        let connection = shared.lock();
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS cursors (
+                     key TEXT PRIMARY KEY,
+                     value INTEGER NOT NULL,
+                     updated_at INTEGER NOT NULL DEFAULT (unixepoch()),
+                     fence INTEGER NOT NULL DEFAULT 0
+                 );",
+            )
+            .map_err(sqlite_err)?;
+        let has_fence: bool = connection
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('cursors') WHERE name = 'fence'",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(sqlite_err)?;
+        if !has_fence {
+            connection
+                .execute(
+                    "ALTER TABLE cursors ADD COLUMN fence INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )
+                .map_err(sqlite_err)?;
+        }
+        drop(connection);
+        Ok(SqliteCache { connection: shared })
+    }
+
+    pub fn journal_mode(&self) -> Result<String, RelayerError> {
+        self.connection
+            .lock()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .map_err(sqlite_err)
+    }
+}
+
+#[async_trait]
+impl CacheTrait for SqliteCache {
+    async fn get_offset(&mut self, key: &str) -> Result<Option<u64>, RelayerError> {
+        let key = key.to_string();
+        self.connection
+            .with_connection(move |connection| {
+                let value: Option<i64> = connection
+                    .query_row(
+                        "SELECT value FROM cursors WHERE key = ?1",
+                        params![key],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(sqlite_err)?;
+                Ok(value.map(|value| value as u64))
+            })
+            .await
+    }
+
+    async fn set_last_offset(&mut self, key: &str, value: u64) -> Result<(), RelayerError> {
+        let key = key.to_string();
+        let value = i64::try_from(value)
+            .map_err(|_| RelayerError::SqliteError(format!("Offset {value} is too large")))?;
+        self.connection
+            .with_connection(move |connection| {
+                let tx = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(sqlite_err)?;
+                tx.execute(
+                    "INSERT INTO cursors (key, value) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = unixepoch()",
+                    params![key, value],
+                )
+                .map_err(sqlite_err)?;
+                tx.commit().map_err(sqlite_err)
+            })
+            .await
+    }
+
+    async fn delete_offset(&mut self, key: &str) -> Result<(), RelayerError> {
+        let key = key.to_string();
+        self.connection
+            .with_connection(move |connection| {
+                connection
+                    .execute("DELETE FROM cursors WHERE key = ?1", params![key])
+                    .map_err(sqlite_err)?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn set_fenced_offset(
+        &mut self,
+        key: &str,
+        value: u64,
+        token: u64,
+    ) -> Result<(), RelayerError> {
+        let key = key.to_string();
+        let stored_value = i64::try_from(value)
+            .map_err(|_| RelayerError::SqliteError(format!("Offset {value} is too large")))?;
+        let stored_token = i64::try_from(token)
+            .map_err(|_| RelayerError::SqliteError(format!("Token {token} is too large")))?;
+        self.connection
+            .with_connection(move |connection| {
+                let tx = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(sqlite_err)?;
+                let current: Option<i64> = tx
+                    .query_row(
+                        "SELECT fence FROM cursors WHERE key = ?1",
+                        params![key],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(sqlite_err)?;
+                let current = current.unwrap_or(0);
+                if stored_token < current {
+                    return Err(RelayerError::FencedOut {
+                        token,
+                        current: current as u64,
+                    });
+                }
+                tx.execute(
+                    "INSERT INTO cursors (key, value, fence) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value,
+                     fence = excluded.fence, updated_at = unixepoch()",
+                    params![key, stored_value, stored_token],
+                )
+                .map_err(sqlite_err)?;
+                tx.commit().map_err(sqlite_err)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wal_mode_and_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cursor.db");
+        {
+            let mut cache = SqliteCache::open(&path).unwrap();
+            assert_eq!(cache.journal_mode().unwrap(), "wal");
+            cache.set_last_offset("from_block", 42).await.unwrap();
+        }
+        let mut cache = SqliteCache::open(&path).unwrap();
+        assert_eq!(cache.get_last_offset("from_block").await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_offset_out_of_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = SqliteCache::open(dir.path().join("cursor.db")).unwrap();
+        let err = cache.set_last_offset("k", u64::MAX).await.unwrap_err();
+        assert!(matches!(err, RelayerError::SqliteError(_)));
+    }
+}
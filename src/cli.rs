@@ -0,0 +1,282 @@
+use crate::{
+    cache::CacheBackend, config::RelayerConfig, errors::RelayerError, queue::QueueBackend,
+};
+use thiserror::Error;
+
+pub const USAGE: &str = "\
+Usage: relayer <command>
+
+Commands:
+  subscribe                  Publish source chain deposits to the queue
+  include                    Mint queued deposits on the destination chain
+  run-all                    Run the subscriber and the includer in one process
+  backfill --from N --to M   Publish the deposits of blocks N..=M again
+  cursor get                 Print the subscriber cursor
+  cursor set <block>         Move the subscriber cursor
+  dlq list [--limit N]       List dead-lettered messages
+  dlq replay <id>            Move a dead letter back to the queue
+  deposit status <id>        Print the lifecycle of a deposit
+  config check               Validate the configuration
+
+Settings are read from relayer.toml (or RELAYER_CONFIG) and the environment.";
+
+/// Dead letters listed by `dlq list` without `--limit`.
+pub const DEFAULT_DLQ_LIMIT: usize = 50;
+
+/// Exit statuses, following the BSD `sysexits.h` codes.
+pub mod exit {
+    pub const OK: u8 = 0;
+    /// The command ran but found nothing, e.g. an unknown deposit id.
+    pub const NOT_FOUND: u8 = 1;
+    pub const USAGE: u8 = 64;
+    pub const UNAVAILABLE: u8 = 69;
+    pub const SOFTWARE: u8 = 70;
+    pub const IO: u8 = 74;
+    pub const CONFIG: u8 = 78;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Help,
+    Subscribe,
+    Include,
+    RunAll,
+    Backfill { from: u64, to: u64 },
+    CursorGet,
+    CursorSet { block: u64 },
+    DlqList { limit: usize },
+    DlqReplay { id: String },
+    DepositStatus { id: String },
+    ConfigCheck,
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("{0}")]
+pub struct UsageError(pub String);
+
+impl Command {
+    /// Parses the arguments after the program name.
+    pub fn parse<I, S>(args: I) -> Result<Command, UsageError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let args: Vec<String> = args.into_iter().map(Into::into).collect();
+        let words: Vec<&str> = args.iter().map(String::as_str).collect();
+        match words.as_slice() {
+            [] | ["help" | "-h" | "--help"] => Ok(Command::Help),
+            ["subscribe"] => Ok(Command::Subscribe),
+            ["include"] => Ok(Command::Include),
+            ["run-all"] => Ok(Command::RunAll),
+            ["backfill", flags @ ..] => {
+                let mut flags = Flags::parse(flags, &["--from", "--to"])?;
+                let from = number(&flags.take("--from")?, "--from")?;
+                let to = number(&flags.take("--to")?, "--to")?;
+                if from > to {
+                    return Err(UsageError(format!("--from {from} is after --to {to}")));
+                }
+                Ok(Command::Backfill { from, to })
+            }
+            ["cursor", "get"] => Ok(Command::CursorGet),
+            ["cursor", "set", block] => Ok(Command::CursorSet {
+                block: number(block, "block")?,
+            }),
+            ["dlq", "list", flags @ ..] => {
+                let mut flags = Flags::parse(flags, &["--limit"])?;
+                let limit = match flags.take("--limit") {
+                    Ok(limit) => number(&limit, "--limit")? as usize,
+                    Err(_) => DEFAULT_DLQ_LIMIT,
+                };
+                Ok(Command::DlqList { limit })
+            }
+            ["dlq", "replay", id] => Ok(Command::DlqReplay { id: id.to_string() }),
+            ["deposit", "status", id] => Ok(Command::DepositStatus { id: id.to_string() }),
+            ["config", "check"] => Ok(Command::ConfigCheck),
+            _ => Err(UsageError(format!("Unknown command: {}", words.join(" ")))),
+        }
+    }
+}
+
+/// `--name value` pairs, in any order.
+struct Flags(Vec<(String, String)>);
+
+impl Flags {
+    fn parse(args: &[&str], known: &[&str]) -> Result<Self, UsageError> {
+        let mut flags = Vec::new();
+        let mut args = args.iter();
+        while let Some(name) = args.next() {
+            if !known.contains(name) {
+                return Err(UsageError(format!("Unknown option: {name}")));
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| UsageError(format!("{name} needs a value")))?;
+            flags.push((name.to_string(), value.to_string()));
+        }
+        Ok(Flags(flags))
+    }
+
+    fn take(&mut self, name: &str) -> Result<String, UsageError> {
+        let index = self
+            .0
+            .iter()
+            .position(|(flag, _)| flag == name)
+            .ok_or_else(|| UsageError(format!("{name} is required")))?;
+        Ok(self.0.remove(index).1)
+    }
+}
+
+fn number(value: &str, name: &str) -> Result<u64, UsageError> {
+    value
+        .parse()
+        .map_err(|_| UsageError(format!("{name} must be a number, got {value}")))
+}
+
+/// Exit status for a failed command.
+pub fn exit_code(error: &eyre::Report) -> u8 {
+    if error.downcast_ref::<UsageError>().is_some() {
+        return exit::USAGE;
+    }
+    match error.downcast_ref::<RelayerError>() {
+        Some(RelayerError::ConfigError(_) | RelayerError::QueueConfigMismatch(_)) => exit::CONFIG,
+        Some(
+            RelayerError::ProviderError(_)
+            | RelayerError::RedisError(_)
+            | RelayerError::SqliteError(_)
+            | RelayerError::AmqpError(_)
+            | RelayerError::StreamError(_),
+        ) => exit::UNAVAILABLE,
+        Some(RelayerError::FsStdIOError(_)) => exit::IO,
+        _ => exit::SOFTWARE,
+    }
+}
+
+/// Settings the components need that `config` lacks, for `config check`.
+/// The Redis URL is only required by the backends that use it.
+pub fn config_problems(
+    config: &RelayerConfig,
+    cache: CacheBackend,
+    queue: QueueBackend,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut check = |res: Result<(), RelayerError>| {
+        if let Err(e) = res {
+            problems.push(e.to_string());
+        }
+    };
+    check(config.source_contract().map(drop));
+    check(config.destination_contract().map(drop));
+    check(config.private_key().map(drop));
+    if cache == CacheBackend::Redis || queue == QueueBackend::Redis {
+        check(config.redis_url().map(drop));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+    #[test]
+    fn test_parse_commands() {
+        let none: [&str; 0] = [];
+        assert_eq!(Command::parse(none), Ok(Command::Help));
+        assert_eq!(Command::parse(["run-all"]), Ok(Command::RunAll));
+        assert_eq!(
+            Command::parse(["backfill", "--to", "20", "--from", "10"]),
+            Ok(Command::Backfill { from: 10, to: 20 })
+        );
+        assert_eq!(
+            Command::parse(["cursor", "set", "42"]),
+            Ok(Command::CursorSet { block: 42 })
+        );
+        assert_eq!(
+            Command::parse(["dlq", "list"]),
+            Ok(Command::DlqList {
+                limit: DEFAULT_DLQ_LIMIT
+            })
+        );
+        assert_eq!(
+            Command::parse(["dlq", "list", "--limit", "5"]),
+            Ok(Command::DlqList { limit: 5 })
+        );
+        assert_eq!(
+            Command::parse(["deposit", "status", "0xab:1"]),
+            Ok(Command::DepositStatus {
+                id: "0xab:1".to_string()
+            })
+        );
+        assert_eq!(
+            Command::parse(["config", "check"]),
+            Ok(Command::ConfigCheck)
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_usage() {
+        for args in [
+            &["frobnicate"][..],
+            &["backfill", "--from", "10"],
+            &["backfill", "--from", "10", "--to"],
+            &["backfill", "--from", "20", "--to", "10"],
+            &["backfill", "--from", "x", "--to", "10"],
+            &["backfill", "--from", "1", "--to", "2", "--step", "1"],
+            &["cursor", "set"],
+            &["dlq", "replay"],
+        ] {
+            assert!(Command::parse(args.iter().copied()).is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let code = |e: RelayerError| exit_code(&eyre::Report::new(e));
+        assert_eq!(code(RelayerError::ConfigError("x".into())), exit::CONFIG);
+        assert_eq!(
+            code(RelayerError::RedisError("x".into())),
+            exit::UNAVAILABLE
+        );
+        assert_eq!(code(RelayerError::Other("x".into())), exit::SOFTWARE);
+        assert_eq!(
+            exit_code(&eyre::Report::new(UsageError("x".into()))),
+            exit::USAGE
+        );
+    }
+
+    #[test]
+    fn test_config_problems() {
+        let config = RelayerConfig::from_toml(
+            r#"
+            [source]
+            rpc_url = "http://localhost:8545"
+            contract = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+            "#,
+            |_| None,
+        )
+        .unwrap();
+        let problems = config_problems(&config, CacheBackend::Sqlite, QueueBackend::Amqp);
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("DST_RPC"));
+        assert!(problems[1].contains("PRIVATE_KEY"));
+
+        let config = RelayerConfig::from_toml(
+            r#"
+            [source]
+            rpc_url = "http://localhost:8545"
+            contract = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+
+            [destination]
+            rpc_url = "http://localhost:8546"
+            contract = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+            "#,
+            |name| (name == "PRIVATE_KEY").then(|| KEY.to_string()),
+        )
+        .unwrap();
+        assert!(config_problems(&config, CacheBackend::Sqlite, QueueBackend::Amqp).is_empty());
+        let problems = config_problems(&config, CacheBackend::Redis, QueueBackend::Amqp);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("DB_URL"));
+    }
+}
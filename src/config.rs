@@ -0,0 +1,1169 @@
+use crate::cache::CacheBackend;
+use crate::cursor::StartBlock;
+use crate::errors::RelayerError;
+use crate::health::{DEFAULT_MAX_LAG_BLOCKS, DEFAULT_MAX_TICK_AGE};
+use crate::leader::{DEFAULT_LEASE_TTL, LeaderElection};
+use crate::quarantine::DEFAULT_QUARANTINE_PATH;
+use crate::queue::file::{FileQueueOptions, FsyncPolicy};
+use crate::queue::{QueueBackend, QueueKind, QueueOptions, QueueTopology, parse_exchange_kind};
+use crate::schedule::{DEFAULT_MAX_POLL_INTERVAL, DEFAULT_POLL_INTERVAL, PollSchedule};
+use crate::shutdown::DEFAULT_SHUTDOWN_GRACE;
+use crate::source_verifier::DEFAULT_MIN_CONFIRMATIONS;
+use crate::utils::{LogFormat, get_dst_contract_addr, get_src_contract_addr};
+use alloy::hex;
+use alloy::primitives::Address;
+use alloy::signers::local::PrivateKeySigner;
+use alloy::transports::http::reqwest::Url;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+pub const DEFAULT_CONFIG_PATH: &str = "relayer.toml";
+pub const DEFAULT_DEPLOYMENTS_PATH: &str = "../project_eth/data/deployments.json";
+pub const DEFAULT_TOKEN_DATA_PATH: &str = "../project_eth/data/TokenData.json";
+pub const DEFAULT_AMQP_ADDR: &str = "amqp://127.0.0.1:5672/%2f";
+pub const DEFAULT_QUEUE_DIR: &str = "relayer-queue";
+pub const DEFAULT_SQLITE_PATH: &str = "relayer.db";
+pub const DEFAULT_STREAM_HOST: &str = "localhost";
+pub const DEFAULT_STREAM_PORT: u16 = 5552;
+
+/// `relayer.toml` as written, before env overrides and validation.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    #[serde(default)]
+    source: FileChain,
+    #[serde(default)]
+    destination: FileChain,
+    #[serde(default)]
+    paths: FilePaths,
+    #[serde(default)]
+    subscriber: FileSubscriber,
+    #[serde(default)]
+    includer: FileIncluder,
+    #[serde(default)]
+    verifier: FileVerifier,
+    #[serde(default)]
+    redis: FileRedis,
+    #[serde(default)]
+    amqp: FileAmqp,
+    #[serde(default)]
+    stream: FileStream,
+    #[serde(default)]
+    queue: FileQueueConfig,
+    #[serde(default)]
+    cache: FileCache,
+    #[serde(default)]
+    leader: FileLeader,
+    #[serde(default)]
+    signing: FileSigning,
+    #[serde(default)]
+    health: FileHealth,
+    #[serde(default)]
+    shutdown: FileShutdown,
+    #[serde(default)]
+    log: FileLog,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileChain {
+    rpc_url: Option<String>,
+    contract: Option<String>,
+    poll_interval_ms: Option<u64>,
+    max_poll_interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FilePaths {
+    deployments: Option<PathBuf>,
+    token_data: Option<PathBuf>,
+    queue_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileSubscriber {
+    start_block: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileIncluder {
+    private_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileVerifier {
+    enabled: Option<bool>,
+    min_confirmations: Option<u64>,
+    quarantine_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileRedis {
+    url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileAmqp {
+    addr: Option<String>,
+    vhost: Option<String>,
+    exchange: Option<String>,
+    exchange_type: Option<String>,
+    queue: Option<String>,
+    routing_keys: Option<Vec<String>>,
+    consumer_tag: Option<String>,
+    dead_letter_queue: Option<String>,
+    queue_type: Option<String>,
+    durable: Option<bool>,
+    message_ttl_ms: Option<u32>,
+    max_length: Option<u32>,
+    overflow: Option<String>,
+    dead_letter_exchange: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileStream {
+    host: Option<String>,
+    port: Option<u16>,
+    replay_from: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileQueueConfig {
+    backend: Option<String>,
+    fsync: Option<String>,
+    max_segment_bytes: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileCache {
+    backend: Option<String>,
+    sqlite_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileLeader {
+    election: Option<String>,
+    lease_ms: Option<u64>,
+    lock_dir: Option<PathBuf>,
+    id: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileSigning {
+    hmac_key: Option<String>,
+    signing_key: Option<String>,
+    signer_address: Option<String>,
+    require_signatures: Option<bool>,
+    max_message_age_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileHealth {
+    addr: Option<String>,
+    max_tick_age_secs: Option<u64>,
+    max_lag_blocks: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileShutdown {
+    grace_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct FileLog {
+    format: Option<String>,
+    level: Option<String>,
+}
+
+/// One side of the bridge. The contract is read from the deployments file
+/// when not given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainConfig {
+    pub rpc_url: Url,
+    pub contract: Option<Address>,
+    /// How often to poll the chain while there is work, best matched to
+    /// its block time.
+    pub poll_interval: Option<Duration>,
+    /// Longest wait between polls while idle.
+    pub max_poll_interval: Option<Duration>,
+}
+
+impl ChainConfig {
+    /// Polling for this chain, with the defaults for what is not set.
+    pub fn poll_schedule(&self) -> PollSchedule {
+        PollSchedule::new(
+            self.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            self.max_poll_interval.unwrap_or(DEFAULT_MAX_POLL_INTERVAL),
+        )
+    }
+}
+
+/// Leader election among replicas of one component.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaderConfig {
+    pub election: LeaderElection,
+    /// How long a Redis lease lasts without renewal.
+    pub lease_ttl: Duration,
+    /// Where file leases keep their lock files. Required with file
+    /// election, since the files hold the fencing counters.
+    pub lock_dir: Option<PathBuf>,
+    /// Holder id of a Redis lease, unique per process by default.
+    pub holder_id: Option<String>,
+}
+
+/// Checking each deposit against the source chain before minting it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifierConfig {
+    pub enabled: bool,
+    pub min_confirmations: u64,
+    /// Where refused deposits are recorded for review.
+    pub quarantine_path: PathBuf,
+}
+
+/// Keys for signing queue messages and checking their signatures. An HMAC
+/// key is used on both sides and wins over the ECDSA settings.
+#[derive(Debug, Clone, Default)]
+pub struct SigningConfig {
+    pub hmac_key: Option<Vec<u8>>,
+    pub signing_key: Option<PrivateKeySigner>,
+    pub signer_address: Option<Address>,
+    /// Refuse to start without the keys to sign or check messages.
+    pub require_signatures: bool,
+    /// Older messages are rejected by the includer. Unlimited when unset.
+    pub max_message_age: Option<Duration>,
+}
+
+/// The HTTP endpoint and the thresholds of `/healthz` and `/readyz`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthConfig {
+    /// No endpoint is served when unset.
+    pub addr: Option<SocketAddr>,
+    pub max_tick_age: Duration,
+    pub max_lag: u64,
+}
+
+/// Settings shared by the subscriber and the includer, loaded from a TOML
+/// file and overridden by env vars. Everything present is validated on
+/// load; settings only one component needs are checked by their accessor.
+#[derive(Debug, Clone)]
+pub struct RelayerConfig {
+    pub source: Option<ChainConfig>,
+    pub destination: Option<ChainConfig>,
+    pub deployments_path: PathBuf,
+    pub token_data_path: PathBuf,
+    /// Directory of the file queue `relayer run-all` connects its tasks with.
+    pub queue_dir: PathBuf,
+    /// How the file queue in `queue_dir` syncs and splits its log.
+    pub file_queue: FileQueueOptions,
+    /// Where the subscriber starts on a route without a cursor.
+    pub start_block: StartBlock,
+    pub private_key: Option<PrivateKeySigner>,
+    pub verifier: VerifierConfig,
+    pub redis_url: Option<String>,
+    pub amqp_addr: String,
+    /// Arguments the AMQP queue is declared with.
+    pub queue_options: QueueOptions,
+    pub topology: QueueTopology,
+    pub stream_host: String,
+    pub stream_port: u16,
+    /// When set, stream consumers read from this offset on, without storing
+    /// their progress, to replay the deposit history.
+    pub stream_replay_from: Option<u64>,
+    pub queue_backend: QueueBackend,
+    /// `None` lets each command pick its default.
+    pub cache_backend: Option<CacheBackend>,
+    pub sqlite_path: PathBuf,
+    pub leader: LeaderConfig,
+    pub signing: SigningConfig,
+    pub health: HealthConfig,
+    pub shutdown_grace: Duration,
+    pub log_format: LogFormat,
+    pub log_level: LevelFilter,
+}
+
+impl RelayerConfig {
+    /// Loads the file named by `RELAYER_CONFIG`, or `relayer.toml` if it
+    /// exists, and applies the env overrides listed in
+    /// `relayer.toml_template`. Without a file, env vars alone are used.
+    pub fn load() -> Result<Self, RelayerError> {
+        let path = match std::env::var("RELAYER_CONFIG") {
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|p| p.exists()),
+        };
+        Self::load_from(path.as_deref(), |name| std::env::var(name).ok())
+    }
+
+    /// Loads `path`, if any, with overrides looked up through `env`.
+    pub fn load_from(
+        path: Option<&Path>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, RelayerError> {
+        let contents = match path {
+            Some(path) => std::fs::read_to_string(path).map_err(|e| {
+                RelayerError::ConfigError(format!("cannot read {}: {e}", path.display()))
+            })?,
+            None => String::new(),
+        };
+        Self::from_toml(&contents, env)
+    }
+
+    pub fn from_toml(
+        contents: &str,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, RelayerError> {
+        let mut file: FileConfig =
+            toml::from_str(contents).map_err(|e| RelayerError::ConfigError(e.to_string()))?;
+        let var = |name: &str| env(name).filter(|v| !v.is_empty());
+        let overrides = [
+            ("SRC_RPC", &mut file.source.rpc_url),
+            ("SRC_CONTRACT", &mut file.source.contract),
+            ("DST_RPC", &mut file.destination.rpc_url),
+            ("DST_CONTRACT", &mut file.destination.contract),
+            ("PRIVATE_KEY", &mut file.includer.private_key),
+            ("DB_URL", &mut file.redis.url),
+            ("AMQP_ADDR", &mut file.amqp.addr),
+            ("AMQP_VHOST", &mut file.amqp.vhost),
+            ("AMQP_EXCHANGE", &mut file.amqp.exchange),
+            ("AMQP_EXCHANGE_TYPE", &mut file.amqp.exchange_type),
+            ("AMQP_QUEUE", &mut file.amqp.queue),
+            ("AMQP_CONSUMER_TAG", &mut file.amqp.consumer_tag),
+            ("AMQP_DEAD_LETTER_QUEUE", &mut file.amqp.dead_letter_queue),
+            ("QUEUE_TYPE", &mut file.amqp.queue_type),
+            ("QUEUE_OVERFLOW", &mut file.amqp.overflow),
+            (
+                "QUEUE_DEAD_LETTER_EXCHANGE",
+                &mut file.amqp.dead_letter_exchange,
+            ),
+            ("STREAM_HOST", &mut file.stream.host),
+            ("QUEUE_BACKEND", &mut file.queue.backend),
+            ("QUEUE_FSYNC", &mut file.queue.fsync),
+            ("CACHE_BACKEND", &mut file.cache.backend),
+            ("LEADER_ELECTION", &mut file.leader.election),
+            ("LEADER_ID", &mut file.leader.id),
+            ("MESSAGE_HMAC_KEY", &mut file.signing.hmac_key),
+            ("MESSAGE_SIGNING_KEY", &mut file.signing.signing_key),
+            ("MESSAGE_SIGNER_ADDRESS", &mut file.signing.signer_address),
+            ("HTTP_ADDR", &mut file.health.addr),
+            ("LOG_FORMAT", &mut file.log.format),
+            ("START_BLOCK", &mut file.subscriber.start_block),
+        ];
+        for (name, setting) in overrides {
+            if let Some(value) = var(name) {
+                *setting = Some(value);
+            }
+        }
+        let millis = [
+            ("SRC_POLL_INTERVAL_MS", &mut file.source.poll_interval_ms),
+            (
+                "SRC_MAX_POLL_INTERVAL_MS",
+                &mut file.source.max_poll_interval_ms,
+            ),
+            (
+                "DST_POLL_INTERVAL_MS",
+                &mut file.destination.poll_interval_ms,
+            ),
+            (
+                "DST_MAX_POLL_INTERVAL_MS",
+                &mut file.destination.max_poll_interval_ms,
+            ),
+            ("LEADER_LEASE_MS", &mut file.leader.lease_ms),
+        ];
+        for (name, setting) in millis {
+            override_parsed(name, var(name), "milliseconds", setting)?;
+        }
+        let secs = [
+            (
+                "HEALTH_MAX_TICK_AGE_SECS",
+                &mut file.health.max_tick_age_secs,
+            ),
+            ("SHUTDOWN_GRACE_SECS", &mut file.shutdown.grace_secs),
+        ];
+        for (name, setting) in secs {
+            override_parsed(name, var(name), "seconds", setting)?;
+        }
+        let name = "REQUIRE_MESSAGE_SIGNATURES";
+        override_parsed(
+            name,
+            var(name),
+            "true or false",
+            &mut file.signing.require_signatures,
+        )?;
+        let name = "MAX_MESSAGE_AGE_SECS";
+        override_parsed(
+            name,
+            var(name),
+            "seconds",
+            &mut file.signing.max_message_age_secs,
+        )?;
+        let name = "READY_MAX_LAG_BLOCKS";
+        override_parsed(name, var(name), "a number", &mut file.health.max_lag_blocks)?;
+        let name = "QUEUE_MESSAGE_TTL_MS";
+        override_parsed(
+            name,
+            var(name),
+            "milliseconds",
+            &mut file.amqp.message_ttl_ms,
+        )?;
+        let name = "QUEUE_MAX_LENGTH";
+        override_parsed(name, var(name), "a number", &mut file.amqp.max_length)?;
+        let name = "QUEUE_DURABLE";
+        override_parsed(name, var(name), "true or false", &mut file.amqp.durable)?;
+        let name = "SOURCE_MIN_CONFIRMATIONS";
+        override_parsed(
+            name,
+            var(name),
+            "a number",
+            &mut file.verifier.min_confirmations,
+        )?;
+        let name = "VERIFY_SOURCE_DEPOSITS";
+        let value = var(name).map(|value| match value.as_str() {
+            "1" => String::from("true"),
+            "0" => String::from("false"),
+            _ => value,
+        });
+        override_parsed(
+            name,
+            value,
+            "true, false, 1 or 0",
+            &mut file.verifier.enabled,
+        )?;
+        let name = "STREAM_PORT";
+        override_parsed(name, var(name), "a port", &mut file.stream.port)?;
+        let name = "QUEUE_MAX_SEGMENT_BYTES";
+        override_parsed(
+            name,
+            var(name),
+            "a number of bytes",
+            &mut file.queue.max_segment_bytes,
+        )?;
+        let name = "STREAM_REPLAY_FROM";
+        override_parsed(name, var(name), "an offset", &mut file.stream.replay_from)?;
+        if let Some(keys) = var("AMQP_ROUTING_KEYS") {
+            file.amqp.routing_keys = Some(keys.split(',').map(str::to_string).collect());
+        }
+        if let Some(path) = var("DEPLOYMENTS_PATH") {
+            file.paths.deployments = Some(path.into());
+        }
+        if let Some(path) = var("TOKEN_DATA_PATH") {
+            file.paths.token_data = Some(path.into());
+        }
+        if let Some(path) = var("QUEUE_DIR") {
+            file.paths.queue_dir = Some(path.into());
+        }
+        if let Some(path) = var("CACHE_SQLITE_PATH") {
+            file.cache.sqlite_path = Some(path.into());
+        }
+        if let Some(path) = var("QUARANTINE_PATH") {
+            file.verifier.quarantine_path = Some(path.into());
+        }
+        if let Some(path) = var("LEADER_LOCK_DIR") {
+            file.leader.lock_dir = Some(path.into());
+        }
+        Self::validate(file)
+    }
+
+    /// Collects every problem instead of stopping at the first one.
+    fn validate(file: FileConfig) -> Result<Self, RelayerError> {
+        let mut problems = Vec::new();
+        let source = chain("source", file.source, &mut problems);
+        let destination = chain("destination", file.destination, &mut problems);
+        let private_key =
+            file.includer
+                .private_key
+                .and_then(|key| match key.parse::<PrivateKeySigner>() {
+                    Ok(signer) => Some(signer),
+                    Err(_) => {
+                        problems
+                            .push("includer.private_key: not a valid secp256k1 key".to_string());
+                        None
+                    }
+                });
+        let start_block = parsed(
+            "subscriber.start_block",
+            file.subscriber.start_block,
+            &mut problems,
+        )
+        .unwrap_or_default();
+        let verifier = VerifierConfig {
+            enabled: file.verifier.enabled.unwrap_or(false),
+            min_confirmations: file
+                .verifier
+                .min_confirmations
+                .unwrap_or(DEFAULT_MIN_CONFIRMATIONS),
+            quarantine_path: file
+                .verifier
+                .quarantine_path
+                .unwrap_or_else(|| DEFAULT_QUARANTINE_PATH.into()),
+        };
+        let redis_url = file.redis.url;
+        if let Some(url) = &redis_url
+            && redis::parse_redis_url(url).is_none()
+        {
+            problems.push(format!("redis.url: invalid Redis URL {url:?}"));
+        }
+        let amqp_addr = file
+            .amqp
+            .addr
+            .clone()
+            .unwrap_or_else(|| DEFAULT_AMQP_ADDR.into());
+        if !amqp_addr.starts_with("amqp://") && !amqp_addr.starts_with("amqps://") {
+            problems.push(format!(
+                "amqp.addr: expected an amqp:// URL, got {amqp_addr:?}"
+            ));
+        }
+        let (queue_options, topology) = amqp(file.amqp, &mut problems);
+        let queue_backend = parsed("queue.backend", file.queue.backend, &mut problems)
+            .unwrap_or(QueueBackend::Amqp);
+        let file_queue = file_queue(
+            file.queue.fsync,
+            file.queue.max_segment_bytes,
+            &mut problems,
+        );
+        let cache_backend = parsed("cache.backend", file.cache.backend, &mut problems);
+        let leader = leader(file.leader, &mut problems);
+        let signing = signing(file.signing, &mut problems);
+        let health = HealthConfig {
+            addr: parsed("health.addr", file.health.addr, &mut problems),
+            max_tick_age: file
+                .health
+                .max_tick_age_secs
+                .map_or(DEFAULT_MAX_TICK_AGE, Duration::from_secs),
+            max_lag: file.health.max_lag_blocks.unwrap_or(DEFAULT_MAX_LAG_BLOCKS),
+        };
+        let log_format = parsed("log.format", file.log.format, &mut problems).unwrap_or_default();
+        let log_level = file
+            .log
+            .level
+            .and_then(|level| match level.parse::<LevelFilter>() {
+                Ok(level) => Some(level),
+                Err(_) => {
+                    problems.push(format!("log.level: unknown level {level:?}"));
+                    None
+                }
+            })
+            .unwrap_or(LevelFilter::INFO);
+        if !problems.is_empty() {
+            return Err(RelayerError::ConfigError(problems.join("; ")));
+        }
+        Ok(RelayerConfig {
+            source,
+            destination,
+            deployments_path: file
+                .paths
+                .deployments
+                .unwrap_or_else(|| DEFAULT_DEPLOYMENTS_PATH.into()),
+            token_data_path: file
+                .paths
+                .token_data
+                .unwrap_or_else(|| DEFAULT_TOKEN_DATA_PATH.into()),
+            queue_dir: file
+                .paths
+                .queue_dir
+                .unwrap_or_else(|| DEFAULT_QUEUE_DIR.into()),
+            file_queue,
+            start_block,
+            private_key,
+            verifier,
+            redis_url,
+            amqp_addr,
+            queue_options,
+            topology,
+            stream_host: file
+                .stream
+                .host
+                .unwrap_or_else(|| DEFAULT_STREAM_HOST.into()),
+            stream_port: file.stream.port.unwrap_or(DEFAULT_STREAM_PORT),
+            stream_replay_from: file.stream.replay_from,
+            queue_backend,
+            cache_backend,
+            sqlite_path: file
+                .cache
+                .sqlite_path
+                .unwrap_or_else(|| DEFAULT_SQLITE_PATH.into()),
+            leader,
+            signing,
+            health,
+            shutdown_grace: file
+                .shutdown
+                .grace_secs
+                .map_or(DEFAULT_SHUTDOWN_GRACE, Duration::from_secs),
+            log_format,
+            log_level,
+        })
+    }
+
+    pub fn source(&self) -> Result<&ChainConfig, RelayerError> {
+        self.source
+            .as_ref()
+            .ok_or_else(|| missing("source.rpc_url", "SRC_RPC"))
+    }
+
+    pub fn destination(&self) -> Result<&ChainConfig, RelayerError> {
+        self.destination
+            .as_ref()
+            .ok_or_else(|| missing("destination.rpc_url", "DST_RPC"))
+    }
+
+    pub fn private_key(&self) -> Result<&PrivateKeySigner, RelayerError> {
+        self.private_key
+            .as_ref()
+            .ok_or_else(|| missing("includer.private_key", "PRIVATE_KEY"))
+    }
+
+    pub fn redis_url(&self) -> Result<&str, RelayerError> {
+        self.redis_url
+            .as_deref()
+            .ok_or_else(|| missing("redis.url", "DB_URL"))
+    }
+
+    /// The deposit contract, from `source.contract` or the deployments file.
+    pub fn source_contract(&self) -> Result<Address, RelayerError> {
+        match self.source()?.contract {
+            Some(contract) => Ok(contract),
+            None => get_src_contract_addr(&self.deployments_path.to_string_lossy()),
+        }
+    }
+
+    /// The token contract, from `destination.contract` or the deployments
+    /// file.
+    pub fn destination_contract(&self) -> Result<Address, RelayerError> {
+        match self.destination()?.contract {
+            Some(contract) => Ok(contract),
+            None => get_dst_contract_addr(&self.deployments_path.to_string_lossy()),
+        }
+    }
+}
+
+/// Replaces `setting` with the env var `name`, if set.
+fn override_parsed<T: FromStr>(
+    name: &str,
+    value: Option<String>,
+    expected: &str,
+    setting: &mut Option<T>,
+) -> Result<(), RelayerError> {
+    if let Some(value) = value {
+        let parsed = value.parse().map_err(|_| {
+            RelayerError::ConfigError(format!("{name}: expected {expected}, got {value:?}"))
+        })?;
+        *setting = Some(parsed);
+    }
+    Ok(())
+}
+
+/// Parses `value`, recording a problem with `setting` if it is invalid.
+fn parsed<T: FromStr>(setting: &str, value: Option<String>, problems: &mut Vec<String>) -> Option<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value.and_then(|value| match value.parse() {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            problems.push(format!("{setting}: {e} ({value:?})"));
+            None
+        }
+    })
+}
+
+fn amqp(file: FileAmqp, problems: &mut Vec<String>) -> (QueueOptions, QueueTopology) {
+    let defaults = QueueOptions::default();
+    let options = QueueOptions {
+        kind: parsed::<QueueKind>("amqp.queue_type", file.queue_type, problems)
+            .unwrap_or(defaults.kind),
+        durable: file.durable.unwrap_or(defaults.durable),
+        message_ttl_ms: file.message_ttl_ms,
+        max_length: file.max_length,
+        overflow: parsed("amqp.overflow", file.overflow, problems),
+        dead_letter_exchange: file.dead_letter_exchange,
+    };
+    if let Err(e) = options.validate() {
+        problems.push(format!("amqp: {e}"));
+    }
+    let defaults = QueueTopology::default();
+    let topology = QueueTopology {
+        vhost: file.vhost,
+        exchange: file.exchange.unwrap_or(defaults.exchange),
+        exchange_kind: file
+            .exchange_type
+            .map(|kind| parse_exchange_kind(&kind))
+            .unwrap_or(defaults.exchange_kind),
+        queue_name: file.queue.unwrap_or(defaults.queue_name),
+        routing_keys: file
+            .routing_keys
+            .map(|keys| {
+                keys.into_iter()
+                    .map(|key| key.trim().to_string())
+                    .filter(|key| !key.is_empty())
+                    .collect()
+            })
+            .unwrap_or(defaults.routing_keys),
+        consumer_tag: file.consumer_tag.unwrap_or(defaults.consumer_tag),
+        auto_delete: defaults.auto_delete,
+        dead_letter_queue: file.dead_letter_queue,
+    };
+    (options, topology)
+}
+
+fn file_queue(
+    fsync: Option<String>,
+    max_segment_bytes: Option<u64>,
+    problems: &mut Vec<String>,
+) -> FileQueueOptions {
+    if max_segment_bytes == Some(0) {
+        problems.push(String::from("queue.max_segment_bytes must be positive"));
+    }
+    let defaults = FileQueueOptions::default();
+    FileQueueOptions {
+        fsync: parsed::<FsyncPolicy>("queue.fsync", fsync, problems).unwrap_or(defaults.fsync),
+        max_segment_bytes: max_segment_bytes.unwrap_or(defaults.max_segment_bytes),
+        ..defaults
+    }
+}
+
+fn leader(file: FileLeader, problems: &mut Vec<String>) -> LeaderConfig {
+    if file.lease_ms == Some(0) {
+        problems.push(String::from("leader.lease_ms must be positive"));
+    }
+    let election =
+        parsed("leader.election", file.election, problems).unwrap_or(LeaderElection::None);
+    if election == LeaderElection::File && file.lock_dir.is_none() {
+        problems.push(String::from(
+            "leader.lock_dir is required with file election",
+        ));
+    }
+    LeaderConfig {
+        election,
+        lease_ttl: file
+            .lease_ms
+            .map_or(DEFAULT_LEASE_TTL, Duration::from_millis),
+        lock_dir: file.lock_dir,
+        holder_id: file.id,
+    }
+}
+
+fn signing(file: FileSigning, problems: &mut Vec<String>) -> SigningConfig {
+    let hmac_key = file.hmac_key.and_then(|key| match hex::decode(key) {
+        Ok(key) if key.len() >= 32 => Some(key),
+        Ok(_) => {
+            problems.push("signing.hmac_key must be at least 32 bytes".to_string());
+            None
+        }
+        Err(_) => {
+            problems.push("signing.hmac_key: not a hex string".to_string());
+            None
+        }
+    });
+    let signing_key = file
+        .signing_key
+        .and_then(|key| match key.parse::<PrivateKeySigner>() {
+            Ok(signer) => Some(signer),
+            Err(_) => {
+                problems.push("signing.signing_key: not a valid secp256k1 key".to_string());
+                None
+            }
+        });
+    let signer_address = parsed("signing.signer_address", file.signer_address, problems);
+    let require_signatures = file.require_signatures.unwrap_or(false);
+    if require_signatures && hmac_key.is_none() && signing_key.is_none() && signer_address.is_none()
+    {
+        problems.push(
+            "signing.require_signatures is set but no signing.hmac_key, signing.signing_key \
+             or signing.signer_address is"
+                .to_string(),
+        );
+    }
+    if file.max_message_age_secs == Some(0) {
+        problems.push(String::from(
+            "signing.max_message_age_secs must be positive",
+        ));
+    }
+    SigningConfig {
+        hmac_key,
+        signing_key,
+        signer_address,
+        require_signatures,
+        max_message_age: file.max_message_age_secs.map(Duration::from_secs),
+    }
+}
+
+fn missing(setting: &str, var: &str) -> RelayerError {
+    RelayerError::ConfigError(format!(
+        "{setting} is not set (set it in the config file or {var})"
+    ))
+}
+
+fn chain(section: &str, file: FileChain, problems: &mut Vec<String>) -> Option<ChainConfig> {
+    let contract = file.contract.and_then(|c| match c.parse::<Address>() {
+        Ok(address) => Some(address),
+        Err(_) => {
+            problems.push(format!("{section}.contract: invalid address {c:?}"));
+            None
+        }
+    });
+    if file.poll_interval_ms == Some(0) {
+        problems.push(format!("{section}.poll_interval_ms must be positive"));
+    }
+    if let (Some(interval), Some(max)) = (file.poll_interval_ms, file.max_poll_interval_ms)
+        && max < interval
+    {
+        problems.push(format!(
+            "{section}.max_poll_interval_ms ({max}) is below {section}.poll_interval_ms ({interval})"
+        ));
+    }
+    let Some(rpc_url) = file.rpc_url else {
+        if contract.is_some() {
+            problems.push(format!(
+                "{section}.contract is set but {section}.rpc_url is not"
+            ));
+        }
+        return None;
+    };
+    match rpc_url.parse::<Url>() {
+        Ok(rpc_url) => Some(ChainConfig {
+            rpc_url,
+            contract,
+            poll_interval: file.poll_interval_ms.map(Duration::from_millis),
+            max_poll_interval: file.max_poll_interval_ms.map(Duration::from_millis),
+        }),
+        Err(e) => {
+            problems.push(format!("{section}.rpc_url: {e} ({rpc_url:?})"));
+            None
+        }
+    }
+}
+
+/// A mocked destination with the zero contract and the first Hardhat key,
+/// read without the environment.
+#[cfg(test)]
+pub(crate) fn test_config() -> RelayerConfig {
+    RelayerConfig::from_toml(
+        r#"
+        [destination]
+        rpc_url = "http://localhost:8546"
+        contract = "0x0000000000000000000000000000000000000000"
+
+        [includer]
+        private_key = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+        "#,
+        |_| None,
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const KEY: &str = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn test_loads_file_with_defaults() {
+        let config = RelayerConfig::from_toml(
+            r#"
+            [source]
+            rpc_url = "http://localhost:8545"
+            contract = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+
+            [destination]
+            rpc_url = "http://localhost:8546"
+
+            [includer]
+            private_key = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+            "#,
+            no_env,
+        )
+        .unwrap();
+        assert_eq!(config.source().unwrap().rpc_url.port(), Some(8545));
+        assert_eq!(
+            config.source_contract().unwrap(),
+            "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+                .parse::<Address>()
+                .unwrap()
+        );
+        assert_eq!(config.destination().unwrap().contract, None);
+        assert_eq!(
+            config.deployments_path,
+            PathBuf::from(DEFAULT_DEPLOYMENTS_PATH)
+        );
+        assert_eq!(config.amqp_addr, DEFAULT_AMQP_ADDR);
+        assert_eq!(config.queue_dir, PathBuf::from(DEFAULT_QUEUE_DIR));
+        assert!(config.private_key().is_ok());
+        assert!(config.redis_url().is_err());
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let env: HashMap<&str, String> = HashMap::from([
+            ("DST_RPC", "http://dst:8546".to_string()),
+            ("PRIVATE_KEY", KEY.to_string()),
+            ("DB_URL", "redis://cache/".to_string()),
+            ("TOKEN_DATA_PATH", "/etc/relayer/token.json".to_string()),
+            ("SRC_CONTRACT", String::new()),
+        ]);
+        let config = RelayerConfig::from_toml(
+            r#"
+            [destination]
+            rpc_url = "http://localhost:8546"
+
+            [redis]
+            url = "redis://127.0.0.1/"
+            "#,
+            |name| env.get(name).cloned(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.destination().unwrap().rpc_url.host_str(),
+            Some("dst")
+        );
+        assert_eq!(config.redis_url().unwrap(), "redis://cache/");
+        assert_eq!(
+            config.token_data_path,
+            PathBuf::from("/etc/relayer/token.json")
+        );
+        // Empty variables do not override anything.
+        assert!(config.source.is_none());
+        let err = config.source().unwrap_err().to_string();
+        assert!(err.contains("SRC_RPC"), "{err}");
+    }
+
+    #[test]
+    fn test_reports_every_problem() {
+        let err = RelayerConfig::from_toml(
+            r#"
+            [source]
+            rpc_url = "not a url"
+
+            [destination]
+            contract = "0x1234"
+
+            [includer]
+            private_key = "0xnope"
+
+            [amqp]
+            addr = "http://rabbit"
+
+            [leader]
+            election = "file"
+            "#,
+            no_env,
+        )
+        .unwrap_err()
+        .to_string();
+        for setting in [
+            "source.rpc_url",
+            "destination.contract",
+            "includer.private_key",
+            "amqp.addr",
+            "leader.lock_dir",
+        ] {
+            assert!(err.contains(setting), "{setting} missing from {err}");
+        }
+    }
+
+    #[test]
+    fn test_poll_intervals_per_chain() {
+        let env: HashMap<&str, String> =
+            HashMap::from([("DST_POLL_INTERVAL_MS", "12000".to_string())]);
+        let config = RelayerConfig::from_toml(
+            r#"
+            [source]
+            rpc_url = "http://localhost:8545"
+            poll_interval_ms = 250
+            max_poll_interval_ms = 5000
+
+            [destination]
+            rpc_url = "http://localhost:8546"
+            "#,
+            |name| env.get(name).cloned(),
+        )
+        .unwrap();
+        let source = config.source().unwrap().poll_schedule();
+        assert_eq!(source.interval, Duration::from_millis(250));
+        assert_eq!(source.max_interval, Duration::from_secs(5));
+        let destination = config.destination().unwrap().poll_schedule();
+        assert_eq!(destination.interval, Duration::from_secs(12));
+        assert_eq!(destination.max_interval, DEFAULT_MAX_POLL_INTERVAL);
+
+        let err = RelayerConfig::from_toml(
+            r#"
+            [source]
+            rpc_url = "http://localhost:8545"
+            poll_interval_ms = 0
+
+            [destination]
+            rpc_url = "http://localhost:8546"
+            poll_interval_ms = 5000
+            max_poll_interval_ms = 1000
+            "#,
+            no_env,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("source.poll_interval_ms"), "{err}");
+        assert!(err.contains("destination.max_poll_interval_ms"), "{err}");
+
+        let err = RelayerConfig::from_toml("", |name| {
+            (name == "SRC_POLL_INTERVAL_MS").then(|| "2s".to_string())
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("SRC_POLL_INTERVAL_MS"), "{err}");
+    }
+
+    #[test]
+    fn test_component_sections() {
+        let env: HashMap<&str, String> = HashMap::from([
+            ("QUEUE_BACKEND", "file".to_string()),
+            ("LEADER_LEASE_MS", "2500".to_string()),
+            ("VERIFY_SOURCE_DEPOSITS", "1".to_string()),
+            ("LOG_FORMAT", "json".to_string()),
+            ("STREAM_REPLAY_FROM", "42".to_string()),
+            ("QUEUE_MAX_SEGMENT_BYTES", "4096".to_string()),
+        ]);
+        let config = RelayerConfig::from_toml(
+            r#"
+            [subscriber]
+            start_block = "latest"
+
+            [amqp]
+            queue = "mints"
+            routing_keys = ["a", " b "]
+            queue_type = "quorum"
+
+            [queue]
+            fsync = "every:100"
+
+            [cache]
+            backend = "sqlite"
+
+            [leader]
+            election = "file"
+            lock_dir = "/run/relayer"
+
+            [health]
+            addr = "127.0.0.1:9100"
+
+            [shutdown]
+            grace_secs = 5
+            "#,
+            |name| env.get(name).cloned(),
+        )
+        .unwrap();
+        assert_eq!(config.start_block, StartBlock::Latest);
+        assert_eq!(config.topology.queue_name, "mints");
+        assert_eq!(config.topology.routing_keys, ["a", "b"]);
+        assert_eq!(config.queue_options.kind, QueueKind::Quorum);
+        assert_eq!(config.queue_backend, QueueBackend::File);
+        assert_eq!(config.file_queue.fsync, FsyncPolicy::EveryN(100));
+        assert_eq!(config.file_queue.max_segment_bytes, 4096);
+        assert_eq!(config.cache_backend, Some(CacheBackend::Sqlite));
+        assert_eq!(config.leader.election, LeaderElection::File);
+        assert_eq!(config.leader.lease_ttl, Duration::from_millis(2500));
+        assert_eq!(config.leader.lock_dir, Some(PathBuf::from("/run/relayer")));
+        assert!(config.verifier.enabled);
+        assert_eq!(config.verifier.min_confirmations, DEFAULT_MIN_CONFIRMATIONS);
+        assert_eq!(config.health.addr, Some("127.0.0.1:9100".parse().unwrap()));
+        assert_eq!(config.shutdown_grace, Duration::from_secs(5));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.stream_replay_from, Some(42));
+        assert!(config.signing.hmac_key.is_none());
+
+        let err = RelayerConfig::from_toml(
+            r#"
+            [amqp]
+            queue_type = "quorum"
+            durable = false
+
+            [queue]
+            backend = "carrier-pigeon"
+            fsync = "sometimes"
+
+            [signing]
+            hmac_key = "abcd"
+            require_signatures = true
+
+            [health]
+            addr = "nowhere"
+
+            [log]
+            format = "xml"
+            "#,
+            no_env,
+        )
+        .unwrap_err()
+        .to_string();
+        for setting in [
+            "amqp:",
+            "queue.backend",
+            "queue.fsync",
+            "signing.hmac_key",
+            "signing.require_signatures",
+            "health.addr",
+            "log.format",
+        ] {
+            assert!(err.contains(setting), "{setting} missing from {err}");
+        }
+
+        // An unknown value must not switch verification off silently.
+        let err = RelayerConfig::from_toml("", |name| {
+            (name == "VERIFY_SOURCE_DEPOSITS").then(|| "yes".to_string())
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("VERIFY_SOURCE_DEPOSITS"), "{err}");
+
+        // RUST_LOG directives are left to `setup_logging`.
+        let config = RelayerConfig::from_toml("", |name| {
+            (name == "RUST_LOG").then(|| "relayer=debug,lapin=warn".to_string())
+        })
+        .unwrap();
+        assert_eq!(config.log_level, LevelFilter::INFO);
+    }
+
+    #[test]
+    fn test_rejects_unknown_settings() {
+        let err = RelayerConfig::from_toml("[source]\nrpc = \"http://x\"\n", no_env)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown field `rpc`"), "{err}");
+    }
+
+    #[test]
+    fn test_missing_file_is_an_error() {
+        let err = RelayerConfig::load_from(Some(Path::new("/nonexistent/relayer.toml")), no_env)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("/nonexistent/relayer.toml"), "{err}");
+    }
+
+    #[test]
+    fn test_template_is_valid() {
+        let template = include_str!("../relayer.toml_template");
+        let config = RelayerConfig::from_toml(template, no_env).unwrap();
+        assert!(config.source().is_ok());
+        assert!(config.destination().is_ok());
+        assert!(config.private_key().is_err());
+    }
+}
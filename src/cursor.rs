@@ -0,0 +1,309 @@
+use crate::cache::CacheTrait;
+use crate::errors::RelayerError;
+use alloy::primitives::Address;
+use alloy::providers::Provider;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use tracing::info;
+
+/// Key used by subscribers before cursors were namespaced per route.
+pub const LEGACY_CURSOR_KEY: &str = "from_block";
+
+/// Identifies the cursor of one (chain, contract, event) route, so several
+/// subscribers can share a cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CursorKey {
+    pub chain_id: u64,
+    pub contract: Address,
+    pub event: String,
+}
+
+impl CursorKey {
+    pub fn new(chain_id: u64, contract: Address, event: &str) -> Self {
+        CursorKey {
+            chain_id,
+            contract,
+            event: event.to_string(),
+        }
+    }
+
+    /// Key of the leader lease guarding this route.
+    pub fn lease_key(&self) -> String {
+        format!(
+            "relayer:{}:{:#x}:{}:leader",
+            self.chain_id, self.contract, self.event
+        )
+    }
+}
+
+impl fmt::Display for CursorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "relayer:{}:{:#x}:{}:cursor",
+            self.chain_id, self.contract, self.event
+        )
+    }
+}
+
+/// Writes a cursor, through the fenced path when the writer holds a
+/// leader lease token.
+pub async fn write_cursor<R: CacheTrait + ?Sized>(
+    cache: &mut R,
+    key: &str,
+    value: u64,
+    fence: Option<u64>,
+) -> Result<(), RelayerError> {
+    match fence {
+        Some(token) => cache.set_fenced_offset(key, value, token).await,
+        None => cache.set_last_offset(key, value).await,
+    }
+}
+
+/// Moves a bare `from_block` cursor to `key` if `key` has no value yet.
+/// The legacy key is deleted afterwards so a second route cannot inherit
+/// it. Returns whether anything was migrated.
+pub async fn migrate_legacy_cursor<R: CacheTrait + ?Sized>(
+    cache: &mut R,
+    key: &str,
+    fence: Option<u64>,
+) -> Result<bool, RelayerError> {
+    if cache.get_offset(key).await?.is_some() {
+        return Ok(false);
+    }
+    let Some(legacy) = cache.get_offset(LEGACY_CURSOR_KEY).await? else {
+        return Ok(false);
+    };
+    write_cursor(cache, key, legacy, fence).await?;
+    cache.delete_offset(LEGACY_CURSOR_KEY).await?;
+    info!(
+        "Migrated cursor {} from {} to {}",
+        legacy, LEGACY_CURSOR_KEY, key
+    );
+    Ok(true)
+}
+
+/// Where a subscriber starts scanning when its route has no cursor yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartBlock {
+    /// First block to scan. `0` scans from genesis.
+    Number(u64),
+    /// Only blocks mined after the first run.
+    Latest,
+    /// The block the deposit contract was deployed in, found by bisecting
+    /// `eth_getCode`. Needs a node that serves historical state.
+    Deployment,
+}
+
+impl Default for StartBlock {
+    fn default() -> Self {
+        StartBlock::Number(0)
+    }
+}
+
+impl FromStr for StartBlock {
+    type Err = RelayerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "latest" => Ok(StartBlock::Latest),
+            "deployment" | "deployed" => Ok(StartBlock::Deployment),
+            number => number
+                .parse()
+                .map(StartBlock::Number)
+                .map_err(|_| RelayerError::Other(format!("Invalid start block: {s}"))),
+        }
+    }
+}
+
+impl StartBlock {
+    /// Cursor value to seed an empty route with. Cursors hold the last block
+    /// already scanned, so this is one less than the first block to scan.
+    pub async fn initial_cursor<P: Provider>(
+        &self,
+        provider: &P,
+        contract: Address,
+    ) -> Result<u64, RelayerError> {
+        let provider_err =
+            |e: alloy::transports::TransportError| RelayerError::ProviderError(e.to_string());
+        match *self {
+            StartBlock::Number(block) => Ok(block.saturating_sub(1)),
+            StartBlock::Latest => provider.get_block_number().await.map_err(provider_err),
+            StartBlock::Deployment => {
+                let head = provider.get_block_number().await.map_err(provider_err)?;
+                let deployed = find_deployment_block(head, |block| async move {
+                    let code = provider
+                        .get_code_at(contract)
+                        .number(block)
+                        .await
+                        .map_err(provider_err)?;
+                    Ok(!code.is_empty())
+                })
+                .await?
+                .ok_or_else(|| {
+                    RelayerError::Other(format!(
+                        "No contract code at {contract} as of block {head}"
+                    ))
+                })?;
+                info!("Contract {} was deployed in block {}", contract, deployed);
+                Ok(deployed.saturating_sub(1))
+            }
+        }
+    }
+}
+
+/// Lowest block in `0..=head` for which `has_code` holds, assuming code
+/// never disappears once deployed. `None` if there is no code at `head`.
+pub async fn find_deployment_block<F, Fut>(
+    head: u64,
+    mut has_code: F,
+) -> Result<Option<u64>, RelayerError>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<bool, RelayerError>>,
+{
+    if !has_code(head).await? {
+        return Ok(None);
+    }
+    let (mut low, mut high) = (0, head);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if has_code(mid).await? {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    Ok(Some(low))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::memory::InMemoryCache;
+
+    fn key() -> String {
+        let contract: Address = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+            .parse()
+            .unwrap();
+        CursorKey::new(31337, contract, "Deposited").to_string()
+    }
+
+    #[test]
+    fn test_cursor_key_format() {
+        assert_eq!(
+            key(),
+            "relayer:31337:0x5fbdb2315678afecb367f032d93f642f64180aa3:Deposited:cursor"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrates_legacy_cursor_once() {
+        let mut cache = InMemoryCache::new();
+        cache.set_last_offset(LEGACY_CURSOR_KEY, 120).await.unwrap();
+
+        assert!(
+            migrate_legacy_cursor(&mut cache, &key(), None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(cache.get_last_offset(&key()).await.unwrap(), 120);
+        assert_eq!(cache.get_last_offset(LEGACY_CURSOR_KEY).await.unwrap(), 0);
+
+        // A second route must not pick up anything.
+        assert!(
+            !migrate_legacy_cursor(&mut cache, "other", None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(cache.get_last_offset("other").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_existing_cursor_wins() {
+        let mut cache = InMemoryCache::new();
+        cache.set_last_offset(LEGACY_CURSOR_KEY, 120).await.unwrap();
+        cache.set_last_offset(&key(), 300).await.unwrap();
+
+        assert!(
+            !migrate_legacy_cursor(&mut cache, &key(), None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(cache.get_last_offset(&key()).await.unwrap(), 300);
+        assert_eq!(cache.get_last_offset(LEGACY_CURSOR_KEY).await.unwrap(), 120);
+    }
+
+    #[test]
+    fn test_start_block_from_str() {
+        assert_eq!(
+            "12345".parse::<StartBlock>().unwrap(),
+            StartBlock::Number(12345)
+        );
+        assert_eq!("Latest".parse::<StartBlock>().unwrap(), StartBlock::Latest);
+        assert_eq!(
+            "deployment".parse::<StartBlock>().unwrap(),
+            StartBlock::Deployment
+        );
+        assert!("soon".parse::<StartBlock>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_find_deployment_block() {
+        for deployed in [0, 1, 37, 99, 100] {
+            let mut calls = 0;
+            let found = find_deployment_block(100, |block| {
+                calls += 1;
+                async move { Ok(block >= deployed) }
+            })
+            .await
+            .unwrap();
+            assert_eq!(found, Some(deployed));
+            assert!(calls <= 9, "{calls} calls");
+        }
+        let found = find_deployment_block(100, |_| async { Ok(false) })
+            .await
+            .unwrap();
+        assert_eq!(found, None);
+    }
+
+    #[tokio::test]
+    async fn test_initial_cursor() {
+        use alloy::primitives::{Bytes, U64};
+        use alloy::providers::{ProviderBuilder, mock::Asserter};
+
+        let asserter = Asserter::new();
+        let provider = ProviderBuilder::new().on_mocked_client(asserter.clone());
+        let contract = Address::default();
+
+        assert_eq!(
+            StartBlock::Number(500)
+                .initial_cursor(&provider, contract)
+                .await
+                .unwrap(),
+            499
+        );
+
+        asserter.push_success(&U64::from(800));
+        assert_eq!(
+            StartBlock::Latest
+                .initial_cursor(&provider, contract)
+                .await
+                .unwrap(),
+            800
+        );
+
+        // Head is 2, with code at block 2 but not at block 1.
+        asserter.push_success(&U64::from(2));
+        asserter.push_success(&Bytes::from_static(&[0x60]));
+        asserter.push_success(&Bytes::new());
+        assert_eq!(
+            StartBlock::Deployment
+                .initial_cursor(&provider, contract)
+                .await
+                .unwrap(),
+            1
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::errors::RelayerError;
+use crate::subscriber::Deposit;
+use alloy::primitives::keccak256;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version written by this build. Bump it when the envelope or a payload
+/// changes shape, and teach `Envelope::into_deposit` the new version.
+pub const ENVELOPE_VERSION: u16 = 1;
+/// Version assigned to bare `Deposit` payloads published before envelopes.
+pub const LEGACY_VERSION: u16 = 0;
+pub const SUPPORTED_VERSIONS: &[u16] = &[LEGACY_VERSION, ENVELOPE_VERSION];
+
+pub const DEPOSIT_KIND: &str = "deposit";
+pub const CONTENT_TYPE: &str = "application/json";
+pub const VERSION_HEADER: &str = "x-envelope-version";
+/// Header carrying `Deposit::id`, to follow a deposit across binaries.
+pub const DEPOSIT_ID_HEADER: &str = "x-deposit-id";
+
+/// How far in the future `produced_at` may be, for clocks that disagree.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+/// How long settled message ids are remembered when messages have no
+/// maximum age. Replays of minted deposits are refused by their state
+/// even after that.
+pub const DEFAULT_SETTLED_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+static MESSAGE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Wrapper around every message put on the queue.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub version: u16,
+    pub message_id: String,
+    pub kind: String,
+    /// Milliseconds since the unix epoch.
+    pub produced_at: u64,
+    pub source: String,
+    pub payload: Value,
+    /// `<scheme>:<hex>` signature over the rest of the envelope, see
+    /// `crate::signing`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// Only the version of a message, read before the rest so a future
+/// envelope shape is reported as an unsupported version.
+#[derive(Deserialize)]
+struct Versioned {
+    version: u16,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn new_message_id(source: &str, produced_at: u64, payload: &[u8]) -> String {
+    let counter = MESSAGE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut seed = Vec::with_capacity(source.len() + payload.len() + 20);
+    seed.extend_from_slice(source.as_bytes());
+    seed.extend_from_slice(&produced_at.to_be_bytes());
+    seed.extend_from_slice(&std::process::id().to_be_bytes());
+    seed.extend_from_slice(&counter.to_be_bytes());
+    seed.extend_from_slice(payload);
+    keccak256(&seed).to_string()
+}
+
+impl Envelope {
+    pub fn deposit(deposit: &Deposit, source: &str) -> Result<Self, RelayerError> {
+        let payload = serde_json::to_value(deposit)?;
+        let produced_at = now_millis();
+        let message_id = new_message_id(source, produced_at, &serde_json::to_vec(&payload)?);
+        Ok(Envelope {
+            version: ENVELOPE_VERSION,
+            message_id,
+            kind: DEPOSIT_KIND.to_string(),
+            produced_at,
+            source: source.to_string(),
+            payload,
+            signature: None,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, RelayerError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// Bytes covered by the signature: the serialized envelope without its
+    /// `signature` field.
+    pub fn signing_bytes(&self) -> Result<Vec<u8>, RelayerError> {
+        let unsigned = Envelope {
+            signature: None,
+            ..self.clone()
+        };
+        unsigned.to_bytes()
+    }
+
+    /// Parses an envelope, falling back to a bare `Deposit` so messages
+    /// published before envelopes existed are still readable. The version
+    /// is checked first, whatever shape the rest has.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, RelayerError> {
+        if let Ok(Versioned { version }) = serde_json::from_slice(data) {
+            if !SUPPORTED_VERSIONS.contains(&version) {
+                return Err(RelayerError::UnsupportedEnvelopeVersion(version));
+            }
+            return Ok(serde_json::from_slice::<Envelope>(data)?);
+        }
+        match serde_json::from_slice::<Envelope>(data) {
+            Ok(envelope) => Ok(envelope),
+            Err(envelope_err) => match serde_json::from_slice::<Deposit>(data) {
+                Ok(deposit) => Ok(Envelope {
+                    version: LEGACY_VERSION,
+                    message_id: keccak256(data).to_string(),
+                    kind: DEPOSIT_KIND.to_string(),
+                    produced_at: 0,
+                    source: String::from("legacy"),
+                    payload: serde_json::to_value(deposit)?,
+                    signature: None,
+                }),
+                Err(_) => Err(RelayerError::SerdeError(envelope_err)),
+            },
+        }
+    }
+
+    /// Whether the envelope was produced within `max_age`, allowing
+    /// `MAX_CLOCK_SKEW` for the producer's clock. Legacy messages carry no
+    /// time and are never fresh.
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        let now = now_millis();
+        self.produced_at > 0
+            && self.produced_at <= now.saturating_add(MAX_CLOCK_SKEW.as_millis() as u64)
+            && now.saturating_sub(self.produced_at) <= max_age.as_millis() as u64
+    }
+
+    /// `Deposit::id` of the payload, if it is a deposit with an origin.
+    pub fn deposit_id(&self) -> Option<String> {
+        if self.kind != DEPOSIT_KIND {
+            return None;
+        }
+        serde_json::from_value::<Deposit>(self.payload.clone())
+            .ok()?
+            .id()
+    }
+
+    pub fn into_deposit(self) -> Result<Deposit, RelayerError> {
+        if !SUPPORTED_VERSIONS.contains(&self.version) {
+            return Err(RelayerError::UnsupportedEnvelopeVersion(self.version));
+        }
+        if self.kind != DEPOSIT_KIND {
+            return Err(RelayerError::Other(format!(
+                "Unexpected message kind: {}",
+                self.kind
+            )));
+        }
+        Ok(serde_json::from_value(self.payload)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscriber::DepositOrigin;
+
+    fn test_deposit() -> Deposit {
+        Deposit {
+            sender: "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
+                .parse()
+                .unwrap(),
+            amount: 42,
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let envelope = Envelope::deposit(&test_deposit(), "test").unwrap();
+        assert_eq!(envelope.version, ENVELOPE_VERSION);
+        let parsed = Envelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed, envelope);
+        assert_eq!(parsed.into_deposit().unwrap(), test_deposit());
+    }
+
+    #[test]
+    fn test_message_ids_are_unique() {
+        let a = Envelope::deposit(&test_deposit(), "test").unwrap();
+        let b = Envelope::deposit(&test_deposit(), "test").unwrap();
+        assert_ne!(a.message_id, b.message_id);
+    }
+
+    #[test]
+    fn test_legacy_payload() {
+        let data = serde_json::to_vec(&test_deposit()).unwrap();
+        let envelope = Envelope::from_bytes(&data).unwrap();
+        assert_eq!(envelope.version, LEGACY_VERSION);
+        assert_eq!(envelope.into_deposit().unwrap(), test_deposit());
+    }
+
+    #[test]
+    fn test_deposit_id() {
+        let mut deposit = test_deposit();
+        let envelope = Envelope::deposit(&deposit, "test").unwrap();
+        assert_eq!(envelope.deposit_id(), None);
+
+        deposit.origin = Some(DepositOrigin {
+            tx_hash: alloy::primitives::B256::repeat_byte(2),
+            log_index: 1,
+            block_number: 5,
+        });
+        let mut envelope = Envelope::deposit(&deposit, "test").unwrap();
+        assert_eq!(envelope.deposit_id(), deposit.id());
+        envelope.kind = "other".to_string();
+        assert_eq!(envelope.deposit_id(), None);
+    }
+
+    #[test]
+    fn test_freshness() {
+        let max_age = Duration::from_secs(60);
+        let mut envelope = Envelope::deposit(&test_deposit(), "test").unwrap();
+        assert!(envelope.is_fresh(max_age));
+        envelope.produced_at -= 120_000;
+        assert!(!envelope.is_fresh(max_age));
+        envelope.produced_at = now_millis() + 3_600_000;
+        assert!(!envelope.is_fresh(max_age));
+
+        let legacy = Envelope::from_bytes(&serde_json::to_vec(&test_deposit()).unwrap()).unwrap();
+        assert!(!legacy.is_fresh(max_age));
+    }
+
+    #[test]
+    fn test_unknown_version_rejected() {
+        let mut envelope = Envelope::deposit(&test_deposit(), "test").unwrap();
+        envelope.version = 99;
+        let err = Envelope::from_bytes(&envelope.to_bytes().unwrap()).unwrap_err();
+        assert!(matches!(err, RelayerError::UnsupportedEnvelopeVersion(99)));
+
+        // A later version may reshape everything but its version.
+        let reshaped = br#"{"version": 2, "id": 7, "body": [1, 2]}"#;
+        let err = Envelope::from_bytes(reshaped).unwrap_err();
+        assert!(matches!(err, RelayerError::UnsupportedEnvelopeVersion(2)));
+
+        // A supported version with a broken body is still malformed.
+        let err = Envelope::from_bytes(br#"{"version": 1}"#).unwrap_err();
+        assert!(matches!(err, RelayerError::SerdeError(_)));
+    }
+
+    #[test]
+    fn test_garbage_rejected() {
+        let err = Envelope::from_bytes(b"not json").unwrap_err();
+        assert!(matches!(err, RelayerError::SerdeError(_)));
+    }
+}
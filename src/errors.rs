@@ -20,6 +20,9 @@ pub enum RelayerError {
     #[error("Data is not string")]
     NotString,
 
+    #[error("Amount is not an i32: {0}")]
+    InvalidAmount(String),
+
     #[error(transparent)]
     AbiError(#[from] alloy::dyn_abi::Error),
 
@@ -32,12 +35,54 @@ pub enum RelayerError {
     #[error("Redis call failed: {0}")]
     RedisError(String),
 
+    #[error("SQLite call failed: {0}")]
+    SqliteError(String),
+
     #[error("Serialization error: {0}")]
     SerdeError(#[from] serde_json::Error),
 
     #[error("AMQP error: {0}")]
     AmqpError(#[from] lapin::Error),
 
+    #[error("Queue exists with different arguments: {0}")]
+    QueueConfigMismatch(String),
+
+    #[error("RabbitMQ stream error: {0}")]
+    StreamError(String),
+
+    #[error("Unsupported envelope version: {0}")]
+    UnsupportedEnvelopeVersion(u16),
+
+    #[error("Invalid message signature: {0}")]
+    InvalidSignature(String),
+
+    #[error("Stale message: {0}")]
+    StaleMessage(String),
+
+    #[error("Replayed message: {0}")]
+    ReplayedMessage(String),
+
+    #[error("Deposit has {0} source confirmations, waiting for more")]
+    DepositUnconfirmed(u64),
+
+    #[error("Deposit quarantined: {0}")]
+    DepositQuarantined(String),
+
+    #[error("Write fenced off: token {token} is older than {current}")]
+    FencedOut { token: u64, current: u64 },
+
+    #[error("Leader lease lost")]
+    LeaseLost,
+
+    #[error("No receipt for transaction {0} yet")]
+    ReceiptTimeout(String),
+
+    #[error("Deposit cannot move from {from} to {to}")]
+    InvalidTransition { from: String, to: String },
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
     #[error("Unhandled error: {0}")]
     Other(String),
 }
@@ -0,0 +1,255 @@
+use crate::config::RelayerConfig;
+use crate::http::{Response, Routes};
+use alloy::providers::Provider;
+use async_trait::async_trait;
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a run loop may go without a tick before `/healthz` fails. Long,
+/// because a loop waits for a mint to be mined between ticks.
+pub const DEFAULT_MAX_TICK_AGE: Duration = Duration::from_secs(300);
+/// Blocks the subscriber may trail the source head by before `/readyz`
+/// fails.
+pub const DEFAULT_MAX_LAG_BLOCKS: u64 = 100;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A dependency checked when `/readyz` is requested.
+#[async_trait]
+pub trait Probe: Send + Sync {
+    /// Detail to show on success, the error otherwise.
+    async fn probe(&self) -> Result<String, String>;
+}
+
+/// Checks that an RPC endpoint answers `eth_blockNumber`.
+pub struct RpcProbe<P>(pub P);
+
+#[async_trait]
+impl<P: Provider + Send + Sync> Probe for RpcProbe<P> {
+    async fn probe(&self) -> Result<String, String> {
+        match self.0.get_block_number().await {
+            Ok(block) => Ok(format!("head at block {block}")),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    ticks: BTreeMap<&'static str, Instant>,
+    /// Outcome of the last use of each dependency by a run loop.
+    reports: BTreeMap<&'static str, Result<(), String>>,
+    probes: BTreeMap<&'static str, Arc<dyn Probe>>,
+    lag: Option<u64>,
+}
+
+/// What `/healthz` and `/readyz` report. Run loops tick and report how
+/// their dependencies behaved; clones share the state, so the endpoint
+/// sees what the loops record.
+#[derive(Clone)]
+pub struct Health {
+    state: Arc<Mutex<State>>,
+    max_tick_age: Duration,
+    max_lag: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            state: Arc::default(),
+            max_tick_age: DEFAULT_MAX_TICK_AGE,
+            max_lag: DEFAULT_MAX_LAG_BLOCKS,
+        }
+    }
+
+    /// With the thresholds from the `health` settings.
+    pub fn from_config(config: &RelayerConfig) -> Self {
+        Self::new()
+            .with_max_tick_age(config.health.max_tick_age)
+            .with_max_lag(config.health.max_lag)
+    }
+
+    pub fn with_max_tick_age(mut self, max_tick_age: Duration) -> Self {
+        self.max_tick_age = max_tick_age;
+        self
+    }
+
+    pub fn with_max_lag(mut self, max_lag: u64) -> Self {
+        self.max_lag = max_lag;
+        self
+    }
+
+    /// Records that the run loop `name` went round.
+    pub fn tick(&self, name: &'static str) {
+        self.lock().ticks.insert(name, Instant::now());
+    }
+
+    /// Records how the last use of `dependency` went.
+    pub fn report<T, E: Display>(&self, dependency: &'static str, result: &Result<T, E>) {
+        let result = match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        };
+        self.lock().reports.insert(dependency, result);
+    }
+
+    /// Checks `dependency` with `probe` on every readiness request.
+    pub fn add_probe(&self, dependency: &'static str, probe: impl Probe + 'static) {
+        self.lock().probes.insert(dependency, Arc::new(probe));
+    }
+
+    /// Records how many blocks the subscriber trails the source head by.
+    pub fn set_lag(&self, blocks: u64) {
+        self.lock().lag = Some(blocks);
+    }
+
+    /// Whether every run loop ticked recently, with the age of each tick.
+    pub fn liveness(&self) -> (bool, Value) {
+        let state = self.lock();
+        let mut loops = Map::new();
+        let mut alive = true;
+        for (name, tick) in &state.ticks {
+            let age = tick.elapsed();
+            let ok = age <= self.max_tick_age;
+            alive &= ok;
+            loops.insert(
+                name.to_string(),
+                json!({ "ok": ok, "last_tick_secs": age.as_secs_f64() }),
+            );
+        }
+        let status = if alive { "ok" } else { "stalled" };
+        (alive, json!({ "status": status, "loops": loops }))
+    }
+
+    /// Whether every dependency works and the lag is acceptable, probing
+    /// the RPC endpoints on the way.
+    pub async fn readiness(&self) -> (bool, Value) {
+        let (reports, probes, lag) = {
+            let state = self.lock();
+            let probes: Vec<_> = state
+                .probes
+                .iter()
+                .map(|(name, probe)| (*name, probe.clone()))
+                .collect();
+            (state.reports.clone(), probes, state.lag)
+        };
+        let mut checks = Map::new();
+        let mut ready = true;
+        for (name, result) in reports {
+            ready &= result.is_ok();
+            checks.insert(name.to_string(), check(result.map(|_| None)));
+        }
+        for (name, probe) in probes {
+            let result = match tokio::time::timeout(PROBE_TIMEOUT, probe.probe()).await {
+                Ok(result) => result,
+                Err(_) => Err(format!("no answer within {PROBE_TIMEOUT:?}")),
+            };
+            ready &= result.is_ok();
+            checks.insert(name.to_string(), check(result.map(Some)));
+        }
+        if let Some(blocks) = lag {
+            let ok = blocks <= self.max_lag;
+            ready &= ok;
+            checks.insert(
+                "lag".to_string(),
+                json!({ "ok": ok, "blocks": blocks, "max": self.max_lag }),
+            );
+        }
+        let status = if ready { "ready" } else { "not_ready" };
+        (ready, json!({ "status": status, "checks": checks }))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("health lock poisoned")
+    }
+}
+
+fn check(result: Result<Option<String>, String>) -> Value {
+    match result {
+        Ok(Some(detail)) => json!({ "ok": true, "detail": detail }),
+        Ok(None) => json!({ "ok": true }),
+        Err(error) => json!({ "ok": false, "error": error }),
+    }
+}
+
+fn json_response(ok: bool, body: Value) -> Response {
+    Response {
+        status: if ok { 200 } else { 503 },
+        content_type: "application/json",
+        body: body.to_string(),
+    }
+}
+
+#[async_trait]
+impl Routes for Health {
+    async fn get(&self, path: &str) -> Option<Response> {
+        match path {
+            "/healthz" => {
+                let (ok, body) = self.liveness();
+                Some(json_response(ok, body))
+            }
+            "/readyz" => {
+                let (ok, body) = self.readiness().await;
+                Some(json_response(ok, body))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::U64;
+    use alloy::providers::{ProviderBuilder, mock::Asserter};
+
+    #[test]
+    fn test_liveness_follows_ticks() {
+        let health = Health::new().with_max_tick_age(Duration::from_millis(20));
+        assert!(health.liveness().0);
+        health.tick("subscriber");
+        let (alive, body) = health.liveness();
+        assert!(alive);
+        assert_eq!(body["loops"]["subscriber"]["ok"], true);
+
+        std::thread::sleep(Duration::from_millis(30));
+        let (alive, body) = health.liveness();
+        assert!(!alive);
+        assert_eq!(body["status"], "stalled");
+    }
+
+    #[tokio::test]
+    async fn test_readiness_reports_each_dependency() {
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(12));
+        asserter.push_failure_msg("connection refused");
+        let provider = ProviderBuilder::new().on_mocked_client(asserter);
+        let health = Health::new().with_max_lag(10);
+        health.add_probe("source_rpc", RpcProbe(provider));
+        health.report("cache", &Ok::<(), String>(()));
+        health.set_lag(3);
+
+        let (ready, body) = health.readiness().await;
+        assert!(ready, "{body}");
+        assert_eq!(body["checks"]["source_rpc"]["detail"], "head at block 12");
+        assert_eq!(body["checks"]["lag"]["blocks"], 3);
+
+        health.report("queue", &Err::<(), _>("channel closed"));
+        health.set_lag(11);
+        let (ready, body) = health.readiness().await;
+        assert!(!ready);
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["checks"]["cache"]["ok"], true);
+        assert_eq!(body["checks"]["queue"]["error"], "channel closed");
+        assert_eq!(body["checks"]["source_rpc"]["ok"], false);
+        assert_eq!(body["checks"]["lag"]["ok"], false);
+    }
+}
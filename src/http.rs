@@ -0,0 +1,166 @@
+use crate::shutdown::Shutdown;
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// How long a client gets to send its request and read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn text(status: u16, body: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: format!("{body}\n"),
+        }
+    }
+}
+
+/// Answers `GET` requests; `None` for paths it does not serve.
+#[async_trait]
+pub trait Routes: Send + Sync + 'static {
+    async fn get(&self, path: &str) -> Option<Response>;
+}
+
+/// Both sets of routes, `A` first.
+#[async_trait]
+impl<A: Routes, B: Routes> Routes for (A, B) {
+    async fn get(&self, path: &str) -> Option<Response> {
+        match self.0.get(path).await {
+            Some(response) => Some(response),
+            None => self.1.get(path).await,
+        }
+    }
+}
+
+/// Serves `routes` over HTTP/1.1 until `shutdown`, closing every connection
+/// after one response.
+pub async fn serve(listener: TcpListener, routes: Arc<dyn Routes>, shutdown: Shutdown) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving HTTP on {}", addr);
+    }
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Could not accept HTTP connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => break,
+        };
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(stream, &*routes)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("HTTP connection failed: {}", e),
+                Err(_) => debug!("HTTP request timed out"),
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, routes: &dyn Routes) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // Headers are not used, but are read so the client sees its request
+    // consumed.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => {
+            let path = target.split('?').next().unwrap_or(target);
+            match routes.get(path).await {
+                Some(response) => response,
+                None => Response::text(404, "Not Found"),
+            }
+        }
+        (Some(_), Some(_)) => Response::text(405, "Method Not Allowed"),
+        _ => Response::text(400, "Bad Request"),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(response.body.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncReadExt;
+
+    struct Hello;
+
+    #[async_trait]
+    impl Routes for Hello {
+        async fn get(&self, path: &str) -> Option<Response> {
+            (path == "/hello").then(|| Response::text(200, "hi"))
+        }
+    }
+
+    async fn request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serves_routes_until_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(serve(listener, Arc::new(Hello), shutdown.clone()));
+
+        let response = request(addr, "GET /hello?x=1 HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nhi\n"), "{response}");
+        let response = request(addr, "GET /nope HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 "), "{response}");
+        let response = request(addr, "POST /hello HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 "), "{response}");
+
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,12 +1,30 @@
 use crate::{
-    errors::RelayerError, queue::QueueTrait, subscriber::Deposit, utils::verify_minted_log,
+    config::RelayerConfig,
+    envelope::{DEFAULT_SETTLED_RETENTION, DEPOSIT_ID_HEADER, Envelope, MAX_CLOCK_SKEW},
+    errors::RelayerError,
+    health::{Health, RpcProbe},
+    leader::{LEASE_RENEW_INTERVAL, LeaderLease},
+    lifecycle::{DepositState, DepositStateStore},
+    metrics::{
+        DEAD_LETTERED, DEPOSITS_MINTED, GAS_SPENT, MINT_LATENCY, Metrics, NACKS, WALLET_BALANCE,
+        rpc_client,
+    },
+    pending::{PendingTx, PendingTxStore},
+    quarantine::Quarantine,
+    queue::{DeliveryOf, QueueConsumer, QueueDelivery, QueueTrait},
+    schedule::PollSchedule,
+    shutdown::Shutdown,
+    signing::MessageVerifier,
+    source_verifier::{SourceCheck, SourceVerifier},
+    subscriber::Deposit,
+    utils::verify_minted_log,
 };
 use alloy::{
     contract::{ContractInstance, Interface},
     dyn_abi::DynSolValue,
     json_abi::JsonAbi,
     network::{Ethereum, EthereumWallet},
-    primitives::Address,
+    primitives::{Address, B256},
     providers::{
         Identity, Provider, ProviderBuilder, RootProvider,
         fillers::{
@@ -19,15 +37,15 @@ use alloy::{
     transports::http::reqwest::Url,
 };
 use eyre::Result;
-use futures_lite::StreamExt;
-use lapin::{
-    Consumer,
-    message::Delivery,
-    options::{BasicAckOptions, BasicNackOptions},
-};
 use serde_json::Value;
-use std::{env, fs, thread, time};
-use tracing::{debug, error, info, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+use tracing::{Instrument, Span, debug, error, field, info, warn};
 type ProviderType = FillProvider<
     JoinFill<
         JoinFill<
@@ -40,159 +58,1785 @@ type ProviderType = FillProvider<
 >;
 type ContractType = ContractInstance<ProviderType, Ethereum>;
 
+/// How long a sent mint may stay unmined before its deposit is handed back
+/// to the queue, to be finished from the pending store on redelivery.
+pub const RECEIPT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Where a recorded mint stands on the destination chain.
+enum MintState {
+    Mined(Box<TransactionReceipt>),
+    /// Still in the mempool under this hash.
+    InFlight(B256),
+    /// The reserved nonce was used, but not by any recorded hash.
+    NonceTaken,
+    /// Never reached the node, or dropped from its mempool.
+    Unsent,
+}
+
 pub struct Includer<C: QueueTrait> {
     pub provider: ProviderType,
     pub contract: ContractType,
     pub queue_connection: C,
+    /// When set, only envelopes with a valid signature are minted.
+    pub verifier: Option<MessageVerifier>,
+    /// When set, older envelopes are rejected.
+    pub max_message_age: Option<Duration>,
+    /// When set, every deposit is checked against the source chain before
+    /// minting and mismatches are quarantined.
+    pub source_check: Option<(SourceVerifier, Quarantine)>,
+    /// Account that signs the mints. Only one includer may submit for it.
+    pub wallet: Address,
+    /// When set, only the lease holder consumes deposits.
+    pub leader_lease: Option<Box<dyn LeaderLease>>,
+    fence: Option<u64>,
+    /// Newer token the store refused a write against, handed to the lease
+    /// on the next acquisition.
+    fenced_by: Option<u64>,
+    /// When set, every mint reserves its nonce here before it is sent, so a
+    /// standby taking over can finish it instead of minting twice.
+    pub pending: Option<Box<dyn PendingTxStore>>,
+    /// Where deposit lifecycles are recorded, if anywhere.
+    pub states: Option<Box<dyn DepositStateStore>>,
+    /// Mints sent by this process whose gas is not counted yet. Receipts of
+    /// mints sent by an earlier leader are not counted again.
+    sent_mints: HashSet<B256>,
+    /// `run` returns once this is triggered.
+    pub shutdown: Shutdown,
+    /// How long `run` waits as a standby and after failures.
+    pub schedule: PollSchedule,
+    /// Also counts the RPC requests of `provider`.
+    pub metrics: Metrics,
+    /// Ticked every round of `run`, with the queue.
+    pub health: Health,
 }
 
-const TOKEN_DATA_PATH: &str = "../project_eth/data/TokenData.json";
+/// Reads the token ABI from a Hardhat artifact such as `TokenData.json`.
+fn read_token_abi(path: &Path) -> Result<JsonAbi> {
+    let data_str = fs::read_to_string(path)?;
+    let data_json: Value = serde_json::from_str(&data_str)?;
+    Ok(serde_json::from_str(&data_json["abi"].to_string())?)
+}
 
 impl<C: QueueTrait> Includer<C> {
-    pub async fn new(
+    /// Same as `from_config`, recording into fresh metrics.
+    pub fn new(config: &RelayerConfig, queue_connection: C) -> Result<Self> {
+        Self::from_config(config, queue_connection, Metrics::new())
+    }
+
+    /// Builds the includer from the `destination`, `includer.private_key`
+    /// and `paths.token_data` settings, recording into `metrics`. A
+    /// destination poll interval also sets how often mint receipts are
+    /// polled.
+    pub fn from_config(
+        config: &RelayerConfig,
+        queue_connection: C,
+        metrics: Metrics,
+    ) -> Result<Self> {
+        let abi = read_token_abi(&config.token_data_path)?;
+        Self::with_abi(config, abi, queue_connection, metrics)
+    }
+
+    /// Same as `from_config`, but takes the token ABI directly instead of
+    /// reading it from the token data file.
+    pub fn with_abi(
+        config: &RelayerConfig,
+        abi: JsonAbi,
+        queue_connection: C,
+        metrics: Metrics,
+    ) -> Result<Self> {
+        let destination = config.destination()?;
+        let incl = Self::with_signer(
+            &destination.rpc_url,
+            config.destination_contract()?,
+            abi,
+            config.private_key()?.clone(),
+            queue_connection,
+            metrics,
+        );
+        if let Some(interval) = destination.poll_interval {
+            incl.provider.client().set_poll_interval(interval);
+        }
+        Ok(incl.with_poll_schedule(destination.poll_schedule()))
+    }
+
+    fn with_signer(
         dst_rpc_url: &Url,
         contract_address: Address,
+        abi: JsonAbi,
+        pk: PrivateKeySigner,
         queue_connection: C,
-    ) -> Result<Self> {
-        let data_str = fs::read_to_string(TOKEN_DATA_PATH)?;
-        let data_json: Value = serde_json::from_str(&data_str)?;
-        let abi: JsonAbi = serde_json::from_str(&data_json["abi"].to_string())?;
-        let pk_str = env::var("PRIVATE_KEY").expect("Private key not set");
-        let pk: PrivateKeySigner = pk_str.parse()?;
+        metrics: Metrics,
+    ) -> Self {
+        let address = pk.address();
         let wallet = EthereumWallet::from(pk);
-        let provider = ProviderBuilder::new()
-            .wallet(wallet)
-            .on_http(dst_rpc_url.clone());
-        let contract: ContractType = ContractInstance::new(
-            contract_address,
-            provider.clone(),
-            Interface::new(abi.clone()),
-        );
-        Ok(Self {
+        let provider = ProviderBuilder::new().wallet(wallet).on_client(rpc_client(
+            dst_rpc_url.clone(),
+            &metrics,
+            "destination",
+        ));
+        let contract: ContractType =
+            ContractInstance::new(contract_address, provider.clone(), Interface::new(abi));
+        Self {
             provider,
             contract,
             queue_connection,
-        })
+            verifier: None,
+            max_message_age: None,
+            source_check: None,
+            wallet: address,
+            leader_lease: None,
+            fence: None,
+            fenced_by: None,
+            pending: None,
+            states: None,
+            sent_mints: HashSet::new(),
+            shutdown: Shutdown::new(),
+            schedule: PollSchedule::default(),
+            metrics,
+            health: Health::new(),
+        }
+    }
+
+    pub fn with_leader_lease(mut self, lease: Option<Box<dyn LeaderLease>>) -> Self {
+        self.leader_lease = lease;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_poll_schedule(mut self, schedule: PollSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Reports into `health`, which also probes the destination provider.
+    pub fn with_health(mut self, health: Health) -> Self {
+        health.add_probe("destination_rpc", RpcProbe(self.provider.clone()));
+        health.tick("includer");
+        self.health = health;
+        self
+    }
+
+    pub fn with_pending_store(mut self, store: Option<Box<dyn PendingTxStore>>) -> Self {
+        self.pending = store;
+        self
+    }
+
+    pub fn with_state_store(mut self, states: Option<Box<dyn DepositStateStore>>) -> Self {
+        self.states = states;
+        self
+    }
+
+    /// Acquires or renews the leader lease. Always true without a lease.
+    pub async fn ensure_leadership(&mut self) -> bool {
+        let Some(lease) = self.leader_lease.as_mut() else {
+            return true;
+        };
+        if let Some(current) = self.fenced_by.take()
+            && let Err(e) = lease.fenced_out(current).await
+        {
+            warn!(
+                "Could not move leader lease past token {}: {:?}",
+                current, e
+            );
+        }
+        match lease.acquire().await {
+            Ok(Some(token)) => {
+                if self.fence != Some(token) {
+                    info!("Became leader with fencing token {}", token);
+                }
+                self.fence = Some(token);
+                true
+            }
+            Ok(None) => {
+                if self.fence.take().is_some() {
+                    warn!("Lost leadership, standing by");
+                } else {
+                    debug!("Another replica leads, standing by");
+                }
+                false
+            }
+            Err(e) => {
+                warn!("Could not renew leader lease: {:?}", e);
+                self.fence = None;
+                false
+            }
+        }
+    }
+
+    pub fn with_verifier(mut self, verifier: Option<MessageVerifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    pub fn with_max_message_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_message_age = max_age;
+        self
+    }
+
+    pub fn with_source_verifier(
+        mut self,
+        verifier: SourceVerifier,
+        quarantine: Quarantine,
+    ) -> Self {
+        self.source_check = Some((verifier, quarantine));
+        self
+    }
+
+    /// Checks the deposit against the source chain. Mismatches are
+    /// quarantined and dead-lettered. The delivery is handed back only if
+    /// minting may go on.
+    ///
+    /// Unconfirmed deposits and provider errors are held rather than
+    /// requeued, so waiting for confirmations does not use up the queue's
+    /// delivery limit. They are checked again every poll interval, renewing
+    /// the lease in between, and only go back to the queue after
+    /// `max_wait`, on shutdown or when leadership is lost.
+    pub async fn verify_source(
+        &mut self,
+        deposit: &Deposit,
+        delivery: DeliveryOf<C>,
+    ) -> Result<DeliveryOf<C>, RelayerError> {
+        let started = Instant::now();
+        let recheck = self.schedule.interval.min(LEASE_RENEW_INTERVAL);
+        loop {
+            let Some((verifier, quarantine)) = &self.source_check else {
+                return Ok(delivery);
+            };
+            let max_wait = verifier.max_wait;
+            let err = match verifier.check(deposit).await {
+                Ok(SourceCheck::Verified) => return Ok(delivery),
+                Ok(SourceCheck::Rejected(reason)) => {
+                    error!(
+                        target: "security",
+                        "SECURITY ALERT: quarantining deposit of {} from {}: {}",
+                        deposit.amount, deposit.sender, reason
+                    );
+                    quarantine.record(deposit, &reason)?;
+                    self.nack_deposit(delivery).await?;
+                    return Err(RelayerError::DepositQuarantined(reason));
+                }
+                Ok(SourceCheck::Unconfirmed { confirmations }) => {
+                    debug!("Deposit has {} confirmations, waiting", confirmations);
+                    RelayerError::DepositUnconfirmed(confirmations)
+                }
+                Err(e) => {
+                    warn!("Source chain check failed, retrying: {}", e);
+                    e
+                }
+            };
+            if started.elapsed() >= max_wait {
+                warn!(
+                    "Deposit still not verified after {:?}, requeueing",
+                    max_wait
+                );
+                self.requeue_deposit(delivery).await?;
+                return Err(err);
+            }
+            self.shutdown.sleep(recheck).await;
+            self.health.tick("includer");
+            if self.shutdown.is_triggered() || !self.ensure_leadership().await {
+                self.requeue_deposit(delivery).await?;
+                return Err(err);
+            }
+        }
+    }
+
+    /// Parses the message, checks its signature and age, and returns its id
+    /// with the deposit it carries.
+    fn decode(&self, data: &[u8]) -> Result<(String, Deposit), RelayerError> {
+        let envelope = Envelope::from_bytes(data)?;
+        let rejected = match (&self.verifier, self.max_message_age) {
+            (Some(verifier), _) if let Err(e) = verifier.verify(&envelope) => Some(e),
+            (_, Some(max_age)) if !envelope.is_fresh(max_age) => {
+                Some(RelayerError::StaleMessage(format!(
+                    "produced at {} ms, not within {:?} of now",
+                    envelope.produced_at, max_age
+                )))
+            }
+            _ => None,
+        };
+        if let Some(e) = rejected {
+            error!(
+                target: "security",
+                "SECURITY ALERT: rejecting message {} from {}: {}",
+                envelope.message_id, envelope.source, e
+            );
+            return Err(e);
+        }
+        let message_id = envelope.message_id.clone();
+        Ok((message_id, envelope.into_deposit()?))
     }
 
-    pub async fn mint(&self, amount: i32) -> Result<Option<TransactionReceipt>> {
+    /// Why the message is a replay, if it is: its id was settled before, or
+    /// its deposit was already minted. Redeliveries, batch republishes,
+    /// backfills and replays of a minted deposit stop here.
+    ///
+    /// A settled message is only an alert when delivered fresh. The broker
+    /// redelivers one whose ack was lost with its channel after it settled.
+    async fn replayed(
+        &mut self,
+        message_id: &str,
+        deposit_id: &str,
+        redelivered: bool,
+    ) -> Result<Option<String>, RelayerError> {
+        let Some(states) = self.states.as_mut() else {
+            return Ok(None);
+        };
+        if states.message_settled(message_id).await? {
+            if redelivered {
+                warn!("Redelivered message {} was already settled", message_id);
+            } else {
+                error!(
+                    target: "security",
+                    "SECURITY ALERT: message {} was already settled", message_id
+                );
+            }
+            return Ok(Some(format!("message {message_id} was already settled")));
+        }
+        let record = states.get(deposit_id).await?;
+        if record.is_some_and(|r| r.state().is_some_and(DepositState::is_final)) {
+            info!("Deposit {} was already minted", deposit_id);
+            return Ok(Some(format!("deposit {deposit_id} was already minted")));
+        }
+        Ok(None)
+    }
+
+    pub async fn mint(&mut self, amount: i32) -> Result<TransactionReceipt> {
         info!("New deposit of amount {}", amount);
         let str_amount = amount.to_string();
         let number_value = DynSolValue::from(str_amount.clone());
-        let tx_hash = self
+        let tx_hash = *self
             .contract
             .function("mint", &[number_value])?
             .send()
             .await?
-            .watch()
-            .await?;
+            .tx_hash();
         debug!("tx_hash: {tx_hash}");
-        let receipt = self.provider.get_transaction_receipt(tx_hash).await?;
+        self.sent_mints.insert(tx_hash);
+        Ok(self.await_receipt(tx_hash).await?)
+    }
 
-        Ok(receipt)
+    /// Polls for the receipt of `hash` every poll interval, and at least
+    /// every `LEASE_RENEW_INTERVAL` so the lease is renewed while waiting.
+    /// Gives up after `RECEIPT_TIMEOUT`, or as soon as leadership is lost;
+    /// either way a pending mint stays recorded for whoever finishes it.
+    async fn await_receipt(&mut self, hash: B256) -> Result<TransactionReceipt, RelayerError> {
+        let started = Instant::now();
+        let interval = self.schedule.interval.min(LEASE_RENEW_INTERVAL);
+        loop {
+            if let Some(receipt) = self
+                .provider
+                .get_transaction_receipt(hash)
+                .await
+                .map_err(|e| RelayerError::ProviderError(e.to_string()))?
+            {
+                return Ok(receipt);
+            }
+            if started.elapsed() >= RECEIPT_TIMEOUT {
+                return Err(RelayerError::ReceiptTimeout(hash.to_string()));
+            }
+            tokio::time::sleep(interval).await;
+            self.health.tick("includer");
+            if !self.ensure_leadership().await {
+                return Err(RelayerError::LeaseLost);
+            }
+        }
     }
 
     pub async fn consume(
-        &self,
-        consumer: &mut Consumer,
-    ) -> Result<(Deposit, Delivery), RelayerError> {
+        &mut self,
+        consumer: &mut C::Consumer,
+    ) -> Result<(Deposit, DeliveryOf<C>), RelayerError> {
         info!("Waiting for a deposit message...");
-        match consumer.next().await {
+        let next = consumer.next_delivery().await;
+        self.open_delivery(next).await
+    }
+
+    /// Decodes the delivery. Undecodable and rejected messages are
+    /// dead-lettered, replays are acked and returned as `ReplayedMessage`.
+    async fn open_delivery(
+        &mut self,
+        next: Option<Result<DeliveryOf<C>, RelayerError>>,
+    ) -> Result<(Deposit, DeliveryOf<C>), RelayerError> {
+        match next {
             None => {
                 warn!("Stream Ended");
                 Err(RelayerError::Other(
                     "Consumer stream ended unexpectedly".into(),
                 ))
             }
-            Some(Err(e)) => Err(RelayerError::AmqpError(e)),
-            Some(Ok(delivery)) => match serde_json::from_slice::<Deposit>(&delivery.data) {
-                Ok(deposit) => {
-                    debug!(
-                        "Got deposit from {:?}, amount {}",
-                        deposit.sender, deposit.amount
-                    );
-                    Ok((deposit, delivery))
+            Some(Err(e)) => Err(e),
+            Some(Ok(delivery)) => {
+                let (message_id, deposit) = match self.decode(delivery.data()) {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        warn!("Rejecting undecodable message: {}", e);
+                        self.nack_deposit(delivery).await?;
+                        return Err(e);
+                    }
+                };
+                let id = deposit.id_or_hash(delivery.data());
+                match self
+                    .replayed(&message_id, &id, delivery.redelivered())
+                    .await
+                {
+                    Ok(None) => {
+                        debug!(
+                            "Got deposit from {:?}, amount {}",
+                            deposit.sender, deposit.amount
+                        );
+                        Ok((deposit, delivery))
+                    }
+                    Ok(Some(reason)) => {
+                        info!("Acking replayed message: {}", reason);
+                        self.settle_message(delivery.data()).await;
+                        self.ack_deposit(delivery).await?;
+                        Err(RelayerError::ReplayedMessage(reason))
+                    }
+                    Err(e) => {
+                        // Minting without knowing could mint twice.
+                        warn!(
+                            "Could not read state of deposit {}, requeueing: {:?}",
+                            id, e
+                        );
+                        self.requeue_deposit(delivery).await?;
+                        Err(e)
+                    }
                 }
-                Err(_) => Err(RelayerError::Other(String::from(
-                    "Failed to parse Deposit, skipping...",
-                ))),
-            },
+            }
         }
     }
 
+    /// Consumes deposits while this instance leads. A standby drops its
+    /// consumer so the broker hands every delivery to the leader, and a new
+    /// leader finishes the mints its predecessor left pending first.
+    ///
+    /// Deliveries are taken as soon as they arrive. Failures back off
+    /// following `schedule`, and a standby retries the lease every
+    /// `schedule.interval`.
+    ///
+    /// On `shutdown` it stops taking deliveries and gives the deposit in
+    /// progress until the grace deadline. A mint abandoned at the deadline
+    /// stays in the pending store and is finished on the next start. The
+    /// lease is then released and the queue connection closed.
     pub async fn run(&mut self) {
-        let mut consumer = self.queue_connection.consumer().await.unwrap();
+        let mut active: Option<(Option<u64>, C::Consumer)> = None;
+        let shutdown = self.shutdown.clone();
         debug!("Includer is alive.");
-        loop {
-            let res = self.process_deposit(&mut consumer).await;
+        while !shutdown.is_triggered() {
+            self.health.tick("includer");
+            if !self.ensure_leadership().await {
+                active = None;
+                shutdown.sleep(self.schedule.interval).await;
+                continue;
+            }
+            if active
+                .as_ref()
+                .is_none_or(|(fence, _)| *fence != self.fence)
+            {
+                active = None;
+                if let Err(e) = self.resume_pending().await {
+                    error!("Could not resume pending mints: {:?}", e);
+                    self.back_off().await;
+                    continue;
+                }
+                self.record_balance().await;
+                let consumer = self.queue_connection.consumer().await;
+                self.health.report("queue", &consumer);
+                match consumer {
+                    Ok(consumer) => active = Some((self.fence, consumer)),
+                    Err(e) => {
+                        error!("Could not create consumer: {:?}", e);
+                        self.back_off().await;
+                        continue;
+                    }
+                }
+            }
+            let (_, consumer) = active.as_mut().expect("consumer was just created");
+            let res = tokio::select! {
+                res = self.process_deposit(consumer) => res,
+                _ = shutdown.deadline() => {
+                    warn!(
+                        "Deposit still in progress {:?} after shutdown, leaving it to the pending store",
+                        shutdown.grace()
+                    );
+                    break;
+                }
+            };
             match res {
-                Ok(_) => {
+                Ok(true) => {
                     info!("Successfully processed Deposit");
+                    self.schedule.busy();
+                    self.record_balance().await;
                 }
+                Ok(false) => {}
                 Err(e) => {
                     error!("Error : {:?}", e);
+                    self.back_off().await;
+                }
+            }
+        }
+        // Unacked deliveries go back to the queue with the consumer.
+        drop(active);
+        self.stop().await;
+    }
+
+    /// Updates the wallet balance metric. Failures are logged, never fatal.
+    async fn record_balance(&self) {
+        match self.provider.get_balance(self.wallet).await {
+            Ok(balance) => {
+                self.metrics.set(&WALLET_BALANCE, &[], f64::from(balance));
+            }
+            Err(e) => warn!("Could not read wallet balance: {}", e),
+        }
+    }
+
+    /// Waits longer after each failure in a row, up to
+    /// `schedule.max_interval`.
+    async fn back_off(&mut self) {
+        self.schedule.idle();
+        self.shutdown.sleep(self.schedule.until_due()).await;
+    }
+
+    /// Releases the lease, so a standby takes over at once, and closes the
+    /// queue connection.
+    async fn stop(&mut self) {
+        if self.fence.take().is_some()
+            && let Some(lease) = self.leader_lease.as_mut()
+            && let Err(e) = lease.release().await
+        {
+            warn!("Could not release leader lease: {:?}", e);
+        }
+        if let Err(e) = self.queue_connection.close().await {
+            warn!("Could not close queue connection: {:?}", e);
+        }
+        info!("Includer stopped");
+    }
+
+    /// Waits for the next delivery and mints it. Returns false when none
+    /// arrived: the wait is cut short now and then, to renew the lease and
+    /// tick `health`, and on shutdown.
+    pub async fn process_deposit(
+        &mut self,
+        consumer: &mut C::Consumer,
+    ) -> Result<bool, RelayerError> {
+        let shutdown = self.shutdown.clone();
+        let next = tokio::select! {
+            next = timeout(LEASE_RENEW_INTERVAL, consumer.next_delivery()) => match next {
+                Ok(next) => next,
+                Err(_) => {
+                    debug!("No deposit within {:?}", LEASE_RENEW_INTERVAL);
+                    return Ok(false);
                 }
+            },
+            _ = shutdown.wait() => return Ok(false),
+        };
+        let received = match &next {
+            Some(Ok(_)) => Ok(()),
+            Some(Err(e)) => Err(e.to_string()),
+            None => Err("consumer stream ended".to_string()),
+        };
+        self.health.report("queue", &received);
+        let (deposit, delivery) = match self.open_delivery(next).await {
+            Ok(opened) => opened,
+            // Acked, with nothing left to mint.
+            Err(RelayerError::ReplayedMessage(_)) => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        debug!("Successfully received");
+        self.handle_deposit(deposit, delivery).await?;
+        Ok(true)
+    }
+
+    /// Mints the deposit inside its span. The span takes the id the
+    /// subscriber put in the message headers, so both binaries log the same
+    /// one; the pending and state stores key on the id derived here.
+    async fn handle_deposit(
+        &mut self,
+        deposit: Deposit,
+        delivery: DeliveryOf<C>,
+    ) -> Result<(), RelayerError> {
+        let id = deposit.id_or_hash(delivery.data());
+        let span = match delivery.header(DEPOSIT_ID_HEADER) {
+            Some(header) => deposit.span(Some(&header)),
+            None => deposit.span(Some(&id)),
+        };
+        self.mint_deposit(id, deposit, delivery)
+            .instrument(span)
+            .await
+    }
+
+    async fn mint_deposit(
+        &mut self,
+        id: String,
+        deposit: Deposit,
+        delivery: DeliveryOf<C>,
+    ) -> Result<(), RelayerError> {
+        let started = Instant::now();
+        let delivery = match self.verify_source(&deposit, delivery).await {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                let state = match &e {
+                    RelayerError::DepositQuarantined(reason) => DepositState::DeadLettered {
+                        reason: format!("quarantined: {reason}"),
+                    },
+                    // Requeued until the source chain confirms it.
+                    e => DepositState::Failed {
+                        reason: e.to_string(),
+                    },
+                };
+                self.record_state(&id, state).await;
+                return Err(e);
             }
-            let two_sec = time::Duration::from_millis(2000);
-            thread::sleep(two_sec);
-        }
-    }
-
-    pub async fn process_deposit(&mut self, consumer: &mut Consumer) -> Result<(), RelayerError> {
-        match self.consume(consumer).await {
-            Ok(dep) => {
-                debug!("Successfully received");
-                match self.mint(dep.0.amount).await {
-                    Ok(Some(receipt)) => {
-                        debug!("Transaction successful! Receipt: {:?}", receipt);
-                        if !receipt.status() {
-                            warn!("Transaction failed, status is 0");
-                        } else {
-                            match verify_minted_log(&receipt) {
-                                Ok(_) => {
-                                    info!("Tokens minted succesfully!");
-                                    self.ack_deposit(dep.1).await?;
-                                }
-                                Err(e) => {
-                                    error!("Couldn't verify minted log : {}", e);
-                                    self.nack_deposit(dep.1).await?;
-                                    return Err(RelayerError::Other(e.to_string()));
-                                }
+        };
+        if self.source_check.is_some() {
+            self.record_state(&id, DepositState::Confirmed).await;
+        }
+        let minted = if self.pending.is_some() {
+            self.mint_tracked(&id, deposit.amount).await
+        } else {
+            self.mint(deposit.amount).await.map_err(|e| {
+                e.downcast::<RelayerError>()
+                    .unwrap_or_else(|e| RelayerError::Other(e.to_string()))
+            })
+        };
+        match minted {
+            Ok(receipt) => {
+                debug!("Transaction successful! Receipt: {:?}", receipt);
+                let tx_hash = receipt.transaction_hash;
+                Span::current().record("dst_tx", field::display(tx_hash));
+                // Tracked mints were recorded by `send_mint` when sent.
+                if self.pending.is_none() {
+                    self.record_state(&id, DepositState::MintSubmitted { tx_hash })
+                        .await;
+                }
+                if self.sent_mints.remove(&tx_hash) {
+                    let gas_spent = receipt.gas_used as f64 * receipt.effective_gas_price as f64;
+                    self.metrics.add(&GAS_SPENT, &[], gas_spent);
+                }
+                if !receipt.status() {
+                    warn!("Transaction failed, status is 0");
+                    self.nack_deposit(delivery).await?;
+                    let reason = format!("mint {tx_hash} reverted");
+                    self.record_state(
+                        &id,
+                        DepositState::DeadLettered {
+                            reason: reason.clone(),
+                        },
+                    )
+                    .await;
+                    // The reverted mint used up its nonce. Clearing it lets
+                    // a replay from the dead letters mint afresh.
+                    if let Err(e) = self.pending_remove(&id).await {
+                        warn!("Could not clear pending mint for {}: {:?}", id, e);
+                    }
+                    return Err(RelayerError::Other(reason));
+                } else {
+                    match verify_minted_log(&receipt) {
+                        Ok(_) => {
+                            info!("Tokens minted succesfully!");
+                            // Recorded before the ack, so a redelivery after
+                            // a crash in between is acked without minting.
+                            let recorded = self
+                                .record_state(&id, DepositState::MintConfirmed { tx_hash })
+                                .await;
+                            self.settle_message(delivery.data()).await;
+                            self.ack_deposit(delivery).await?;
+                            self.metrics.inc(&DEPOSITS_MINTED, &[]);
+                            self.metrics.observe(
+                                &MINT_LATENCY,
+                                &[],
+                                started.elapsed().as_secs_f64(),
+                            );
+                            // The pending entry is the only other trace of
+                            // the mint, so it stays unless the state was
+                            // recorded.
+                            if recorded && let Err(e) = self.pending_remove(&id).await {
+                                warn!("Could not clear pending mint for {}: {:?}", id, e);
                             }
                         }
+                        Err(e) => {
+                            error!("Couldn't verify minted log : {}", e);
+                            self.nack_deposit(delivery).await?;
+                            let reason = format!("could not verify minted log: {e}");
+                            self.record_state(&id, DepositState::DeadLettered { reason })
+                                .await;
+                            return Err(RelayerError::Other(e.to_string()));
+                        }
                     }
-                    Ok(None) => {
-                        warn!("Transaction sent, but no receipt found.");
-                        self.nack_deposit(dep.1).await?;
-                    }
+                }
+            }
+            Err(
+                e @ (RelayerError::FencedOut { .. }
+                | RelayerError::LeaseLost
+                | RelayerError::ReceiptTimeout(_)),
+            ) if self.pending.is_some() => {
+                // The mint is still pending: the new leader, or the
+                // redelivery, finishes it.
+                self.requeue_deposit(delivery).await?;
+                let reason = e.to_string();
+                self.record_state(&id, DepositState::Failed { reason })
+                    .await;
+                return Err(e);
+            }
+            Err(e @ (RelayerError::LeaseLost | RelayerError::ReceiptTimeout(_))) => {
+                // Without a pending store nothing remembers the sent mint,
+                // so a redelivery would mint again. The dead letter is for
+                // an operator to check on chain before replaying it.
+                warn!("Mint sent but unconfirmed, dead-lettering: {:?}", e);
+                self.nack_deposit(delivery).await?;
+                let reason = format!("mint sent but unconfirmed: {e}");
+                self.record_state(&id, DepositState::DeadLettered { reason })
+                    .await;
+                return Err(e);
+            }
+            Err(e) => {
+                error!("Error minting : {:?}", e);
+                if self.mint_may_be_sent(&id).await {
+                    // The sent mint may still land, and the redelivery
+                    // settles it from the pending store.
+                    self.requeue_deposit(delivery).await?;
+                    let reason = e.to_string();
+                    self.record_state(&id, DepositState::Failed { reason })
+                        .await;
+                    return Err(e);
+                }
+                self.nack_deposit(delivery).await?;
+                let reason = e.to_string();
+                self.record_state(&id, DepositState::DeadLettered { reason })
+                    .await;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remembers the id of a message about to be acked, so a replay of it
+    /// is refused. Failures are logged, never fatal: the deposit state
+    /// still stops a replay from minting again.
+    async fn settle_message(&mut self, data: &[u8]) {
+        let Some(states) = self.states.as_mut() else {
+            return;
+        };
+        let Ok(envelope) = Envelope::from_bytes(data) else {
+            return;
+        };
+        // Past its max age a replay is refused as stale, so the id is not
+        // needed any longer.
+        let retention = self
+            .max_message_age
+            .unwrap_or(DEFAULT_SETTLED_RETENTION)
+            .saturating_add(MAX_CLOCK_SKEW);
+        if let Err(e) = states.settle_message(&envelope.message_id, retention).await {
+            warn!(
+                "Could not record message {} as settled: {:?}",
+                envelope.message_id, e
+            );
+        }
+    }
+
+    /// Records a lifecycle transition. Failures are logged, never fatal.
+    /// Returns whether the state was stored.
+    async fn record_state(&mut self, deposit_id: &str, state: DepositState) -> bool {
+        let Some(states) = self.states.as_mut() else {
+            return false;
+        };
+        match states.transition(deposit_id, state, "includer").await {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Could not record state of deposit {}: {:?}", deposit_id, e);
+                false
+            }
+        }
+    }
+
+    /// Whether a failed mint may have reached the chain. A pending entry
+    /// that never got a hash is cleared, so `resume_pending` does not send
+    /// the mint of a dead-lettered deposit. When the store cannot tell, the
+    /// mint is taken as sent.
+    async fn mint_may_be_sent(&mut self, deposit_id: &str) -> bool {
+        let Some(store) = self.pending.as_mut() else {
+            return false;
+        };
+        match store.get(self.wallet, deposit_id).await {
+            Ok(None) => false,
+            Ok(Some(tx)) if tx.tx_hashes.is_empty() => {
+                match self.pending_remove(deposit_id).await {
+                    Ok(()) => false,
                     Err(e) => {
-                        error!("Error minting : {:?}", e);
-                        self.nack_deposit(dep.1).await?;
-                        return Err(RelayerError::Other(e.to_string()));
+                        warn!("Could not clear unsent mint for {}: {:?}", deposit_id, e);
+                        true
                     }
                 }
             }
+            Ok(Some(_)) => true,
             Err(e) => {
-                error!("Error processing receive: {:?}", e);
+                warn!("Could not read pending mint for {}: {:?}", deposit_id, e);
+                true
+            }
+        }
+    }
+
+    /// Mints through the pending store: the nonce is reserved before the
+    /// transaction is sent, and a deposit that already has a pending entry
+    /// is finished with its recorded nonce rather than minted again.
+    pub async fn mint_tracked(
+        &mut self,
+        deposit_id: &str,
+        amount: i32,
+    ) -> Result<TransactionReceipt, RelayerError> {
+        let existing = match self.pending.as_mut() {
+            Some(store) => store.get(self.wallet, deposit_id).await?,
+            None => return Err(RelayerError::Other("No pending store configured".into())),
+        };
+        let hash = match existing {
+            Some(mut tx) => match self.mint_state(&tx).await? {
+                MintState::Mined(receipt) => {
+                    info!("Mint for deposit {} was already mined", deposit_id);
+                    return Ok(*receipt);
+                }
+                MintState::InFlight(hash) => hash,
+                MintState::NonceTaken => {
+                    return Err(RelayerError::Other(format!(
+                        "Nonce {} reserved for deposit {} was used by an unrecorded transaction",
+                        tx.nonce, deposit_id
+                    )));
+                }
+                MintState::Unsent => self.send_mint(&mut tx).await?,
+            },
+            None => {
+                info!("New deposit of amount {}", amount);
+                let mut tx = PendingTx {
+                    deposit_id: deposit_id.to_string(),
+                    amount,
+                    nonce: self.next_nonce().await?,
+                    tx_hashes: Vec::new(),
+                };
+                self.pending_put(&tx).await?;
+                self.send_mint(&mut tx).await?
+            }
+        };
+        debug!("tx_hash: {hash}");
+        self.await_receipt(hash).await
+    }
+
+    /// Resubmits every pending mint that never reached the chain, with its
+    /// recorded nonce, so later nonces are not stuck behind a gap. Mints
+    /// still in flight or already mined are settled when their deposit is
+    /// redelivered.
+    pub async fn resume_pending(&mut self) -> Result<(), RelayerError> {
+        let Some(store) = self.pending.as_mut() else {
+            return Ok(());
+        };
+        let txs = store.list(self.wallet).await?;
+        for mut tx in txs {
+            match self.mint_state(&tx).await? {
+                MintState::Mined(_) => debug!("Mint for deposit {} is mined", tx.deposit_id),
+                MintState::InFlight(hash) => {
+                    info!(
+                        "Mint for deposit {} is in flight as {}",
+                        tx.deposit_id, hash
+                    )
+                }
+                MintState::NonceTaken => warn!(
+                    "Nonce {} of deposit {} was used by an unrecorded transaction",
+                    tx.nonce, tx.deposit_id
+                ),
+                MintState::Unsent => {
+                    info!(
+                        "Resubmitting mint for deposit {} with nonce {}",
+                        tx.deposit_id, tx.nonce
+                    );
+                    self.send_mint(&mut tx).await?;
+                }
             }
         }
         Ok(())
     }
 
-    pub async fn nack_deposit(&self, delivery: Delivery) -> Result<(), RelayerError> {
-        delivery
-            .nack(BasicNackOptions {
-                multiple: false,
-                requeue: false,
-            })
+    async fn mint_state(&self, tx: &PendingTx) -> Result<MintState, RelayerError> {
+        let provider_err =
+            |e: alloy::transports::TransportError| RelayerError::ProviderError(e.to_string());
+        // Read the nonce first: if it is used and none of our hashes has a
+        // receipt afterwards, the nonce went to some other transaction.
+        let mined_nonce = self
+            .provider
+            .get_transaction_count(self.wallet)
+            .latest()
+            .await
+            .map_err(provider_err)?;
+        for hash in tx.tx_hashes.iter().rev() {
+            if let Some(receipt) = self
+                .provider
+                .get_transaction_receipt(*hash)
+                .await
+                .map_err(provider_err)?
+            {
+                return Ok(MintState::Mined(Box::new(receipt)));
+            }
+        }
+        if mined_nonce > tx.nonce {
+            return Ok(MintState::NonceTaken);
+        }
+        for hash in tx.tx_hashes.iter().rev() {
+            if self
+                .provider
+                .get_transaction_by_hash(*hash)
+                .await
+                .map_err(provider_err)?
+                .is_some()
+            {
+                return Ok(MintState::InFlight(*hash));
+            }
+        }
+        Ok(MintState::Unsent)
+    }
+
+    /// First nonce not taken on chain nor reserved by a pending mint.
+    async fn next_nonce(&mut self) -> Result<u64, RelayerError> {
+        let on_chain = self
+            .provider
+            .get_transaction_count(self.wallet)
+            .pending()
+            .await
+            .map_err(|e| RelayerError::ProviderError(e.to_string()))?;
+        let reserved = match self.pending.as_mut() {
+            Some(store) => store
+                .list(self.wallet)
+                .await?
+                .iter()
+                .map(|tx| tx.nonce + 1)
+                .max()
+                .unwrap_or(0),
+            None => 0,
+        };
+        Ok(on_chain.max(reserved))
+    }
+
+    /// Sends the mint with its reserved nonce and records the hash.
+    async fn send_mint(&mut self, tx: &mut PendingTx) -> Result<B256, RelayerError> {
+        let number_value = DynSolValue::from(tx.amount.to_string());
+        let pending = self
+            .contract
+            .function("mint", &[number_value])
+            .map_err(|e| RelayerError::Other(e.to_string()))?
+            .nonce(tx.nonce)
+            .send()
+            .await
+            .map_err(|e| RelayerError::ProviderError(e.to_string()))?;
+        let hash = *pending.tx_hash();
+        Span::current().record("dst_tx", field::display(hash));
+        self.sent_mints.insert(hash);
+        tx.tx_hashes.push(hash);
+        self.pending_put(tx).await?;
+        self.record_state(
+            &tx.deposit_id,
+            DepositState::MintSubmitted { tx_hash: hash },
+        )
+        .await;
+        Ok(hash)
+    }
+
+    /// Token for pending store writes. Without a lease there is no
+    /// election to fence against, so writes go through unchecked.
+    fn write_fence(&self) -> Option<u64> {
+        self.leader_lease.as_ref().map(|_| self.fence.unwrap_or(0))
+    }
+
+    async fn pending_put(&mut self, tx: &PendingTx) -> Result<(), RelayerError> {
+        let fence = self.write_fence();
+        let Some(store) = self.pending.as_mut() else {
+            return Ok(());
+        };
+        let res = store.put(self.wallet, tx, fence).await;
+        self.check_fenced(res)
+    }
+
+    async fn pending_remove(&mut self, deposit_id: &str) -> Result<(), RelayerError> {
+        let fence = self.write_fence();
+        let Some(store) = self.pending.as_mut() else {
+            return Ok(());
+        };
+        let res = store.remove(self.wallet, deposit_id, fence).await;
+        self.check_fenced(res)
+    }
+
+    fn check_fenced(&mut self, res: Result<(), RelayerError>) -> Result<(), RelayerError> {
+        if let Err(RelayerError::FencedOut { current, .. }) = &res {
+            error!("Pending mint write was fenced off, stepping down");
+            self.fence = None;
+            self.fenced_by = Some(*current);
+        }
+        res
+    }
+
+    pub async fn nack_deposit(&self, delivery: DeliveryOf<C>) -> Result<(), RelayerError> {
+        delivery.nack(false).await?;
+        self.metrics.inc(&NACKS, &[("requeue", "false")]);
+        self.metrics.inc(&DEAD_LETTERED, &[]);
+        Ok(())
+    }
+
+    pub async fn requeue_deposit(&self, delivery: DeliveryOf<C>) -> Result<(), RelayerError> {
+        delivery.nack(true).await?;
+        self.metrics.inc(&NACKS, &[("requeue", "true")]);
+        Ok(())
+    }
+
+    pub async fn ack_deposit(&self, delivery: DeliveryOf<C>) -> Result<(), RelayerError> {
+        delivery.ack().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::leader::FileLease;
+    use crate::lifecycle::InMemoryStateStore;
+    use crate::pending::InMemoryPendingStore;
+    use crate::queue::memory::InMemoryQueue;
+    use crate::signing::MessageSigner;
+    use crate::subscriber::DepositOrigin;
+    use alloy::primitives::U64;
+    use alloy::primitives::keccak256;
+    use alloy::providers::mock::Asserter;
+    use serde_json::json;
+    use std::time::Duration;
+
+    const PRIVATE_KEY: &str = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+    fn includer(queue: InMemoryQueue) -> Includer<InMemoryQueue> {
+        Includer::with_abi(&test_config(), JsonAbi::default(), queue, Metrics::new()).unwrap()
+    }
+
+    /// Points the includer at a mocked destination chain.
+    fn mock_chain(incl: &mut Includer<InMemoryQueue>, asserter: Asserter) {
+        let pk: PrivateKeySigner = PRIVATE_KEY.parse().unwrap();
+        incl.provider = ProviderBuilder::new()
+            .wallet(EthereumWallet::from(pk))
+            .on_mocked_client(asserter);
+        incl.contract = ContractInstance::new(
+            *incl.contract.address(),
+            incl.provider.clone(),
+            Interface::new(incl.contract.abi().clone()),
+        );
+    }
+
+    /// An includer whose contract has a `mint` function, for tests that
+    /// send mints.
+    fn minting_includer(queue: InMemoryQueue) -> Includer<InMemoryQueue> {
+        let abi = JsonAbi::parse(["function mint(string amount)"]).unwrap();
+        Includer::with_abi(&test_config(), abi, queue, Metrics::new()).unwrap()
+    }
+
+    /// Answers the requests that fill and send a transaction hashed `hash`.
+    fn push_sent_mint(asserter: &Asserter, hash: B256) {
+        asserter.push_success(&U64::from(21000));
+        asserter.push_success(&json!({
+            "oldestBlock": "0x1",
+            "baseFeePerGas": ["0x1", "0x1"],
+            "gasUsedRatio": [0.5],
+            "reward": [["0x1"]]
+        }));
+        asserter.push_success(&U64::from(7));
+        asserter.push_success(&U64::from(31337));
+        asserter.push_success(&hash);
+    }
+
+    fn pending_tx(hash: B256) -> PendingTx {
+        PendingTx {
+            deposit_id: "deposit".into(),
+            amount: 42,
+            nonce: 7,
+            tx_hashes: vec![hash],
+        }
+    }
+
+    fn receipt(hash: B256) -> Value {
+        receipt_with_logs(hash, json!([]))
+    }
+
+    fn reverted_receipt(hash: B256) -> Value {
+        let mut receipt = receipt(hash);
+        receipt["status"] = json!("0x0");
+        receipt
+    }
+
+    fn receipt_with_logs(hash: B256, logs: Value) -> Value {
+        json!({
+            "transactionHash": hash,
+            "transactionIndex": "0x0",
+            "blockHash": B256::repeat_byte(2),
+            "blockNumber": "0x64",
+            "from": Address::default(),
+            "to": Address::default(),
+            "contractAddress": null,
+            "gasUsed": "0x5208",
+            "cumulativeGasUsed": "0x5208",
+            "effectiveGasPrice": "0x1",
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "type": "0x2",
+            "status": "0x1",
+            "logs": logs
+        })
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_waiting_on_shutdown_and_releases_lease() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("includer.lock");
+        let queue = InMemoryQueue::new();
+        let shutdown = Shutdown::new();
+        let mut incl = includer(queue.clone())
+            .with_leader_lease(Some(Box::new(FileLease::new(&lock))))
+            .with_shutdown(shutdown.clone());
+        let handle = tokio::spawn(async move { incl.run().await });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut standby = FileLease::new(&lock);
+        assert_eq!(standby.acquire().await.unwrap(), None);
+
+        // Nothing is queued: the includer is waiting for a delivery.
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .expect("includer did not stop")
+            .unwrap();
+        assert!(standby.acquire().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_run_backs_off_after_failures_until_shutdown() {
+        let queue = InMemoryQueue::new();
+        let shutdown = Shutdown::new();
+        let mut incl = includer(queue.clone())
+            .with_poll_schedule(PollSchedule::new(
+                Duration::from_secs(60),
+                Duration::from_secs(600),
+            ))
+            .with_shutdown(shutdown.clone());
+        // The consumer stream ends at once, which fails the first delivery.
+        queue.close();
+        let handle = tokio::spawn(async move {
+            incl.run().await;
+            incl
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!handle.is_finished());
+        shutdown.trigger();
+        let incl = tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("includer did not stop")
+            .unwrap();
+        assert!(incl.schedule.until_due() > Duration::from_secs(50));
+    }
+
+    #[tokio::test]
+    async fn test_standby_is_fenced_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("includer.lock");
+        let store = InMemoryPendingStore::new();
+        let queue = InMemoryQueue::new();
+        let mut a = includer(queue.clone())
+            .with_leader_lease(Some(Box::new(FileLease::new(&lock))))
+            .with_pending_store(Some(Box::new(store.clone())));
+        let mut b = includer(queue)
+            .with_leader_lease(Some(Box::new(FileLease::new(&lock))))
+            .with_pending_store(Some(Box::new(store.clone())));
+
+        assert!(a.ensure_leadership().await);
+        assert!(!b.ensure_leadership().await);
+        a.pending_put(&pending_tx(B256::ZERO)).await.unwrap();
+
+        // `a` stalls and loses the lease; `b` takes over.
+        a.leader_lease.as_mut().unwrap().release().await.unwrap();
+        assert!(b.ensure_leadership().await);
+        b.pending_remove("deposit").await.unwrap();
+
+        // A late write from the old leader is refused and it steps down.
+        let err = a.pending_put(&pending_tx(B256::ZERO)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            RelayerError::FencedOut {
+                token: 1,
+                current: 2
+            }
+        ));
+        assert_eq!(a.fence, None);
+        let mut store = store;
+        assert!(store.list(a.wallet).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lost_lock_file_recovers_past_store_fence() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = InMemoryPendingStore::new();
+        let mut incl = includer(InMemoryQueue::new())
+            .with_leader_lease(Some(Box::new(FileLease::new(
+                dir.path().join("includer.lock"),
+            ))))
+            .with_pending_store(Some(Box::new(store.clone())));
+        // The store saw token 5 before the lock file was deleted.
+        store
+            .put(incl.wallet, &pending_tx(B256::ZERO), Some(5))
+            .await
+            .unwrap();
+
+        assert!(incl.ensure_leadership().await);
+        assert_eq!(incl.fence, Some(1));
+        assert!(incl.pending_remove("deposit").await.is_err());
+        assert!(incl.ensure_leadership().await);
+        assert_eq!(incl.fence, Some(6));
+        incl.pending_remove("deposit").await.unwrap();
+        assert!(store.list(incl.wallet).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unleased_writes_ignore_stored_fence() {
+        let mut store = InMemoryPendingStore::new();
+        let mut incl =
+            includer(InMemoryQueue::new()).with_pending_store(Some(Box::new(store.clone())));
+        // A leased includer left token 3 behind before election was turned off.
+        store
+            .put(incl.wallet, &pending_tx(B256::ZERO), Some(3))
+            .await
+            .unwrap();
+
+        incl.pending_put(&pending_tx(B256::repeat_byte(1)))
+            .await
+            .unwrap();
+        incl.pending_remove("deposit").await.unwrap();
+        assert!(store.list(incl.wallet).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mint_tracked_finishes_recorded_mint() {
+        let hash = B256::repeat_byte(1);
+        let mut incl = includer(InMemoryQueue::new())
+            .with_pending_store(Some(Box::new(InMemoryPendingStore::new())));
+        incl.pending_put(&pending_tx(hash)).await.unwrap();
+
+        // The previous leader's transaction was mined: nothing is sent.
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(8));
+        asserter.push_success(&receipt(hash));
+        mock_chain(&mut incl, asserter);
+        let receipt = incl.mint_tracked("deposit", 42).await.unwrap();
+        assert_eq!(receipt.transaction_hash, hash);
+    }
+
+    #[tokio::test]
+    async fn test_mint_tracked_refuses_taken_nonce() {
+        let hash = B256::repeat_byte(1);
+        let mut incl = includer(InMemoryQueue::new())
+            .with_pending_store(Some(Box::new(InMemoryPendingStore::new())));
+        incl.pending_put(&pending_tx(hash)).await.unwrap();
+
+        // Nonce 7 is used, but not by the recorded hash.
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(8));
+        asserter.push_success(&Value::Null);
+        mock_chain(&mut incl, asserter);
+        let err = incl.mint_tracked("deposit", 42).await.unwrap_err();
+        assert!(err.to_string().contains("unrecorded transaction"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_await_receipt_renews_lease_between_polls() {
+        let hash = B256::repeat_byte(1);
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("includer.lock");
+        let mut incl = includer(InMemoryQueue::new())
+            .with_leader_lease(Some(Box::new(FileLease::new(&lock))))
+            .with_poll_schedule(PollSchedule::new(
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            ));
+        assert!(incl.ensure_leadership().await);
+
+        // Not mined on the first poll, mined on the second.
+        let asserter = Asserter::new();
+        asserter.push_success(&Value::Null);
+        asserter.push_success(&receipt(hash));
+        mock_chain(&mut incl, asserter);
+        let receipt = incl.await_receipt(hash).await.unwrap();
+        assert_eq!(receipt.transaction_hash, hash);
+
+        // Once the lease is gone it stops waiting.
+        incl.leader_lease.as_mut().unwrap().release().await.unwrap();
+        let mut standby = FileLease::new(&lock);
+        assert!(standby.acquire().await.unwrap().is_some());
+        let asserter = Asserter::new();
+        asserter.push_success(&Value::Null);
+        mock_chain(&mut incl, asserter);
+        let err = incl.await_receipt(hash).await.unwrap_err();
+        assert!(matches!(err, RelayerError::LeaseLost));
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_mint_is_recorded_and_acked() {
+        let hash = B256::repeat_byte(1);
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(9),
+                log_index: 0,
+                block_number: 5,
+            }),
+        };
+        let id = deposit.id().unwrap();
+        let mut queue = InMemoryQueue::new();
+        queue
+            .publish_envelope(&Envelope::deposit(&deposit, "test").unwrap())
+            .await
+            .unwrap();
+
+        let mut pending = InMemoryPendingStore::new();
+        let mut states = InMemoryStateStore::new();
+        let mut incl = includer(queue.clone())
+            .with_pending_store(Some(Box::new(pending.clone())))
+            .with_state_store(Some(Box::new(states.clone())));
+        let mut tx = pending_tx(hash);
+        tx.deposit_id = id.clone();
+        incl.pending_put(&tx).await.unwrap();
+        states
+            .transition(
+                &id,
+                DepositState::MintSubmitted { tx_hash: hash },
+                "includer",
+            )
+            .await
+            .unwrap();
+
+        // The mint went through before the previous leader could ack.
+        let minted_log = json!([{
+            "address": Address::default(),
+            "topics": [keccak256("Minted(address,string)")],
+            "data": "0x",
+            "blockHash": B256::repeat_byte(2),
+            "blockNumber": "0x64",
+            "transactionHash": hash,
+            "transactionIndex": "0x0",
+            "logIndex": "0x0",
+            "removed": false
+        }]);
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(8));
+        asserter.push_success(&receipt_with_logs(hash, minted_log));
+        mock_chain(&mut incl, asserter);
+
+        let mut consumer = queue.consumer().await.unwrap();
+        assert!(incl.process_deposit(&mut consumer).await.unwrap());
+        assert_eq!(queue.ready_len(), 0);
+        assert!(queue.dead_letters().is_empty());
+        assert!(pending.list(incl.wallet).await.unwrap().is_empty());
+
+        let record = states.get(&id).await.unwrap().unwrap();
+        let history: Vec<_> = record.history.iter().map(|t| t.state.clone()).collect();
+        assert_eq!(
+            history,
+            vec![
+                DepositState::MintSubmitted { tx_hash: hash },
+                DepositState::MintConfirmed { tx_hash: hash },
+            ]
+        );
+        assert_eq!(incl.metrics.get(&DEPOSITS_MINTED, &[]), Some(1.0));
+        assert_eq!(incl.metrics.get(&MINT_LATENCY, &[]), Some(1.0));
+        // The gas was spent, and counted, by the previous leader.
+        assert_eq!(incl.metrics.get(&GAS_SPENT, &[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_minted_deposit_is_acked_without_minting() {
+        let hash = B256::repeat_byte(1);
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(9),
+                log_index: 0,
+                block_number: 5,
+            }),
+        };
+        let id = deposit.id().unwrap();
+        let mut queue = InMemoryQueue::new();
+        let envelope = Envelope::deposit(&deposit, "test").unwrap();
+        queue.publish_envelope(&envelope).await.unwrap();
+
+        let mut states = InMemoryStateStore::new();
+        for state in [
+            DepositState::MintSubmitted { tx_hash: hash },
+            DepositState::MintConfirmed { tx_hash: hash },
+        ] {
+            states.transition(&id, state, "includer").await.unwrap();
+        }
+        // Any request to the chain fails the test.
+        let mut incl = includer(queue.clone()).with_state_store(Some(Box::new(states)));
+        mock_chain(&mut incl, Asserter::new());
+
+        let mut consumer = queue.consumer().await.unwrap();
+        assert!(incl.process_deposit(&mut consumer).await.unwrap());
+        assert_eq!(queue.ready_len(), 0);
+        assert_eq!(queue.unacked_len(), 0);
+        assert!(queue.dead_letters().is_empty());
+        assert_eq!(incl.metrics.get(&DEPOSITS_MINTED, &[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_consume_and_ack_deposit() {
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: None,
+        };
+        let mut queue = InMemoryQueue::new();
+        queue
+            .publish_envelope(&Envelope::deposit(&deposit, "test").unwrap())
             .await
-            .map_err(RelayerError::AmqpError)
+            .unwrap();
+        let mut incl = includer(queue.clone());
+        let mut consumer = queue.consumer().await.unwrap();
+        let (received, delivery) = incl.consume(&mut consumer).await.unwrap();
+        assert_eq!(received, deposit);
+        incl.ack_deposit(delivery).await.unwrap();
+        assert_eq!(queue.ready_len(), 0);
+        assert_eq!(queue.unacked_len(), 0);
     }
 
-    pub async fn ack_deposit(&self, delivery: Delivery) -> Result<(), RelayerError> {
-        delivery
-            .ack(BasicAckOptions::default())
+    #[tokio::test]
+    async fn test_unparseable_message_is_dead_lettered() {
+        let mut queue = InMemoryQueue::new();
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 1,
+            origin: None,
+        };
+        let mut future_envelope = Envelope::deposit(&deposit, "test").unwrap();
+        future_envelope.version = 99;
+        queue.publish(b"not a deposit").await.unwrap();
+        queue.publish_envelope(&future_envelope).await.unwrap();
+        let mut incl = includer(queue.clone());
+        let mut consumer = queue.consumer().await.unwrap();
+        let err = incl.consume(&mut consumer).await.err().unwrap();
+        assert!(matches!(err, RelayerError::SerdeError(_)));
+        let err = incl.consume(&mut consumer).await.err().unwrap();
+        assert!(matches!(err, RelayerError::UnsupportedEnvelopeVersion(99)));
+        assert_eq!(queue.dead_letters().len(), 2);
+        assert_eq!(queue.unacked_len(), 0);
+        assert_eq!(incl.metrics.get(&DEAD_LETTERED, &[]), Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_and_tampered_messages_are_rejected() {
+        let key = vec![3u8; 32];
+        let signer = MessageSigner::Hmac(key.clone());
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 5,
+            origin: None,
+        };
+        let mut queue = InMemoryQueue::new();
+
+        let unsigned = Envelope::deposit(&deposit, "test").unwrap();
+        let mut tampered = Envelope::deposit(&deposit, "test").unwrap();
+        signer.sign(&mut tampered).unwrap();
+        tampered.payload["amount"] = json!(5_000_000);
+        let mut valid = Envelope::deposit(&deposit, "test").unwrap();
+        signer.sign(&mut valid).unwrap();
+        for envelope in [&unsigned, &tampered, &valid] {
+            queue.publish_envelope(envelope).await.unwrap();
+        }
+
+        let mut incl = includer(queue.clone()).with_verifier(Some(MessageVerifier::Hmac(key)));
+        let mut consumer = queue.consumer().await.unwrap();
+        for _ in 0..2 {
+            let err = incl.consume(&mut consumer).await.err().unwrap();
+            assert!(matches!(err, RelayerError::InvalidSignature(_)));
+        }
+        let (received, delivery) = incl.consume(&mut consumer).await.unwrap();
+        assert_eq!(received, deposit);
+        incl.ack_deposit(delivery).await.unwrap();
+        assert_eq!(queue.dead_letters().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_settled_message_is_acked_without_minting() {
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: None,
+        };
+        let mut queue = InMemoryQueue::new();
+        let envelope = Envelope::deposit(&deposit, "test").unwrap();
+        queue.publish_envelope(&envelope).await.unwrap();
+
+        let mut states = InMemoryStateStore::new();
+        states
+            .settle_message(&envelope.message_id, Duration::from_secs(60))
+            .await
+            .unwrap();
+        // Any request to the chain fails the test.
+        let mut incl = includer(queue.clone()).with_state_store(Some(Box::new(states)));
+        mock_chain(&mut incl, Asserter::new());
+
+        let mut consumer = queue.consumer().await.unwrap();
+        assert!(incl.process_deposit(&mut consumer).await.unwrap());
+        assert_eq!(queue.unacked_len(), 0);
+        assert!(queue.dead_letters().is_empty());
+        assert_eq!(incl.metrics.get(&DEPOSITS_MINTED, &[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_stale_message_is_dead_lettered() {
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: None,
+        };
+        let mut queue = InMemoryQueue::new();
+        let mut envelope = Envelope::deposit(&deposit, "test").unwrap();
+        envelope.produced_at -= 3_600_000;
+        queue.publish_envelope(&envelope).await.unwrap();
+
+        let mut incl = includer(queue.clone()).with_max_message_age(Some(Duration::from_secs(600)));
+        mock_chain(&mut incl, Asserter::new());
+
+        let mut consumer = queue.consumer().await.unwrap();
+        let err = incl.process_deposit(&mut consumer).await.unwrap_err();
+        assert!(matches!(err, RelayerError::StaleMessage(_)), "{err}");
+        assert_eq!(queue.dead_letters().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reverted_mint_is_dead_lettered() {
+        let hash = B256::repeat_byte(1);
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(9),
+                log_index: 0,
+                block_number: 5,
+            }),
+        };
+        let id = deposit.id().unwrap();
+        let mut queue = InMemoryQueue::new();
+        queue
+            .publish_envelope(&Envelope::deposit(&deposit, "test").unwrap())
+            .await
+            .unwrap();
+
+        let mut pending = InMemoryPendingStore::new();
+        let mut states = InMemoryStateStore::new();
+        let mut incl = includer(queue.clone())
+            .with_pending_store(Some(Box::new(pending.clone())))
+            .with_state_store(Some(Box::new(states.clone())));
+        let mut tx = pending_tx(hash);
+        tx.deposit_id = id.clone();
+        incl.pending_put(&tx).await.unwrap();
+        // Sent by this process before the deposit was redelivered.
+        incl.sent_mints.insert(hash);
+
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(8));
+        asserter.push_success(&reverted_receipt(hash));
+        mock_chain(&mut incl, asserter);
+
+        let mut consumer = queue.consumer().await.unwrap();
+        let err = incl.process_deposit(&mut consumer).await.unwrap_err();
+        assert!(err.to_string().contains("reverted"), "{err}");
+        assert_eq!(queue.unacked_len(), 0);
+        assert_eq!(queue.ready_len(), 0);
+        assert_eq!(queue.dead_letters().len(), 1);
+        assert!(pending.list(incl.wallet).await.unwrap().is_empty());
+        let record = states.get(&id).await.unwrap().unwrap();
+        assert!(matches!(
+            record.state(),
+            Some(DepositState::DeadLettered { .. })
+        ));
+        // A reverted mint still pays for its gas.
+        assert_eq!(incl.metrics.get(&GAS_SPENT, &[]), Some(21000.0));
+        assert!(incl.sent_mints.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_mint_without_pending_store_is_dead_lettered() {
+        let hash = B256::repeat_byte(1);
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(9),
+                log_index: 0,
+                block_number: 5,
+            }),
+        };
+        let id = deposit.id().unwrap();
+        let mut queue = InMemoryQueue::new();
+        queue
+            .publish_envelope(&Envelope::deposit(&deposit, "test").unwrap())
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("includer.lock");
+        let mut states = InMemoryStateStore::new();
+        let mut incl = minting_includer(queue.clone())
+            .with_state_store(Some(Box::new(states.clone())))
+            .with_leader_lease(Some(Box::new(FileLease::new(&lock))))
+            .with_poll_schedule(PollSchedule::new(
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            ));
+        assert!(incl.ensure_leadership().await);
+        let asserter = Asserter::new();
+        push_sent_mint(&asserter, hash);
+        asserter.push_success(&Value::Null);
+        mock_chain(&mut incl, asserter);
+
+        // The lease is lost while the mint waits for its receipt.
+        incl.leader_lease.as_mut().unwrap().release().await.unwrap();
+        let mut standby = FileLease::new(&lock);
+        assert!(standby.acquire().await.unwrap().is_some());
+
+        let mut consumer = queue.consumer().await.unwrap();
+        let err = incl.process_deposit(&mut consumer).await.unwrap_err();
+        assert!(matches!(err, RelayerError::LeaseLost), "{err}");
+        // Requeueing would mint a second time, as nothing recorded the mint.
+        assert_eq!(queue.ready_len(), 0);
+        assert_eq!(queue.dead_letters().len(), 1);
+        let record = states.get(&id).await.unwrap().unwrap();
+        assert!(matches!(
+            record.state(),
+            Some(DepositState::DeadLettered { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_failed_send_is_not_resumed() {
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(9),
+                log_index: 0,
+                block_number: 5,
+            }),
+        };
+        let id = deposit.id().unwrap();
+        let mut queue = InMemoryQueue::new();
+        queue
+            .publish_envelope(&Envelope::deposit(&deposit, "test").unwrap())
             .await
-            .map_err(RelayerError::AmqpError)
+            .unwrap();
+
+        let mut pending = InMemoryPendingStore::new();
+        let mut states = InMemoryStateStore::new();
+        let mut incl = minting_includer(queue.clone())
+            .with_pending_store(Some(Box::new(pending.clone())))
+            .with_state_store(Some(Box::new(states.clone())));
+        // The nonce is reserved, then the send fails.
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(7));
+        asserter.push_failure_msg("execution reverted");
+        mock_chain(&mut incl, asserter);
+
+        let mut consumer = queue.consumer().await.unwrap();
+        incl.process_deposit(&mut consumer).await.unwrap_err();
+        assert_eq!(queue.dead_letters().len(), 1);
+        assert!(pending.list(incl.wallet).await.unwrap().is_empty());
+        let record = states.get(&id).await.unwrap().unwrap();
+        assert!(matches!(
+            record.state(),
+            Some(DepositState::DeadLettered { .. })
+        ));
+
+        // Any request to the chain fails the test.
+        mock_chain(&mut incl, Asserter::new());
+        incl.resume_pending().await.unwrap();
+        assert!(incl.sent_mints.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_requeued_mint_is_recorded_as_failed() {
+        let hash = B256::repeat_byte(1);
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(9),
+                log_index: 0,
+                block_number: 5,
+            }),
+        };
+        let id = deposit.id().unwrap();
+        let mut queue = InMemoryQueue::new();
+        queue
+            .publish_envelope(&Envelope::deposit(&deposit, "test").unwrap())
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("includer.lock");
+        let pending = InMemoryPendingStore::new();
+        let mut states = InMemoryStateStore::new();
+        let mut incl = includer(queue.clone())
+            .with_pending_store(Some(Box::new(pending.clone())))
+            .with_state_store(Some(Box::new(states.clone())))
+            .with_leader_lease(Some(Box::new(FileLease::new(&lock))))
+            .with_poll_schedule(PollSchedule::new(
+                Duration::from_millis(10),
+                Duration::from_millis(10),
+            ));
+        assert!(incl.ensure_leadership().await);
+        let mut tx = pending_tx(hash);
+        tx.deposit_id = id.clone();
+        incl.pending_put(&tx).await.unwrap();
+
+        // The recorded mint is in flight, and the lease is lost while
+        // waiting for its receipt.
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(7));
+        asserter.push_success(&Value::Null);
+        asserter.push_success(&json!({
+            "hash": hash,
+            "nonce": "0x7",
+            "blockHash": null,
+            "blockNumber": null,
+            "transactionIndex": null,
+            "from": incl.wallet,
+            "to": Address::default(),
+            "value": "0x0",
+            "gas": "0x5208",
+            "maxFeePerGas": "0x1",
+            "maxPriorityFeePerGas": "0x1",
+            "input": "0x",
+            "type": "0x2",
+            "chainId": "0x7a69",
+            "accessList": [],
+            "v": "0x0",
+            "yParity": "0x0",
+            "r": "0x1",
+            "s": "0x1"
+        }));
+        asserter.push_success(&Value::Null);
+        mock_chain(&mut incl, asserter);
+        incl.leader_lease.as_mut().unwrap().release().await.unwrap();
+        let mut standby = FileLease::new(&lock);
+        assert!(standby.acquire().await.unwrap().is_some());
+
+        let mut consumer = queue.consumer().await.unwrap();
+        let err = incl.process_deposit(&mut consumer).await.unwrap_err();
+        assert!(matches!(err, RelayerError::LeaseLost), "{err}");
+        assert_eq!(queue.ready_len(), 1);
+        let record = states.get(&id).await.unwrap().unwrap();
+        assert!(matches!(record.state(), Some(DepositState::Failed { .. })));
     }
 }
@@ -0,0 +1,362 @@
+use crate::config::RelayerConfig;
+use crate::errors::RelayerError;
+use async_trait::async_trait;
+use redis::{Client, aio::MultiplexedConnection};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+pub const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(10);
+/// How often a leader that is otherwise waiting renews its lease, well
+/// within `DEFAULT_LEASE_TTL`.
+pub const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Exclusive right to run one replica's work. Every successful acquisition
+/// hands out a fencing token larger than all earlier ones, which writers
+/// pass to the cursor store so a deposed leader's late writes are refused.
+#[async_trait]
+pub trait LeaderLease: Send + Sync {
+    /// Acquires the lease, or renews it if already held. Returns the
+    /// fencing token while this process leads and `None` while another
+    /// process holds the lease.
+    async fn acquire(&mut self) -> Result<Option<u64>, RelayerError>;
+    async fn release(&mut self) -> Result<(), RelayerError>;
+    /// Called when a write with this lease's token was refused because the
+    /// store has seen `current`. Leases whose counter can be lost move it
+    /// past `current`, so the next acquisition is accepted again.
+    async fn fenced_out(&mut self, _current: u64) -> Result<(), RelayerError> {
+        Ok(())
+    }
+}
+
+/// Which lease implementation the binaries use, selected with
+/// `leader.election`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeaderElection {
+    None,
+    Redis,
+    File,
+}
+
+impl std::str::FromStr for LeaderElection {
+    type Err = RelayerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "off" | "" => Ok(LeaderElection::None),
+            "redis" => Ok(LeaderElection::Redis),
+            "file" => Ok(LeaderElection::File),
+            other => Err(RelayerError::Other(format!(
+                "Unknown leader election backend: {other}"
+            ))),
+        }
+    }
+}
+
+/// Builds the lease named `name` from the `leader` settings. Returns
+/// `None` when election is off.
+pub async fn lease_from_config(
+    name: &str,
+    config: &RelayerConfig,
+) -> Result<Option<Box<dyn LeaderLease>>, RelayerError> {
+    let leader = &config.leader;
+    match leader.election {
+        LeaderElection::None => Ok(None),
+        LeaderElection::Redis => {
+            let mut lease = RedisLease::new(config.redis_url()?.to_string(), name)
+                .await?
+                .with_ttl(leader.lease_ttl);
+            if let Some(holder) = &leader.holder_id {
+                lease = lease.with_holder_id(holder);
+            }
+            Ok(Some(Box::new(lease)))
+        }
+        LeaderElection::File => {
+            let lock_dir = leader.lock_dir.as_ref().ok_or_else(|| {
+                RelayerError::Other("leader.lock_dir is required with file election".into())
+            })?;
+            let file_name = name.replace(':', "_");
+            let lease = FileLease::new(lock_dir.join(format!("{file_name}.lock")));
+            Ok(Some(Box::new(lease)))
+        }
+    }
+}
+
+fn default_holder_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    format!("{}-{nanos:x}", std::process::id())
+}
+
+/// Takes the lease if it is free and bumps the fencing counter, or renews
+/// it if this holder already has it. Returns the token, or -1 if another
+/// holder has the lease.
+const ACQUIRE_SCRIPT: &str = r"
+local holder = redis.call('GET', KEYS[1])
+if holder == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return tonumber(redis.call('GET', KEYS[2]) or '0')
+end
+if holder then
+    return -1
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+return redis.call('INCR', KEYS[2])
+";
+
+const RELEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Lease held in a Redis key with a TTL (`SET NX PX` semantics). A standby
+/// takes over at most one TTL after the leader stops renewing.
+pub struct RedisLease {
+    connection: MultiplexedConnection,
+    key: String,
+    holder: String,
+    ttl: Duration,
+}
+
+impl RedisLease {
+    pub async fn new(db_url: String, key: &str) -> Result<Self, RelayerError> {
+        let client = Client::open(db_url).map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        let connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(RedisLease {
+            connection,
+            key: key.to_string(),
+            holder: default_holder_id(),
+            ttl: DEFAULT_LEASE_TTL,
+        })
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_holder_id(mut self, holder: &str) -> Self {
+        self.holder = holder.to_string();
+        self
+    }
+
+    fn fence_key(&self) -> String {
+        format!("{}:fence", self.key)
+    }
+}
+
+#[async_trait]
+impl LeaderLease for RedisLease {
+    /// A Redis error is returned as is; callers must treat it as not
+    /// leading, since the lease may expire before Redis is reachable again.
+    async fn acquire(&mut self) -> Result<Option<u64>, RelayerError> {
+        let token: i64 = redis::Script::new(ACQUIRE_SCRIPT)
+            .key(&self.key)
+            .key(self.fence_key())
+            .arg(&self.holder)
+            .arg(self.ttl.as_millis() as u64)
+            .invoke_async(&mut self.connection)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(u64::try_from(token).ok())
+    }
+
+    async fn release(&mut self) -> Result<(), RelayerError> {
+        let _: i64 = redis::Script::new(RELEASE_SCRIPT)
+            .key(&self.key)
+            .arg(&self.holder)
+            .invoke_async(&mut self.connection)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Lease backed by an advisory lock on a local file, for replicas sharing
+/// a host or a filesystem with working locks. The OS drops the lock when
+/// the leader exits, so a standby takes over on its next attempt. There is
+/// no timeout: a leader that hangs but keeps running is never deposed.
+///
+/// The lock file itself stores the fencing counter. If it is lost, the
+/// first write with a restarted token is refused and `fenced_out` moves
+/// the counter past the store's.
+pub struct FileLease {
+    path: PathBuf,
+    held: Option<(File, u64)>,
+}
+
+impl FileLease {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileLease {
+            path: path.into(),
+            held: None,
+        }
+    }
+
+    fn try_lock(&mut self) -> Result<Option<u64>, RelayerError> {
+        if let Some((_, token)) = &self.held {
+            return Ok(Some(*token));
+        }
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let token = contents.trim().parse::<u64>().unwrap_or(0) + 1;
+        write_token(&mut file, token)?;
+        self.held = Some((file, token));
+        Ok(Some(token))
+    }
+}
+
+fn write_token(file: &mut File, token: u64) -> Result<(), RelayerError> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(token.to_string().as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[async_trait]
+impl LeaderLease for FileLease {
+    async fn acquire(&mut self) -> Result<Option<u64>, RelayerError> {
+        self.try_lock()
+    }
+
+    async fn release(&mut self) -> Result<(), RelayerError> {
+        if let Some((file, _)) = self.held.take() {
+            file.unlock()?;
+        }
+        Ok(())
+    }
+
+    /// While the lock is held nobody else can have handed out `current`
+    /// from this file, so the counter went backwards: the file was
+    /// deleted or `lock_dir` changed. Jumps past the store's token.
+    async fn fenced_out(&mut self, current: u64) -> Result<(), RelayerError> {
+        if let Some((file, token)) = self.held.as_mut()
+            && *token <= current
+        {
+            warn!(
+                "Lock file {} is behind the store's fencing token {}, moving past it",
+                self.path.display(),
+                current
+            );
+            write_token(file, current + 1)?;
+            *token = current + 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leader_election_from_str() {
+        assert_eq!(
+            "Redis".parse::<LeaderElection>().unwrap(),
+            LeaderElection::Redis
+        );
+        assert_eq!(
+            "file".parse::<LeaderElection>().unwrap(),
+            LeaderElection::File
+        );
+        assert_eq!(
+            "none".parse::<LeaderElection>().unwrap(),
+            LeaderElection::None
+        );
+        assert!("zookeeper".parse::<LeaderElection>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_lease_failover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("subscriber.lock");
+        let mut a = FileLease::new(&path);
+        let mut b = FileLease::new(&path);
+
+        assert_eq!(a.acquire().await.unwrap(), Some(1));
+        assert_eq!(a.acquire().await.unwrap(), Some(1));
+        assert_eq!(b.acquire().await.unwrap(), None);
+
+        a.release().await.unwrap();
+        assert_eq!(b.acquire().await.unwrap(), Some(2));
+        assert_eq!(a.acquire().await.unwrap(), None);
+
+        // Dropping the holder, as a crash would, frees the lock too.
+        drop(b);
+        assert_eq!(a.acquire().await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_file_lease_moves_past_store_fence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("includer.lock");
+        let mut a = FileLease::new(&path);
+        assert_eq!(a.acquire().await.unwrap(), Some(1));
+
+        // The store has seen token 7 from before the lock file was lost.
+        a.fenced_out(7).await.unwrap();
+        assert_eq!(a.acquire().await.unwrap(), Some(8));
+        a.release().await.unwrap();
+        let mut b = FileLease::new(&path);
+        assert_eq!(b.acquire().await.unwrap(), Some(9));
+
+        // A deposed holder no longer has the lock and leaves it alone.
+        a.fenced_out(20).await.unwrap();
+        b.release().await.unwrap();
+        assert_eq!(a.acquire().await.unwrap(), Some(10));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at DB_URL"]
+    async fn test_redis_lease_failover() {
+        dotenv::dotenv().ok();
+        let db_url = std::env::var("DB_URL").unwrap_or_else(|_| "redis://127.0.0.1/".into());
+        let key = format!("test_relayer:{}:leader", std::process::id());
+        let ttl = Duration::from_millis(300);
+        let mut a = RedisLease::new(db_url.clone(), &key)
+            .await
+            .unwrap()
+            .with_ttl(ttl)
+            .with_holder_id("a");
+        let mut b = RedisLease::new(db_url, &key)
+            .await
+            .unwrap()
+            .with_ttl(ttl)
+            .with_holder_id("b");
+
+        let first = a.acquire().await.unwrap().unwrap();
+        assert_eq!(a.acquire().await.unwrap(), Some(first));
+        assert_eq!(b.acquire().await.unwrap(), None);
+
+        // `a` stops renewing; `b` takes over once the TTL runs out.
+        tokio::time::sleep(ttl + Duration::from_millis(100)).await;
+        let second = b.acquire().await.unwrap().unwrap();
+        assert!(second > first);
+        assert_eq!(a.acquire().await.unwrap(), None);
+        b.release().await.unwrap();
+    }
+}
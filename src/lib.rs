@@ -1,5 +1,22 @@
+pub mod app;
+pub mod cache;
+pub mod cli;
+pub mod config;
+pub mod cursor;
+pub mod envelope;
 pub mod errors;
+pub mod health;
+pub mod http;
 pub mod includer;
+pub mod leader;
+pub mod lifecycle;
+pub mod metrics;
+pub mod pending;
+pub mod quarantine;
 pub mod queue;
+pub mod schedule;
+pub mod shutdown;
+pub mod signing;
+pub mod source_verifier;
 pub mod subscriber;
 pub mod utils;
@@ -0,0 +1,708 @@
+use crate::cache::CacheBackend;
+use crate::cache::sqlite::{SqliteConnection, open_sqlite, sqlite_err};
+use crate::config::RelayerConfig;
+use crate::errors::RelayerError;
+use alloy::primitives::B256;
+use async_trait::async_trait;
+use redis::{AsyncCommands, Client, aio::MultiplexedConnection};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where a deposit is in the relay, from the subscriber seeing its log to
+/// the mint being confirmed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DepositState {
+    /// The subscriber found the `Deposited` log.
+    Observed,
+    /// The includer checked the log against the source chain.
+    Confirmed,
+    /// The deposit is on the queue.
+    Published,
+    MintSubmitted {
+        tx_hash: B256,
+    },
+    MintConfirmed {
+        tx_hash: B256,
+    },
+    Failed {
+        reason: String,
+    },
+    DeadLettered {
+        reason: String,
+    },
+}
+
+impl DepositState {
+    pub fn is_final(&self) -> bool {
+        matches!(self, DepositState::MintConfirmed { .. })
+    }
+
+    /// Whether a deposit in this state may move to `next`. Source checks
+    /// happen in the includer, so `Confirmed` may come before or after
+    /// `Published`. Failed deposits are retried and dead-lettered ones can
+    /// be replayed.
+    pub fn can_move_to(&self, next: &DepositState) -> bool {
+        use DepositState::*;
+        match (self, next) {
+            (MintConfirmed { .. }, _) => false,
+            (_, Failed { .. } | DeadLettered { .. }) => true,
+            (Observed, Confirmed | Published) => true,
+            (Confirmed, Published | MintSubmitted { .. }) => true,
+            (Published, Confirmed | MintSubmitted { .. }) => true,
+            // A stuck mint may be sent again with a new hash.
+            (MintSubmitted { .. }, MintSubmitted { .. } | MintConfirmed { .. }) => true,
+            // A failed publish is scanned again.
+            (Failed { .. }, Observed) => true,
+            // Retried or replayed from the dead-letter queue.
+            (Failed { .. } | DeadLettered { .. }, Published | Confirmed | MintSubmitted { .. }) => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether writing `next` over this state only repeats a step the
+    /// deposit already passed, as rescans and backfills do with `Observed`
+    /// and `Published`. Such writes change nothing.
+    pub fn has_passed(&self, next: &DepositState) -> bool {
+        use DepositState::*;
+        match next {
+            Observed => !matches!(self, Failed { .. }),
+            Published => matches!(self, MintSubmitted { .. } | MintConfirmed { .. }),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DepositState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DepositState::Observed => write!(f, "observed"),
+            DepositState::Confirmed => write!(f, "confirmed"),
+            DepositState::Published => write!(f, "published"),
+            DepositState::MintSubmitted { tx_hash } => write!(f, "mint submitted ({tx_hash})"),
+            DepositState::MintConfirmed { tx_hash } => write!(f, "mint confirmed ({tx_hash})"),
+            DepositState::Failed { reason } => write!(f, "failed: {reason}"),
+            DepositState::DeadLettered { reason } => write!(f, "dead-lettered: {reason}"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Transition {
+    #[serde(flatten)]
+    pub state: DepositState,
+    /// Milliseconds since the unix epoch.
+    pub at: u64,
+    /// Component that wrote the transition, e.g. `subscriber`.
+    pub by: String,
+}
+
+/// Everything known about one deposit. The last transition is the current
+/// state.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DepositRecord {
+    pub deposit_id: String,
+    pub history: Vec<Transition>,
+}
+
+impl DepositRecord {
+    pub fn state(&self) -> Option<&DepositState> {
+        self.history.last().map(|t| &t.state)
+    }
+
+    /// `record`, or a new record of `deposit_id`, moved to `state`, and
+    /// whether it changed. Writing the current state or one the deposit
+    /// `has_passed` again changes nothing; moves `can_move_to` forbids are
+    /// refused with `InvalidTransition`.
+    fn advance(
+        record: Option<DepositRecord>,
+        deposit_id: &str,
+        state: DepositState,
+        by: &str,
+    ) -> Result<(DepositRecord, bool), RelayerError> {
+        let mut record = record.unwrap_or_else(|| DepositRecord {
+            deposit_id: deposit_id.to_string(),
+            history: Vec::new(),
+        });
+        if let Some(current) = record.state() {
+            if *current == state || current.has_passed(&state) {
+                return Ok((record, false));
+            }
+            if !current.can_move_to(&state) {
+                return Err(RelayerError::InvalidTransition {
+                    from: current.to_string(),
+                    to: state.to_string(),
+                });
+            }
+        }
+        record.history.push(Transition {
+            state,
+            at: now_millis(),
+            by: by.to_string(),
+        });
+        Ok((record, true))
+    }
+}
+
+/// Shared store of deposit lifecycles, keyed by deposit id. Every component
+/// writes its transitions here.
+#[async_trait]
+pub trait DepositStateStore: Send + Sync {
+    async fn get(&mut self, deposit_id: &str) -> Result<Option<DepositRecord>, RelayerError>;
+    async fn put(&mut self, record: &DepositRecord) -> Result<(), RelayerError>;
+
+    /// Whether the queue message `message_id` was settled before.
+    async fn message_settled(&mut self, message_id: &str) -> Result<bool, RelayerError>;
+    /// Remembers that the message `message_id` was settled, so a replay of
+    /// it is refused, for `retention`. Older messages are refused as stale
+    /// anyway, so their markers are dropped.
+    async fn settle_message(
+        &mut self,
+        message_id: &str,
+        retention: Duration,
+    ) -> Result<(), RelayerError>;
+
+    /// Appends `state` to the deposit's history, atomically so concurrent
+    /// writers cannot lose each other's transitions. Writing the current
+    /// state again, or an earlier one on a rescan, is a no-op; moves
+    /// `can_move_to` forbids are refused with `InvalidTransition`. Unknown deposits may start in any state, since
+    /// not every component shares a store.
+    async fn transition(
+        &mut self,
+        deposit_id: &str,
+        state: DepositState,
+        by: &str,
+    ) -> Result<DepositRecord, RelayerError>;
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Builds the store for the backend in `cache.backend`, next to the
+/// subscriber cursors.
+pub async fn state_store_from_config(
+    config: &RelayerConfig,
+) -> Result<Box<dyn DepositStateStore>, RelayerError> {
+    state_store(CacheBackend::from_config(config), config).await
+}
+
+pub async fn state_store(
+    backend: CacheBackend,
+    config: &RelayerConfig,
+) -> Result<Box<dyn DepositStateStore>, RelayerError> {
+    match backend {
+        CacheBackend::Redis => {
+            let db_url = config.redis_url()?.to_string();
+            Ok(Box::new(RedisStateStore::new(db_url).await?))
+        }
+        CacheBackend::Sqlite => Ok(Box::new(SqliteStateStore::open(&config.sqlite_path)?)),
+        CacheBackend::Memory => Ok(Box::new(InMemoryStateStore::new())),
+    }
+}
+
+/// Process-local store. Clones share state.
+#[derive(Clone, Default)]
+pub struct InMemoryStateStore {
+    records: Arc<Mutex<HashMap<String, DepositRecord>>>,
+    /// When each settled message may be forgotten, in unix milliseconds.
+    messages: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl InMemoryStateStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl DepositStateStore for InMemoryStateStore {
+    async fn get(&mut self, deposit_id: &str) -> Result<Option<DepositRecord>, RelayerError> {
+        let records = self.records.lock().expect("state store lock poisoned");
+        Ok(records.get(deposit_id).cloned())
+    }
+
+    async fn put(&mut self, record: &DepositRecord) -> Result<(), RelayerError> {
+        let mut records = self.records.lock().expect("state store lock poisoned");
+        records.insert(record.deposit_id.clone(), record.clone());
+        Ok(())
+    }
+
+    async fn transition(
+        &mut self,
+        deposit_id: &str,
+        state: DepositState,
+        by: &str,
+    ) -> Result<DepositRecord, RelayerError> {
+        let mut records = self.records.lock().expect("state store lock poisoned");
+        let current = records.get(deposit_id).cloned();
+        let (record, changed) = DepositRecord::advance(current, deposit_id, state, by)?;
+        if changed {
+            records.insert(deposit_id.to_string(), record.clone());
+        }
+        Ok(record)
+    }
+
+    async fn message_settled(&mut self, message_id: &str) -> Result<bool, RelayerError> {
+        let messages = self.messages.lock().expect("state store lock poisoned");
+        Ok(messages
+            .get(message_id)
+            .is_some_and(|&expires| expires > now_millis()))
+    }
+
+    async fn settle_message(
+        &mut self,
+        message_id: &str,
+        retention: Duration,
+    ) -> Result<(), RelayerError> {
+        let now = now_millis();
+        let mut messages = self.messages.lock().expect("state store lock poisoned");
+        messages.retain(|_, &mut expires| expires > now);
+        messages
+            .entry(message_id.to_string())
+            .or_insert(now.saturating_add(retention.as_millis() as u64));
+        Ok(())
+    }
+}
+
+/// Sets KEYS[1] to ARGV[2] if it still holds ARGV[1], an empty ARGV[1]
+/// standing for no value. Returns 1 if it was set.
+const COMPARE_AND_SET_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1]) or ''
+if current ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2])
+return 1
+";
+
+/// One JSON value per deposit at `relayer:deposit:{id}`, and the settle
+/// time of each message at `relayer:message:{id}`, expiring after its
+/// retention.
+pub struct RedisStateStore {
+    connection: MultiplexedConnection,
+}
+
+impl RedisStateStore {
+    pub async fn new(db_url: String) -> Result<Self, RelayerError> {
+        let client = Client::open(db_url).map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        let connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(RedisStateStore { connection })
+    }
+
+    fn key(deposit_id: &str) -> String {
+        format!("relayer:deposit:{deposit_id}")
+    }
+
+    fn message_key(message_id: &str) -> String {
+        format!("relayer:message:{message_id}")
+    }
+}
+
+#[async_trait]
+impl DepositStateStore for RedisStateStore {
+    async fn get(&mut self, deposit_id: &str) -> Result<Option<DepositRecord>, RelayerError> {
+        let value: Option<String> = self
+            .connection
+            .get(Self::key(deposit_id))
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        value
+            .map(|v| serde_json::from_str(&v).map_err(RelayerError::from))
+            .transpose()
+    }
+
+    async fn put(&mut self, record: &DepositRecord) -> Result<(), RelayerError> {
+        let value = serde_json::to_string(record)?;
+        self.connection
+            .set(Self::key(&record.deposit_id), value)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))
+    }
+
+    /// Reads the record, advances it and writes it back only if nobody
+    /// wrote in between, starting over otherwise.
+    async fn transition(
+        &mut self,
+        deposit_id: &str,
+        state: DepositState,
+        by: &str,
+    ) -> Result<DepositRecord, RelayerError> {
+        let key = Self::key(deposit_id);
+        loop {
+            let stored: Option<String> = self
+                .connection
+                .get(&key)
+                .await
+                .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+            let current = stored.as_deref().map(serde_json::from_str).transpose()?;
+            let (record, changed) = DepositRecord::advance(current, deposit_id, state.clone(), by)?;
+            if !changed {
+                return Ok(record);
+            }
+            let set: i64 = redis::Script::new(COMPARE_AND_SET_SCRIPT)
+                .key(&key)
+                .arg(stored.unwrap_or_default())
+                .arg(serde_json::to_string(&record)?)
+                .invoke_async(&mut self.connection)
+                .await
+                .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+            if set == 1 {
+                return Ok(record);
+            }
+        }
+    }
+
+    async fn message_settled(&mut self, message_id: &str) -> Result<bool, RelayerError> {
+        self.connection
+            .exists(Self::message_key(message_id))
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))
+    }
+
+    async fn settle_message(
+        &mut self,
+        message_id: &str,
+        retention: Duration,
+    ) -> Result<(), RelayerError> {
+        let _: Option<String> = redis::cmd("SET")
+            .arg(Self::message_key(message_id))
+            .arg(now_millis())
+            .arg("NX")
+            .arg("PX")
+            .arg(retention.as_millis().max(1) as u64)
+            .query_async(&mut self.connection)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Deposit records in a SQLite table, by default in the cursor database.
+#[derive(Clone)]
+pub struct SqliteStateStore {
+    connection: SqliteConnection,
+}
+
+impl SqliteStateStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RelayerError> {
+        let connection = open_sqlite(path)?;
+        connection
+            .lock()
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS deposits (
+                     deposit_id TEXT PRIMARY KEY,
+                     state TEXT NOT NULL,
+                     record TEXT NOT NULL,
+                     updated_at INTEGER NOT NULL
+                 );
+                 CREATE TABLE IF NOT EXISTS settled_messages (
+                     message_id TEXT PRIMARY KEY,
+                     settled_at INTEGER NOT NULL,
+                     expires_at INTEGER NOT NULL
+                 );",
+            )
+            .map_err(sqlite_err)?;
+        Ok(SqliteStateStore { connection })
+    }
+}
+
+fn select_record(
+    connection: &Connection,
+    deposit_id: &str,
+) -> Result<Option<DepositRecord>, RelayerError> {
+    let value: Option<String> = connection
+        .query_row(
+            "SELECT record FROM deposits WHERE deposit_id = ?1",
+            params![deposit_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .map_err(sqlite_err)?;
+    value
+        .map(|v| serde_json::from_str(&v).map_err(RelayerError::from))
+        .transpose()
+}
+
+fn upsert_record(connection: &Connection, record: &DepositRecord) -> Result<(), RelayerError> {
+    let value = serde_json::to_string(record)?;
+    let (state, updated_at) = match record.history.last() {
+        Some(t) => (t.state.to_string(), t.at as i64),
+        None => (String::new(), 0),
+    };
+    connection
+        .execute(
+            "INSERT INTO deposits (deposit_id, state, record, updated_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(deposit_id) DO UPDATE SET
+                 state = excluded.state,
+                 record = excluded.record,
+                 updated_at = excluded.updated_at",
+            params![record.deposit_id, state, value, updated_at],
+        )
+        .map_err(sqlite_err)?;
+    Ok(())
+}
+
+#[async_trait]
+impl DepositStateStore for SqliteStateStore {
+    async fn get(&mut self, deposit_id: &str) -> Result<Option<DepositRecord>, RelayerError> {
+        let deposit_id = deposit_id.to_string();
+        self.connection
+            .with_connection(move |connection| select_record(connection, &deposit_id))
+            .await
+    }
+
+    async fn put(&mut self, record: &DepositRecord) -> Result<(), RelayerError> {
+        let record = record.clone();
+        self.connection
+            .with_connection(move |connection| upsert_record(connection, &record))
+            .await
+    }
+
+    async fn transition(
+        &mut self,
+        deposit_id: &str,
+        state: DepositState,
+        by: &str,
+    ) -> Result<DepositRecord, RelayerError> {
+        let (deposit_id, by) = (deposit_id.to_string(), by.to_string());
+        self.connection
+            .with_connection(move |connection| {
+                let tx = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(sqlite_err)?;
+                let current = select_record(&tx, &deposit_id)?;
+                let (record, changed) = DepositRecord::advance(current, &deposit_id, state, &by)?;
+                if changed {
+                    upsert_record(&tx, &record)?;
+                    tx.commit().map_err(sqlite_err)?;
+                }
+                Ok(record)
+            })
+            .await
+    }
+
+    async fn message_settled(&mut self, message_id: &str) -> Result<bool, RelayerError> {
+        let message_id = message_id.to_string();
+        let now = now_millis() as i64;
+        self.connection
+            .with_connection(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT 1 FROM settled_messages WHERE message_id = ?1 AND expires_at > ?2",
+                        params![message_id, now],
+                        |_| Ok(()),
+                    )
+                    .optional()
+                    .map(|row| row.is_some())
+                    .map_err(sqlite_err)
+            })
+            .await
+    }
+
+    /// Rows past their retention are deleted on the way.
+    async fn settle_message(
+        &mut self,
+        message_id: &str,
+        retention: Duration,
+    ) -> Result<(), RelayerError> {
+        let message_id = message_id.to_string();
+        let now = now_millis();
+        let expires_at = now.saturating_add(retention.as_millis() as u64) as i64;
+        let now = now as i64;
+        self.connection
+            .with_connection(move |connection| {
+                let tx = connection.transaction().map_err(sqlite_err)?;
+                tx.execute(
+                    "DELETE FROM settled_messages WHERE expires_at <= ?1",
+                    params![now],
+                )
+                .map_err(sqlite_err)?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO settled_messages (message_id, settled_at, expires_at)
+                 VALUES (?1, ?2, ?3)",
+                    params![message_id, now, expires_at],
+                )
+                .map_err(sqlite_err)?;
+                tx.commit().map_err(sqlite_err)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DepositState::*;
+
+    #[test]
+    fn test_allowed_transitions() {
+        let hash = B256::repeat_byte(1);
+        let failed = Failed {
+            reason: "boom".into(),
+        };
+        let dead = DeadLettered {
+            reason: "boom".into(),
+        };
+        for (from, to) in [
+            (Observed, Published),
+            (Published, Confirmed),
+            (Confirmed, MintSubmitted { tx_hash: hash }),
+            (
+                MintSubmitted { tx_hash: hash },
+                MintConfirmed { tx_hash: hash },
+            ),
+            (Published, failed.clone()),
+            (failed.clone(), dead.clone()),
+            (failed.clone(), Observed),
+            (failed.clone(), MintSubmitted { tx_hash: hash }),
+            (dead.clone(), Published),
+        ] {
+            assert!(from.can_move_to(&to), "{from} -> {to}");
+        }
+        for (from, to) in [
+            (Published, Observed),
+            (dead.clone(), Observed),
+            (Observed, MintConfirmed { tx_hash: hash }),
+            (MintConfirmed { tx_hash: hash }, failed),
+            (dead, MintConfirmed { tx_hash: hash }),
+        ] {
+            assert!(!from.can_move_to(&to), "{from} -> {to}");
+        }
+    }
+
+    async fn check_store_behaviour<S: DepositStateStore>(store: &mut S, id: &str) {
+        let hash = B256::repeat_byte(7);
+        assert!(store.get(id).await.unwrap().is_none());
+
+        store.transition(id, Observed, "subscriber").await.unwrap();
+        store.transition(id, Published, "subscriber").await.unwrap();
+        // Redelivery writes the same state again, a rescan an earlier one.
+        store.transition(id, Published, "subscriber").await.unwrap();
+        let record = store.transition(id, Observed, "subscriber").await.unwrap();
+        assert_eq!(record.state(), Some(&Published));
+        store
+            .transition(id, MintSubmitted { tx_hash: hash }, "includer")
+            .await
+            .unwrap();
+        store
+            .transition(id, MintConfirmed { tx_hash: hash }, "includer")
+            .await
+            .unwrap();
+        store.transition(id, Published, "subscriber").await.unwrap();
+        let reason = "late".to_string();
+        let err = store.transition(id, Failed { reason }, "subscriber").await;
+        assert!(matches!(err, Err(RelayerError::InvalidTransition { .. })));
+
+        let record = store.get(id).await.unwrap().unwrap();
+        assert_eq!(record.state(), Some(&MintConfirmed { tx_hash: hash }));
+        let states: Vec<&str> = record.history.iter().map(|t| t.by.as_str()).collect();
+        assert_eq!(
+            states,
+            vec!["subscriber", "subscriber", "includer", "includer"]
+        );
+
+        let message_id = format!("{id}-message");
+        let retention = Duration::from_secs(60);
+        assert!(!store.message_settled(&message_id).await.unwrap());
+        store.settle_message(&message_id, retention).await.unwrap();
+        store.settle_message(&message_id, retention).await.unwrap();
+        assert!(store.message_settled(&message_id).await.unwrap());
+
+        // Markers past their retention are dropped.
+        let short_lived = format!("{id}-short-lived");
+        store
+            .settle_message(&short_lived, Duration::from_millis(1))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        store.settle_message(&message_id, retention).await.unwrap();
+        assert!(!store.message_settled(&short_lived).await.unwrap());
+        assert!(store.message_settled(&message_id).await.unwrap());
+    }
+
+    /// Every writer's transition ends up in the history, none overwritten.
+    async fn check_concurrent_transitions<S: DepositStateStore + 'static>(
+        stores: Vec<S>,
+        id: &str,
+    ) {
+        let writers = stores.len();
+        let handles: Vec<_> = stores
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut store)| {
+                let id = id.to_string();
+                tokio::spawn(async move {
+                    let reason = format!("writer {i}");
+                    store
+                        .transition(&id, DeadLettered { reason }, "test")
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut longest = 0;
+        for handle in handles {
+            longest = longest.max(handle.await.unwrap().history.len());
+        }
+        assert_eq!(longest, writers);
+    }
+
+    #[tokio::test]
+    async fn test_memory_state_store() {
+        check_store_behaviour(&mut InMemoryStateStore::new(), "deposit").await;
+        let store = InMemoryStateStore::new();
+        check_concurrent_transitions(vec![store.clone(); 8], "concurrent").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_sqlite_state_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relayer.db");
+        let mut store = SqliteStateStore::open(&path).unwrap();
+        check_store_behaviour(&mut store, "deposit").await;
+        // Separate connections, as separate processes would have.
+        let stores = (0..8)
+            .map(|_| SqliteStateStore::open(&path).unwrap())
+            .collect();
+        check_concurrent_transitions(stores, "concurrent").await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at DB_URL"]
+    async fn test_redis_state_store() {
+        dotenv::dotenv().ok();
+        let db_url = std::env::var("DB_URL").unwrap_or_else(|_| "redis://127.0.0.1/".into());
+        let mut store = RedisStateStore::new(db_url.clone()).await.unwrap();
+        let id = format!("test_{}", std::process::id());
+        check_store_behaviour(&mut store, &id).await;
+        let mut stores = Vec::new();
+        for _ in 0..8 {
+            stores.push(RedisStateStore::new(db_url.clone()).await.unwrap());
+        }
+        let concurrent = format!("{id}_concurrent");
+        check_concurrent_transitions(stores, &concurrent).await;
+
+        let keys = [
+            RedisStateStore::key(&id),
+            RedisStateStore::key(&concurrent),
+            RedisStateStore::message_key(&format!("{id}-message")),
+            RedisStateStore::message_key(&format!("{id}-short-lived")),
+        ];
+        let _deleted: usize = store.connection.del(&keys).await.unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+use crate::http::{Response, Routes};
+use alloy::{
+    rpc::{
+        client::{ClientBuilder, RpcClient},
+        json_rpc::{Id, RequestPacket, ResponsePacket},
+    },
+    transports::{TransportError, TransportFut, http::reqwest::Url},
+};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+pub enum Kind {
+    Counter,
+    Gauge,
+    /// Upper bounds of the buckets, ascending.
+    Histogram(&'static [f64]),
+}
+
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+    /// Names of the labels each update passes.
+    pub labels: &'static [&'static str],
+}
+
+pub const BLOCKS_BEHIND_HEAD: Metric = Metric {
+    name: "relayer_blocks_behind_head",
+    help: "Blocks between the subscriber cursor and the source chain head when the last scan started.",
+    kind: Kind::Gauge,
+    labels: &[],
+};
+pub const LAST_SCANNED_BLOCK: Metric = Metric {
+    name: "relayer_last_scanned_block",
+    help: "Last source chain block whose deposits were all published.",
+    kind: Kind::Gauge,
+    labels: &[],
+};
+pub const DEPOSITS_OBSERVED: Metric = Metric {
+    name: "relayer_deposits_observed_total",
+    help: "Deposits found on the source chain.",
+    kind: Kind::Counter,
+    labels: &[],
+};
+pub const DEPOSITS_PUBLISHED: Metric = Metric {
+    name: "relayer_deposits_published_total",
+    help: "Deposits confirmed by the queue.",
+    kind: Kind::Counter,
+    labels: &[],
+};
+pub const QUEUE_PUBLISH_FAILURES: Metric = Metric {
+    name: "relayer_queue_publish_failures_total",
+    help: "Deposits the queue failed to accept.",
+    kind: Kind::Counter,
+    labels: &[],
+};
+pub const DEPOSITS_MINTED: Metric = Metric {
+    name: "relayer_deposits_minted_total",
+    help: "Deposits minted and verified on the destination chain.",
+    kind: Kind::Counter,
+    labels: &[],
+};
+pub const MINT_LATENCY: Metric = Metric {
+    name: "relayer_mint_latency_seconds",
+    help: "Time from taking a delivery to its mint being confirmed.",
+    kind: Kind::Histogram(&[1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+    labels: &[],
+};
+pub const GAS_SPENT: Metric = Metric {
+    name: "relayer_gas_spent_wei_total",
+    help: "Wei paid for mined mints, reverted ones included.",
+    kind: Kind::Counter,
+    labels: &[],
+};
+pub const NACKS: Metric = Metric {
+    name: "relayer_nacks_total",
+    help: "Deliveries nacked by the includer, by whether they were requeued.",
+    kind: Kind::Counter,
+    labels: &["requeue"],
+};
+pub const DEAD_LETTERED: Metric = Metric {
+    name: "relayer_dead_lettered_total",
+    help: "Deliveries sent to the dead letter queue, by the includer or after too many deliveries.",
+    kind: Kind::Counter,
+    labels: &[],
+};
+pub const WALLET_BALANCE: Metric = Metric {
+    name: "relayer_wallet_balance_wei",
+    help: "Balance of the includer wallet on the destination chain.",
+    kind: Kind::Gauge,
+    labels: &[],
+};
+pub const RPC_REQUESTS: Metric = Metric {
+    name: "relayer_rpc_requests_total",
+    help: "JSON-RPC requests, by chain and method.",
+    kind: Kind::Counter,
+    labels: &["chain", "method"],
+};
+pub const RPC_ERRORS: Metric = Metric {
+    name: "relayer_rpc_errors_total",
+    help: "JSON-RPC requests that failed or returned an error, by chain and method.",
+    kind: Kind::Counter,
+    labels: &["chain", "method"],
+};
+
+/// Every metric, in the order they are rendered.
+const ALL: [&Metric; 13] = [
+    &BLOCKS_BEHIND_HEAD,
+    &LAST_SCANNED_BLOCK,
+    &DEPOSITS_OBSERVED,
+    &DEPOSITS_PUBLISHED,
+    &QUEUE_PUBLISH_FAILURES,
+    &DEPOSITS_MINTED,
+    &MINT_LATENCY,
+    &GAS_SPENT,
+    &NACKS,
+    &DEAD_LETTERED,
+    &WALLET_BALANCE,
+    &RPC_REQUESTS,
+    &RPC_ERRORS,
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+enum Value {
+    Number(f64),
+    Histogram {
+        /// Observations per bucket, not cumulative.
+        buckets: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+/// Values of the relayer metrics, rendered in the Prometheus text format.
+/// Clones share the values, so the subscriber, the includer and the HTTP
+/// endpoint can each hold one.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    series: Arc<Mutex<BTreeMap<(&'static str, Labels), Value>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inc(&self, metric: &Metric, labels: &[(&'static str, &str)]) {
+        self.add(metric, labels, 1.0);
+    }
+
+    pub fn add(&self, metric: &Metric, labels: &[(&'static str, &str)], value: f64) {
+        let mut series = self.lock();
+        let entry = series
+            .entry(key(metric, labels))
+            .or_insert(Value::Number(0.0));
+        if let Value::Number(total) = entry {
+            *total += value;
+        }
+    }
+
+    pub fn set(&self, metric: &Metric, labels: &[(&'static str, &str)], value: f64) {
+        self.lock()
+            .insert(key(metric, labels), Value::Number(value));
+    }
+
+    pub fn observe(&self, metric: &Metric, labels: &[(&'static str, &str)], value: f64) {
+        let Kind::Histogram(bounds) = metric.kind else {
+            return;
+        };
+        let mut series = self.lock();
+        let entry = series
+            .entry(key(metric, labels))
+            .or_insert_with(|| Value::Histogram {
+                buckets: vec![0; bounds.len()],
+                sum: 0.0,
+                count: 0,
+            });
+        if let Value::Histogram {
+            buckets,
+            sum,
+            count,
+        } = entry
+        {
+            if let Some(bucket) = bounds.iter().position(|bound| value <= *bound) {
+                buckets[bucket] += 1;
+            }
+            *sum += value;
+            *count += 1;
+        }
+    }
+
+    /// Current value of a counter or gauge, or the number of observations
+    /// of a histogram.
+    pub fn get(&self, metric: &Metric, labels: &[(&'static str, &str)]) -> Option<f64> {
+        match self.lock().get(&key(metric, labels))? {
+            Value::Number(value) => Some(*value),
+            Value::Histogram { count, .. } => Some(*count as f64),
+        }
+    }
+
+    /// Every metric in the Prometheus text format. Metrics without labels
+    /// are shown even before their first update.
+    pub fn render(&self) -> String {
+        let series = self.lock();
+        let mut out = String::new();
+        for metric in ALL {
+            let kind = match metric.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram(_) => "histogram",
+            };
+            let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+            let _ = writeln!(out, "# TYPE {} {}", metric.name, kind);
+            let mut shown = false;
+            for ((name, labels), value) in series.iter() {
+                if *name == metric.name {
+                    render_value(&mut out, metric, labels, value);
+                    shown = true;
+                }
+            }
+            if !shown && metric.labels.is_empty() {
+                let empty = match metric.kind {
+                    Kind::Histogram(bounds) => Value::Histogram {
+                        buckets: vec![0; bounds.len()],
+                        sum: 0.0,
+                        count: 0,
+                    },
+                    _ => Value::Number(0.0),
+                };
+                render_value(&mut out, metric, &Vec::new(), &empty);
+            }
+        }
+        out
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<(&'static str, Labels), Value>> {
+        self.series.lock().expect("metrics lock poisoned")
+    }
+}
+
+fn key(metric: &Metric, labels: &[(&'static str, &str)]) -> (&'static str, Labels) {
+    let labels = labels
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect();
+    (metric.name, labels)
+}
+
+fn render_value(out: &mut String, metric: &Metric, labels: &Labels, value: &Value) {
+    match value {
+        Value::Number(value) => {
+            let _ = writeln!(out, "{}{} {}", metric.name, label_set(labels, None), value);
+        }
+        Value::Histogram {
+            buckets,
+            sum,
+            count,
+        } => {
+            let Kind::Histogram(bounds) = metric.kind else {
+                return;
+            };
+            let mut cumulative = 0;
+            for (bound, observed) in bounds.iter().zip(buckets) {
+                cumulative += observed;
+                let le = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    metric.name,
+                    label_set(labels, Some(&le)),
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                metric.name,
+                label_set(labels, Some("+Inf")),
+                count
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{} {}",
+                metric.name,
+                label_set(labels, None),
+                sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{} {}",
+                metric.name,
+                label_set(labels, None),
+                count
+            );
+        }
+    }
+}
+
+/// `{name="value",...}`, or nothing without labels.
+fn label_set(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[async_trait]
+impl Routes for Metrics {
+    async fn get(&self, path: &str) -> Option<Response> {
+        (path == "/metrics").then(|| Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: self.render(),
+        })
+    }
+}
+
+/// RPC client for `url` that counts requests and errors per method in
+/// `metrics`, labelled with `chain`.
+pub fn rpc_client(url: Url, metrics: &Metrics, chain: &'static str) -> RpcClient {
+    ClientBuilder::default()
+        .layer(RpcMetricsLayer {
+            metrics: metrics.clone(),
+            chain,
+        })
+        .http(url)
+}
+
+/// Transport layer counting JSON-RPC requests and errors.
+#[derive(Clone)]
+pub struct RpcMetricsLayer {
+    pub metrics: Metrics,
+    pub chain: &'static str,
+}
+
+impl<S> Layer<S> for RpcMetricsLayer {
+    type Service = RpcMetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcMetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+            chain: self.chain,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RpcMetricsService<S> {
+    inner: S,
+    metrics: Metrics,
+    chain: &'static str,
+}
+
+impl<S> Service<RequestPacket> for RpcMetricsService<S>
+where
+    S: Service<
+            RequestPacket,
+            Response = ResponsePacket,
+            Error = TransportError,
+            Future = TransportFut<'static>,
+        >,
+{
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: RequestPacket) -> Self::Future {
+        let methods: Vec<(Id, String)> = match &request {
+            RequestPacket::Single(request) => vec![(request.id().clone(), request.method().into())],
+            RequestPacket::Batch(batch) => batch
+                .iter()
+                .map(|request| (request.id().clone(), request.method().into()))
+                .collect(),
+        };
+        let (metrics, chain) = (self.metrics.clone(), self.chain);
+        for (_, method) in &methods {
+            metrics.inc(&RPC_REQUESTS, &[("chain", chain), ("method", method)]);
+        }
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            let failed: Vec<&Id> = match &response {
+                Err(_) => methods.iter().map(|(id, _)| id).collect(),
+                Ok(ResponsePacket::Single(single)) if single.is_error() => vec![&single.id],
+                Ok(ResponsePacket::Single(_)) => Vec::new(),
+                Ok(ResponsePacket::Batch(batch)) => batch
+                    .iter()
+                    .filter(|single| single.is_error())
+                    .map(|single| &single.id)
+                    .collect(),
+            };
+            for (id, method) in &methods {
+                if failed.contains(&id) {
+                    metrics.inc(&RPC_ERRORS, &[("chain", chain), ("method", method)]);
+                }
+            }
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::U64;
+    use alloy::providers::{Provider, ProviderBuilder};
+    use alloy::transports::mock::{Asserter, MockTransport};
+
+    #[test]
+    fn test_renders_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.add(&DEPOSITS_OBSERVED, &[], 3.0);
+        metrics.set(&LAST_SCANNED_BLOCK, &[], 120.0);
+        metrics.inc(&NACKS, &[("requeue", "true")]);
+        metrics.observe(&MINT_LATENCY, &[], 4.0);
+        metrics.observe(&MINT_LATENCY, &[], 700.0);
+
+        let text = metrics.render();
+        for line in [
+            "# TYPE relayer_deposits_observed_total counter",
+            "relayer_deposits_observed_total 3",
+            "relayer_last_scanned_block 120",
+            "relayer_nacks_total{requeue=\"true\"} 1",
+            "relayer_mint_latency_seconds_bucket{le=\"2.5\"} 0",
+            "relayer_mint_latency_seconds_bucket{le=\"5\"} 1",
+            "relayer_mint_latency_seconds_bucket{le=\"600\"} 1",
+            "relayer_mint_latency_seconds_bucket{le=\"+Inf\"} 2",
+            "relayer_mint_latency_seconds_sum 704",
+            "relayer_mint_latency_seconds_count 2",
+            // Not updated yet, but shown.
+            "relayer_deposits_minted_total 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from\n{text}"
+            );
+        }
+        assert_eq!(metrics.get(&MINT_LATENCY, &[]), Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn test_rpc_layer_counts_requests_and_errors() {
+        let metrics = Metrics::new();
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(7));
+        asserter.push_failure_msg("boom");
+        let client = ClientBuilder::default()
+            .layer(RpcMetricsLayer {
+                metrics: metrics.clone(),
+                chain: "source",
+            })
+            .transport(MockTransport::new(asserter), true);
+        let provider = ProviderBuilder::new().on_client(client);
+
+        assert_eq!(provider.get_block_number().await.unwrap(), 7);
+        assert!(provider.get_block_number().await.is_err());
+        let labels = [("chain", "source"), ("method", "eth_blockNumber")];
+        assert_eq!(metrics.get(&RPC_REQUESTS, &labels), Some(2.0));
+        assert_eq!(metrics.get(&RPC_ERRORS, &labels), Some(1.0));
+    }
+}
@@ -0,0 +1,553 @@
+use crate::cache::CacheBackend;
+use crate::cache::sqlite::{SqliteConnection, open_sqlite, sqlite_err};
+use crate::config::RelayerConfig;
+use crate::errors::RelayerError;
+use alloy::primitives::{Address, B256};
+use async_trait::async_trait;
+use redis::{AsyncCommands, Client, aio::MultiplexedConnection};
+use rusqlite::{OptionalExtension, TransactionBehavior, params};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A mint the includer has reserved a nonce for. It is recorded before the
+/// transaction is sent and removed only after the deposit is acked, so
+/// whoever leads next can tell whether the mint already happened.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingTx {
+    pub deposit_id: String,
+    pub amount: i32,
+    pub nonce: u64,
+    /// Every hash sent with this nonce, oldest first. At most one of them
+    /// can be mined.
+    pub tx_hashes: Vec<B256>,
+}
+
+/// Shared record of in-flight mints per wallet. Writes carry the writer's
+/// fencing token and are refused once a newer token has written. Writes
+/// without a token (no leader election) skip the check and leave the
+/// stored token alone.
+#[async_trait]
+pub trait PendingTxStore: Send + Sync {
+    async fn put(
+        &mut self,
+        wallet: Address,
+        tx: &PendingTx,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError>;
+    async fn remove(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError>;
+    async fn get(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+    ) -> Result<Option<PendingTx>, RelayerError>;
+    async fn list(&mut self, wallet: Address) -> Result<Vec<PendingTx>, RelayerError>;
+}
+
+/// Builds the store for the backend in `cache.backend`, next to the
+/// subscriber cursors.
+pub async fn pending_store_from_config(
+    config: &RelayerConfig,
+) -> Result<Box<dyn PendingTxStore>, RelayerError> {
+    pending_store(CacheBackend::from_config(config), config).await
+}
+
+pub async fn pending_store(
+    backend: CacheBackend,
+    config: &RelayerConfig,
+) -> Result<Box<dyn PendingTxStore>, RelayerError> {
+    match backend {
+        CacheBackend::Redis => {
+            let db_url = config.redis_url()?.to_string();
+            Ok(Box::new(RedisPendingStore::new(db_url).await?))
+        }
+        CacheBackend::Sqlite => Ok(Box::new(SqlitePendingStore::open(&config.sqlite_path)?)),
+        CacheBackend::Memory => Ok(Box::new(InMemoryPendingStore::new())),
+    }
+}
+
+fn check_fence(fence: u64, current: u64) -> Result<(), RelayerError> {
+    if fence < current {
+        return Err(RelayerError::FencedOut {
+            token: fence,
+            current,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct MemoryState {
+    fences: HashMap<Address, u64>,
+    txs: HashMap<Address, HashMap<String, PendingTx>>,
+}
+
+/// Process-local store for tests and single-instance setups. Clones share
+/// state.
+#[derive(Clone, Default)]
+pub struct InMemoryPendingStore {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+impl InMemoryPendingStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().expect("pending store lock poisoned")
+    }
+
+    fn fenced(
+        &self,
+        wallet: Address,
+        fence: Option<u64>,
+    ) -> Result<std::sync::MutexGuard<'_, MemoryState>, RelayerError> {
+        let mut state = self.lock();
+        if let Some(fence) = fence {
+            check_fence(fence, state.fences.get(&wallet).copied().unwrap_or(0))?;
+            state.fences.insert(wallet, fence);
+        }
+        Ok(state)
+    }
+}
+
+#[async_trait]
+impl PendingTxStore for InMemoryPendingStore {
+    async fn put(
+        &mut self,
+        wallet: Address,
+        tx: &PendingTx,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError> {
+        let mut state = self.fenced(wallet, fence)?;
+        state
+            .txs
+            .entry(wallet)
+            .or_default()
+            .insert(tx.deposit_id.clone(), tx.clone());
+        Ok(())
+    }
+
+    async fn remove(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError> {
+        let mut state = self.fenced(wallet, fence)?;
+        if let Some(txs) = state.txs.get_mut(&wallet) {
+            txs.remove(deposit_id);
+        }
+        Ok(())
+    }
+
+    async fn get(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+    ) -> Result<Option<PendingTx>, RelayerError> {
+        Ok(self
+            .lock()
+            .txs
+            .get(&wallet)
+            .and_then(|txs| txs.get(deposit_id))
+            .cloned())
+    }
+
+    async fn list(&mut self, wallet: Address) -> Result<Vec<PendingTx>, RelayerError> {
+        let mut txs: Vec<PendingTx> = self
+            .lock()
+            .txs
+            .get(&wallet)
+            .map(|txs| txs.values().cloned().collect())
+            .unwrap_or_default();
+        txs.sort_by_key(|tx| tx.nonce);
+        Ok(txs)
+    }
+}
+
+/// Runs HSET or HDEL on the pending hash if the fencing token is current.
+/// An empty token skips the check. Returns -1 on success or the newer
+/// token that fenced the write off.
+const FENCED_WRITE_SCRIPT: &str = r"
+if ARGV[1] ~= '' then
+    local current = tonumber(redis.call('GET', KEYS[2]) or '0')
+    if tonumber(ARGV[1]) < current then
+        return current
+    end
+    redis.call('SET', KEYS[2], ARGV[1])
+end
+if ARGV[3] then
+    redis.call('HSET', KEYS[1], ARGV[2], ARGV[3])
+else
+    redis.call('HDEL', KEYS[1], ARGV[2])
+end
+return -1
+";
+
+/// Pending mints in a Redis hash per wallet,
+/// `relayer:includer:{wallet}:pending`.
+pub struct RedisPendingStore {
+    connection: MultiplexedConnection,
+}
+
+impl RedisPendingStore {
+    pub async fn new(db_url: String) -> Result<Self, RelayerError> {
+        let client = Client::open(db_url).map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        let connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        Ok(RedisPendingStore { connection })
+    }
+
+    fn key(wallet: Address) -> String {
+        format!("relayer:includer:{wallet:#x}:pending")
+    }
+
+    async fn fenced_write(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+        value: Option<String>,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError> {
+        let key = Self::key(wallet);
+        let script = redis::Script::new(FENCED_WRITE_SCRIPT);
+        let mut invocation = script.key(&key);
+        invocation
+            .key(format!("{key}:fence"))
+            .arg(fence.map(|fence| fence.to_string()).unwrap_or_default())
+            .arg(deposit_id);
+        if let Some(value) = value {
+            invocation.arg(value);
+        }
+        let current: i64 = invocation
+            .invoke_async(&mut self.connection)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        if current >= 0 {
+            return Err(RelayerError::FencedOut {
+                token: fence.unwrap_or(0),
+                current: current as u64,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PendingTxStore for RedisPendingStore {
+    async fn put(
+        &mut self,
+        wallet: Address,
+        tx: &PendingTx,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError> {
+        let value = serde_json::to_string(tx)?;
+        self.fenced_write(wallet, &tx.deposit_id, Some(value), fence)
+            .await
+    }
+
+    async fn remove(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError> {
+        self.fenced_write(wallet, deposit_id, None, fence).await
+    }
+
+    async fn get(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+    ) -> Result<Option<PendingTx>, RelayerError> {
+        let value: Option<String> = self
+            .connection
+            .hget(Self::key(wallet), deposit_id)
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        value
+            .map(|v| serde_json::from_str(&v).map_err(RelayerError::from))
+            .transpose()
+    }
+
+    async fn list(&mut self, wallet: Address) -> Result<Vec<PendingTx>, RelayerError> {
+        let values: HashMap<String, String> = self
+            .connection
+            .hgetall(Self::key(wallet))
+            .await
+            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
+        let mut txs = values
+            .values()
+            .map(|v| serde_json::from_str(v).map_err(RelayerError::from))
+            .collect::<Result<Vec<PendingTx>, _>>()?;
+        txs.sort_by_key(|tx| tx.nonce);
+        Ok(txs)
+    }
+}
+
+/// Pending mints in SQLite, for instances sharing a host. Uses the same
+/// file as `SqliteCache` by default.
+#[derive(Clone)]
+pub struct SqlitePendingStore {
+    connection: SqliteConnection,
+}
+
+impl SqlitePendingStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RelayerError> {
+        let connection = open_sqlite(path)?;
+        connection
+            .lock()
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS pending_txs (
+                     wallet TEXT NOT NULL,
+                     deposit_id TEXT NOT NULL,
+                     nonce INTEGER NOT NULL,
+                     tx TEXT NOT NULL,
+                     PRIMARY KEY (wallet, deposit_id)
+                 );
+                 CREATE TABLE IF NOT EXISTS pending_fences (
+                     wallet TEXT PRIMARY KEY,
+                     fence INTEGER NOT NULL
+                 );",
+            )
+            .map_err(sqlite_err)?;
+        Ok(SqlitePendingStore { connection })
+    }
+
+    /// Runs `write` in an immediate transaction after checking and bumping
+    /// the wallet's fencing token, if there is one.
+    async fn fenced<F>(
+        &self,
+        wallet: Address,
+        fence: Option<u64>,
+        write: F,
+    ) -> Result<(), RelayerError>
+    where
+        F: FnOnce(&rusqlite::Transaction, &str) -> Result<(), rusqlite::Error> + Send + 'static,
+    {
+        let wallet = format!("{wallet:#x}");
+        let stored_fence = fence
+            .map(|fence| {
+                i64::try_from(fence)
+                    .map_err(|_| RelayerError::SqliteError(format!("Token {fence} is too large")))
+            })
+            .transpose()?;
+        self.connection
+            .with_connection(move |connection| {
+                let tx = connection
+                    .transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(sqlite_err)?;
+                if let (Some(fence), Some(stored_fence)) = (fence, stored_fence) {
+                    let current: Option<i64> = tx
+                        .query_row(
+                            "SELECT fence FROM pending_fences WHERE wallet = ?1",
+                            params![wallet],
+                            |row| row.get(0),
+                        )
+                        .optional()
+                        .map_err(sqlite_err)?;
+                    check_fence(fence, current.unwrap_or(0) as u64)?;
+                    tx.execute(
+                        "INSERT INTO pending_fences (wallet, fence) VALUES (?1, ?2)
+                     ON CONFLICT(wallet) DO UPDATE SET fence = excluded.fence",
+                        params![wallet, stored_fence],
+                    )
+                    .map_err(sqlite_err)?;
+                }
+                write(&tx, &wallet).map_err(sqlite_err)?;
+                tx.commit().map_err(sqlite_err)
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl PendingTxStore for SqlitePendingStore {
+    async fn put(
+        &mut self,
+        wallet: Address,
+        tx: &PendingTx,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError> {
+        let value = serde_json::to_string(tx)?;
+        let deposit_id = tx.deposit_id.clone();
+        let nonce = tx.nonce as i64;
+        self.fenced(wallet, fence, move |db, wallet| {
+            db.execute(
+                "INSERT INTO pending_txs (wallet, deposit_id, nonce, tx) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(wallet, deposit_id) DO UPDATE SET nonce = excluded.nonce, tx = excluded.tx",
+                params![wallet, deposit_id, nonce, value],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn remove(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+        fence: Option<u64>,
+    ) -> Result<(), RelayerError> {
+        let deposit_id = deposit_id.to_string();
+        self.fenced(wallet, fence, move |db, wallet| {
+            db.execute(
+                "DELETE FROM pending_txs WHERE wallet = ?1 AND deposit_id = ?2",
+                params![wallet, deposit_id],
+            )
+            .map(|_| ())
+        })
+        .await
+    }
+
+    async fn get(
+        &mut self,
+        wallet: Address,
+        deposit_id: &str,
+    ) -> Result<Option<PendingTx>, RelayerError> {
+        let wallet = format!("{wallet:#x}");
+        let deposit_id = deposit_id.to_string();
+        let value: Option<String> = self
+            .connection
+            .with_connection(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT tx FROM pending_txs WHERE wallet = ?1 AND deposit_id = ?2",
+                        params![wallet, deposit_id],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                    .map_err(sqlite_err)
+            })
+            .await?;
+        value
+            .map(|v| serde_json::from_str(&v).map_err(RelayerError::from))
+            .transpose()
+    }
+
+    async fn list(&mut self, wallet: Address) -> Result<Vec<PendingTx>, RelayerError> {
+        let wallet = format!("{wallet:#x}");
+        let values: Vec<String> = self
+            .connection
+            .with_connection(move |connection| {
+                let mut stmt = connection
+                    .prepare("SELECT tx FROM pending_txs WHERE wallet = ?1 ORDER BY nonce")
+                    .map_err(sqlite_err)?;
+                let rows = stmt
+                    .query_map(params![wallet], |row| row.get(0))
+                    .map_err(sqlite_err)?;
+                rows.collect::<Result<Vec<String>, _>>().map_err(sqlite_err)
+            })
+            .await?;
+        values
+            .iter()
+            .map(|v| serde_json::from_str(v).map_err(RelayerError::from))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(deposit_id: &str, nonce: u64) -> PendingTx {
+        PendingTx {
+            deposit_id: deposit_id.to_string(),
+            amount: 42,
+            nonce,
+            tx_hashes: Vec::new(),
+        }
+    }
+
+    async fn check_store_behaviour<S: PendingTxStore>(store: &mut S, wallet: Address) {
+        let other_wallet = Address::repeat_byte(9);
+        assert!(store.get(wallet, "a").await.unwrap().is_none());
+
+        let mut a = pending("a", 7);
+        store.put(wallet, &a, Some(1)).await.unwrap();
+        store.put(wallet, &pending("b", 5), Some(1)).await.unwrap();
+        a.tx_hashes.push(B256::repeat_byte(1));
+        store.put(wallet, &a, Some(1)).await.unwrap();
+        assert_eq!(store.get(wallet, "a").await.unwrap(), Some(a.clone()));
+        let nonces: Vec<u64> = store
+            .list(wallet)
+            .await
+            .unwrap()
+            .iter()
+            .map(|tx| tx.nonce)
+            .collect();
+        assert_eq!(nonces, vec![5, 7]);
+        assert!(store.list(other_wallet).await.unwrap().is_empty());
+
+        // A new leader writes with token 2; the old one is fenced off.
+        store.remove(wallet, "b", Some(2)).await.unwrap();
+        let err = store
+            .put(wallet, &pending("c", 8), Some(1))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            RelayerError::FencedOut {
+                token: 1,
+                current: 2
+            }
+        ));
+        assert!(store.remove(wallet, "a", Some(1)).await.is_err());
+        assert_eq!(store.list(wallet).await.unwrap(), vec![a]);
+
+        store.remove(wallet, "a", Some(2)).await.unwrap();
+        assert!(store.list(wallet).await.unwrap().is_empty());
+
+        // Without leader election writes carry no token and are never
+        // fenced off, however high the stored token got.
+        store.put(wallet, &pending("d", 9), Some(3)).await.unwrap();
+        store.put(wallet, &pending("e", 10), None).await.unwrap();
+        store.remove(wallet, "d", None).await.unwrap();
+        let nonces: Vec<u64> = store
+            .list(wallet)
+            .await
+            .unwrap()
+            .iter()
+            .map(|tx| tx.nonce)
+            .collect();
+        assert_eq!(nonces, vec![10]);
+        // The stored token is left alone.
+        assert!(store.remove(wallet, "e", Some(2)).await.is_err());
+        store.remove(wallet, "e", Some(3)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_pending_store() {
+        let mut store = InMemoryPendingStore::new();
+        check_store_behaviour(&mut store, Address::repeat_byte(1)).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_pending_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = SqlitePendingStore::open(dir.path().join("relayer.db")).unwrap();
+        check_store_behaviour(&mut store, Address::repeat_byte(1)).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at DB_URL"]
+    async fn test_redis_pending_store() {
+        dotenv::dotenv().ok();
+        let db_url = std::env::var("DB_URL").unwrap_or_else(|_| "redis://127.0.0.1/".into());
+        let mut store = RedisPendingStore::new(db_url).await.unwrap();
+        // A fresh wallet per run keeps the fencing token at zero.
+        let mut seed = [0u8; 20];
+        seed[..4].copy_from_slice(&std::process::id().to_be_bytes());
+        check_store_behaviour(&mut store, Address::from(seed)).await;
+    }
+}
@@ -0,0 +1,110 @@
+use crate::config::RelayerConfig;
+use crate::errors::RelayerError;
+use crate::subscriber::Deposit;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_QUARANTINE_PATH: &str = "quarantine.jsonl";
+
+/// A deposit the includer refused to mint.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuarantineEntry {
+    /// Milliseconds since the unix epoch.
+    pub quarantined_at: u64,
+    pub reason: String,
+    pub deposit: Deposit,
+}
+
+/// Append-only JSON-lines file of quarantined deposits, kept for manual
+/// review. Entries are never replayed automatically.
+pub struct Quarantine {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Quarantine {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RelayerError> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Quarantine {
+            path,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Opens the file at `verifier.quarantine_path`.
+    pub fn from_config(config: &RelayerConfig) -> Result<Self, RelayerError> {
+        Self::open(&config.verifier.quarantine_path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, deposit: &Deposit, reason: &str) -> Result<(), RelayerError> {
+        let entry = QuarantineEntry {
+            quarantined_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            reason: reason.to_string(),
+            deposit: deposit.clone(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Result<Vec<QuarantineEntry>, RelayerError> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        BufReader::new(file)
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::Address;
+
+    #[test]
+    fn test_record_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let quarantine = Quarantine::open(dir.path().join("q/quarantine.jsonl")).unwrap();
+        assert!(quarantine.entries().unwrap().is_empty());
+
+        let deposit = Deposit {
+            sender: Address::default(),
+            amount: 7,
+            origin: None,
+        };
+        quarantine.record(&deposit, "no receipt").unwrap();
+        quarantine.record(&deposit, "log mismatch").unwrap();
+
+        let entries = quarantine.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].reason, "no receipt");
+        assert_eq!(entries[1].deposit, deposit);
+    }
+}
@@ -1,58 +1,632 @@
+use crate::config::RelayerConfig;
+use crate::envelope::{CONTENT_TYPE, DEPOSIT_ID_HEADER, Envelope, VERSION_HEADER};
 use crate::errors::RelayerError;
+use crate::metrics::Metrics;
+use alloy::primitives::keccak256;
 use async_trait::async_trait;
-use mockall::automock;
-use mockall::predicate::eq;
-use tracing::debug;
+use futures_lite::StreamExt;
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
 
 use lapin::{
-    BasicProperties, Channel, Connection, ConnectionProperties, Consumer, options::*,
-    types::FieldTable,
+    BasicProperties, Channel, Connection, ConnectionProperties, Consumer, ExchangeKind,
+    message::Delivery,
+    options::*,
+    protocol::{AMQPErrorKind, AMQPSoftError, constants::REPLY_SUCCESS},
+    types::{AMQPValue, FieldTable},
+    uri::AMQPUri,
 };
+
+pub mod file;
+pub mod memory;
+pub mod redis_stream;
+pub mod stream;
+
+use file::{FileQueue, FileQueueConsumer, FileQueueDelivery};
+use redis_stream::{RedisStreamConsumer, RedisStreamDelivery, RedisStreamQueue};
+use stream::{RabbitStreamConsumer, RabbitStreamDelivery, RabbitStreamQueue};
+
+/// Queue implementation used by the binaries, selected with `queue.backend`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueBackend {
+    Amqp,
+    Redis,
+    Stream,
+    File,
+}
+
+impl FromStr for QueueBackend {
+    type Err = RelayerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "amqp" | "rabbitmq" => Ok(QueueBackend::Amqp),
+            "redis" => Ok(QueueBackend::Redis),
+            "stream" | "rabbitmq-stream" => Ok(QueueBackend::Stream),
+            "file" => Ok(QueueBackend::File),
+            other => Err(RelayerError::Other(format!(
+                "Unknown queue backend: {other}"
+            ))),
+        }
+    }
+}
+
 #[async_trait]
-//#[cfg_attr(test, mockall::automock)]
-pub trait QueueTrait {
-    type Consumer;
-    async fn publish(&mut self, dep: &Vec<u8>) -> Result<(), RelayerError>;
-    async fn consumer(&mut self) -> Result<lapin::Consumer, RelayerError>;
+pub trait QueueTrait: Send {
+    type Consumer: QueueConsumer;
+    async fn publish(&mut self, dep: &[u8]) -> Result<(), RelayerError>;
+    async fn consumer(&mut self) -> Result<Self::Consumer, RelayerError>;
+
+    /// Publishes the serialized envelope. Backends with message metadata
+    /// override this to also expose the envelope fields there.
+    async fn publish_envelope(&mut self, envelope: &Envelope) -> Result<(), RelayerError> {
+        self.publish(&envelope.to_bytes()?).await
+    }
+
+    /// Publishes the envelopes in order and reports which ones were not
+    /// confirmed. The default publishes one at a time and stops at the first
+    /// failure, so everything after it is reported failed without being
+    /// sent. Backends that can keep several publishes in flight override it.
+    async fn publish_batch(&mut self, envelopes: &[Envelope]) -> BatchReport {
+        let mut report = BatchReport::new(envelopes.len());
+        for (index, envelope) in envelopes.iter().enumerate() {
+            if let Err(e) = self.publish_envelope(envelope).await {
+                report.fail(index, e);
+                report.fail_unsent(index + 1);
+                break;
+            }
+        }
+        report
+    }
+
+    /// Closes the connection on shutdown. Backends holding no server-side
+    /// state keep the default, which does nothing.
+    async fn close(&mut self) -> Result<(), RelayerError> {
+        Ok(())
+    }
 }
-#[derive(Clone)]
 
-pub struct LapinConnection {
-    channel: Channel,
-    queue_name: String,
+/// A message from a batch that the queue did not confirm.
+#[derive(Debug)]
+pub struct BatchFailure {
+    /// Position of the message in the batch.
+    pub index: usize,
+    pub error: RelayerError,
 }
 
+/// Outcome of `QueueTrait::publish_batch`.
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub total: usize,
+    /// Sorted by `index`.
+    pub failed: Vec<BatchFailure>,
+}
+
+impl BatchReport {
+    pub fn new(total: usize) -> Self {
+        BatchReport {
+            total,
+            failed: Vec::new(),
+        }
+    }
+
+    pub fn fail(&mut self, index: usize, error: RelayerError) {
+        let pos = self.failed.partition_point(|f| f.index < index);
+        self.failed.insert(pos, BatchFailure { index, error });
+    }
+
+    /// Reports every message from `index` on as failed without being sent.
+    pub fn fail_unsent(&mut self, index: usize) {
+        for index in index..self.total {
+            let error = RelayerError::Other(String::from("Not sent after an earlier failure"));
+            self.fail(index, error);
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    pub fn confirmed(&self) -> usize {
+        self.total - self.failed.len()
+    }
+
+    /// Index of the earliest message that failed. Everything before it was
+    /// confirmed.
+    pub fn first_failure(&self) -> Option<usize> {
+        self.failed.first().map(|f| f.index)
+    }
+}
+
+/// A stream of deliveries handed out by a queue backend.
 #[async_trait]
-impl QueueTrait for LapinConnection {
-    type Consumer = lapin::Consumer;
-
-    async fn publish(&mut self, serialized_item: &Vec<u8>) -> Result<(), RelayerError> {
-        let confirm = self
-            .channel
-            .basic_publish(
-                "",
-                &self.queue_name,
-                BasicPublishOptions::default(),
-                serialized_item,
-                BasicProperties::default(),
-            )
-            .await?
-            .await?;
+pub trait QueueConsumer: Send {
+    type Delivery: QueueDelivery;
+    /// Waits for the next delivery. `None` means the consumer has been closed.
+    async fn next_delivery(&mut self) -> Option<Result<Self::Delivery, RelayerError>>;
+}
 
-        if confirm.is_ack() {
-            return Ok(());
-        } else {
+/// A single message received from a queue, which must be settled with either
+/// `ack` or `nack`.
+#[async_trait]
+pub trait QueueDelivery: Send + Sized {
+    fn data(&self) -> &[u8];
+    fn redelivered(&self) -> bool;
+    /// Value of a string header, for backends with message metadata.
+    fn header(&self, _name: &str) -> Option<String> {
+        None
+    }
+    async fn ack(self) -> Result<(), RelayerError>;
+    /// Rejects the message. With `requeue` set it is handed out again,
+    /// otherwise it is dead-lettered.
+    async fn nack(self, requeue: bool) -> Result<(), RelayerError>;
+}
+
+pub type DeliveryOf<C> = <<C as QueueTrait>::Consumer as QueueConsumer>::Delivery;
+
+/// A message parked in a backend's dead-letter queue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    /// Message id of the envelope, or the hash of the raw message.
+    pub id: String,
+    pub data: Vec<u8>,
+}
+
+impl DeadLetter {
+    pub fn new(data: Vec<u8>) -> Self {
+        let id = match Envelope::from_bytes(&data) {
+            Ok(envelope) => envelope.message_id,
+            Err(_) => keccak256(&data).to_string(),
+        };
+        DeadLetter { id, data }
+    }
+}
+
+/// Backends whose dead letters can be listed and replayed.
+#[async_trait]
+pub trait DeadLetterQueue: Send {
+    /// Up to `limit` dead letters, oldest first.
+    async fn dead_letters(&mut self, limit: usize) -> Result<Vec<DeadLetter>, RelayerError>;
+    /// Publishes the dead letter with this id to the main queue again and
+    /// drops it from the dead letters. Returns false if there is none.
+    async fn replay(&mut self, id: &str) -> Result<bool, RelayerError>;
+}
+
+/// AMQP delivery mode that makes the broker write the message to disk.
+const PERSISTENT_DELIVERY_MODE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueKind {
+    Classic,
+    Quorum,
+}
+
+impl FromStr for QueueKind {
+    type Err = RelayerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "classic" => Ok(QueueKind::Classic),
+            "quorum" => Ok(QueueKind::Quorum),
+            other => Err(RelayerError::Other(format!("Unknown queue type: {other}"))),
+        }
+    }
+}
+
+/// What the broker does when a queue reaches `max_length`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    DropHead,
+    RejectPublish,
+    RejectPublishDlx,
+}
+
+impl OverflowPolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OverflowPolicy::DropHead => "drop-head",
+            OverflowPolicy::RejectPublish => "reject-publish",
+            OverflowPolicy::RejectPublishDlx => "reject-publish-dlx",
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = RelayerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-head" => Ok(OverflowPolicy::DropHead),
+            "reject-publish" => Ok(OverflowPolicy::RejectPublish),
+            "reject-publish-dlx" => Ok(OverflowPolicy::RejectPublishDlx),
+            other => Err(RelayerError::Other(format!(
+                "Unknown overflow policy: {other}"
+            ))),
+        }
+    }
+}
+
+/// Arguments the AMQP queue is declared with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueOptions {
+    pub kind: QueueKind,
+    /// Survive broker restarts, with persistent messages. On by default;
+    /// a queue or exchange declared before that is transient, and must be
+    /// deleted (or `amqp.durable` set to false) before the relayer starts.
+    pub durable: bool,
+    pub message_ttl_ms: Option<u32>,
+    pub max_length: Option<u32>,
+    pub overflow: Option<OverflowPolicy>,
+    pub dead_letter_exchange: Option<String>,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            kind: QueueKind::Classic,
+            durable: true,
+            message_ttl_ms: None,
+            max_length: None,
+            overflow: None,
+            dead_letter_exchange: None,
+        }
+    }
+}
+
+impl QueueOptions {
+    pub fn validate(&self) -> Result<(), RelayerError> {
+        if self.kind == QueueKind::Quorum && !self.durable {
+            return Err(RelayerError::Other(String::from(
+                "Quorum queues must be durable",
+            )));
+        }
+        if self.kind == QueueKind::Quorum && self.overflow == Some(OverflowPolicy::RejectPublishDlx)
+        {
             return Err(RelayerError::Other(String::from(
-                "Failed to publish to Queue",
+                "Quorum queues do not support reject-publish-dlx",
             )));
         }
+        Ok(())
+    }
+
+    pub fn arguments(&self) -> FieldTable {
+        let mut args = FieldTable::default();
+        if self.kind == QueueKind::Quorum {
+            args.insert(
+                "x-queue-type".into(),
+                AMQPValue::LongString("quorum".into()),
+            );
+        }
+        if let Some(ttl) = self.message_ttl_ms {
+            args.insert("x-message-ttl".into(), AMQPValue::LongUInt(ttl));
+        }
+        if let Some(max_length) = self.max_length {
+            args.insert("x-max-length".into(), AMQPValue::LongUInt(max_length));
+        }
+        if let Some(overflow) = self.overflow {
+            args.insert(
+                "x-overflow".into(),
+                AMQPValue::LongString(overflow.as_str().into()),
+            );
+        }
+        if let Some(dlx) = &self.dead_letter_exchange {
+            args.insert(
+                "x-dead-letter-exchange".into(),
+                AMQPValue::LongString(dlx.as_str().into()),
+            );
+        }
+        args
     }
-    async fn consumer(&mut self) -> Result<Consumer, RelayerError> {
+
+    pub fn properties(&self) -> BasicProperties {
+        if self.durable {
+            BasicProperties::default().with_delivery_mode(PERSISTENT_DELIVERY_MODE)
+        } else {
+            BasicProperties::default()
+        }
+    }
+}
+
+/// Where AMQP messages are published and consumed from.
+///
+/// With the default (nameless) exchange, messages are routed straight to
+/// `queue_name`. With a named exchange, the queue is bound to it with every
+/// routing key, and messages are published with the first one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueTopology {
+    pub vhost: Option<String>,
+    pub exchange: String,
+    pub exchange_kind: ExchangeKind,
+    pub queue_name: String,
+    pub routing_keys: Vec<String>,
+    pub consumer_tag: String,
+    /// Delete the queue once its last consumer disconnects.
+    pub auto_delete: bool,
+    /// Queue bound to the dead-letter exchange, read by `relayer dlq`.
+    pub dead_letter_queue: Option<String>,
+}
+
+impl Default for QueueTopology {
+    fn default() -> Self {
+        QueueTopology {
+            vhost: None,
+            exchange: String::new(),
+            exchange_kind: ExchangeKind::Direct,
+            queue_name: String::from("relayer"),
+            routing_keys: Vec::new(),
+            consumer_tag: String::from("my_consumer"),
+            auto_delete: false,
+            dead_letter_queue: None,
+        }
+    }
+}
+
+static THROWAWAY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn parse_exchange_kind(s: &str) -> ExchangeKind {
+    match s.to_ascii_lowercase().as_str() {
+        "direct" => ExchangeKind::Direct,
+        "fanout" => ExchangeKind::Fanout,
+        "headers" => ExchangeKind::Headers,
+        "topic" => ExchangeKind::Topic,
+        _ => ExchangeKind::Custom(s.to_string()),
+    }
+}
+
+impl QueueTopology {
+    /// A uniquely named, auto-deleted queue, so tests never see each other's
+    /// messages.
+    pub fn throwaway(prefix: &str) -> Self {
+        let n = THROWAWAY_COUNTER.fetch_add(1, Ordering::Relaxed);
+        QueueTopology {
+            queue_name: format!("{prefix}-{}-{n}", std::process::id()),
+            auto_delete: true,
+            ..QueueTopology::default()
+        }
+    }
+
+    pub fn publish_routing_key(&self) -> &str {
+        if self.exchange.is_empty() {
+            &self.queue_name
+        } else {
+            self.routing_keys.first().map(String::as_str).unwrap_or("")
+        }
+    }
+
+    /// Applies `vhost` to a broker address, replacing any vhost in it.
+    pub fn amqp_uri(&self, addr: &str) -> Result<AMQPUri, RelayerError> {
+        let mut uri: AMQPUri = addr
+            .parse()
+            .map_err(|e| RelayerError::Other(format!("Invalid AMQP_ADDR: {e}")))?;
+        if let Some(vhost) = &self.vhost {
+            uri.vhost = vhost.clone();
+        }
+        Ok(uri)
+    }
+}
+
+/// Delays between AMQP reconnection attempts. The delay doubles from
+/// `initial_delay` up to `max_delay`; `max_attempts: None` retries forever.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+}
+
+/// How many times a publish is retried on a fresh channel after the
+/// previous one was lost mid-publish. The broker may have taken the message
+/// before the loss, so a retry can publish it twice; the includer acks a
+/// deposit already recorded as minted instead of minting it again.
+const PUBLISH_RETRIES: u32 = 3;
+
+struct AmqpSession {
+    connection: Connection,
+    channel: Channel,
+}
+
+impl AmqpSession {
+    fn is_connected(&self) -> bool {
+        self.connection.status().connected() && self.channel.status().connected()
+    }
+
+    /// Closes the connection of a session whose channel was lost, so it is
+    /// not left open next to the new one.
+    async fn abandon(self) {
+        if !self.connection.status().connected() {
+            return;
+        }
+        if let Err(e) = self
+            .connection
+            .close(REPLY_SUCCESS, "relayer reconnecting")
+            .await
+        {
+            debug!("Closing the lost AMQP connection failed: {}", e);
+        }
+    }
+}
+
+/// AMQP queue connection that supervises its own session: when the broker
+/// restarts or the TCP connection drops, the next publish or consume
+/// reconnects with backoff, re-declares the queue and re-enables publisher
+/// confirms. Clones share one session.
+#[derive(Clone)]
+pub struct LapinConnection {
+    addr: String,
+    topology: QueueTopology,
+    options: QueueOptions,
+    properties: BasicProperties,
+    reconnect: ReconnectPolicy,
+    session: Arc<Mutex<Option<AmqpSession>>>,
+}
+
+impl LapinConnection {
+    /// Connects to the broker at `amqp.addr`, with the queue and its
+    /// arguments from the `amqp` settings.
+    pub async fn new(config: &RelayerConfig) -> Result<Self, RelayerError> {
+        Self::from_config(config, config.topology.clone()).await
+    }
+
+    /// Same as `new`, but for the queue in `topology`.
+    pub async fn from_config(
+        config: &RelayerConfig,
+        topology: QueueTopology,
+    ) -> Result<Self, RelayerError> {
+        Self::connect(&config.amqp_addr, topology, config.queue_options.clone()).await
+    }
+
+    pub async fn connect(
+        addr: &str,
+        topology: QueueTopology,
+        options: QueueOptions,
+    ) -> Result<Self, RelayerError> {
+        options.validate()?;
+        let addr = addr.to_string();
+
+        // The first connection is not retried, so a misconfigured broker
+        // address fails at startup.
+        let session = open_session(&addr, &topology, &options).await?;
+
+        Ok(LapinConnection {
+            addr,
+            topology,
+            properties: options.properties(),
+            options,
+            reconnect: ReconnectPolicy::default(),
+            session: Arc::new(Mutex::new(Some(session))),
+        })
+    }
+
+    pub fn topology(&self) -> &QueueTopology {
+        &self.topology
+    }
+
+    pub fn with_reconnect_policy(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Returns a connected channel, reconnecting first if the current one
+    /// has been lost.
+    async fn channel(&self) -> Result<Channel, RelayerError> {
+        let mut attempt = 0;
+        loop {
+            let delay = {
+                let mut session = self.session.lock().await;
+                if let Some(current) = session.as_ref() {
+                    if current.is_connected() {
+                        return Ok(current.channel.clone());
+                    }
+                    warn!("AMQP connection lost, reconnecting");
+                }
+                if let Some(lost) = session.take() {
+                    lost.abandon().await;
+                }
+
+                match open_session(&self.addr, &self.topology, &self.options).await {
+                    Ok(new_session) => {
+                        info!("AMQP connection re-established");
+                        let channel = new_session.channel.clone();
+                        *session = Some(new_session);
+                        return Ok(channel);
+                    }
+                    // Retrying won't fix a queue declared with other arguments.
+                    Err(e @ RelayerError::QueueConfigMismatch(_)) => return Err(e),
+                    Err(e) => {
+                        if self
+                            .reconnect
+                            .max_attempts
+                            .is_some_and(|max| attempt + 1 >= max)
+                        {
+                            return Err(e);
+                        }
+                        let delay = self.reconnect.delay(attempt);
+                        warn!(
+                            "Reconnect attempt {} failed: {}, retrying in {:?}",
+                            attempt + 1,
+                            e,
+                            delay
+                        );
+                        delay
+                    }
+                }
+            };
+            // The session is unlocked while waiting, so clones are not held
+            // up by the backoff and use a session another one reconnected.
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn publish_with_properties(
+        &mut self,
+        serialized_item: &[u8],
+        properties: BasicProperties,
+    ) -> Result<(), RelayerError> {
+        let mut retries = 0;
+        loop {
+            let channel = self.channel().await?;
+            let published = async {
+                channel
+                    .basic_publish(
+                        &self.topology.exchange,
+                        self.topology.publish_routing_key(),
+                        BasicPublishOptions::default(),
+                        serialized_item,
+                        properties.clone(),
+                    )
+                    .await?
+                    .await
+            }
+            .await;
+
+            match published {
+                Ok(confirm) if confirm.is_ack() => return Ok(()),
+                Ok(_) => {
+                    return Err(RelayerError::Other(String::from(
+                        "Failed to publish to Queue",
+                    )));
+                }
+                Err(e) if !channel.status().connected() && retries < PUBLISH_RETRIES => {
+                    warn!("Publish failed on a lost channel: {}, retrying", e);
+                    retries += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn basic_consume(&self) -> Result<Consumer, RelayerError> {
         let consumer = self
-            .channel
+            .channel()
+            .await?
             .basic_consume(
-                &self.queue_name,
-                "my_consumer",
+                &self.topology.queue_name,
+                &self.topology.consumer_tag,
                 BasicConsumeOptions::default(),
                 FieldTable::default(),
             )
@@ -61,86 +635,639 @@ impl QueueTrait for LapinConnection {
     }
 }
 
-impl LapinConnection {
-    pub async fn new(is_test: bool) -> Result<Self, RelayerError> {
-        let addr =
-            std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".into());
-
-        let conn = Connection::connect(&addr, ConnectionProperties::default())
+async fn open_session(
+    addr: &str,
+    topology: &QueueTopology,
+    options: &QueueOptions,
+) -> Result<AmqpSession, RelayerError> {
+    let connection =
+        Connection::connect_uri(topology.amqp_uri(addr)?, ConnectionProperties::default())
             .await
             .map_err(|e| RelayerError::Other(e.to_string()))?;
 
-        debug!("CONNECTED");
+    debug!("CONNECTED");
 
-        let channel = conn
-            .create_channel()
-            .await
-            .map_err(|e| RelayerError::Other(e.to_string()))?;
+    let channel = connection
+        .create_channel()
+        .await
+        .map_err(|e| RelayerError::Other(e.to_string()))?;
 
-        channel
-            .confirm_select(ConfirmSelectOptions { nowait: false })
-            .await?;
-        let queue_name = if is_test { "test_relayer" } else { "relayer" };
+    channel
+        .confirm_select(ConfirmSelectOptions { nowait: false })
+        .await?;
 
-        let _queue = channel
-            .queue_declare(
-                queue_name,
-                QueueDeclareOptions::default(),
+    let queue_name = topology.queue_name.as_str();
+    let mismatch = |e: lapin::Error| match e {
+        lapin::Error::ProtocolError(ref amqp)
+            if *amqp.kind() == AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED) =>
+        {
+            RelayerError::QueueConfigMismatch(format!(
+                "{queue_name}: {} (delete the queue, or match its arguments such as amqp.durable)",
+                amqp.get_message()
+            ))
+        }
+        e => RelayerError::Other(e.to_string()),
+    };
+
+    if !topology.exchange.is_empty() {
+        channel
+            .exchange_declare(
+                &topology.exchange,
+                topology.exchange_kind.clone(),
+                ExchangeDeclareOptions {
+                    durable: options.durable,
+                    ..ExchangeDeclareOptions::default()
+                },
                 FieldTable::default(),
             )
             .await
-            .map_err(|e| RelayerError::Other(e.to_string()))?;
+            .map_err(mismatch)?;
+    }
 
-        Ok(LapinConnection {
-            channel,
-            queue_name: queue_name.to_string(),
+    // Redeclaring an existing queue with different arguments fails with
+    // PRECONDITION_FAILED, which is how a mismatch is detected.
+    let _queue = channel
+        .queue_declare(
+            queue_name,
+            QueueDeclareOptions {
+                durable: options.durable,
+                auto_delete: topology.auto_delete,
+                ..QueueDeclareOptions::default()
+            },
+            options.arguments(),
+        )
+        .await
+        .map_err(mismatch)?;
+
+    if !topology.exchange.is_empty() {
+        let default_key = [String::new()];
+        let keys = if topology.routing_keys.is_empty() {
+            &default_key[..]
+        } else {
+            &topology.routing_keys[..]
+        };
+        for key in keys {
+            channel
+                .queue_bind(
+                    queue_name,
+                    &topology.exchange,
+                    key,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+    }
+
+    Ok(AmqpSession {
+        connection,
+        channel,
+    })
+}
+
+/// Maps the envelope metadata onto the AMQP message properties, so it can be
+/// inspected by the broker and tooling without parsing the body.
+pub fn envelope_properties(envelope: &Envelope, base: BasicProperties) -> BasicProperties {
+    let mut headers = FieldTable::default();
+    headers.insert(
+        VERSION_HEADER.into(),
+        AMQPValue::ShortUInt(envelope.version),
+    );
+    if let Some(id) = envelope.deposit_id() {
+        headers.insert(DEPOSIT_ID_HEADER.into(), AMQPValue::LongString(id.into()));
+    }
+    base.with_message_id(envelope.message_id.as_str().into())
+        .with_content_type(CONTENT_TYPE.into())
+        .with_timestamp(envelope.produced_at / 1000)
+        .with_type(envelope.kind.as_str().into())
+        .with_app_id(envelope.source.as_str().into())
+        .with_headers(headers)
+}
+
+#[async_trait]
+impl QueueTrait for LapinConnection {
+    type Consumer = LapinConsumer;
+
+    async fn publish(&mut self, serialized_item: &[u8]) -> Result<(), RelayerError> {
+        let properties = self.properties.clone();
+        self.publish_with_properties(serialized_item, properties)
+            .await
+    }
+
+    async fn publish_envelope(&mut self, envelope: &Envelope) -> Result<(), RelayerError> {
+        let properties = envelope_properties(envelope, self.properties.clone());
+        self.publish_with_properties(&envelope.to_bytes()?, properties)
+            .await
+    }
+
+    /// Sends the whole batch on one channel before waiting for any publisher
+    /// confirm, so a batch costs about one round trip instead of one per
+    /// message. Sending stops at the first message that cannot be sent, but
+    /// messages already sent after one the broker nacks stay published; a
+    /// rescan sends those again and the includer skips them as minted.
+    /// Nothing is retried here: failed messages are reported and left to
+    /// the caller.
+    async fn publish_batch(&mut self, envelopes: &[Envelope]) -> BatchReport {
+        let mut report = BatchReport::new(envelopes.len());
+        let channel = match self.channel().await {
+            Ok(channel) => channel,
+            Err(e) => {
+                let reason = e.to_string();
+                for index in 0..envelopes.len() {
+                    report.fail(index, RelayerError::Other(reason.clone()));
+                }
+                return report;
+            }
+        };
+
+        let mut pending = Vec::with_capacity(envelopes.len());
+        for (index, envelope) in envelopes.iter().enumerate() {
+            let payload = match envelope.to_bytes() {
+                Ok(payload) => payload,
+                Err(e) => {
+                    report.fail(index, e);
+                    report.fail_unsent(index + 1);
+                    break;
+                }
+            };
+            let published = channel
+                .basic_publish(
+                    &self.topology.exchange,
+                    self.topology.publish_routing_key(),
+                    BasicPublishOptions::default(),
+                    &payload,
+                    envelope_properties(envelope, self.properties.clone()),
+                )
+                .await;
+            match published {
+                Ok(confirm) => pending.push((index, confirm)),
+                Err(e) => {
+                    report.fail(index, e.into());
+                    report.fail_unsent(index + 1);
+                    break;
+                }
+            }
+        }
+
+        for (index, confirm) in pending {
+            match confirm.await {
+                Ok(confirm) if confirm.is_ack() => {}
+                Ok(_) => report.fail(
+                    index,
+                    RelayerError::Other(String::from("Broker nacked the message")),
+                ),
+                Err(e) => report.fail(index, e.into()),
+            }
+        }
+        debug!(
+            "Published batch: {} of {} confirmed",
+            report.confirmed(),
+            report.total
+        );
+        report
+    }
+
+    async fn consumer(&mut self) -> Result<LapinConsumer, RelayerError> {
+        let consumer = self.basic_consume().await?;
+        Ok(LapinConsumer {
+            connection: self.clone(),
+            consumer: Some(consumer),
+        })
+    }
+
+    /// Closes the shared session, which also cancels its consumers; the
+    /// broker requeues their unacked deliveries. A later publish or consume
+    /// reconnects.
+    async fn close(&mut self) -> Result<(), RelayerError> {
+        let Some(session) = self.session.lock().await.take() else {
+            return Ok(());
+        };
+        if session.is_connected() {
+            session
+                .channel
+                .close(REPLY_SUCCESS, "relayer shutting down")
+                .await?;
+            session
+                .connection
+                .close(REPLY_SUCCESS, "relayer shutting down")
+                .await?;
+            info!("AMQP connection closed");
+        }
+        Ok(())
+    }
+}
+
+/// AMQP consumer that re-subscribes on a fresh channel when its stream ends
+/// or fails. Deliveries received before the loss can no longer be acked;
+/// the broker redelivers them to the new consumer.
+pub struct LapinConsumer {
+    connection: LapinConnection,
+    consumer: Option<Consumer>,
+}
+
+#[async_trait]
+impl QueueConsumer for LapinConsumer {
+    type Delivery = Delivery;
+
+    async fn next_delivery(&mut self) -> Option<Result<Delivery, RelayerError>> {
+        loop {
+            let consumer = match self.consumer.as_mut() {
+                Some(consumer) => consumer,
+                None => match self.connection.basic_consume().await {
+                    Ok(consumer) => {
+                        info!("AMQP consumer re-created");
+                        self.consumer.insert(consumer)
+                    }
+                    Err(e) => return Some(Err(e)),
+                },
+            };
+            match consumer.next().await {
+                Some(Ok(delivery)) => return Some(Ok(delivery)),
+                Some(Err(e)) => warn!("AMQP consumer failed: {}, re-subscribing", e),
+                None => warn!("AMQP consumer stream ended, re-subscribing"),
+            }
+            self.consumer = None;
+        }
+    }
+}
+
+#[async_trait]
+impl QueueDelivery for Delivery {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn redelivered(&self) -> bool {
+        self.redelivered
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        match self.properties.headers().as_ref()?.inner().get(name)? {
+            AMQPValue::LongString(value) => Some(value.to_string()),
+            AMQPValue::ShortString(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+
+    async fn ack(self) -> Result<(), RelayerError> {
+        self.acker
+            .ack(BasicAckOptions::default())
+            .await
+            .map_err(RelayerError::AmqpError)
+    }
+
+    async fn nack(self, requeue: bool) -> Result<(), RelayerError> {
+        self.acker
+            .nack(BasicNackOptions {
+                multiple: false,
+                requeue,
+            })
+            .await
+            .map_err(RelayerError::AmqpError)
+    }
+}
+
+/// Reads the dead-letter queue with `basic_get` and requeues whatever it does
+/// not replay, so listing leaves the queue as it was.
+#[async_trait]
+impl DeadLetterQueue for LapinConnection {
+    async fn dead_letters(&mut self, limit: usize) -> Result<Vec<DeadLetter>, RelayerError> {
+        let (channel, queue) = self.dead_letter_channel().await?;
+        let mut letters = Vec::new();
+        let mut held = Vec::new();
+        while letters.len() < limit {
+            let Some(message) = channel
+                .basic_get(&queue, BasicGetOptions { no_ack: false })
+                .await?
+            else {
+                break;
+            };
+            letters.push(DeadLetter::new(message.delivery.data.clone()));
+            held.push(message.delivery);
+        }
+        for delivery in held {
+            delivery.nack(true).await?;
+        }
+        Ok(letters)
+    }
+
+    async fn replay(&mut self, id: &str) -> Result<bool, RelayerError> {
+        let (channel, queue) = self.dead_letter_channel().await?;
+        let mut held = Vec::new();
+        let mut found = None;
+        while let Some(message) = channel
+            .basic_get(&queue, BasicGetOptions { no_ack: false })
+            .await?
+        {
+            if DeadLetter::new(message.delivery.data.clone()).id == id {
+                found = Some(message.delivery);
+                break;
+            }
+            held.push(message.delivery);
+        }
+        let replayed = match found {
+            Some(delivery) => {
+                // Kept like a first publish, so the deposit can still be
+                // traced by its headers. A raw message has none.
+                let properties = match Envelope::from_bytes(&delivery.data) {
+                    Ok(envelope) => envelope_properties(&envelope, self.properties.clone()),
+                    Err(_) => self.properties.clone(),
+                };
+                self.publish_with_properties(&delivery.data, properties)
+                    .await?;
+                delivery.ack().await?;
+                true
+            }
+            None => false,
+        };
+        for delivery in held {
+            delivery.nack(true).await?;
+        }
+        Ok(replayed)
+    }
+}
+
+impl LapinConnection {
+    async fn dead_letter_channel(&self) -> Result<(Channel, String), RelayerError> {
+        let queue = self.topology.dead_letter_queue.clone().ok_or_else(|| {
+            RelayerError::ConfigError(String::from("AMQP_DEAD_LETTER_QUEUE is not set"))
+        })?;
+        Ok((self.channel().await?, queue))
+    }
+}
+
+/// Queue backend chosen at runtime, for code that cannot be generic over
+/// `QueueTrait`.
+pub enum QueueConnection {
+    Amqp(Box<LapinConnection>),
+    Redis(RedisStreamQueue),
+    Stream(RabbitStreamQueue),
+    File(FileQueue),
+}
+
+impl QueueConnection {
+    /// Connects to the backend from `queue.backend` (default amqp). The
+    /// file backend opens `queue_dir`, so only one process on the host can
+    /// use it. With `stream.replay_from` the stream backend is consumed from
+    /// that offset.
+    pub async fn from_config(config: &RelayerConfig) -> Result<Self, RelayerError> {
+        let topology = &config.topology;
+        match config.queue_backend {
+            QueueBackend::Amqp => Ok(QueueConnection::Amqp(Box::new(
+                LapinConnection::new(config).await?,
+            ))),
+            QueueBackend::Redis => {
+                let redis_url = config.redis_url()?.to_string();
+                Ok(QueueConnection::Redis(
+                    RedisStreamQueue::new(redis_url, &topology.queue_name).await?,
+                ))
+            }
+            QueueBackend::Stream => {
+                let stream = format!("{}-stream", topology.queue_name);
+                let queue = RabbitStreamQueue::new(config, &stream).await?;
+                Ok(QueueConnection::Stream(match config.stream_replay_from {
+                    Some(offset) => queue.replay_from(offset),
+                    None => queue,
+                }))
+            }
+            QueueBackend::File => Ok(QueueConnection::File(FileQueue::open_with(
+                &config.queue_dir,
+                config.file_queue.clone(),
+            )?)),
+        }
+    }
+
+    /// Counts the deliveries the backend dead-letters by itself, after too
+    /// many attempts. The AMQP broker does that without telling the client.
+    pub fn with_metrics(self, metrics: Metrics) -> Self {
+        match self {
+            QueueConnection::Redis(queue) => QueueConnection::Redis(queue.with_metrics(metrics)),
+            QueueConnection::Stream(queue) => QueueConnection::Stream(queue.with_metrics(metrics)),
+            QueueConnection::File(queue) => QueueConnection::File(queue.with_metrics(metrics)),
+            other => other,
+        }
+    }
+}
+
+#[async_trait]
+impl QueueTrait for QueueConnection {
+    type Consumer = QueueConnectionConsumer;
+
+    async fn publish(&mut self, dep: &[u8]) -> Result<(), RelayerError> {
+        match self {
+            QueueConnection::Amqp(queue) => queue.publish(dep).await,
+            QueueConnection::Redis(queue) => queue.publish(dep).await,
+            QueueConnection::Stream(queue) => queue.publish(dep).await,
+            QueueConnection::File(queue) => queue.publish(dep).await,
+        }
+    }
+
+    async fn consumer(&mut self) -> Result<QueueConnectionConsumer, RelayerError> {
+        Ok(match self {
+            QueueConnection::Amqp(queue) => {
+                QueueConnectionConsumer::Amqp(Box::new(queue.consumer().await?))
+            }
+            QueueConnection::Redis(queue) => {
+                QueueConnectionConsumer::Redis(queue.consumer().await?)
+            }
+            QueueConnection::Stream(queue) => {
+                QueueConnectionConsumer::Stream(queue.consumer().await?)
+            }
+            QueueConnection::File(queue) => QueueConnectionConsumer::File(queue.consumer().await?),
         })
     }
+
+    async fn publish_envelope(&mut self, envelope: &Envelope) -> Result<(), RelayerError> {
+        match self {
+            QueueConnection::Amqp(queue) => queue.publish_envelope(envelope).await,
+            QueueConnection::Redis(queue) => queue.publish_envelope(envelope).await,
+            QueueConnection::Stream(queue) => queue.publish_envelope(envelope).await,
+            QueueConnection::File(queue) => queue.publish_envelope(envelope).await,
+        }
+    }
+
+    async fn publish_batch(&mut self, envelopes: &[Envelope]) -> BatchReport {
+        match self {
+            QueueConnection::Amqp(queue) => queue.publish_batch(envelopes).await,
+            QueueConnection::Redis(queue) => queue.publish_batch(envelopes).await,
+            QueueConnection::Stream(queue) => queue.publish_batch(envelopes).await,
+            QueueConnection::File(queue) => queue.publish_batch(envelopes).await,
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), RelayerError> {
+        match self {
+            QueueConnection::Amqp(queue) => queue.close().await,
+            QueueConnection::Redis(queue) => queue.close().await,
+            QueueConnection::Stream(queue) => queue.close().await,
+            QueueConnection::File(queue) => QueueTrait::close(queue).await,
+        }
+    }
 }
 
-pub async fn get_queue_connection(is_test: bool) -> Result<LapinConnection, RelayerError> {
-    let queue_connection = LapinConnection::new(is_test).await?;
+#[async_trait]
+impl DeadLetterQueue for QueueConnection {
+    async fn dead_letters(&mut self, limit: usize) -> Result<Vec<DeadLetter>, RelayerError> {
+        match self {
+            QueueConnection::Amqp(queue) => queue.dead_letters(limit).await,
+            QueueConnection::Redis(queue) => queue.dead_letters(limit).await,
+            QueueConnection::Stream(queue) => queue.dead_letters(limit).await,
+            QueueConnection::File(queue) => DeadLetterQueue::dead_letters(queue, limit).await,
+        }
+    }
+
+    async fn replay(&mut self, id: &str) -> Result<bool, RelayerError> {
+        match self {
+            QueueConnection::Amqp(queue) => queue.replay(id).await,
+            QueueConnection::Redis(queue) => queue.replay(id).await,
+            QueueConnection::Stream(queue) => queue.replay(id).await,
+            QueueConnection::File(queue) => queue.replay(id).await,
+        }
+    }
+}
+
+pub enum QueueConnectionConsumer {
+    Amqp(Box<LapinConsumer>),
+    Redis(RedisStreamConsumer),
+    Stream(RabbitStreamConsumer),
+    File(FileQueueConsumer),
+}
+
+#[async_trait]
+impl QueueConsumer for QueueConnectionConsumer {
+    type Delivery = QueueConnectionDelivery;
+
+    async fn next_delivery(&mut self) -> Option<Result<QueueConnectionDelivery, RelayerError>> {
+        Some(match self {
+            QueueConnectionConsumer::Amqp(consumer) => consumer
+                .next_delivery()
+                .await?
+                .map(QueueConnectionDelivery::Amqp),
+            QueueConnectionConsumer::Redis(consumer) => consumer
+                .next_delivery()
+                .await?
+                .map(QueueConnectionDelivery::Redis),
+            QueueConnectionConsumer::Stream(consumer) => consumer
+                .next_delivery()
+                .await?
+                .map(QueueConnectionDelivery::Stream),
+            QueueConnectionConsumer::File(consumer) => consumer
+                .next_delivery()
+                .await?
+                .map(QueueConnectionDelivery::File),
+        })
+    }
+}
+
+pub enum QueueConnectionDelivery {
+    Amqp(Delivery),
+    Redis(RedisStreamDelivery),
+    Stream(RabbitStreamDelivery),
+    File(FileQueueDelivery),
+}
+
+#[async_trait]
+impl QueueDelivery for QueueConnectionDelivery {
+    fn data(&self) -> &[u8] {
+        match self {
+            QueueConnectionDelivery::Amqp(delivery) => delivery.data(),
+            QueueConnectionDelivery::Redis(delivery) => delivery.data(),
+            QueueConnectionDelivery::Stream(delivery) => delivery.data(),
+            QueueConnectionDelivery::File(delivery) => delivery.data(),
+        }
+    }
+
+    fn redelivered(&self) -> bool {
+        match self {
+            QueueConnectionDelivery::Amqp(delivery) => delivery.redelivered(),
+            QueueConnectionDelivery::Redis(delivery) => delivery.redelivered(),
+            QueueConnectionDelivery::Stream(delivery) => delivery.redelivered(),
+            QueueConnectionDelivery::File(delivery) => delivery.redelivered(),
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        match self {
+            QueueConnectionDelivery::Amqp(delivery) => delivery.header(name),
+            QueueConnectionDelivery::Redis(delivery) => delivery.header(name),
+            QueueConnectionDelivery::Stream(delivery) => delivery.header(name),
+            QueueConnectionDelivery::File(delivery) => delivery.header(name),
+        }
+    }
+
+    async fn ack(self) -> Result<(), RelayerError> {
+        match self {
+            QueueConnectionDelivery::Amqp(delivery) => delivery.ack().await,
+            QueueConnectionDelivery::Redis(delivery) => delivery.ack().await,
+            QueueConnectionDelivery::Stream(delivery) => delivery.ack().await,
+            QueueConnectionDelivery::File(delivery) => delivery.ack().await,
+        }
+    }
+
+    async fn nack(self, requeue: bool) -> Result<(), RelayerError> {
+        match self {
+            QueueConnectionDelivery::Amqp(delivery) => delivery.nack(requeue).await,
+            QueueConnectionDelivery::Redis(delivery) => delivery.nack(requeue).await,
+            QueueConnectionDelivery::Stream(delivery) => delivery.nack(requeue).await,
+            QueueConnectionDelivery::File(delivery) => delivery.nack(requeue).await,
+        }
+    }
+}
+
+pub async fn get_queue_connection(
+    config: &RelayerConfig,
+    topology: QueueTopology,
+) -> Result<LapinConnection, RelayerError> {
+    let queue_connection = LapinConnection::from_config(config, topology).await?;
     Ok(queue_connection)
 }
-// move to includer
 
+#[cfg(test)]
 mod tests {
-    use std::env;
-
-    use alloy::transports::http::reqwest::Url;
-    use futures_lite::StreamExt;
+    use alloy::primitives::B256;
 
+    use super::*;
     use crate::{
-        includer::{self, Includer},
-        subscriber::Deposit,
-        utils::get_dst_contract_addr,
+        includer,
+        subscriber::{Deposit, DepositOrigin},
     };
-    // move to integration tests this one check what the convention is
-    use super::*;
-    #[tokio::test]
 
-    async fn test_publish_and_consume() {
+    /// The local broker and destination chain, with env overrides.
+    fn test_config() -> RelayerConfig {
         dotenv::dotenv().ok();
+        RelayerConfig::from_toml(
+            r#"
+            [destination]
+            rpc_url = "http://localhost:8546"
 
-        const ADDRESS_PATH: &str = "../project_eth/data/deployments.json";
-        let mut con = get_queue_connection(true).await.unwrap();
+            [includer]
+            private_key = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+            "#,
+            |name| std::env::var(name).ok(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_consume() {
+        let config = test_config();
+        let mut con = get_queue_connection(&config, QueueTopology::throwaway("test_relayer"))
+            .await
+            .unwrap();
         let test_deposit = Deposit {
             sender: "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
                 .parse()
                 .unwrap(),
             amount: 42,
+            origin: None,
         };
         let test_item = serde_json::to_vec(&test_deposit).unwrap();
         let resp = con.publish(&test_item).await;
         assert!(resp.is_ok());
         let mut consumer = con.consumer().await.unwrap();
-        let dst_rpc = "http://localhost:8546";
-        let rpc_url_dst: Url = dst_rpc.parse().unwrap();
-        let dst_contract_address = get_dst_contract_addr(ADDRESS_PATH).unwrap();
-        let incl_res =
-            includer::Includer::new(&rpc_url_dst, dst_contract_address, con.clone()).await;
+        let incl_res = includer::Includer::new(&config, con.clone());
         assert!(incl_res.is_ok());
-        let incl = incl_res.unwrap();
+        let mut incl = incl_res.unwrap();
         let res = incl.consume(&mut consumer).await;
         assert!(res.is_ok());
         let tuple = res.unwrap();
@@ -152,67 +1279,265 @@ mod tests {
 
     #[tokio::test]
     async fn test_publish_and_consume_without_includer() {
-        dotenv::dotenv().ok();
-        let mut con = get_queue_connection(true).await.unwrap();
+        let mut con =
+            get_queue_connection(&test_config(), QueueTopology::throwaway("test_relayer"))
+                .await
+                .unwrap();
         let test_deposit = Deposit {
             sender: "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
                 .parse()
                 .unwrap(),
             amount: 42,
+            origin: None,
         };
         let test_item = serde_json::to_vec(&test_deposit).unwrap();
         let resp = con.publish(&test_item).await;
         assert!(resp.is_ok());
         let mut consumer = con.consumer().await.unwrap();
-        let res = consumer.next().await.unwrap();
+        let res = consumer.next_delivery().await.unwrap();
         assert!(res.is_ok());
         let delivery = res.unwrap();
-        let deposit = serde_json::from_slice::<Deposit>(&delivery.data).unwrap();
+        let deposit = serde_json::from_slice::<Deposit>(delivery.data()).unwrap();
         assert_eq!(deposit, test_deposit);
     }
-}
 
-// mod tests {
-//     use super::*;
-//     #[tokio::test]
-//     async fn test_publish() {
-//         let mut mock_queue_connection = MockQueue::new();
-//         let deposit = Deposit {
-//             sender: "0x1234567890123456789012345678901234567890"
-//                 .parse()
-//                 .unwrap(),
-//             amount: 100,
-//         };
-//         mock_queue_connection
-//             .expect_publish()
-//             .with(eq(deposit.clone()))
-//             .once()
-//             .returning(|_| Ok(()));
-//         let result = mock_queue_connection.publish(deposit).await;
-//         assert!(result.is_ok());
-//     }
-
-//     #[tokio::test]
-//     async fn test_consume() {
-//         let mut mock_queue_connection = MockQueue::new();
-//         mock_queue_connection.expect_consume().once().returning(|| {
-//             Ok(Deposit {
-//                 sender: "0x1234567890123456789012345678901234567890"
-//                     .parse()
-//                     .unwrap(),
-//                 amount: 100,
-//             })
-//         });
-//         let result = mock_queue_connection.consume().await;
-//         assert!(result.is_ok());
-//         assert_eq!(
-//             result.unwrap(),
-//             Deposit {
-//                 sender: "0x1234567890123456789012345678901234567890"
-//                     .parse()
-//                     .unwrap(),
-//                 amount: 100
-//             }
-//         );
-//     }
-// }
+    #[test]
+    fn test_queue_backend_from_str() {
+        assert_eq!("amqp".parse::<QueueBackend>().unwrap(), QueueBackend::Amqp);
+        assert_eq!(
+            "Redis".parse::<QueueBackend>().unwrap(),
+            QueueBackend::Redis
+        );
+        assert_eq!(
+            "rabbitmq-stream".parse::<QueueBackend>().unwrap(),
+            QueueBackend::Stream
+        );
+        assert_eq!("file".parse::<QueueBackend>().unwrap(), QueueBackend::File);
+        assert!("kafka".parse::<QueueBackend>().is_err());
+    }
+
+    #[test]
+    fn test_queue_options_arguments() {
+        let options = QueueOptions {
+            kind: QueueKind::Quorum,
+            message_ttl_ms: Some(60_000),
+            max_length: Some(1000),
+            overflow: Some(OverflowPolicy::RejectPublish),
+            ..QueueOptions::default()
+        };
+        let args = options.arguments();
+        let args = args.inner();
+        assert_eq!(
+            args.get("x-queue-type"),
+            Some(&AMQPValue::LongString("quorum".into()))
+        );
+        assert_eq!(
+            args.get("x-message-ttl"),
+            Some(&AMQPValue::LongUInt(60_000))
+        );
+        assert_eq!(args.get("x-max-length"), Some(&AMQPValue::LongUInt(1000)));
+        assert_eq!(
+            args.get("x-overflow"),
+            Some(&AMQPValue::LongString("reject-publish".into()))
+        );
+        assert_eq!(options.properties().delivery_mode(), &Some(2));
+    }
+
+    #[test]
+    fn test_queue_options_validate() {
+        let options = QueueOptions {
+            kind: QueueKind::Quorum,
+            durable: false,
+            ..QueueOptions::default()
+        };
+        assert!(options.validate().is_err());
+        assert!(QueueOptions::default().validate().is_ok());
+        assert!(QueueOptions::default().arguments().inner().is_empty());
+    }
+
+    #[test]
+    fn test_batch_report() {
+        let mut report = BatchReport::new(5);
+        assert!(report.is_success());
+        report.fail(3, RelayerError::Other("late".into()));
+        report.fail(1, RelayerError::Other("early".into()));
+        assert!(!report.is_success());
+        assert_eq!(report.first_failure(), Some(1));
+        assert_eq!(report.confirmed(), 3);
+        let indexes: Vec<usize> = report.failed.iter().map(|f| f.index).collect();
+        assert_eq!(indexes, vec![1, 3]);
+        report.fail_unsent(4);
+        assert_eq!(report.confirmed(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_default_publish_batch_reports_failures() {
+        let mut queue = memory::InMemoryQueue::new();
+        let deposit = Deposit {
+            sender: Default::default(),
+            amount: 1,
+            origin: None,
+        };
+        let envelopes = vec![Envelope::deposit(&deposit, "test").unwrap(); 3];
+        let report = queue.publish_batch(&envelopes).await;
+        assert!(report.is_success());
+        assert_eq!(queue.ready_len(), 3);
+
+        queue.close();
+        let report = queue.publish_batch(&envelopes).await;
+        assert_eq!(report.failed.len(), 3);
+        assert_eq!(report.first_failure(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_publish_batch_keeps_order() {
+        let mut queue = memory::InMemoryQueue::new();
+        let envelopes: Vec<Envelope> = (0..50)
+            .map(|amount| {
+                let deposit = Deposit {
+                    sender: Default::default(),
+                    amount,
+                    origin: None,
+                };
+                Envelope::deposit(&deposit, "test").unwrap()
+            })
+            .collect();
+        let report = queue.publish_batch(&envelopes).await;
+        assert!(report.is_success(), "{:?}", report.failed);
+        assert_eq!(report.confirmed(), envelopes.len());
+
+        let mut consumer = queue.consumer().await.unwrap();
+        for expected in &envelopes {
+            let delivery = consumer.next_delivery().await.unwrap().unwrap();
+            assert_eq!(&Envelope::from_bytes(delivery.data()).unwrap(), expected);
+            delivery.ack().await.unwrap();
+        }
+        assert_eq!(queue.ready_len(), 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a RabbitMQ broker at AMQP_ADDR"]
+    async fn test_publish_batch() {
+        let mut con =
+            get_queue_connection(&test_config(), QueueTopology::throwaway("test_relayer"))
+                .await
+                .unwrap();
+        let envelopes: Vec<Envelope> = (0..50)
+            .map(|amount| {
+                let deposit = Deposit {
+                    sender: Default::default(),
+                    amount,
+                    origin: None,
+                };
+                Envelope::deposit(&deposit, "test").unwrap()
+            })
+            .collect();
+        let report = con.publish_batch(&envelopes).await;
+        assert!(report.is_success(), "{:?}", report.failed);
+
+        let mut consumer = con.consumer().await.unwrap();
+        for expected in &envelopes {
+            let delivery = consumer.next_delivery().await.unwrap().unwrap();
+            assert_eq!(&Envelope::from_bytes(delivery.data()).unwrap(), expected);
+            delivery.ack().await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_envelope_properties() {
+        let deposit = Deposit {
+            sender: "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
+                .parse()
+                .unwrap(),
+            amount: 42,
+            origin: None,
+        };
+        let envelope = Envelope::deposit(&deposit, "test").unwrap();
+        let props = envelope_properties(&envelope, QueueOptions::default().properties());
+        assert_eq!(
+            props.message_id().as_ref().map(|id| id.as_str()),
+            Some(envelope.message_id.as_str())
+        );
+        assert_eq!(
+            props.content_type().as_ref().map(|c| c.as_str()),
+            Some(CONTENT_TYPE)
+        );
+        assert_eq!(props.kind().as_ref().map(|k| k.as_str()), Some("deposit"));
+        assert_eq!(props.app_id().as_ref().map(|a| a.as_str()), Some("test"));
+        assert_eq!(props.delivery_mode(), &Some(2));
+        let headers = props.headers().as_ref().unwrap();
+        assert_eq!(
+            headers.inner().get(VERSION_HEADER),
+            Some(&AMQPValue::ShortUInt(envelope.version))
+        );
+        // Without an origin the deposit has no id to carry.
+        assert_eq!(headers.inner().get(DEPOSIT_ID_HEADER), None);
+
+        let deposit = Deposit {
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(1),
+                log_index: 3,
+                block_number: 7,
+            }),
+            ..deposit
+        };
+        let envelope = Envelope::deposit(&deposit, "test").unwrap();
+        let props = envelope_properties(&envelope, QueueOptions::default().properties());
+        let headers = props.headers().as_ref().unwrap();
+        assert_eq!(
+            headers.inner().get(DEPOSIT_ID_HEADER),
+            Some(&AMQPValue::LongString(deposit.id().unwrap().into()))
+        );
+    }
+
+    #[test]
+    fn test_reconnect_policy_delay() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+        assert_eq!(policy.delay(10), Duration::from_secs(30));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_topology_default_exchange_routes_to_queue() {
+        let topology = QueueTopology::default();
+        assert_eq!(topology.publish_routing_key(), "relayer");
+        assert_eq!(topology.consumer_tag, "my_consumer");
+    }
+
+    #[test]
+    fn test_topology_named_exchange() {
+        let topology = QueueTopology {
+            exchange: String::from("deposits"),
+            exchange_kind: parse_exchange_kind("topic"),
+            routing_keys: vec![String::from("eth.sepolia"), String::from("eth.*")],
+            ..QueueTopology::default()
+        };
+        assert_eq!(topology.exchange_kind, ExchangeKind::Topic);
+        assert_eq!(topology.publish_routing_key(), "eth.sepolia");
+    }
+
+    #[test]
+    fn test_topology_throwaway_is_unique() {
+        let a = QueueTopology::throwaway("test_relayer");
+        let b = QueueTopology::throwaway("test_relayer");
+        assert_ne!(a.queue_name, b.queue_name);
+        assert!(a.auto_delete);
+    }
+
+    #[test]
+    fn test_topology_vhost() {
+        let topology = QueueTopology {
+            vhost: Some(String::from("bridge")),
+            ..QueueTopology::default()
+        };
+        let uri = topology.amqp_uri("amqp://127.0.0.1:5672/%2f").unwrap();
+        assert_eq!(uri.vhost, "bridge");
+        let uri = QueueTopology::default()
+            .amqp_uri("amqp://127.0.0.1:5672/%2f")
+            .unwrap();
+        assert_eq!(uri.vhost, "/");
+    }
+}
@@ -0,0 +1,1079 @@
+use crate::envelope::Envelope;
+use crate::errors::RelayerError;
+use crate::metrics::{DEAD_LETTERED, Metrics};
+use crate::queue::{
+    BatchReport, DeadLetter, DeadLetterQueue, QueueConsumer, QueueDelivery, QueueTrait,
+};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+const SEGMENTS_DIR: &str = "segments";
+const SEGMENT_EXT: &str = "log";
+const OFFSET_FILE: &str = "consumer.offset";
+const ACK_LOG: &str = "acks.log";
+const DEAD_LETTER_LOG: &str = "dead_letters.log";
+const LOCK_FILE: &str = "lock";
+/// len (u32) + crc32 (u32) + offset (u64)
+const HEADER_LEN: usize = 16;
+pub const DEFAULT_MAX_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_DELIVERIES: u32 = 5;
+
+/// When appended records and acks are flushed to disk.
+///
+/// Every write reaches the OS before `publish` or `ack` returns, so a killed
+/// process never loses data. The policy only matters for power loss or a
+/// kernel crash.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    Always,
+    EveryN(u32),
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = RelayerError;
+
+    /// `always`, `every:N` or `never`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match s.strip_prefix("every:").map(str::parse::<u32>) {
+                Some(Ok(n)) if n > 0 => Ok(FsyncPolicy::EveryN(n)),
+                _ => Err(RelayerError::Other(format!(
+                    "Unknown fsync policy: {s}, expected always, every:N or never"
+                ))),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileQueueOptions {
+    pub fsync: FsyncPolicy,
+    pub max_segment_bytes: u64,
+    /// Messages delivered `max_deliveries` times without an ack are
+    /// dead-lettered instead of being requeued again.
+    pub max_deliveries: u32,
+}
+
+impl Default for FileQueueOptions {
+    fn default() -> Self {
+        FileQueueOptions {
+            fsync: FsyncPolicy::Always,
+            max_segment_bytes: DEFAULT_MAX_SEGMENT_BYTES,
+            max_deliveries: DEFAULT_MAX_DELIVERIES,
+        }
+    }
+}
+
+struct Segment {
+    path: PathBuf,
+    len: u64,
+}
+
+struct State {
+    dir: PathBuf,
+    options: FileQueueOptions,
+    segments: BTreeMap<u64, Segment>,
+    active: File,
+    index: BTreeMap<u64, (u64, u64)>,
+    next_offset: u64,
+    /// Every offset below this one has been acked.
+    committed: u64,
+    /// Acked offsets at or above `committed`.
+    acked: BTreeSet<u64>,
+    ack_log: File,
+    dead_letters: File,
+    next_read: u64,
+    in_flight: HashSet<u64>,
+    redeliver: VecDeque<u64>,
+    /// Deliveries of each unsettled offset since the queue was opened.
+    deliveries: HashMap<u64, u32>,
+    unsynced: u32,
+    closed: bool,
+    /// Holds the exclusive lock on `dir` while the queue is open.
+    _lock: File,
+}
+
+/// Durable queue stored as an append-only segment log in a local directory.
+///
+/// Layout of `dir`:
+/// - `segments/<base offset>.log`: records of `len | crc32 | offset | payload`
+/// - `consumer.offset`: every offset below this value has been acked
+/// - `acks.log`: offsets acked out of order above `consumer.offset`
+/// - `dead_letters.log`: rejected messages, in the segment record format
+/// - `lock`: locked exclusively while a process has the queue open
+///
+/// A torn record at the tail of the last segment or the dead-letter log is
+/// truncated on open. Segments are deleted once every message in them has
+/// been acked. A message counts as settled once its dead letter is written,
+/// so a crash before its ack is recorded does not dead-letter it twice.
+///
+/// A message requeued after `max_deliveries` is dead-lettered instead.
+/// Delivery counts are kept in memory, so they start over when the queue
+/// is opened again.
+#[derive(Clone)]
+pub struct FileQueue {
+    state: Arc<Mutex<State>>,
+    notify: Arc<Notify>,
+    metrics: Metrics,
+}
+
+fn segment_path(dir: &Path, base: u64) -> PathBuf {
+    dir.join(SEGMENTS_DIR)
+        .join(format!("{base:020}.{SEGMENT_EXT}"))
+}
+
+fn encode_record(offset: u64, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(&offset.to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Reads one record, returning `None` on a clean end of file or a torn or
+/// corrupt record.
+fn read_record(reader: &mut impl Read) -> Result<Option<(u64, Vec<u8>)>, RelayerError> {
+    let mut header = [0u8; HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let offset = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let mut payload = vec![0u8; len];
+    match reader.read_exact(&mut payload) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Ok(None);
+    }
+    Ok(Some((offset, payload)))
+}
+
+/// Reads every complete record of `path`, truncating a torn or corrupt
+/// tail so later appends stay readable.
+fn read_log(path: &Path) -> Result<Vec<(u64, Vec<u8>)>, RelayerError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut records = Vec::new();
+    let mut pos = 0u64;
+    while let Some((offset, payload)) = read_record(&mut reader)? {
+        pos += (HEADER_LEN + payload.len()) as u64;
+        records.push((offset, payload));
+    }
+    if pos < file_len {
+        warn!(
+            "Truncating torn tail of {} from {file_len} to {pos} bytes",
+            path.display()
+        );
+        OpenOptions::new().write(true).open(path)?.set_len(pos)?;
+    }
+    Ok(records)
+}
+
+/// Locks `dir` for this process, failing if another one has it open.
+fn lock_dir(dir: &Path) -> Result<File, RelayerError> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => Err(RelayerError::Other(format!(
+            "File queue {} is open in another process",
+            dir.display()
+        ))),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, RelayerError> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// Replaces `path` with `contents` so a crash leaves either the old or the
+/// new file, never a partial one.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), RelayerError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+impl FileQueue {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, RelayerError> {
+        Self::open_with(dir, FileQueueOptions::default())
+    }
+
+    pub fn open_with(
+        dir: impl AsRef<Path>,
+        options: FileQueueOptions,
+    ) -> Result<Self, RelayerError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join(SEGMENTS_DIR))?;
+        let lock = lock_dir(&dir)?;
+
+        let mut bases = Vec::new();
+        for entry in fs::read_dir(dir.join(SEGMENTS_DIR))? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+                continue;
+            }
+            if let Some(base) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                bases.push(base);
+            }
+        }
+        bases.sort_unstable();
+
+        let mut segments = BTreeMap::new();
+        let mut index = BTreeMap::new();
+        let mut next_offset = bases.first().copied().unwrap_or(0);
+        for (i, base) in bases.iter().enumerate() {
+            let path = segment_path(&dir, *base);
+            let mut reader = BufReader::new(File::open(&path)?);
+            let mut pos = 0u64;
+            while let Some((offset, payload)) = read_record(&mut reader)? {
+                index.insert(offset, (*base, pos));
+                pos += (HEADER_LEN + payload.len()) as u64;
+                next_offset = offset + 1;
+            }
+            let file_len = fs::metadata(&path)?.len();
+            if pos < file_len {
+                if i + 1 != bases.len() {
+                    return Err(RelayerError::Other(format!(
+                        "Corrupt record in sealed segment {}",
+                        path.display()
+                    )));
+                }
+                warn!(
+                    "Truncating torn tail of {} from {file_len} to {pos} bytes",
+                    path.display()
+                );
+                OpenOptions::new().write(true).open(&path)?.set_len(pos)?;
+            }
+            segments.insert(*base, Segment { path, len: pos });
+        }
+
+        let active_base = match segments.keys().next_back() {
+            Some(base) => *base,
+            None => {
+                let path = segment_path(&dir, next_offset);
+                File::create(&path)?.sync_all()?;
+                segments.insert(next_offset, Segment { path, len: 0 });
+                next_offset
+            }
+        };
+        let active = open_append(&segments[&active_base].path)?;
+
+        let mut committed = match fs::read_to_string(dir.join(OFFSET_FILE)) {
+            Ok(s) => s
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| RelayerError::Other(format!("Invalid {OFFSET_FILE}: {e}")))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        committed = committed.max(*segments.keys().next().unwrap_or(&0));
+
+        let mut acked = BTreeSet::new();
+        if let Ok(bytes) = fs::read(dir.join(ACK_LOG)) {
+            // A torn trailing entry is ignored: its ack never returned.
+            for chunk in bytes.chunks_exact(8) {
+                let offset = u64::from_le_bytes(chunk.try_into().unwrap());
+                if offset >= committed {
+                    acked.insert(offset);
+                }
+            }
+        }
+        for (offset, _) in read_log(&dir.join(DEAD_LETTER_LOG))? {
+            if offset >= committed {
+                acked.insert(offset);
+            }
+        }
+
+        let mut state = State {
+            ack_log: open_append(&dir.join(ACK_LOG))?,
+            dead_letters: open_append(&dir.join(DEAD_LETTER_LOG))?,
+            dir,
+            options,
+            segments,
+            active,
+            index,
+            next_offset,
+            committed,
+            acked,
+            next_read: committed,
+            in_flight: HashSet::new(),
+            redeliver: VecDeque::new(),
+            deliveries: HashMap::new(),
+            unsynced: 0,
+            closed: false,
+            _lock: lock,
+        };
+        state.advance_committed()?;
+        info!(
+            "Opened file queue at {} ({} pending)",
+            state.dir.display(),
+            state.next_offset - state.committed - state.acked.len() as u64
+        );
+
+        Ok(FileQueue {
+            state: Arc::new(Mutex::new(state)),
+            notify: Arc::new(Notify::new()),
+            metrics: Metrics::new(),
+        })
+    }
+
+    /// Counts the messages dead-lettered after too many deliveries.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Number of messages that have not been acked or dead-lettered yet.
+    pub fn pending_len(&self) -> u64 {
+        let state = self.lock();
+        state.next_offset - state.committed - state.acked.len() as u64
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.lock().segments.len()
+    }
+
+    /// Ends every consumer stream once the pending messages are drained.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.notify.notify_waiters();
+    }
+
+    /// Reads the dead-letter log back.
+    pub fn dead_letters(&self) -> Result<Vec<Vec<u8>>, RelayerError> {
+        let path = self.lock().dir.join(DEAD_LETTER_LOG);
+        Ok(read_log(&path)?
+            .into_iter()
+            .map(|(_, payload)| payload)
+            .collect())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("file queue lock poisoned")
+    }
+
+    /// Runs `f` on the state from tokio's blocking pool, so file writes
+    /// and syncs stay off the async workers.
+    async fn with_state<T, F>(&self, f: F) -> Result<T, RelayerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut State) -> Result<T, RelayerError> + Send + 'static,
+    {
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut state = state.lock().expect("file queue lock poisoned");
+            f(&mut state)
+        })
+        .await
+        .map_err(|e| RelayerError::Other(e.to_string()))?
+    }
+}
+
+/// What `nack` did with a delivery.
+enum Nacked {
+    Requeued,
+    /// Dead-lettered after `max_deliveries`.
+    Exhausted,
+    Rejected,
+}
+
+impl State {
+    /// Syncs the active segment and the ack log if `writes` more writes
+    /// make a sync due.
+    fn maybe_sync(&mut self, writes: u32) -> Result<(), RelayerError> {
+        let due = match self.options.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryN(n) => {
+                self.unsynced += writes;
+                self.unsynced >= n
+            }
+            FsyncPolicy::Never => false,
+        };
+        if due {
+            self.active.sync_data()?;
+            self.ack_log.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, payload: &[u8]) -> Result<u64, RelayerError> {
+        let offset = self.write_record(payload)?;
+        self.maybe_sync(1)?;
+        Ok(offset)
+    }
+
+    /// Appends `payloads` in order and syncs once for all of them. Returns
+    /// how many were appended and the error that stopped the rest, if any.
+    /// Fails outright if the final sync does.
+    fn append_batch(
+        &mut self,
+        payloads: &[Vec<u8>],
+    ) -> Result<(usize, Option<RelayerError>), RelayerError> {
+        let mut stopped = None;
+        let mut written = 0;
+        for payload in payloads {
+            if let Err(e) = self.write_record(payload) {
+                stopped = Some(e);
+                break;
+            }
+            written += 1;
+        }
+        if written > 0 {
+            self.maybe_sync(written as u32)?;
+        }
+        Ok((written, stopped))
+    }
+
+    /// Writes one record to the active segment, rolling to a new segment
+    /// first if it is full, without syncing it.
+    fn write_record(&mut self, payload: &[u8]) -> Result<u64, RelayerError> {
+        let (active_base, active_len) = self
+            .segments
+            .iter()
+            .next_back()
+            .map(|(base, seg)| (*base, seg.len))
+            .expect("file queue has an active segment");
+        let mut base = active_base;
+        if active_len >= self.options.max_segment_bytes {
+            self.active.sync_all()?;
+            base = self.next_offset;
+            let path = segment_path(&self.dir, base);
+            self.active = open_append(&path)?;
+            self.segments.insert(base, Segment { path, len: 0 });
+            debug!("Rolled file queue to segment {base}");
+        }
+
+        let offset = self.next_offset;
+        let record = encode_record(offset, payload);
+        self.active.write_all(&record)?;
+
+        let segment = self.segments.get_mut(&base).expect("active segment");
+        self.index.insert(offset, (base, segment.len));
+        segment.len += record.len() as u64;
+        self.next_offset += 1;
+        Ok(offset)
+    }
+
+    fn read(&self, offset: u64) -> Result<Vec<u8>, RelayerError> {
+        let (base, pos) = self
+            .index
+            .get(&offset)
+            .ok_or_else(|| RelayerError::Other(format!("Offset {offset} not in file queue")))?;
+        let mut file = File::open(&self.segments[base].path)?;
+        file.seek(SeekFrom::Start(*pos))?;
+        read_record(&mut file)?
+            .map(|(_, payload)| payload)
+            .ok_or_else(|| RelayerError::Other(format!("Corrupt record at offset {offset}")))
+    }
+
+    /// Records `offset` as settled. The ack log is written first, so once
+    /// this returns the message is never delivered again.
+    fn settle(&mut self, offset: u64) -> Result<(), RelayerError> {
+        if !self.in_flight.remove(&offset) {
+            return Err(RelayerError::Other(format!(
+                "Offset {offset} is not in flight"
+            )));
+        }
+        self.ack_log.write_all(&offset.to_le_bytes())?;
+        self.maybe_sync(1)?;
+        self.acked.insert(offset);
+        self.deliveries.remove(&offset);
+        self.advance_committed()
+    }
+
+    /// Writes the dead letter, then settles `offset`.
+    fn dead_letter(&mut self, offset: u64, data: &[u8]) -> Result<(), RelayerError> {
+        let record = encode_record(offset, data);
+        self.dead_letters.write_all(&record)?;
+        self.dead_letters.sync_data()?;
+        self.settle(offset)
+    }
+
+    fn advance_committed(&mut self) -> Result<(), RelayerError> {
+        let before = self.committed;
+        while self.acked.remove(&self.committed) {
+            self.committed += 1;
+        }
+        if self.committed == before {
+            return Ok(());
+        }
+        write_atomic(
+            &self.dir.join(OFFSET_FILE),
+            self.committed.to_string().as_bytes(),
+        )?;
+        let remaining: Vec<u8> = self.acked.iter().flat_map(|o| o.to_le_bytes()).collect();
+        write_atomic(&self.dir.join(ACK_LOG), &remaining)?;
+        self.ack_log = open_append(&self.dir.join(ACK_LOG))?;
+        self.next_read = self.next_read.max(self.committed);
+        self.compact()
+    }
+
+    /// Deletes sealed segments whose messages have all been acked.
+    fn compact(&mut self) -> Result<(), RelayerError> {
+        let bases: Vec<u64> = self.segments.keys().copied().collect();
+        for pair in bases.windows(2) {
+            let (base, next_base) = (pair[0], pair[1]);
+            if next_base > self.committed {
+                break;
+            }
+            let segment = self.segments.remove(&base).expect("segment exists");
+            fs::remove_file(&segment.path)?;
+            self.index = self.index.split_off(&next_base);
+            debug!("Compacted file queue segment {base}");
+        }
+        Ok(())
+    }
+
+    fn next_deliverable(&mut self) -> Option<u64> {
+        if let Some(offset) = self.redeliver.pop_front() {
+            return Some(offset);
+        }
+        while self.next_read < self.next_offset {
+            let offset = self.next_read;
+            self.next_read += 1;
+            if !self.acked.contains(&offset) && !self.in_flight.contains(&offset) {
+                return Some(offset);
+            }
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for FileQueue {
+    async fn dead_letters(&mut self, limit: usize) -> Result<Vec<DeadLetter>, RelayerError> {
+        Ok(FileQueue::dead_letters(self)?
+            .into_iter()
+            .take(limit)
+            .map(DeadLetter::new)
+            .collect())
+    }
+
+    /// The message is appended before the dead-letter log is rewritten, so
+    /// a crash in between leaves it in both rather than in neither.
+    async fn replay(&mut self, id: &str) -> Result<bool, RelayerError> {
+        let target = id.to_string();
+        let replayed = self
+            .with_state(move |state| {
+                let path = state.dir.join(DEAD_LETTER_LOG);
+                let mut records = read_log(&path)?;
+                let Some(index) = records
+                    .iter()
+                    .position(|(_, payload)| DeadLetter::new(payload.clone()).id == target)
+                else {
+                    return Ok(None);
+                };
+                let (_, payload) = records.remove(index);
+                let offset = state.append(&payload)?;
+                let remaining: Vec<u8> = records
+                    .iter()
+                    .flat_map(|(offset, payload)| encode_record(*offset, payload))
+                    .collect();
+                write_atomic(&path, &remaining)?;
+                state.dead_letters = open_append(&path)?;
+                Ok(Some(offset))
+            })
+            .await?;
+        let Some(offset) = replayed else {
+            return Ok(false);
+        };
+        debug!("Replayed dead letter {id} at file queue offset {offset}");
+        self.notify.notify_one();
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl QueueTrait for FileQueue {
+    type Consumer = FileQueueConsumer;
+
+    async fn publish(&mut self, dep: &[u8]) -> Result<(), RelayerError> {
+        let payload = dep.to_vec();
+        let offset = self
+            .with_state(move |state| {
+                if state.closed {
+                    return Err(RelayerError::Other(String::from("Queue is closed")));
+                }
+                state.append(&payload)
+            })
+            .await?;
+        debug!("Appended file queue offset {offset}");
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Appends the whole batch under one lock and syncs once. Everything
+    /// appended before a failed write is reported confirmed; if the final
+    /// sync fails, nothing is.
+    async fn publish_batch(&mut self, envelopes: &[Envelope]) -> BatchReport {
+        let mut report = BatchReport::new(envelopes.len());
+        let mut payloads = Vec::with_capacity(envelopes.len());
+        for (index, envelope) in envelopes.iter().enumerate() {
+            match envelope.to_bytes() {
+                Ok(payload) => payloads.push(payload),
+                Err(e) => {
+                    report.fail(index, e);
+                    report.fail_unsent(index + 1);
+                    break;
+                }
+            }
+        }
+        let encoded = payloads.len();
+        let appended = self
+            .with_state(move |state| {
+                if state.closed {
+                    return Err(RelayerError::Other(String::from("Queue is closed")));
+                }
+                state.append_batch(&payloads)
+            })
+            .await;
+        match appended {
+            Ok((written, stopped)) => {
+                if let Some(e) = stopped {
+                    report.fail(written, e);
+                    report.fail_unsent(written + 1);
+                }
+                if written > 0 {
+                    debug!("Appended {written} messages to the file queue");
+                    self.notify.notify_waiters();
+                }
+            }
+            Err(e) => {
+                let reason = e.to_string();
+                for index in 0..encoded {
+                    report.fail(index, RelayerError::Other(reason.clone()));
+                }
+            }
+        }
+        report
+    }
+
+    async fn consumer(&mut self) -> Result<FileQueueConsumer, RelayerError> {
+        Ok(FileQueueConsumer {
+            queue: self.clone(),
+        })
+    }
+}
+
+pub struct FileQueueConsumer {
+    queue: FileQueue,
+}
+
+#[async_trait]
+impl QueueConsumer for FileQueueConsumer {
+    type Delivery = FileQueueDelivery;
+
+    async fn next_delivery(&mut self) -> Option<Result<FileQueueDelivery, RelayerError>> {
+        loop {
+            let notified = self.queue.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut state = self.queue.lock();
+                if let Some(offset) = state.next_deliverable() {
+                    let data = match state.read(offset) {
+                        Ok(data) => data,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    state.in_flight.insert(offset);
+                    let deliveries = state.deliveries.entry(offset).or_default();
+                    *deliveries += 1;
+                    return Some(Ok(FileQueueDelivery {
+                        offset,
+                        data,
+                        deliveries: *deliveries,
+                        queue: self.queue.clone(),
+                    }));
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+pub struct FileQueueDelivery {
+    offset: u64,
+    data: Vec<u8>,
+    /// Including this one.
+    deliveries: u32,
+    queue: FileQueue,
+}
+
+impl FileQueueDelivery {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+#[async_trait]
+impl QueueDelivery for FileQueueDelivery {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn redelivered(&self) -> bool {
+        self.deliveries > 1
+    }
+
+    async fn ack(self) -> Result<(), RelayerError> {
+        let offset = self.offset;
+        self.queue
+            .with_state(move |state| state.settle(offset))
+            .await
+    }
+
+    async fn nack(mut self, requeue: bool) -> Result<(), RelayerError> {
+        let (offset, deliveries) = (self.offset, self.deliveries);
+        let data = std::mem::take(&mut self.data);
+        let nacked = self
+            .queue
+            .with_state(move |state| {
+                if requeue && deliveries < state.options.max_deliveries {
+                    if !state.in_flight.remove(&offset) {
+                        return Err(RelayerError::Other(format!(
+                            "Offset {offset} is not in flight"
+                        )));
+                    }
+                    state.redeliver.push_back(offset);
+                    return Ok(Nacked::Requeued);
+                }
+                if requeue {
+                    warn!(
+                        "File queue offset {} exceeded {} deliveries, dead-lettering",
+                        offset, state.options.max_deliveries
+                    );
+                    state.dead_letter(offset, &data)?;
+                    return Ok(Nacked::Exhausted);
+                }
+                warn!("Dead-lettering file queue offset {}", offset);
+                state.dead_letter(offset, &data)?;
+                Ok(Nacked::Rejected)
+            })
+            .await?;
+        match nacked {
+            Nacked::Requeued => self.queue.notify.notify_one(),
+            Nacked::Exhausted => self.queue.metrics.inc(&DEAD_LETTERED, &[]),
+            Nacked::Rejected => {}
+        }
+        Ok(())
+    }
+}
+
+/// A delivery dropped without being settled is delivered again, as
+/// `InMemoryDelivery` is, instead of staying in flight until a restart.
+impl Drop for FileQueueDelivery {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.queue.state.lock() else {
+            return;
+        };
+        if state.in_flight.remove(&self.offset) {
+            debug!("Requeueing unsettled file queue offset {}", self.offset);
+            state.redeliver.push_back(self.offset);
+            drop(state);
+            self.queue.notify.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscriber::Deposit;
+    use tempfile::TempDir;
+
+    fn small_segments() -> FileQueueOptions {
+        FileQueueOptions {
+            fsync: FsyncPolicy::Never,
+            max_segment_bytes: 64,
+            ..FileQueueOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_fsync_policy() {
+        assert_eq!(
+            "always".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Always
+        );
+        assert_eq!(
+            "every:100".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::EveryN(100)
+        );
+        assert_eq!("never".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Never);
+        for invalid in ["every:0", "every:", "sometimes"] {
+            assert!(invalid.parse::<FsyncPolicy>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_consume_and_ack() {
+        let dir = TempDir::new().unwrap();
+        let mut queue = FileQueue::open(dir.path()).unwrap();
+        queue.publish(b"first").await.unwrap();
+        queue.publish(b"second").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"first");
+        delivery.ack().await.unwrap();
+        assert_eq!(queue.pending_len(), 1);
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"second");
+        delivery.ack().await.unwrap();
+        assert_eq!(queue.pending_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_publish_batch() {
+        let dir = TempDir::new().unwrap();
+        let mut queue = FileQueue::open_with(dir.path(), small_segments()).unwrap();
+        let envelopes: Vec<Envelope> = (0..3)
+            .map(|amount| {
+                let deposit = Deposit {
+                    sender: Default::default(),
+                    amount,
+                    origin: None,
+                };
+                Envelope::deposit(&deposit, "test").unwrap()
+            })
+            .collect();
+        let report = queue.publish_batch(&envelopes).await;
+        assert!(report.is_success(), "{:?}", report.failed);
+        assert_eq!(queue.pending_len(), 3);
+
+        let mut consumer = queue.consumer().await.unwrap();
+        for expected in &envelopes {
+            let delivery = consumer.next_delivery().await.unwrap().unwrap();
+            assert_eq!(&Envelope::from_bytes(delivery.data()).unwrap(), expected);
+            delivery.ack().await.unwrap();
+        }
+
+        queue.close();
+        let report = queue.publish_batch(&envelopes).await;
+        assert_eq!(report.failed.len(), 3);
+        assert_eq!(queue.pending_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reopen_redelivers_only_unacked() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut queue = FileQueue::open(dir.path()).unwrap();
+            for msg in [b"a", b"b", b"c"] {
+                queue.publish(msg).await.unwrap();
+            }
+            let mut consumer = queue.consumer().await.unwrap();
+            let a = consumer.next_delivery().await.unwrap().unwrap();
+            let b = consumer.next_delivery().await.unwrap().unwrap();
+            let c = consumer.next_delivery().await.unwrap().unwrap();
+            // Out of order: only "a" and "c" are acked before the "crash".
+            c.ack().await.unwrap();
+            a.ack().await.unwrap();
+            drop(b);
+        }
+
+        let mut queue = FileQueue::open(dir.path()).unwrap();
+        assert_eq!(queue.pending_len(), 1);
+        queue.close();
+        let mut consumer = queue.consumer().await.unwrap();
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"b");
+        delivery.ack().await.unwrap();
+        assert!(consumer.next_delivery().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_torn_tail_is_truncated() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut queue = FileQueue::open(dir.path()).unwrap();
+            queue.publish(b"complete").await.unwrap();
+        }
+        let segment = segment_path(dir.path(), 0);
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&encode_record(1, b"torn")[..10]).unwrap();
+
+        let mut queue = FileQueue::open(dir.path()).unwrap();
+        assert_eq!(queue.pending_len(), 1);
+        queue.publish(b"next").await.unwrap();
+        queue.close();
+
+        let mut consumer = queue.consumer().await.unwrap();
+        let first = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(first.data(), b"complete");
+        first.ack().await.unwrap();
+        let second = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(second.data(), b"next");
+        assert_eq!(second.offset(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_segments_roll_and_compact() {
+        let dir = TempDir::new().unwrap();
+        let mut queue = FileQueue::open_with(dir.path(), small_segments()).unwrap();
+        for _ in 0..10 {
+            queue.publish(&[7u8; 50]).await.unwrap();
+        }
+        assert_eq!(queue.segment_count(), 10);
+
+        let mut consumer = queue.consumer().await.unwrap();
+        for _ in 0..9 {
+            let delivery = consumer.next_delivery().await.unwrap().unwrap();
+            delivery.ack().await.unwrap();
+        }
+        assert_eq!(queue.segment_count(), 1);
+        assert_eq!(queue.pending_len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_nack_requeue_and_dead_letter() {
+        let dir = TempDir::new().unwrap();
+        let mut queue = FileQueue::open(dir.path()).unwrap();
+        queue.publish(b"retry").await.unwrap();
+        queue.publish(b"poison").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        let retry = consumer.next_delivery().await.unwrap().unwrap();
+        retry.nack(true).await.unwrap();
+        let retry = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(retry.data(), b"retry");
+        assert!(retry.redelivered());
+        retry.ack().await.unwrap();
+
+        let poison = consumer.next_delivery().await.unwrap().unwrap();
+        poison.nack(false).await.unwrap();
+        assert_eq!(queue.pending_len(), 0);
+        assert_eq!(queue.dead_letters().unwrap(), vec![b"poison".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_max_deliveries_dead_letters() {
+        let dir = TempDir::new().unwrap();
+        let metrics = Metrics::new();
+        let options = FileQueueOptions {
+            max_deliveries: 2,
+            ..FileQueueOptions::default()
+        };
+        let mut queue = FileQueue::open_with(dir.path(), options)
+            .unwrap()
+            .with_metrics(metrics.clone());
+        queue.publish(b"poison").await.unwrap();
+        queue.close();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        for _ in 0..2 {
+            let delivery = consumer.next_delivery().await.unwrap().unwrap();
+            delivery.nack(true).await.unwrap();
+        }
+        assert!(consumer.next_delivery().await.is_none());
+        assert_eq!(queue.pending_len(), 0);
+        assert_eq!(queue.dead_letters().unwrap(), vec![b"poison".to_vec()]);
+        assert_eq!(metrics.get(&DEAD_LETTERED, &[]), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_delivery_is_requeued() {
+        let dir = TempDir::new().unwrap();
+        let mut queue = FileQueue::open(dir.path()).unwrap();
+        queue.publish(b"dep").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        drop(consumer.next_delivery().await.unwrap().unwrap());
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"dep");
+        assert!(delivery.redelivered());
+        delivery.ack().await.unwrap();
+        assert_eq!(queue.pending_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_directory_is_locked_while_open() {
+        let dir = TempDir::new().unwrap();
+        let queue = FileQueue::open(dir.path()).unwrap();
+        let err = FileQueue::open(dir.path()).err().unwrap().to_string();
+        assert!(err.contains("another process"), "{err}");
+        drop(queue);
+        FileQueue::open(dir.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_before_ack_is_settled() {
+        let dir = TempDir::new().unwrap();
+        {
+            let mut queue = FileQueue::open(dir.path()).unwrap();
+            queue.publish(b"poison").await.unwrap();
+            queue.publish(b"next").await.unwrap();
+        }
+        // A crash after the dead letter was written but before the ack.
+        let mut log = open_append(&dir.path().join(DEAD_LETTER_LOG)).unwrap();
+        log.write_all(&encode_record(0, b"poison")).unwrap();
+        log.write_all(&encode_record(9, b"torn")[..10]).unwrap();
+
+        let mut queue = FileQueue::open(dir.path()).unwrap();
+        assert_eq!(queue.pending_len(), 1);
+        assert_eq!(queue.dead_letters().unwrap(), vec![b"poison".to_vec()]);
+        queue.close();
+        let mut consumer = queue.consumer().await.unwrap();
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"next");
+    }
+
+    #[tokio::test]
+    async fn test_replay_dead_letter() {
+        let dir = TempDir::new().unwrap();
+        let mut queue = FileQueue::open(dir.path()).unwrap();
+        queue.publish(b"bad").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        delivery.nack(false).await.unwrap();
+
+        let letters = DeadLetterQueue::dead_letters(&mut queue, 10).await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert!(!queue.replay("missing").await.unwrap());
+        assert!(queue.replay(&letters[0].id).await.unwrap());
+        assert!(queue.dead_letters().unwrap().is_empty());
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"bad");
+        assert_eq!(delivery.offset(), 1);
+    }
+}
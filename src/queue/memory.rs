@@ -0,0 +1,399 @@
+use crate::errors::RelayerError;
+use crate::metrics::{DEAD_LETTERED, Metrics};
+use crate::queue::{DeadLetter, DeadLetterQueue, QueueConsumer, QueueDelivery, QueueTrait};
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+const DEFAULT_MAX_DELIVERIES: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub data: Vec<u8>,
+    pub deliveries: u32,
+}
+
+#[derive(Default)]
+struct State {
+    ready: VecDeque<StoredMessage>,
+    unacked: HashMap<u64, StoredMessage>,
+    dead_letters: Vec<StoredMessage>,
+    next_tag: u64,
+    closed: bool,
+}
+
+/// Process-local queue with the same delivery semantics as the AMQP backend:
+/// messages stay unacked until settled, `nack(true)` redelivers them and
+/// `nack(false)` (or too many redeliveries) moves them to the dead letters.
+///
+/// Clones share the same underlying queue, so one clone can be handed to a
+/// `Subscriber` and another to an `Includer`.
+#[derive(Clone)]
+pub struct InMemoryQueue {
+    state: Arc<Mutex<State>>,
+    notify: Arc<Notify>,
+    max_deliveries: u32,
+    metrics: Metrics,
+}
+
+impl Default for InMemoryQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryQueue {
+    pub fn new() -> Self {
+        Self::with_max_deliveries(DEFAULT_MAX_DELIVERIES)
+    }
+
+    /// Messages delivered `max_deliveries` times without an ack are
+    /// dead-lettered instead of being requeued again.
+    pub fn with_max_deliveries(max_deliveries: u32) -> Self {
+        InMemoryQueue {
+            state: Arc::new(Mutex::new(State::default())),
+            notify: Arc::new(Notify::new()),
+            max_deliveries,
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// Counts the messages dead-lettered after too many deliveries.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn ready_len(&self) -> usize {
+        self.lock().ready.len()
+    }
+
+    pub fn unacked_len(&self) -> usize {
+        self.lock().unacked.len()
+    }
+
+    pub fn dead_letters(&self) -> Vec<StoredMessage> {
+        self.lock().dead_letters.clone()
+    }
+
+    /// Ends every consumer stream once the ready messages are drained.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.notify.notify_waiters();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("in-memory queue lock poisoned")
+    }
+
+    fn settle(&self, tag: u64, outcome: Settle) -> Result<(), RelayerError> {
+        let mut state = self.lock();
+        let message = state
+            .unacked
+            .remove(&tag)
+            .ok_or_else(|| RelayerError::Other(format!("Unknown delivery tag {tag}")))?;
+        match outcome {
+            Settle::Ack => {}
+            Settle::Requeue if message.deliveries < self.max_deliveries => {
+                state.ready.push_front(message);
+                drop(state);
+                self.notify.notify_one();
+            }
+            Settle::Requeue => {
+                warn!(
+                    "Message exceeded {} deliveries, dead-lettering",
+                    self.max_deliveries
+                );
+                state.dead_letters.push(message);
+                self.metrics.inc(&DEAD_LETTERED, &[]);
+            }
+            Settle::Reject => state.dead_letters.push(message),
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for InMemoryQueue {
+    async fn dead_letters(&mut self, limit: usize) -> Result<Vec<DeadLetter>, RelayerError> {
+        Ok(self
+            .lock()
+            .dead_letters
+            .iter()
+            .take(limit)
+            .map(|message| DeadLetter::new(message.data.clone()))
+            .collect())
+    }
+
+    /// The replayed message starts over with no deliveries.
+    async fn replay(&mut self, id: &str) -> Result<bool, RelayerError> {
+        let mut state = self.lock();
+        let Some(index) = state
+            .dead_letters
+            .iter()
+            .position(|message| DeadLetter::new(message.data.clone()).id == id)
+        else {
+            return Ok(false);
+        };
+        let mut message = state.dead_letters.remove(index);
+        message.deliveries = 0;
+        state.ready.push_back(message);
+        drop(state);
+        self.notify.notify_one();
+        Ok(true)
+    }
+}
+
+enum Settle {
+    Ack,
+    Requeue,
+    Reject,
+}
+
+#[async_trait]
+impl QueueTrait for InMemoryQueue {
+    type Consumer = InMemoryConsumer;
+
+    async fn publish(&mut self, dep: &[u8]) -> Result<(), RelayerError> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(RelayerError::Other(String::from("Queue is closed")));
+        }
+        state.ready.push_back(StoredMessage {
+            data: dep.to_vec(),
+            deliveries: 0,
+        });
+        drop(state);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    async fn consumer(&mut self) -> Result<InMemoryConsumer, RelayerError> {
+        Ok(InMemoryConsumer {
+            queue: self.clone(),
+        })
+    }
+}
+
+pub struct InMemoryConsumer {
+    queue: InMemoryQueue,
+}
+
+#[async_trait]
+impl QueueConsumer for InMemoryConsumer {
+    type Delivery = InMemoryDelivery;
+
+    async fn next_delivery(&mut self) -> Option<Result<InMemoryDelivery, RelayerError>> {
+        loop {
+            let notified = self.queue.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut state = self.queue.lock();
+                if let Some(mut message) = state.ready.pop_front() {
+                    message.deliveries += 1;
+                    let tag = state.next_tag;
+                    state.next_tag += 1;
+                    let delivery = InMemoryDelivery {
+                        tag,
+                        data: message.data.clone(),
+                        redelivered: message.deliveries > 1,
+                        queue: self.queue.clone(),
+                    };
+                    state.unacked.insert(tag, message);
+                    debug!("Delivering in-memory message {tag}");
+                    return Some(Ok(delivery));
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+pub struct InMemoryDelivery {
+    tag: u64,
+    data: Vec<u8>,
+    redelivered: bool,
+    queue: InMemoryQueue,
+}
+
+#[async_trait]
+impl QueueDelivery for InMemoryDelivery {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn redelivered(&self) -> bool {
+        self.redelivered
+    }
+
+    async fn ack(self) -> Result<(), RelayerError> {
+        self.queue.settle(self.tag, Settle::Ack)
+    }
+
+    async fn nack(self, requeue: bool) -> Result<(), RelayerError> {
+        let outcome = if requeue {
+            Settle::Requeue
+        } else {
+            Settle::Reject
+        };
+        self.queue.settle(self.tag, outcome)
+    }
+}
+
+/// A delivery dropped without being settled goes back to the queue, as the
+/// broker requeues the deliveries of a closed channel.
+impl Drop for InMemoryDelivery {
+    fn drop(&mut self) {
+        let Ok(state) = self.queue.state.lock() else {
+            return;
+        };
+        let unsettled = state.unacked.contains_key(&self.tag);
+        drop(state);
+        if unsettled {
+            debug!("Requeueing unsettled in-memory message {}", self.tag);
+            let _ = self.queue.settle(self.tag, Settle::Requeue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_publish_and_consume() {
+        let mut queue = InMemoryQueue::new();
+        queue.publish(b"first").await.unwrap();
+        queue.publish(b"second").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"first");
+        assert!(!delivery.redelivered());
+        assert_eq!(queue.unacked_len(), 1);
+        delivery.ack().await.unwrap();
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"second");
+        delivery.ack().await.unwrap();
+
+        assert_eq!(queue.ready_len(), 0);
+        assert_eq!(queue.unacked_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_nack_requeue_redelivers() {
+        let mut queue = InMemoryQueue::new();
+        queue.publish(b"dep").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        delivery.nack(true).await.unwrap();
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"dep");
+        assert!(delivery.redelivered());
+        delivery.ack().await.unwrap();
+        assert!(queue.dead_letters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_nack_without_requeue_dead_letters() {
+        let mut queue = InMemoryQueue::new();
+        queue.publish(b"bad").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        delivery.nack(false).await.unwrap();
+
+        let dead = queue.dead_letters();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].data, b"bad");
+        assert_eq!(queue.ready_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_max_deliveries_dead_letters() {
+        let metrics = Metrics::new();
+        let mut queue = InMemoryQueue::with_max_deliveries(2).with_metrics(metrics.clone());
+        queue.publish(b"poison").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        for _ in 0..2 {
+            let delivery = consumer.next_delivery().await.unwrap().unwrap();
+            delivery.nack(true).await.unwrap();
+        }
+
+        let dead = queue.dead_letters();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].deliveries, 2);
+        assert_eq!(queue.ready_len(), 0);
+        assert_eq!(metrics.get(&DEAD_LETTERED, &[]), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_delivery_is_requeued() {
+        let mut queue = InMemoryQueue::new();
+        queue.publish(b"dep").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        drop(consumer.next_delivery().await.unwrap().unwrap());
+        assert_eq!(queue.unacked_len(), 0);
+        assert_eq!(queue.ready_len(), 1);
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert!(delivery.redelivered());
+        delivery.ack().await.unwrap();
+        assert_eq!(queue.ready_len(), 0);
+        assert_eq!(queue.unacked_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_dead_letter() {
+        let mut queue = InMemoryQueue::new();
+        queue.publish(b"bad").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        delivery.nack(false).await.unwrap();
+
+        let letters = DeadLetterQueue::dead_letters(&mut queue, 10).await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].data, b"bad");
+        assert!(!queue.replay("missing").await.unwrap());
+        assert!(queue.replay(&letters[0].id).await.unwrap());
+        assert!(queue.dead_letters().is_empty());
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"bad");
+        assert!(!delivery.redelivered());
+    }
+
+    #[tokio::test]
+    async fn test_consumer_waits_for_publish() {
+        let mut queue = InMemoryQueue::new();
+        let mut consumer = queue.consumer().await.unwrap();
+        let handle = tokio::spawn(async move { consumer.next_delivery().await });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        queue.publish(b"late").await.unwrap();
+
+        let delivery = handle.await.unwrap().unwrap().unwrap();
+        assert_eq!(delivery.data(), b"late");
+    }
+
+    #[tokio::test]
+    async fn test_close_ends_consumer() {
+        let mut queue = InMemoryQueue::new();
+        let mut consumer = queue.consumer().await.unwrap();
+        queue.close();
+        assert!(consumer.next_delivery().await.is_none());
+        assert!(queue.publish(b"late").await.is_err());
+    }
+}
@@ -0,0 +1,460 @@
+use crate::errors::RelayerError;
+use crate::metrics::{DEAD_LETTERED, Metrics};
+use crate::queue::{DeadLetter, DeadLetterQueue, QueueConsumer, QueueDelivery, QueueTrait};
+use async_trait::async_trait;
+use redis::{
+    AsyncCommands, Client, FromRedisValue, Value,
+    aio::MultiplexedConnection,
+    streams::{
+        StreamClaimOptions, StreamClaimReply, StreamId, StreamPendingCountReply, StreamRangeReply,
+        StreamReadOptions, StreamReadReply,
+    },
+};
+use tracing::{debug, warn};
+
+const PAYLOAD_FIELD: &str = "payload";
+const ORIGINAL_ID_FIELD: &str = "original_id";
+const DEFAULT_GROUP: &str = "relayer-includers";
+const DEFAULT_CLAIM_IDLE_MS: usize = 30_000;
+const DEFAULT_MAX_DELIVERIES: usize = 5;
+/// Wait before the first retry of a requeued entry, doubled on every
+/// further delivery up to `claim_idle_ms`.
+const DEFAULT_REQUEUE_DELAY_MS: usize = 1_000;
+const READ_BLOCK_MS: usize = 1_000;
+
+fn redis_err(e: redis::RedisError) -> RelayerError {
+    RelayerError::RedisError(e.to_string())
+}
+
+/// Queue backed by a Redis stream and a consumer group.
+///
+/// Entries are read with XREADGROUP and stay in the group's pending list until
+/// XACKed. Entries left pending for longer than `claim_idle_ms` (a crashed
+/// includer) are taken over with XAUTOCLAIM, and after `max_deliveries`
+/// attempts they are moved to `<stream>:dlq`. A `nack(true)` makes the entry
+/// claimable after a delay that doubles with each delivery.
+///
+/// Settled entries are deleted from the stream, so it only holds entries
+/// not yet acked. Dead letters stay in `<stream>:dlq` until replayed.
+#[derive(Clone)]
+pub struct RedisStreamQueue {
+    client: Client,
+    connection: MultiplexedConnection,
+    stream: String,
+    group: String,
+    consumer_name: String,
+    claim_idle_ms: usize,
+    max_deliveries: usize,
+    requeue_delay_ms: usize,
+    metrics: Metrics,
+}
+
+impl RedisStreamQueue {
+    pub async fn new(db_url: String, stream: &str) -> Result<Self, RelayerError> {
+        let client = Client::open(db_url).map_err(redis_err)?;
+        let mut connection = client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(redis_err)?;
+
+        let created: Result<(), redis::RedisError> = connection
+            .xgroup_create_mkstream(stream, DEFAULT_GROUP, "0")
+            .await;
+        match created {
+            Ok(()) => debug!("Created consumer group {DEFAULT_GROUP} on {stream}"),
+            Err(e) if e.code() == Some("BUSYGROUP") => {}
+            Err(e) => return Err(redis_err(e)),
+        }
+
+        Ok(RedisStreamQueue {
+            client,
+            connection,
+            stream: stream.to_string(),
+            group: DEFAULT_GROUP.to_string(),
+            consumer_name: format!("includer-{}", std::process::id()),
+            claim_idle_ms: DEFAULT_CLAIM_IDLE_MS,
+            max_deliveries: DEFAULT_MAX_DELIVERIES,
+            requeue_delay_ms: DEFAULT_REQUEUE_DELAY_MS,
+            metrics: Metrics::new(),
+        })
+    }
+
+    /// Counts the entries dead-lettered after too many deliveries.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_claim_idle_ms(mut self, claim_idle_ms: usize) -> Self {
+        self.claim_idle_ms = claim_idle_ms;
+        self
+    }
+
+    pub fn with_max_deliveries(mut self, max_deliveries: usize) -> Self {
+        self.max_deliveries = max_deliveries;
+        self
+    }
+
+    pub fn with_requeue_delay_ms(mut self, requeue_delay_ms: usize) -> Self {
+        self.requeue_delay_ms = requeue_delay_ms;
+        self
+    }
+
+    pub fn with_consumer_name(mut self, consumer_name: &str) -> Self {
+        self.consumer_name = consumer_name.to_string();
+        self
+    }
+
+    pub fn dead_letter_stream(&self) -> String {
+        format!("{}:dlq", self.stream)
+    }
+
+    /// Acks the entry and deletes it, so the stream does not keep every
+    /// deposit ever published.
+    async fn ack_id(&mut self, id: &str) -> Result<(), RelayerError> {
+        let (_acked, _deleted): (usize, usize) = redis::pipe()
+            .atomic()
+            .xack(&self.stream, &self.group, &[id])
+            .xdel(&self.stream, &[id])
+            .query_async(&mut self.connection)
+            .await
+            .map_err(redis_err)?;
+        Ok(())
+    }
+
+    async fn dead_letter(&mut self, id: &str, data: &[u8]) -> Result<(), RelayerError> {
+        let dlq = self.dead_letter_stream();
+        let _dlq_id: String = self
+            .connection
+            .xadd(
+                &dlq,
+                "*",
+                &[(PAYLOAD_FIELD, data), (ORIGINAL_ID_FIELD, id.as_bytes())],
+            )
+            .await
+            .map_err(redis_err)?;
+        warn!("Moved stream entry {id} to {dlq}");
+        self.ack_id(id).await
+    }
+
+    async fn times_delivered(&mut self, id: &str) -> Result<usize, RelayerError> {
+        let pending: StreamPendingCountReply = self
+            .connection
+            .xpending_count(&self.stream, &self.group, id, id, 1)
+            .await
+            .map_err(redis_err)?;
+        Ok(pending
+            .ids
+            .first()
+            .map(|entry| entry.times_delivered)
+            .unwrap_or(0))
+    }
+}
+
+#[async_trait]
+impl QueueTrait for RedisStreamQueue {
+    type Consumer = RedisStreamConsumer;
+
+    async fn publish(&mut self, dep: &[u8]) -> Result<(), RelayerError> {
+        let id: String = self
+            .connection
+            .xadd(&self.stream, "*", &[(PAYLOAD_FIELD, dep)])
+            .await
+            .map_err(redis_err)?;
+        debug!("Published stream entry {id}");
+        Ok(())
+    }
+
+    async fn consumer(&mut self) -> Result<RedisStreamConsumer, RelayerError> {
+        // XREADGROUP blocks its connection, so the consumer gets its own.
+        let read_connection = self
+            .client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(redis_err)?;
+        Ok(RedisStreamConsumer {
+            queue: self.clone(),
+            read_connection,
+        })
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for RedisStreamQueue {
+    async fn dead_letters(&mut self, limit: usize) -> Result<Vec<DeadLetter>, RelayerError> {
+        let reply: StreamRangeReply = self
+            .connection
+            .xrange_count(self.dead_letter_stream(), "-", "+", limit)
+            .await
+            .map_err(redis_err)?;
+        Ok(reply
+            .ids
+            .iter()
+            .map(|entry| DeadLetter::new(entry.get(PAYLOAD_FIELD).unwrap_or_default()))
+            .collect())
+    }
+
+    async fn replay(&mut self, id: &str) -> Result<bool, RelayerError> {
+        let dlq = self.dead_letter_stream();
+        let reply: StreamRangeReply = self.connection.xrange_all(&dlq).await.map_err(redis_err)?;
+        for entry in reply.ids {
+            let data: Vec<u8> = entry.get(PAYLOAD_FIELD).unwrap_or_default();
+            if DeadLetter::new(data.clone()).id != id {
+                continue;
+            }
+            self.publish(&data).await?;
+            let _deleted: usize = self
+                .connection
+                .xdel(&dlq, &[&entry.id])
+                .await
+                .map_err(redis_err)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+pub struct RedisStreamConsumer {
+    queue: RedisStreamQueue,
+    read_connection: MultiplexedConnection,
+}
+
+impl RedisStreamConsumer {
+    async fn claim_stale(&mut self) -> Result<Option<StreamId>, RelayerError> {
+        let reply: Value = redis::cmd("XAUTOCLAIM")
+            .arg(&self.queue.stream)
+            .arg(&self.queue.group)
+            .arg(&self.queue.consumer_name)
+            .arg(self.queue.claim_idle_ms)
+            .arg("0-0")
+            .arg("COUNT")
+            .arg(1)
+            .query_async(&mut self.queue.connection)
+            .await
+            .map_err(redis_err)?;
+        parse_autoclaim_reply(&reply).map(|claimed| claimed.ids.into_iter().next())
+    }
+
+    async fn read_new(&mut self) -> Result<Option<StreamId>, RelayerError> {
+        let opts = StreamReadOptions::default()
+            .group(&self.queue.group, &self.queue.consumer_name)
+            .count(1)
+            .block(READ_BLOCK_MS);
+        let reply: Option<StreamReadReply> = self
+            .read_connection
+            .xread_options(&[&self.queue.stream], &[">"], &opts)
+            .await
+            .map_err(redis_err)?;
+        Ok(reply.and_then(|r| r.keys.into_iter().next()?.ids.into_iter().next()))
+    }
+
+    async fn make_delivery(
+        &mut self,
+        entry: StreamId,
+        redelivered: bool,
+    ) -> Result<Option<RedisStreamDelivery>, RelayerError> {
+        let data: Vec<u8> = entry.get(PAYLOAD_FIELD).unwrap_or_default();
+        if redelivered {
+            let deliveries = self.queue.times_delivered(&entry.id).await?;
+            if deliveries > self.queue.max_deliveries {
+                self.queue.dead_letter(&entry.id, &data).await?;
+                self.queue.metrics.inc(&DEAD_LETTERED, &[]);
+                return Ok(None);
+            }
+        }
+        Ok(Some(RedisStreamDelivery {
+            id: entry.id,
+            data,
+            redelivered,
+            queue: self.queue.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl QueueConsumer for RedisStreamConsumer {
+    type Delivery = RedisStreamDelivery;
+
+    async fn next_delivery(&mut self) -> Option<Result<RedisStreamDelivery, RelayerError>> {
+        loop {
+            let next = match self.claim_stale().await {
+                Ok(Some(entry)) => Ok(Some((entry, true))),
+                Ok(None) => self.read_new().await.map(|e| e.map(|e| (e, false))),
+                Err(e) => Err(e),
+            };
+            match next {
+                Ok(Some((entry, redelivered))) => {
+                    match self.make_delivery(entry, redelivered).await {
+                        Ok(Some(delivery)) => return Some(Ok(delivery)),
+                        Ok(None) => continue,
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+pub struct RedisStreamDelivery {
+    id: String,
+    data: Vec<u8>,
+    redelivered: bool,
+    queue: RedisStreamQueue,
+}
+
+impl RedisStreamDelivery {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[async_trait]
+impl QueueDelivery for RedisStreamDelivery {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn redelivered(&self) -> bool {
+        self.redelivered
+    }
+
+    async fn ack(mut self) -> Result<(), RelayerError> {
+        self.queue.ack_id(&self.id).await
+    }
+
+    async fn nack(mut self, requeue: bool) -> Result<(), RelayerError> {
+        if !requeue {
+            return self.queue.dead_letter(&self.id, &self.data).await;
+        }
+        // Mark the entry as idle for long enough that XAUTOCLAIM picks it up
+        // once the backoff is over. JUSTID keeps the delivery counter
+        // untouched.
+        let deliveries = self.queue.times_delivered(&self.id).await?;
+        let idle = requeue_idle(
+            self.queue.claim_idle_ms,
+            self.queue.requeue_delay_ms,
+            deliveries,
+        );
+        let opts = StreamClaimOptions::default().idle(idle).with_justid();
+        let _claimed: Value = self
+            .queue
+            .connection
+            .xclaim_options(
+                &self.queue.stream,
+                &self.queue.group,
+                &self.queue.consumer_name,
+                0,
+                &[&self.id],
+                opts,
+            )
+            .await
+            .map_err(redis_err)?;
+        Ok(())
+    }
+}
+
+/// Idle time to give an entry requeued after `deliveries` deliveries, so it
+/// is claimed again `requeue_delay_ms` later, doubled for each delivery
+/// after the first, and never later than `claim_idle_ms`.
+fn requeue_idle(claim_idle_ms: usize, requeue_delay_ms: usize, deliveries: usize) -> usize {
+    let doublings = deliveries.saturating_sub(1).min(usize::BITS as usize - 1) as u32;
+    let delay = requeue_delay_ms.saturating_mul(1 << doublings);
+    claim_idle_ms.saturating_sub(delay)
+}
+
+/// XAUTOCLAIM replies with `[next_cursor, [entries...]]`, plus a list of
+/// deleted ids since Redis 7.
+fn parse_autoclaim_reply(reply: &Value) -> Result<StreamClaimReply, RelayerError> {
+    match reply {
+        Value::Bulk(parts) if parts.len() >= 2 => {
+            StreamClaimReply::from_redis_value(&parts[1]).map_err(redis_err)
+        }
+        other => Err(RelayerError::RedisError(format!(
+            "Unexpected XAUTOCLAIM reply: {other:?}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, payload: &[u8]) -> Value {
+        Value::Bulk(vec![
+            Value::Data(id.as_bytes().to_vec()),
+            Value::Bulk(vec![
+                Value::Data(PAYLOAD_FIELD.as_bytes().to_vec()),
+                Value::Data(payload.to_vec()),
+            ]),
+        ])
+    }
+
+    #[test]
+    fn test_parse_autoclaim_reply() {
+        let reply = Value::Bulk(vec![
+            Value::Data(b"0-0".to_vec()),
+            Value::Bulk(vec![entry("1-0", b"dep")]),
+            Value::Bulk(vec![]),
+        ]);
+        let claimed = parse_autoclaim_reply(&reply).unwrap();
+        assert_eq!(claimed.ids.len(), 1);
+        assert_eq!(claimed.ids[0].id, "1-0");
+        let payload: Vec<u8> = claimed.ids[0].get(PAYLOAD_FIELD).unwrap();
+        assert_eq!(payload, b"dep");
+    }
+
+    #[test]
+    fn test_requeue_backoff() {
+        assert_eq!(requeue_idle(30_000, 1_000, 1), 29_000);
+        assert_eq!(requeue_idle(30_000, 1_000, 2), 28_000);
+        assert_eq!(requeue_idle(30_000, 1_000, 4), 22_000);
+        // Never later than a crashed consumer's entries.
+        assert_eq!(requeue_idle(30_000, 1_000, 6), 0);
+        assert_eq!(requeue_idle(30_000, 1_000, 200), 0);
+    }
+
+    #[test]
+    fn test_parse_autoclaim_reply_empty() {
+        let reply = Value::Bulk(vec![Value::Data(b"0-0".to_vec()), Value::Bulk(vec![])]);
+        let claimed = parse_autoclaim_reply(&reply).unwrap();
+        assert!(claimed.ids.is_empty());
+    }
+
+    #[test]
+    fn test_parse_autoclaim_reply_err() {
+        let err = parse_autoclaim_reply(&Value::Nil).unwrap_err();
+        assert!(matches!(err, RelayerError::RedisError(_)));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at DB_URL"]
+    async fn test_publish_and_consume() {
+        dotenv::dotenv().ok();
+        let db_url = std::env::var("DB_URL").unwrap_or_else(|_| "redis://127.0.0.1/".into());
+        let mut queue = RedisStreamQueue::new(db_url, "test_relayer_stream")
+            .await
+            .unwrap()
+            .with_claim_idle_ms(10);
+        queue.publish(b"dep").await.unwrap();
+        let mut consumer = queue.consumer().await.unwrap();
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.data(), b"dep");
+        delivery.nack(true).await.unwrap();
+
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        assert!(delivery.redelivered());
+        let id = delivery.id().to_string();
+        delivery.ack().await.unwrap();
+
+        // Acked entries are deleted from the stream.
+        let left: StreamRangeReply = queue
+            .connection
+            .xrange(&queue.stream, &id, &id)
+            .await
+            .unwrap();
+        assert!(left.ids.is_empty());
+    }
+}
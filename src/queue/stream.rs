@@ -0,0 +1,478 @@
+use crate::config::RelayerConfig;
+use crate::errors::RelayerError;
+use crate::metrics::{DEAD_LETTERED, Metrics};
+use crate::queue::{DeadLetter, DeadLetterQueue, QueueConsumer, QueueDelivery, QueueTrait};
+use async_trait::async_trait;
+use futures_lite::StreamExt;
+use rabbitmq_stream_client::{
+    Consumer, Environment, NoDedup, Producer,
+    error::{ClientError, ConsumerStoreOffsetError, StreamCreateError},
+    types::{Message, OffsetSpecification, ResponseCode, SimpleValue},
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+const DEFAULT_CONSUMER_NAME: &str = "relayer-includer";
+const DEFAULT_MAX_DELIVERIES: u32 = 5;
+/// Application property counting how often a requeued message was
+/// delivered before it was appended again.
+const DELIVERY_COUNT_PROPERTY: &str = "x-delivery-count";
+/// Application property of the marker appended to the dead-letter stream
+/// when the dead letter whose id is the marker's body is replayed.
+const REPLAYED_PROPERTY: &str = "x-replayed";
+/// How long reading the dead-letter stream waits for another message before
+/// taking its tail as reached.
+const DEAD_LETTER_READ_IDLE: Duration = Duration::from_millis(500);
+/// Sentinel for "nothing acked yet", since offset 0 is a valid stream offset.
+const NO_OFFSET: u64 = u64::MAX;
+
+fn stream_err(e: impl std::fmt::Display) -> RelayerError {
+    RelayerError::StreamError(e.to_string())
+}
+
+fn dead_letter_stream(stream: &str) -> String {
+    format!("{stream}-dlq")
+}
+
+fn message(data: &[u8], deliveries: u32) -> Message {
+    let builder = Message::builder().body(data.to_vec());
+    if deliveries == 0 {
+        return builder.build();
+    }
+    builder
+        .application_properties()
+        .insert(DELIVERY_COUNT_PROPERTY, deliveries)
+        .message_builder()
+        .build()
+}
+
+fn replay_marker(id: &str) -> Message {
+    Message::builder()
+        .body(id.as_bytes().to_vec())
+        .application_properties()
+        .insert(REPLAYED_PROPERTY, 1u32)
+        .message_builder()
+        .build()
+}
+
+fn is_replay_marker(message: &Message) -> bool {
+    message
+        .application_properties()
+        .is_some_and(|properties| properties.get(REPLAYED_PROPERTY).is_some())
+}
+
+/// Earlier deliveries of `message`, 0 unless it was requeued.
+fn delivery_count(message: &Message) -> u32 {
+    match message
+        .application_properties()
+        .and_then(|properties| properties.get(DELIVERY_COUNT_PROPERTY))
+    {
+        Some(SimpleValue::Uint(count)) => *count,
+        _ => 0,
+    }
+}
+
+/// Queue backed by a RabbitMQ stream.
+///
+/// Unlike a classic queue a stream keeps every message, and consumers only
+/// track how far they have read. The offset of the last acked delivery is
+/// stored server-side under the consumer name, so a restarted includer
+/// resumes where it left off. `replay_from` opens an anonymous reader at any
+/// offset for audits and rebuilds without touching the stored offset.
+///
+/// A stream cannot put a message back, so a requeued message is appended
+/// again with its delivery count, and after `max_deliveries` it goes to
+/// `<stream>-dlq` instead. That stream cannot drop a letter either, so a
+/// replay appends a marker after it, and listing skips the marked letters.
+#[derive(Clone)]
+pub struct RabbitStreamQueue {
+    environment: Environment,
+    producer: Producer<NoDedup>,
+    dead_letter_producer: Producer<NoDedup>,
+    stream: String,
+    consumer_name: String,
+    replay_from: Option<u64>,
+    max_deliveries: u32,
+    metrics: Metrics,
+    /// Offset of the last acked delivery, and the last one stored.
+    acked: Arc<AtomicU64>,
+    stored: Arc<AtomicU64>,
+}
+
+impl RabbitStreamQueue {
+    /// Connects to the broker at `stream.host` and `stream.port`.
+    pub async fn new(config: &RelayerConfig, stream: &str) -> Result<Self, RelayerError> {
+        let (host, port) = (&config.stream_host, config.stream_port);
+
+        let environment = Environment::builder()
+            .host(host)
+            .port(port)
+            .build()
+            .await
+            .map_err(stream_err)?;
+        debug!("CONNECTED to stream {host}:{port}");
+
+        let dead_letter_stream = dead_letter_stream(stream);
+        for name in [stream, dead_letter_stream.as_str()] {
+            match environment.stream_creator().create(name).await {
+                Ok(()) => info!("Created stream {name}"),
+                Err(StreamCreateError::Create {
+                    status: ResponseCode::StreamAlreadyExists,
+                    ..
+                }) => {}
+                Err(e) => return Err(stream_err(e)),
+            }
+        }
+
+        let producer = environment
+            .producer()
+            .build(stream)
+            .await
+            .map_err(stream_err)?;
+        let dead_letter_producer = environment
+            .producer()
+            .build(&dead_letter_stream)
+            .await
+            .map_err(stream_err)?;
+
+        Ok(RabbitStreamQueue {
+            environment,
+            producer,
+            dead_letter_producer,
+            stream: stream.to_string(),
+            consumer_name: DEFAULT_CONSUMER_NAME.to_string(),
+            replay_from: None,
+            max_deliveries: DEFAULT_MAX_DELIVERIES,
+            metrics: Metrics::new(),
+            acked: Arc::new(AtomicU64::new(NO_OFFSET)),
+            stored: Arc::new(AtomicU64::new(NO_OFFSET)),
+        })
+    }
+
+    pub fn with_consumer_name(mut self, consumer_name: &str) -> Self {
+        self.consumer_name = consumer_name.to_string();
+        self
+    }
+
+    /// Messages delivered `max_deliveries` times without an ack are
+    /// dead-lettered instead of being requeued again.
+    pub fn with_max_deliveries(mut self, max_deliveries: u32) -> Self {
+        self.max_deliveries = max_deliveries;
+        self
+    }
+
+    /// Counts the messages dead-lettered after too many deliveries.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Consumers created from the returned queue start at `offset` and never
+    /// store their progress.
+    pub fn replay_from(mut self, offset: u64) -> Self {
+        self.replay_from = Some(offset);
+        // Acks of the replay must not reach the original queue's offset.
+        self.acked = Arc::new(AtomicU64::new(NO_OFFSET));
+        self.stored = Arc::new(AtomicU64::new(NO_OFFSET));
+        self
+    }
+
+    /// Offset stored for this queue's consumer name, if any.
+    pub async fn stored_offset(&self) -> Result<Option<u64>, RelayerError> {
+        let probe = self
+            .environment
+            .consumer()
+            .name(&self.consumer_name)
+            .offset(OffsetSpecification::Next)
+            .build(&self.stream)
+            .await
+            .map_err(stream_err)?;
+        let stored = match probe.query_offset().await {
+            Ok(offset) => Some(offset),
+            Err(ConsumerStoreOffsetError::Client(ClientError::RequestError(
+                ResponseCode::OffsetNotFound,
+            ))) => None,
+            Err(e) => return Err(stream_err(e)),
+        };
+        probe.handle().close().await.map_err(stream_err)?;
+        Ok(stored)
+    }
+
+    /// The acked offset that has not been stored yet, if any.
+    fn unstored_offset(&self) -> Option<u64> {
+        let acked = self.acked.load(Ordering::SeqCst);
+        let stale = acked == NO_OFFSET || acked == self.stored.load(Ordering::SeqCst);
+        (!stale && self.replay_from.is_none()).then_some(acked)
+    }
+
+    /// Reads `<stream>-dlq` from its start to its tail, dropping each letter
+    /// a replay marker follows.
+    async fn unreplayed_dead_letters(&self) -> Result<Vec<DeadLetter>, RelayerError> {
+        let mut reader = self
+            .environment
+            .consumer()
+            .offset(OffsetSpecification::First)
+            .build(&dead_letter_stream(&self.stream))
+            .await
+            .map_err(stream_err)?;
+        let mut letters: Vec<DeadLetter> = Vec::new();
+        while let Ok(Some(delivery)) = timeout(DEAD_LETTER_READ_IDLE, reader.next()).await {
+            let delivery = delivery.map_err(stream_err)?;
+            let data = delivery
+                .message()
+                .data()
+                .map(|d| d.to_vec())
+                .unwrap_or_default();
+            if is_replay_marker(delivery.message()) {
+                let id = String::from_utf8_lossy(&data);
+                if let Some(index) = letters.iter().position(|letter| letter.id == id) {
+                    letters.remove(index);
+                }
+            } else {
+                letters.push(DeadLetter::new(data));
+            }
+        }
+        reader.handle().close().await.map_err(stream_err)?;
+        Ok(letters)
+    }
+
+    async fn send(producer: &Producer<NoDedup>, message: Message) -> Result<(), RelayerError> {
+        let status = producer
+            .send_with_confirm(message)
+            .await
+            .map_err(stream_err)?;
+        if status.confirmed() {
+            Ok(())
+        } else {
+            Err(RelayerError::StreamError(String::from(
+                "Failed to publish to stream",
+            )))
+        }
+    }
+}
+
+#[async_trait]
+impl QueueTrait for RabbitStreamQueue {
+    type Consumer = RabbitStreamConsumer;
+
+    async fn publish(&mut self, dep: &[u8]) -> Result<(), RelayerError> {
+        Self::send(&self.producer, message(dep, 0)).await
+    }
+
+    async fn consumer(&mut self) -> Result<RabbitStreamConsumer, RelayerError> {
+        let (offset, name) = match self.replay_from {
+            Some(offset) => (OffsetSpecification::Offset(offset), None),
+            None => {
+                let offset = match self.stored_offset().await? {
+                    Some(stored) => OffsetSpecification::Offset(stored + 1),
+                    None => OffsetSpecification::First,
+                };
+                (offset, Some(self.consumer_name.clone()))
+            }
+        };
+        info!("Consuming {} from {:?}", self.stream, offset);
+
+        let consumer = self
+            .environment
+            .consumer()
+            .name_optional(name)
+            .offset(offset)
+            .build(&self.stream)
+            .await
+            .map_err(stream_err)?;
+
+        Ok(RabbitStreamConsumer {
+            consumer,
+            queue: self.clone(),
+        })
+    }
+
+    /// Stores the offset acked since the consumer's last read, which it
+    /// would otherwise only store before its next one.
+    async fn close(&mut self) -> Result<(), RelayerError> {
+        let Some(acked) = self.unstored_offset() else {
+            return Ok(());
+        };
+        let writer = self
+            .environment
+            .consumer()
+            .name(&self.consumer_name)
+            .offset(OffsetSpecification::Next)
+            .build(&self.stream)
+            .await
+            .map_err(stream_err)?;
+        writer.store_offset(acked).await.map_err(stream_err)?;
+        writer.handle().close().await.map_err(stream_err)?;
+        self.stored.store(acked, Ordering::SeqCst);
+        debug!("Stored offset {acked} of {} on close", self.stream);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for RabbitStreamQueue {
+    async fn dead_letters(&mut self, limit: usize) -> Result<Vec<DeadLetter>, RelayerError> {
+        let mut letters = self.unreplayed_dead_letters().await?;
+        letters.truncate(limit);
+        Ok(letters)
+    }
+
+    async fn replay(&mut self, id: &str) -> Result<bool, RelayerError> {
+        let letters = self.unreplayed_dead_letters().await?;
+        let Some(letter) = letters.into_iter().find(|letter| letter.id == id) else {
+            return Ok(false);
+        };
+        self.publish(&letter.data).await?;
+        Self::send(&self.dead_letter_producer, replay_marker(id)).await?;
+        Ok(true)
+    }
+}
+
+pub struct RabbitStreamConsumer {
+    consumer: Consumer,
+    queue: RabbitStreamQueue,
+}
+
+impl RabbitStreamConsumer {
+    /// Deliveries only record their offset when acked; it is written to the
+    /// server before the next read and when the queue is closed, so a crash
+    /// redelivers at most the messages acked since the last read.
+    async fn store_acked_offset(&mut self) -> Result<(), RelayerError> {
+        let Some(acked) = self.queue.unstored_offset() else {
+            return Ok(());
+        };
+        self.consumer
+            .store_offset(acked)
+            .await
+            .map_err(stream_err)?;
+        self.queue.stored.store(acked, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl QueueConsumer for RabbitStreamConsumer {
+    type Delivery = RabbitStreamDelivery;
+
+    async fn next_delivery(&mut self) -> Option<Result<RabbitStreamDelivery, RelayerError>> {
+        if let Err(e) = self.store_acked_offset().await {
+            return Some(Err(e));
+        }
+        let delivery = match self.consumer.next().await? {
+            Ok(delivery) => delivery,
+            Err(e) => return Some(Err(stream_err(e))),
+        };
+        let data = delivery
+            .message()
+            .data()
+            .map(|d| d.to_vec())
+            .unwrap_or_default();
+        Some(Ok(RabbitStreamDelivery {
+            offset: delivery.offset(),
+            data,
+            deliveries: delivery_count(delivery.message()) + 1,
+            queue: self.queue.clone(),
+        }))
+    }
+}
+
+pub struct RabbitStreamDelivery {
+    offset: u64,
+    data: Vec<u8>,
+    /// Including this one.
+    deliveries: u32,
+    queue: RabbitStreamQueue,
+}
+
+impl RabbitStreamDelivery {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+#[async_trait]
+impl QueueDelivery for RabbitStreamDelivery {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn redelivered(&self) -> bool {
+        self.deliveries > 1
+    }
+
+    async fn ack(self) -> Result<(), RelayerError> {
+        self.queue.acked.store(self.offset, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// A requeued message is appended to the tail again, counting this
+    /// delivery, and a rejected one or one out of deliveries to
+    /// `<stream>-dlq`. Either way the original offset counts as handled.
+    async fn nack(self, requeue: bool) -> Result<(), RelayerError> {
+        let queue = &self.queue;
+        if requeue && self.deliveries < queue.max_deliveries {
+            let requeued = message(&self.data, self.deliveries);
+            RabbitStreamQueue::send(&queue.producer, requeued).await?;
+        } else {
+            if requeue {
+                warn!(
+                    "Stream offset {} exceeded {} deliveries, dead-lettering",
+                    self.offset, queue.max_deliveries
+                );
+                queue.metrics.inc(&DEAD_LETTERED, &[]);
+            } else {
+                warn!("Dead-lettering stream offset {}", self.offset);
+            }
+            let dead = message(&self.data, self.deliveries);
+            RabbitStreamQueue::send(&queue.dead_letter_producer, dead).await?;
+        }
+        self.queue.acked.store(self.offset, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delivery_count_property() {
+        assert_eq!(delivery_count(&message(b"dep", 0)), 0);
+        let requeued = message(b"dep", 3);
+        assert_eq!(delivery_count(&requeued), 3);
+        assert_eq!(requeued.data(), Some(&b"dep"[..]));
+        assert!(!is_replay_marker(&requeued));
+        assert!(is_replay_marker(&replay_marker("id")));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a RabbitMQ Streams broker at STREAM_HOST"]
+    async fn test_publish_consume_and_replay() {
+        dotenv::dotenv().ok();
+        let config = RelayerConfig::from_toml("", |name| std::env::var(name).ok()).unwrap();
+        let mut queue = RabbitStreamQueue::new(&config, "test_relayer_stream")
+            .await
+            .unwrap()
+            .with_consumer_name("test_relayer_consumer");
+        queue.publish(b"dep").await.unwrap();
+
+        let mut consumer = queue.consumer().await.unwrap();
+        let delivery = consumer.next_delivery().await.unwrap().unwrap();
+        let offset = delivery.offset();
+        delivery.ack().await.unwrap();
+        // Closing stores the offset acked since the last read.
+        queue.close().await.unwrap();
+        assert_eq!(queue.stored_offset().await.unwrap(), Some(offset));
+
+        let mut replay = queue.clone().replay_from(offset).consumer().await.unwrap();
+        let delivery = replay.next_delivery().await.unwrap().unwrap();
+        assert_eq!(delivery.offset(), offset);
+    }
+}
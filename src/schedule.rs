@@ -0,0 +1,97 @@
+use tokio::time::{Duration, Instant};
+
+/// Wait between polls while there is work, unless configured per chain.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Longest wait an idle poller backs off to, unless configured per chain.
+pub const DEFAULT_MAX_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When to poll next. Polls that find work keep the wait at `interval`;
+/// each one that finds nothing (or fails) doubles it, up to `max_interval`.
+#[derive(Debug, Clone)]
+pub struct PollSchedule {
+    pub interval: Duration,
+    pub max_interval: Duration,
+    /// Wait after the next idle poll.
+    backoff: Duration,
+    due: Instant,
+}
+
+impl Default for PollSchedule {
+    fn default() -> Self {
+        Self::new(DEFAULT_POLL_INTERVAL, DEFAULT_MAX_POLL_INTERVAL)
+    }
+}
+
+impl PollSchedule {
+    /// The first poll is due at once. `max_interval` is raised to
+    /// `interval` if lower.
+    pub fn new(interval: Duration, max_interval: Duration) -> Self {
+        PollSchedule {
+            interval,
+            max_interval: max_interval.max(interval),
+            backoff: interval,
+            due: Instant::now(),
+        }
+    }
+
+    /// The last poll found work: poll again after `interval`.
+    pub fn busy(&mut self) {
+        self.backoff = self.interval;
+        self.due = Instant::now() + self.interval;
+    }
+
+    /// The last poll found nothing or failed: wait longer than last time.
+    pub fn idle(&mut self) {
+        self.due = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(self.max_interval);
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.due
+    }
+
+    /// Time left until the next poll, zero if it is due.
+    pub fn until_due(&self) -> Duration {
+        self.due.saturating_duration_since(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Rounds up the time left, which shrinks while the test runs.
+    fn wait(schedule: &PollSchedule) -> Duration {
+        secs(schedule.until_due().as_secs_f64().ceil() as u64)
+    }
+
+    #[test]
+    fn test_backs_off_when_idle_and_resets_on_work() {
+        let mut schedule = PollSchedule::new(secs(2), secs(10));
+        assert!(schedule.is_due());
+
+        let waits: Vec<_> = (0..5)
+            .map(|_| {
+                schedule.idle();
+                wait(&schedule)
+            })
+            .collect();
+        assert_eq!(waits, [secs(2), secs(4), secs(8), secs(10), secs(10)]);
+        assert!(!schedule.is_due());
+
+        schedule.busy();
+        assert_eq!(wait(&schedule), secs(2));
+        schedule.idle();
+        assert_eq!(wait(&schedule), secs(2));
+    }
+
+    #[test]
+    fn test_max_interval_is_at_least_interval() {
+        let schedule = PollSchedule::new(secs(60), secs(30));
+        assert_eq!(schedule.max_interval, secs(60));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::config::RelayerConfig;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How long work in progress may run on after a shutdown was requested.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Tells run loops to stop. Clones share the signal, so one clone can be
+/// triggered while the others are checked or awaited.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    grace: Duration,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Shutdown {
+            sender: Arc::new(sender),
+            grace: DEFAULT_SHUTDOWN_GRACE,
+        }
+    }
+
+    /// With the grace period from `shutdown.grace_secs`.
+    pub fn from_config(config: &RelayerConfig) -> Self {
+        Self::new().with_grace(config.shutdown_grace)
+    }
+
+    pub fn with_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// Triggers the shutdown on SIGINT or SIGTERM.
+    pub fn trigger_on_signals(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            info!("Shutdown requested, finishing work in progress");
+            shutdown.trigger();
+        });
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Resolves once `trigger` has been called, immediately if it already
+    /// was.
+    pub async fn wait(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives in `self`, so the channel cannot close.
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Sleeps for `duration`, or until the shutdown is triggered if that
+    /// comes first.
+    pub async fn sleep(&self, duration: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = self.wait() => {}
+        }
+    }
+
+    /// Resolves when work still running after a shutdown should be given
+    /// up on: `grace` after the trigger.
+    pub async fn deadline(&self) {
+        self.wait().await;
+        tokio::time::sleep(self.grace).await;
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(e) => {
+            warn!("Cannot listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trigger_wakes_waiters() {
+        let shutdown = Shutdown::new();
+        let waiter = shutdown.clone();
+        let handle = tokio::spawn(async move { waiter.wait().await });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!shutdown.is_triggered());
+        shutdown.trigger();
+        handle.await.unwrap();
+        assert!(shutdown.is_triggered());
+        // Waiting after the trigger returns at once.
+        shutdown.wait().await;
+    }
+
+    #[tokio::test]
+    async fn test_trigger_interrupts_sleep() {
+        let shutdown = Shutdown::new();
+        let sleeper = shutdown.clone();
+        let handle = tokio::spawn(async move { sleeper.sleep(Duration::from_secs(60)).await });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deadline_follows_grace() {
+        let shutdown = Shutdown::new().with_grace(Duration::from_millis(50));
+        let deadline = shutdown.clone();
+        let handle = tokio::spawn(async move { deadline.deadline().await });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!handle.is_finished());
+        shutdown.trigger();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!handle.is_finished());
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
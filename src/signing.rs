@@ -0,0 +1,190 @@
+use crate::config::RelayerConfig;
+use crate::envelope::Envelope;
+use crate::errors::RelayerError;
+use alloy::hex;
+use alloy::primitives::Address;
+use alloy::signers::{Signature, SignerSync, local::PrivateKeySigner};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const HMAC_SCHEME: &str = "hmac-sha256";
+const ECDSA_SCHEME: &str = "ecdsa-eip191";
+
+/// Signs envelopes on the publishing side.
+///
+/// The signature covers the whole envelope (metadata and payload) and is
+/// stored as `<scheme>:<hex>` in `Envelope::signature`.
+#[derive(Clone)]
+pub enum MessageSigner {
+    Hmac(Vec<u8>),
+    Ecdsa(PrivateKeySigner),
+}
+
+/// Checks envelope signatures on the consuming side. For ECDSA only the
+/// signer's address is needed.
+#[derive(Clone, Debug)]
+pub enum MessageVerifier {
+    Hmac(Vec<u8>),
+    Ecdsa(Address),
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac
+}
+
+impl MessageSigner {
+    /// Signs with `signing.hmac_key` or `signing.signing_key`. Returns
+    /// `None` if neither is set.
+    pub fn from_config(config: &RelayerConfig) -> Option<Self> {
+        let signing = &config.signing;
+        if let Some(key) = &signing.hmac_key {
+            return Some(MessageSigner::Hmac(key.clone()));
+        }
+        signing.signing_key.clone().map(MessageSigner::Ecdsa)
+    }
+
+    pub fn sign(&self, envelope: &mut Envelope) -> Result<(), RelayerError> {
+        let data = envelope.signing_bytes()?;
+        let signature = match self {
+            MessageSigner::Hmac(key) => {
+                let tag = hmac(key, &data).finalize().into_bytes();
+                format!("{HMAC_SCHEME}:{}", hex::encode(tag))
+            }
+            MessageSigner::Ecdsa(signer) => {
+                let sig = signer
+                    .sign_message_sync(&data)
+                    .map_err(|e| RelayerError::Other(e.to_string()))?;
+                format!("{ECDSA_SCHEME}:{}", hex::encode(sig.as_bytes()))
+            }
+        };
+        envelope.signature = Some(signature);
+        Ok(())
+    }
+}
+
+impl MessageVerifier {
+    /// Checks against `signing.hmac_key` or `signing.signer_address`.
+    /// Returns `None` if neither is set.
+    pub fn from_config(config: &RelayerConfig) -> Option<Self> {
+        let signing = &config.signing;
+        if let Some(key) = &signing.hmac_key {
+            return Some(MessageVerifier::Hmac(key.clone()));
+        }
+        signing.signer_address.map(MessageVerifier::Ecdsa)
+    }
+
+    pub fn verify(&self, envelope: &Envelope) -> Result<(), RelayerError> {
+        let invalid = |reason: &str| RelayerError::InvalidSignature(reason.to_string());
+        let signature = envelope
+            .signature
+            .as_deref()
+            .ok_or_else(|| invalid("message is not signed"))?;
+        let (scheme, sig_hex) = signature
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed signature"))?;
+        let sig_bytes = hex::decode(sig_hex).map_err(|_| invalid("malformed signature"))?;
+        let data = envelope.signing_bytes()?;
+
+        match (self, scheme) {
+            (MessageVerifier::Hmac(key), HMAC_SCHEME) => hmac(key, &data)
+                .verify_slice(&sig_bytes)
+                .map_err(|_| invalid("HMAC mismatch")),
+            (MessageVerifier::Ecdsa(expected), ECDSA_SCHEME) => {
+                let sig = Signature::try_from(sig_bytes.as_slice())
+                    .map_err(|_| invalid("malformed signature"))?;
+                let recovered = sig
+                    .recover_address_from_msg(&data)
+                    .map_err(|_| invalid("signature does not recover"))?;
+                if recovered == *expected {
+                    Ok(())
+                } else {
+                    Err(invalid("signed by an unexpected key"))
+                }
+            }
+            _ => Err(invalid("unexpected signature scheme")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscriber::Deposit;
+
+    fn envelope() -> Envelope {
+        let deposit = Deposit {
+            sender: "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
+                .parse()
+                .unwrap(),
+            amount: 42,
+            origin: None,
+        };
+        Envelope::deposit(&deposit, "test").unwrap()
+    }
+
+    fn tamper(envelope: &mut Envelope) {
+        envelope.payload["amount"] = serde_json::json!(1_000_000);
+    }
+
+    #[test]
+    fn test_hmac_sign_and_verify() {
+        let key = vec![7u8; 32];
+        let mut env = envelope();
+        MessageSigner::Hmac(key.clone()).sign(&mut env).unwrap();
+        let verifier = MessageVerifier::Hmac(key);
+        verifier.verify(&env).unwrap();
+
+        // The signature survives a round trip through the queue.
+        let parsed = Envelope::from_bytes(&env.to_bytes().unwrap()).unwrap();
+        verifier.verify(&parsed).unwrap();
+
+        tamper(&mut env);
+        let err = verifier.verify(&env).unwrap_err();
+        assert!(matches!(err, RelayerError::InvalidSignature(_)));
+    }
+
+    #[test]
+    fn test_hmac_wrong_key() {
+        let mut env = envelope();
+        MessageSigner::Hmac(vec![7u8; 32]).sign(&mut env).unwrap();
+        let err = MessageVerifier::Hmac(vec![8u8; 32])
+            .verify(&env)
+            .unwrap_err();
+        assert!(matches!(err, RelayerError::InvalidSignature(_)));
+    }
+
+    #[test]
+    fn test_ecdsa_sign_and_verify() {
+        let signer: PrivateKeySigner =
+            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+                .parse()
+                .unwrap();
+        let verifier = MessageVerifier::Ecdsa(signer.address());
+        let mut env = envelope();
+        MessageSigner::Ecdsa(signer).sign(&mut env).unwrap();
+        verifier.verify(&env).unwrap();
+
+        tamper(&mut env);
+        assert!(verifier.verify(&env).is_err());
+    }
+
+    #[test]
+    fn test_unsigned_and_wrong_scheme_rejected() {
+        let env = envelope();
+        let err = MessageVerifier::Hmac(vec![7u8; 32])
+            .verify(&env)
+            .unwrap_err();
+        assert!(matches!(err, RelayerError::InvalidSignature(_)));
+
+        let mut env = envelope();
+        MessageSigner::Hmac(vec![7u8; 32]).sign(&mut env).unwrap();
+        let err = MessageVerifier::Ecdsa(Address::default())
+            .verify(&env)
+            .unwrap_err();
+        assert!(matches!(err, RelayerError::InvalidSignature(_)));
+    }
+}
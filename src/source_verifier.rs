@@ -0,0 +1,342 @@
+use crate::errors::RelayerError;
+use crate::subscriber::{DEPOSIT_EVENT_SIG, Deposit, ProviderType};
+use crate::utils::push_deposits;
+use alloy::primitives::{Address, B256, keccak256};
+use alloy::providers::Provider;
+use std::time::Duration;
+use tracing::debug;
+
+pub const DEFAULT_MIN_CONFIRMATIONS: u64 = 12;
+const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(600);
+
+/// Outcome of checking a deposit against the source chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceCheck {
+    Verified,
+    /// The log is not buried deep enough yet, or the node has no receipt
+    /// for it yet. Retry later.
+    Unconfirmed {
+        confirmations: u64,
+    },
+    /// The deposit does not match the chain and must not be minted.
+    Rejected(String),
+}
+
+/// Re-reads each deposit's `Deposited` log from the source chain so the
+/// includer does not have to trust the queue.
+pub struct SourceVerifier {
+    pub provider: ProviderType,
+    pub contract_address: Address,
+    pub event_sig: B256,
+    pub min_confirmations: u64,
+    /// How long the includer holds an unconfirmed deposit before handing
+    /// it back to the queue.
+    pub max_wait: Duration,
+}
+
+impl SourceVerifier {
+    pub fn new(provider: ProviderType, contract_address: Address) -> Self {
+        SourceVerifier {
+            provider,
+            contract_address,
+            event_sig: keccak256(DEPOSIT_EVENT_SIG),
+            min_confirmations: DEFAULT_MIN_CONFIRMATIONS,
+            max_wait: DEFAULT_MAX_WAIT,
+        }
+    }
+
+    pub fn with_min_confirmations(mut self, min_confirmations: u64) -> Self {
+        self.min_confirmations = min_confirmations;
+        self
+    }
+
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// Provider failures are returned as errors, since they say nothing
+    /// about the deposit itself.
+    pub async fn check(&self, deposit: &Deposit) -> Result<SourceCheck, RelayerError> {
+        let Some(origin) = deposit.origin else {
+            return Ok(SourceCheck::Rejected(
+                "deposit carries no source transaction".into(),
+            ));
+        };
+        let rejected = |reason: String| Ok(SourceCheck::Rejected(reason));
+
+        let receipt = self
+            .provider
+            .get_transaction_receipt(origin.tx_hash)
+            .await
+            .map_err(|e| RelayerError::ProviderError(e.to_string()))?;
+        // A lagging node may not have the transaction yet.
+        let Some(receipt) = receipt else {
+            return Ok(SourceCheck::Unconfirmed { confirmations: 0 });
+        };
+        if !receipt.status() {
+            return rejected(format!("source transaction {} reverted", origin.tx_hash));
+        }
+        let Some(block_number) = receipt.block_number else {
+            return Ok(SourceCheck::Unconfirmed { confirmations: 0 });
+        };
+        if block_number != origin.block_number {
+            return rejected(format!(
+                "transaction is in block {block_number}, deposit claims {}",
+                origin.block_number
+            ));
+        }
+
+        let Some(log) = receipt
+            .inner
+            .logs()
+            .iter()
+            .find(|log| log.log_index == Some(origin.log_index))
+        else {
+            return rejected(format!("no log at index {}", origin.log_index));
+        };
+        if log.address() != self.contract_address {
+            return rejected(format!(
+                "log was emitted by {}, not the deposit contract",
+                log.address()
+            ));
+        }
+        let topics = log.topics();
+        if topics.len() < 2 || topics[0] != self.event_sig {
+            return rejected("log is not a Deposited event".into());
+        }
+        let on_chain = match push_deposits(vec![log.clone()], Vec::new()).await {
+            Ok(mut deposits) if deposits.len() == 1 => deposits.remove(0),
+            Ok(_) => return rejected("log is not a Deposited event".into()),
+            Err(e) => return rejected(format!("could not decode log: {e}")),
+        };
+        if on_chain.sender != deposit.sender || on_chain.amount != deposit.amount {
+            return rejected(format!(
+                "log records {} from {}, deposit claims {} from {}",
+                on_chain.amount, on_chain.sender, deposit.amount, deposit.sender
+            ));
+        }
+
+        let head = self
+            .provider
+            .get_block_number()
+            .await
+            .map_err(|e| RelayerError::ProviderError(e.to_string()))?;
+        let confirmations = head.saturating_sub(block_number) + 1;
+        debug!(
+            "Deposit in {} has {} confirmations",
+            origin.tx_hash, confirmations
+        );
+        if confirmations < self.min_confirmations {
+            return Ok(SourceCheck::Unconfirmed { confirmations });
+        }
+        Ok(SourceCheck::Verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::envelope::Envelope;
+    use crate::includer::Includer;
+    use crate::metrics::Metrics;
+    use crate::quarantine::Quarantine;
+    use crate::queue::{QueueDelivery, QueueTrait, memory::InMemoryQueue};
+    use crate::schedule::PollSchedule;
+    use crate::subscriber::DepositOrigin;
+    use alloy::dyn_abi::DynSolValue;
+    use alloy::json_abi::JsonAbi;
+    use alloy::primitives::U64;
+    use alloy::providers::{ProviderBuilder, mock::Asserter};
+    use serde_json::{Value, json};
+
+    const CONTRACT: &str = "0x5fbdb2315678afecb367f032d93f642f64180aa3";
+    const SENDER: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+    const TX_HASH: &str = "0x1111111111111111111111111111111111111111111111111111111111111111";
+
+    fn deposit(amount: i32) -> Deposit {
+        Deposit {
+            sender: SENDER.parse().unwrap(),
+            amount,
+            origin: Some(DepositOrigin {
+                tx_hash: TX_HASH.parse().unwrap(),
+                log_index: 3,
+                block_number: 100,
+            }),
+        }
+    }
+
+    /// JSON-RPC receipt holding one `Deposited` log for 42 tokens.
+    fn receipt(status: bool) -> Value {
+        receipt_with_amount(status, "42")
+    }
+
+    fn receipt_with_amount(status: bool, amount: &str) -> Value {
+        let sender: Address = SENDER.parse().unwrap();
+        let topic1 = B256::from_slice(&DynSolValue::Address(sender).abi_encode());
+        let data = DynSolValue::String(amount.into()).abi_encode();
+        json!({
+            "transactionHash": TX_HASH,
+            "transactionIndex": "0x0",
+            "blockHash": B256::repeat_byte(2),
+            "blockNumber": "0x64",
+            "from": SENDER,
+            "to": CONTRACT,
+            "contractAddress": null,
+            "gasUsed": "0x5208",
+            "cumulativeGasUsed": "0x5208",
+            "effectiveGasPrice": "0x1",
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "type": "0x2",
+            "status": if status { "0x1" } else { "0x0" },
+            "logs": [{
+                "address": CONTRACT,
+                "topics": [keccak256(DEPOSIT_EVENT_SIG), topic1],
+                "data": alloy::hex::encode_prefixed(data),
+                "blockHash": B256::repeat_byte(2),
+                "blockNumber": "0x64",
+                "transactionHash": TX_HASH,
+                "transactionIndex": "0x0",
+                "logIndex": "0x3",
+                "removed": false
+            }]
+        })
+    }
+
+    fn verifier(asserter: Asserter) -> SourceVerifier {
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(asserter);
+        SourceVerifier::new(provider, CONTRACT.parse().unwrap()).with_min_confirmations(5)
+    }
+
+    #[tokio::test]
+    async fn test_verified_and_unconfirmed() {
+        let asserter = Asserter::new();
+        asserter.push_success(&receipt(true));
+        asserter.push_success(&U64::from(104));
+        asserter.push_success(&receipt(true));
+        asserter.push_success(&U64::from(101));
+        // Not known to the node yet.
+        asserter.push_success(&Value::Null);
+        let verifier = verifier(asserter);
+
+        assert_eq!(
+            verifier.check(&deposit(42)).await.unwrap(),
+            SourceCheck::Verified
+        );
+        assert_eq!(
+            verifier.check(&deposit(42)).await.unwrap(),
+            SourceCheck::Unconfirmed { confirmations: 2 }
+        );
+        assert_eq!(
+            verifier.check(&deposit(42)).await.unwrap(),
+            SourceCheck::Unconfirmed { confirmations: 0 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejections() {
+        let asserter = Asserter::new();
+        // Amount differs from the log.
+        asserter.push_success(&receipt(true));
+        // Reverted transaction.
+        asserter.push_success(&receipt(false));
+        // Wrong log index.
+        asserter.push_success(&receipt(true));
+        let verifier = verifier(asserter);
+
+        let mut checks = Vec::new();
+        checks.push(verifier.check(&deposit(1_000_000)).await.unwrap());
+        checks.push(verifier.check(&deposit(42)).await.unwrap());
+        let mut wrong_index = deposit(42);
+        wrong_index.origin.as_mut().unwrap().log_index = 4;
+        checks.push(verifier.check(&wrong_index).await.unwrap());
+        for check in checks {
+            assert!(matches!(check, SourceCheck::Rejected(_)), "{check:?}");
+        }
+
+        let mut no_origin = deposit(42);
+        no_origin.origin = None;
+        assert!(matches!(
+            verifier.check(&no_origin).await.unwrap(),
+            SourceCheck::Rejected(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_amount_that_is_not_an_i32_is_rejected() {
+        let asserter = Asserter::new();
+        asserter.push_success(&receipt_with_amount(true, "abc"));
+        asserter.push_success(&receipt_with_amount(true, "1e30"));
+        let verifier = verifier(asserter);
+
+        for _ in 0..2 {
+            let check = verifier.check(&deposit(42)).await.unwrap();
+            assert!(
+                matches!(&check, SourceCheck::Rejected(reason) if reason.contains("not an i32")),
+                "{check:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_includer_holds_unconfirmed_and_quarantines() {
+        let asserter = Asserter::new();
+        // One confirmation, then five on the second check.
+        asserter.push_success(&receipt(true));
+        asserter.push_success(&U64::from(100));
+        asserter.push_success(&receipt(true));
+        asserter.push_success(&U64::from(104));
+        // The forged deposit.
+        asserter.push_success(&receipt(true));
+        // Unknown to the node for longer than the includer waits.
+        asserter.push_success(&Value::Null);
+        let dir = tempfile::tempdir().unwrap();
+        let quarantine = Quarantine::open(dir.path().join("quarantine.jsonl")).unwrap();
+
+        let mut queue = InMemoryQueue::new();
+        for amount in [42, 1_000_000, 42] {
+            let envelope = Envelope::deposit(&deposit(amount), "test").unwrap();
+            queue.publish_envelope(&envelope).await.unwrap();
+        }
+        let mut incl = Includer::with_abi(
+            &test_config(),
+            JsonAbi::default(),
+            queue.clone(),
+            Metrics::new(),
+        )
+        .unwrap()
+        .with_poll_schedule(PollSchedule::new(
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+        ))
+        .with_source_verifier(verifier(asserter), quarantine);
+        let mut consumer = queue.consumer().await.unwrap();
+
+        // Held until confirmed, without going back to the queue.
+        let (received, delivery) = incl.consume(&mut consumer).await.unwrap();
+        let delivery = incl.verify_source(&received, delivery).await.unwrap();
+        assert!(!delivery.redelivered());
+        assert_eq!(queue.ready_len(), 2);
+        delivery.ack().await.unwrap();
+
+        let (forged, delivery) = incl.consume(&mut consumer).await.unwrap();
+        assert_eq!(forged.amount, 1_000_000);
+        let err = incl.verify_source(&forged, delivery).await.err().unwrap();
+        assert!(matches!(err, RelayerError::DepositQuarantined(_)));
+        assert_eq!(queue.dead_letters().len(), 1);
+
+        let (_, quarantine) = incl.source_check.as_ref().unwrap();
+        let entries = quarantine.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].deposit, forged);
+
+        let (verifier, _) = incl.source_check.as_mut().unwrap();
+        verifier.max_wait = Duration::ZERO;
+        let (unknown, delivery) = incl.consume(&mut consumer).await.unwrap();
+        let err = incl.verify_source(&unknown, delivery).await.err().unwrap();
+        assert!(matches!(err, RelayerError::DepositUnconfirmed(0)));
+        assert_eq!(queue.ready_len(), 1);
+        assert_eq!(queue.dead_letters().len(), 1);
+    }
+}
@@ -1,23 +1,32 @@
+use crate::cache::CacheTrait;
+use crate::config::RelayerConfig;
+use crate::cursor::{CursorKey, StartBlock, migrate_legacy_cursor, write_cursor};
+use crate::envelope::Envelope;
 use crate::errors::RelayerError;
-use crate::queue::QueueTrait;
+use crate::health::{Health, RpcProbe};
+use crate::leader::{LEASE_RENEW_INTERVAL, LeaderLease};
+use crate::lifecycle::{DepositState, DepositStateStore};
+use crate::metrics::{
+    BLOCKS_BEHIND_HEAD, DEPOSITS_OBSERVED, DEPOSITS_PUBLISHED, LAST_SCANNED_BLOCK, Metrics,
+    QUEUE_PUBLISH_FAILURES, rpc_client,
+};
+use crate::queue::{BatchReport, QueueTrait};
+use crate::schedule::PollSchedule;
+use crate::shutdown::Shutdown;
+use crate::signing::MessageSigner;
 use crate::utils::push_deposits;
 use alloy::{
     dyn_abi::{DynSolType, DynSolValue},
     primitives::{Address, B256, FixedBytes, keccak256},
     providers::{
-        Identity, Provider, RootProvider,
+        Identity, Provider, ProviderBuilder, RootProvider,
         fillers::{BlobGasFiller, ChainIdFiller, FillProvider, GasFiller, JoinFill, NonceFiller},
     },
     rpc::types::{Filter, Log},
 };
-use async_trait::async_trait;
 use eyre::Result;
-use lapin::Queue;
-use mockall::predicate::*;
-use redis::{AsyncCommands, Client, aio::MultiplexedConnection}; // make connection pool at some point
 use serde::{Deserialize, Serialize};
-use std::{thread, time};
-use tracing::{debug, error, info};
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
 pub type ProviderType = FillProvider<
     JoinFill<
         Identity,
@@ -31,63 +40,95 @@ pub type ProviderType = FillProvider<
 pub struct Deposit {
     pub sender: Address,
     pub amount: i32,
+    /// Where the `Deposited` log was seen on the source chain. Missing for
+    /// messages published by older subscribers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<DepositOrigin>,
 }
 
-pub struct Subscriber<C: QueueTrait, R: CacheTrait> {
-    pub contract_address: Address,
-    pub provider: ProviderType,
-    pub event_sig: FixedBytes<32>,
-    pub queue_connection: C,
-    pub cache_connection: R,
-}
-#[cfg_attr(test, mockall::automock)]
-#[async_trait]
-pub trait CacheTrait {
-    async fn get_last_offset(&mut self, key: &str) -> Result<u64, RelayerError>;
-    async fn set_last_offset(&mut self, key: &str, value: u64) -> Result<(), RelayerError>;
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct DepositOrigin {
+    pub tx_hash: B256,
+    pub log_index: u64,
+    pub block_number: u64,
 }
 
-pub struct RedisCache {
-    connection: MultiplexedConnection,
-}
+impl Deposit {
+    /// Identifies the deposit by its source log, `{tx_hash}:{log_index}`.
+    pub fn id(&self) -> Option<String> {
+        self.origin
+            .map(|origin| format!("{}:{}", origin.tx_hash, origin.log_index))
+    }
 
-impl RedisCache {
-    pub async fn new(db_url: String) -> Result<Self, RelayerError> {
-        let client = Client::open(db_url).map_err(|e| RelayerError::RedisError(e.to_string()))?;
-        let connection = client
-            .get_multiplexed_async_connection()
-            .await
-            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
-        Ok(RedisCache { connection })
+    /// `id`, or for a deposit without an origin the hash of `message`, the
+    /// queue message carrying it. The includer's stores key on this.
+    pub fn id_or_hash(&self, message: &[u8]) -> String {
+        self.id().unwrap_or_else(|| keccak256(message).to_string())
     }
-}
 
-#[async_trait]
-impl CacheTrait for RedisCache {
-    async fn get_last_offset(&mut self, key: &str) -> Result<u64, RelayerError> {
-        let mut from_block: u64 = 0;
-        let from_block_response: Option<u64> = self
-            .connection
-            .get(key)
-            .await
-            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
-        if from_block_response.is_some() {
-            from_block = from_block_response.unwrap();
+    /// Span correlating the log lines of this deposit in both binaries. The
+    /// includer records `dst_tx` once the mint is sent.
+    pub fn span(&self, id: Option<&str>) -> Span {
+        let span = info_span!(
+            "deposit",
+            deposit_id = field::Empty,
+            src_tx = field::Empty,
+            dst_tx = field::Empty
+        );
+        if let Some(id) = id {
+            span.record("deposit_id", id);
         }
-        Ok(from_block)
+        if let Some(origin) = self.origin {
+            span.record("src_tx", field::display(origin.tx_hash));
+        }
+        span
     }
+}
 
-    async fn set_last_offset(&mut self, key: &str, value: u64) -> Result<(), RelayerError> {
-        let _res: () = self
-            .connection
-            .set(key, value)
-            .await
-            .map_err(|e| RelayerError::RedisError(e.to_string()))?;
-        Ok(())
+impl DepositOrigin {
+    pub fn from_log(log: &Log) -> Option<Self> {
+        Some(DepositOrigin {
+            tx_hash: log.transaction_hash?,
+            log_index: log.log_index?,
+            block_number: log.block_number?,
+        })
     }
 }
 
-const DEPOSIT_EVENT_SIG: &str = "Deposited(address,string)";
+pub struct Subscriber<C: QueueTrait, R: CacheTrait> {
+    pub contract_address: Address,
+    pub provider: ProviderType,
+    pub event_sig: FixedBytes<32>,
+    pub queue_connection: C,
+    pub cache_connection: R,
+    /// Signs every published envelope when set.
+    pub signer: Option<MessageSigner>,
+    /// Looked up with `eth_chainId` on first use when not set.
+    pub chain_id: Option<u64>,
+    /// Used only when the route has no cursor yet.
+    pub start_block: StartBlock,
+    /// When set, only the replica holding the lease scans.
+    pub leader_lease: Option<Box<dyn LeaderLease>>,
+    /// Fencing token of the lease while this replica leads.
+    fence: Option<u64>,
+    /// Newer token the store refused a write against, handed to the lease
+    /// on the next acquisition.
+    fenced_by: Option<u64>,
+    cursor_key: Option<String>,
+    /// Where deposit lifecycles are recorded, if anywhere.
+    pub states: Option<Box<dyn DepositStateStore>>,
+    /// `run` returns once this is triggered.
+    pub shutdown: Shutdown,
+    /// When `run` scans next.
+    pub schedule: PollSchedule,
+    pub metrics: Metrics,
+    /// Ticked every round of `run`, with the cache, the queue and the lag.
+    pub health: Health,
+}
+pub const DEPOSIT_EVENT_SIG: &str = "Deposited(address,string)";
+pub const DEPOSIT_EVENT_NAME: &str = "Deposited";
+/// Blocks per `eth_getLogs` call during a backfill.
+pub const BACKFILL_CHUNK: u64 = 10_000;
 
 impl<C: QueueTrait, R: CacheTrait> Subscriber<C, R> {
     pub async fn new(
@@ -104,9 +145,178 @@ impl<C: QueueTrait, R: CacheTrait> Subscriber<C, R> {
             event_sig,
             queue_connection,
             cache_connection,
+            signer: None,
+            chain_id: None,
+            start_block: StartBlock::default(),
+            leader_lease: None,
+            states: None,
+            shutdown: Shutdown::new(),
+            schedule: PollSchedule::default(),
+            metrics: Metrics::new(),
+            health: Health::new(),
+            fence: None,
+            fenced_by: None,
+            cursor_key: None,
         })
     }
 
+    /// Builds the subscriber for the `source` chain and deposit contract,
+    /// polling at the chain's interval and recording into `metrics`.
+    pub async fn from_config(
+        config: &RelayerConfig,
+        queue_connection: C,
+        cache_connection: R,
+        metrics: Metrics,
+    ) -> Result<Self, RelayerError> {
+        let source = config.source()?;
+        let client = rpc_client(source.rpc_url.clone(), &metrics, "source");
+        let provider: ProviderType = ProviderBuilder::new().on_client(client);
+        let mut sub = Self::new(
+            config.source_contract()?,
+            queue_connection,
+            cache_connection,
+            provider,
+        )
+        .await?;
+        sub.metrics = metrics;
+        Ok(sub.with_poll_schedule(source.poll_schedule()))
+    }
+
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = Some(chain_id);
+        self
+    }
+
+    pub fn with_start_block(mut self, start_block: StartBlock) -> Self {
+        self.start_block = start_block;
+        self
+    }
+
+    pub fn with_leader_lease(mut self, lease: Option<Box<dyn LeaderLease>>) -> Self {
+        self.leader_lease = lease;
+        self
+    }
+
+    pub fn with_state_store(mut self, states: Option<Box<dyn DepositStateStore>>) -> Self {
+        self.states = states;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_poll_schedule(mut self, schedule: PollSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Reports into `health`, which also probes the source provider.
+    pub fn with_health(mut self, health: Health) -> Self {
+        health.add_probe("source_rpc", RpcProbe(self.provider.clone()));
+        health.tick("subscriber");
+        self.health = health;
+        self
+    }
+
+    /// Records a lifecycle transition. Failures are logged, never fatal.
+    async fn record_state(&mut self, deposit: &Deposit, state: DepositState) {
+        let (Some(states), Some(id)) = (self.states.as_mut(), deposit.id()) else {
+            return;
+        };
+        if let Err(e) = states.transition(&id, state, "subscriber").await {
+            warn!("Could not record state of deposit {}: {:?}", id, e);
+        }
+    }
+
+    /// Acquires or renews the leader lease. Always true without a lease.
+    pub async fn ensure_leadership(&mut self) -> bool {
+        let Some(lease) = self.leader_lease.as_mut() else {
+            return true;
+        };
+        if let Some(current) = self.fenced_by.take()
+            && let Err(e) = lease.fenced_out(current).await
+        {
+            warn!(
+                "Could not move leader lease past token {}: {:?}",
+                current, e
+            );
+        }
+        match lease.acquire().await {
+            Ok(Some(token)) => {
+                if self.fence != Some(token) {
+                    info!("Became leader with fencing token {}", token);
+                }
+                self.fence = Some(token);
+                true
+            }
+            Ok(None) => {
+                if self.fence.take().is_some() {
+                    warn!("Lost leadership, standing by");
+                } else {
+                    debug!("Another replica leads, standing by");
+                }
+                false
+            }
+            Err(e) => {
+                warn!("Could not renew leader lease: {:?}", e);
+                self.fence = None;
+                false
+            }
+        }
+    }
+
+    async fn write_cursor(&mut self, key: &str, value: u64) -> Result<(), RelayerError> {
+        let res = write_cursor(&mut self.cache_connection, key, value, self.fence).await;
+        if let Err(RelayerError::FencedOut { current, .. }) = &res {
+            error!("Cursor write for {} was fenced off, stepping down", key);
+            self.fence = None;
+            self.fenced_by = Some(*current);
+        }
+        res
+    }
+
+    /// Cache key holding this subscriber's cursor. Resolved once, migrating
+    /// the legacy `from_block` value into it or seeding it from
+    /// `start_block` on the way.
+    pub async fn cursor_key(&mut self) -> Result<String, RelayerError> {
+        if let Some(key) = &self.cursor_key {
+            return Ok(key.clone());
+        }
+        let chain_id = match self.chain_id {
+            Some(chain_id) => chain_id,
+            None => self
+                .provider
+                .get_chain_id()
+                .await
+                .map_err(|e| RelayerError::ProviderError(e.to_string()))?,
+        };
+        self.chain_id = Some(chain_id);
+        let key = CursorKey::new(chain_id, self.contract_address, DEPOSIT_EVENT_NAME).to_string();
+        migrate_legacy_cursor(&mut self.cache_connection, &key, self.fence).await?;
+        if self.start_block != StartBlock::default()
+            && self.cache_connection.get_offset(&key).await?.is_none()
+        {
+            let cursor = self
+                .start_block
+                .initial_cursor(&self.provider, self.contract_address)
+                .await?;
+            info!(
+                "No cursor for {}, starting after block {} ({:?})",
+                key, cursor, self.start_block
+            );
+            self.write_cursor(&key, cursor).await?;
+        }
+        self.cursor_key = Some(key.clone());
+        Ok(key)
+    }
+
+    pub fn with_signer(mut self, signer: Option<MessageSigner>) -> Self {
+        self.signer = signer;
+        self
+    }
+
     pub async fn get_deposits(
         &mut self,
         from_block: u64,
@@ -115,13 +325,18 @@ impl<C: QueueTrait, R: CacheTrait> Subscriber<C, R> {
         if from_block >= to_block {
             return Err(RelayerError::Other(String::from("No blocks to scan")));
         }
+        self.scan(from_block + 1, to_block).await
+    }
+
+    /// Deposits emitted in `first..=last`.
+    async fn scan(&mut self, first: u64, last: u64) -> Result<Vec<Deposit>, RelayerError> {
         let deposits = Vec::new();
         let filter = Filter::new()
             .address(self.contract_address)
-            .from_block(from_block + 1)
-            .to_block(to_block);
+            .from_block(first)
+            .to_block(last);
 
-        info!("Scanning from {} to {to_block}...", from_block + 1);
+        info!("Scanning from {first} to {last}...");
         debug!("Filter topic0: {:?}", B256::from(self.event_sig));
 
         let logs = self
@@ -143,7 +358,10 @@ impl<C: QueueTrait, R: CacheTrait> Subscriber<C, R> {
         mut deposits: Vec<Deposit>,
     ) -> Result<Vec<Deposit>, RelayerError> {
         for log in logs {
-            println!("Transfer event: {log:?}");
+            debug!(
+                "Deposit log {:?} in tx {:?}",
+                log.log_index, log.transaction_hash
+            );
             let topics = log.topics();
             let raw_topic = topics.get(1).expect("Expected at least 2 topics");
 
@@ -164,72 +382,399 @@ impl<C: QueueTrait, R: CacheTrait> Subscriber<C, R> {
                 _ => return Err(RelayerError::NotString),
             };
 
-            let amount = amount_str.parse::<i32>().unwrap();
+            let amount = amount_str
+                .parse::<i32>()
+                .map_err(|_| RelayerError::InvalidAmount(amount_str.clone()))?;
 
-            deposits.push(Deposit { sender, amount });
+            let origin = DepositOrigin::from_log(&log);
+            deposits.push(Deposit {
+                sender,
+                amount,
+                origin,
+            });
         }
         Ok(deposits)
     }
 
-    pub async fn run(&mut self) {
+    /// Publishes the deposits emitted in `from..=to` again, leaving the
+    /// cursor alone. Deposits whose mint was already sent or confirmed are
+    /// skipped. The range is scanned in chunks of `BACKFILL_CHUNK` blocks.
+    /// Returns how many deposits were published.
+    ///
+    /// With a lease, backfilling takes it like a running subscriber would,
+    /// so the running subscriber has to be stopped first.
+    pub async fn backfill(&mut self, from: u64, to: u64) -> Result<usize, RelayerError> {
+        if from > to {
+            return Err(RelayerError::Other(format!(
+                "Backfill range {from}..={to} is empty"
+            )));
+        }
+        let res = self.backfill_chunks(from, to).await;
+        self.release_lease().await;
+        res
+    }
+
+    async fn backfill_chunks(&mut self, from: u64, to: u64) -> Result<usize, RelayerError> {
+        let mut published = 0;
+        let mut first = from;
         loop {
-            if let Err(e) = self.work().await {
-                error!("Error: {:?}", e);
+            if !self.ensure_leadership().await {
+                return Err(RelayerError::Other(String::from(
+                    "Another subscriber holds the leader lease, stop it before backfilling",
+                )));
+            }
+            let last = first.saturating_add(BACKFILL_CHUNK - 1).min(to);
+            let deposits = self.scan(first, last).await?;
+            let deposits = self.unminted(deposits).await?;
+            let report = self.publish_deposits(&deposits).await?;
+            published += report.confirmed();
+            if !report.is_success() {
+                return Err(RelayerError::Other(format!(
+                    "{} of {} deposits in blocks {first}..={last} failed to publish",
+                    report.failed.len(),
+                    report.total
+                )));
+            }
+            if last == to {
+                break;
+            }
+            first = last + 1;
+        }
+        info!(
+            "Backfilled {} deposits from blocks {from}..={to}",
+            published
+        );
+        Ok(published)
+    }
+
+    /// Drops the deposits whose recorded state is past `Published`: their
+    /// mint is sent or confirmed, and publishing them again could mint
+    /// them twice.
+    async fn unminted(&mut self, deposits: Vec<Deposit>) -> Result<Vec<Deposit>, RelayerError> {
+        let Some(states) = self.states.as_mut() else {
+            return Ok(deposits);
+        };
+        let mut unminted = Vec::with_capacity(deposits.len());
+        for dep in deposits {
+            if let Some(id) = dep.id()
+                && let Some(record) = states.get(&id).await?
+                && let Some(
+                    state @ (DepositState::MintSubmitted { .. }
+                    | DepositState::MintConfirmed { .. }),
+                ) = record.state()
+            {
+                info!("Skipping deposit {}, already {}", id, state);
+                continue;
             }
-            let two_sec = time::Duration::from_millis(2000);
-            thread::sleep(two_sec);
+            unminted.push(dep);
         }
+        Ok(unminted)
     }
 
-    async fn work(&mut self) -> Result<(), RelayerError> {
-        let from_block = self.cache_connection.get_last_offset("from_block").await?;
+    /// Scans and publishes until `shutdown` is triggered, following
+    /// `schedule`: scans that publish keep the base interval, empty or
+    /// failed ones back off. With a lease, it wakes up in between to renew
+    /// it, and renews it while a scan runs; a scan is abandoned as soon as
+    /// the lease is lost, so a long one never outlives its leadership.
+    ///
+    /// A scan in progress at shutdown gets until the grace deadline to
+    /// publish and write its cursor; one abandoned there is scanned again
+    /// on the next start. The lease is then released and the queue
+    /// connection closed.
+    pub async fn run(&mut self) {
+        let shutdown = self.shutdown.clone();
+        while !shutdown.is_triggered() {
+            self.health.tick("subscriber");
+            let leading = self.ensure_leadership().await;
+            if leading && self.schedule.is_due() {
+                let mut lease = self.leader_lease.take();
+                let fence = self.fence;
+                let outcome = tokio::select! {
+                    res = self.work() => Some(res),
+                    _ = hold_lease(&mut lease, fence) => {
+                        warn!("Lost leadership during a scan, abandoning it");
+                        Some(Err(RelayerError::Other(String::from("Leadership lost"))))
+                    }
+                    _ = shutdown.deadline() => {
+                        warn!(
+                            "Scan still in progress {:?} after shutdown, abandoning it",
+                            shutdown.grace()
+                        );
+                        None
+                    }
+                };
+                self.leader_lease = lease;
+                match outcome {
+                    Some(Ok(0)) => self.schedule.idle(),
+                    Some(Ok(_)) => self.schedule.busy(),
+                    Some(Err(e)) => {
+                        error!("Error: {:?}", e);
+                        self.schedule.idle();
+                    }
+                    None => break,
+                }
+            }
+            let mut wait = if leading {
+                self.schedule.until_due()
+            } else {
+                self.schedule.interval
+            };
+            if self.leader_lease.is_some() {
+                wait = wait.min(LEASE_RENEW_INTERVAL);
+            }
+            shutdown.sleep(wait).await;
+        }
+        self.stop().await;
+    }
+
+    /// Releases the lease, so a standby takes over at once, and closes the
+    /// queue connection.
+    async fn stop(&mut self) {
+        self.release_lease().await;
+        if let Err(e) = self.queue_connection.close().await {
+            warn!("Could not close queue connection: {:?}", e);
+        }
+        info!("Subscriber stopped");
+    }
+
+    async fn release_lease(&mut self) {
+        if self.fence.take().is_some()
+            && let Some(lease) = self.leader_lease.as_mut()
+            && let Err(e) = lease.release().await
+        {
+            warn!("Could not release leader lease: {:?}", e);
+        }
+    }
+
+    /// Last block whose deposits were all published. On a failure the cursor
+    /// stops before the failed deposit's block, so that block is scanned
+    /// again: deposits of that block published before the failure, and with
+    /// AMQP any sent after a nacked one, are published twice. The includer
+    /// acks those without minting once the first copy is minted.
+    fn cursor_after(
+        from_block: u64,
+        to_block: u64,
+        deposits: &[Deposit],
+        report: &BatchReport,
+    ) -> u64 {
+        match report.first_failure() {
+            None => to_block,
+            Some(index) => match deposits[index].origin {
+                Some(origin) => origin.block_number.saturating_sub(1).max(from_block),
+                None => from_block,
+            },
+        }
+    }
+
+    /// Publishes the deposits of the blocks after the cursor and moves it.
+    /// Returns how many deposits were published.
+    async fn work(&mut self) -> Result<usize, RelayerError> {
+        let cursor_key = self.cursor_key().await?;
+        let from_block = self.cache_connection.get_last_offset(&cursor_key).await;
+        self.health.report("cache", &from_block);
+        let from_block = from_block?;
         let to_block = self
             .provider
             .get_block_number()
             .await
             .map_err(|e| RelayerError::ProviderError(e.to_string()))?;
+        let lag = to_block.saturating_sub(from_block);
+        self.metrics.set(&BLOCKS_BEHIND_HEAD, &[], lag as f64);
+        self.health.set_lag(lag);
+        if to_block <= from_block {
+            debug!("No new blocks after {}", from_block);
+            return Ok(0);
+        }
         let deposits = self.get_deposits(from_block, to_block).await?;
-        if let Err(e) = self
-            .cache_connection
-            .set_last_offset("from_block", to_block)
-            .await
-        {
-            error!("Failed to set last_offset: {:?}", e);
-        } else {
-            debug!("last_offset updated successfully");
+        let report = self.publish_deposits(&deposits).await?;
+        let cursor = Self::cursor_after(from_block, to_block, &deposits, &report);
+        if cursor > from_block {
+            if let Err(e) = self.write_cursor(&cursor_key, cursor).await {
+                error!("Failed to set last_offset: {:?}", e);
+            } else {
+                debug!("last_offset updated successfully");
+                self.metrics.set(&LAST_SCANNED_BLOCK, &[], cursor as f64);
+            }
         }
+        if !report.is_success() {
+            return Err(RelayerError::Other(format!(
+                "{} of {} deposits failed to publish",
+                report.failed.len(),
+                report.total
+            )));
+        }
+        info!("Published {} deposits", report.confirmed());
+        Ok(report.confirmed())
+    }
+
+    /// Signs and publishes the deposits as one batch, recording each one as
+    /// observed and, once confirmed by the queue, as published.
+    async fn publish_deposits(
+        &mut self,
+        deposits: &[Deposit],
+    ) -> Result<BatchReport, RelayerError> {
+        let source = format!("subscriber:{}", self.contract_address);
+        let mut envelopes = Vec::with_capacity(deposits.len());
         for dep in deposits {
-            let serialized_deposit = serde_json::to_vec(&dep).map_err(RelayerError::SerdeError)?;
-            info!("Event emitted from sender: {:?}", dep.sender);
-            match self.queue_connection.publish(&serialized_deposit).await {
-                Ok(_) => {
-                    info!("Successfully processed deposit");
-                }
-                Err(e) => {
-                    error!("Error processing deposit: {:?}", e);
-                    return Err(RelayerError::Other(e.to_string()));
-                }
+            let mut envelope = Envelope::deposit(dep, &source)?;
+            if let Some(signer) = &self.signer {
+                signer.sign(&mut envelope)?;
+            }
+            let span = dep.span(dep.id().as_deref());
+            span.in_scope(|| info!("Event emitted from sender: {:?}", dep.sender));
+            envelopes.push(envelope);
+            self.record_state(dep, DepositState::Observed)
+                .instrument(span)
+                .await;
+        }
+
+        let report = self.queue_connection.publish_batch(&envelopes).await;
+        if !deposits.is_empty() {
+            let outcome = match report.failed.first() {
+                Some(failure) => Err(failure.error.to_string()),
+                None => Ok(()),
+            };
+            self.health.report("queue", &outcome);
+        }
+        self.metrics
+            .add(&DEPOSITS_OBSERVED, &[], deposits.len() as f64);
+        self.metrics
+            .add(&DEPOSITS_PUBLISHED, &[], report.confirmed() as f64);
+        self.metrics
+            .add(&QUEUE_PUBLISH_FAILURES, &[], report.failed.len() as f64);
+        for failure in &report.failed {
+            let dep = &deposits[failure.index];
+            let span = dep.span(dep.id().as_deref());
+            span.in_scope(|| error!("Error publishing deposit {:?}: {:?}", dep, failure.error));
+            // Scanned and published again on the next run.
+            let reason = failure.error.to_string();
+            self.record_state(dep, DepositState::Failed { reason })
+                .instrument(span)
+                .await;
+        }
+        for (index, dep) in deposits.iter().enumerate() {
+            if !report.failed.iter().any(|f| f.index == index) {
+                self.record_state(dep, DepositState::Published)
+                    .instrument(dep.span(dep.id().as_deref()))
+                    .await;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Renews `lease` every `LEASE_RENEW_INTERVAL` while a scan runs, and
+/// returns once it is lost or was taken over since `fence` was handed out.
+/// Never returns without a lease.
+async fn hold_lease(lease: &mut Option<Box<dyn LeaderLease>>, fence: Option<u64>) {
+    let Some(lease) = lease.as_mut() else {
+        return std::future::pending().await;
+    };
+    loop {
+        tokio::time::sleep(LEASE_RENEW_INTERVAL).await;
+        match lease.acquire().await {
+            Ok(token) if token.is_some() && token == fence => debug!("Renewed leader lease"),
+            Ok(_) => return,
+            Err(e) => {
+                warn!("Could not renew leader lease: {:?}", e);
+                return;
             }
         }
-        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use alloy::providers::{ProviderBuilder, mock::Asserter};
+    use alloy::{
+        primitives::{Bytes, Log as RawLog, LogData, U64},
+        providers::{ProviderBuilder, mock::Asserter},
+    };
 
     use crate::{
-        queue::{self, LapinConnection},
+        cache::{MockCacheTrait, memory::InMemoryCache},
+        leader::FileLease,
+        queue::{
+            self, LapinConnection, QueueConsumer, QueueDelivery, QueueTopology,
+            memory::InMemoryQueue,
+        },
         utils::get_src_contract_addr,
     };
 
     use super::*;
+    use crate::config::test_config;
+    use crate::cursor::LEGACY_CURSOR_KEY;
+    use crate::includer::Includer;
+    use crate::lifecycle::InMemoryStateStore;
+    use alloy::contract::{ContractInstance, Interface};
+    use alloy::json_abi::JsonAbi;
+    use alloy::network::EthereumWallet;
+    use alloy::signers::local::PrivateKeySigner;
+    use mockall::predicate::eq;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn deposit_log(sender: Address, amount: &str) -> Log {
+        let topic0 = keccak256(DEPOSIT_EVENT_SIG);
+        let topic1 = B256::from_slice(&DynSolValue::Address(sender).abi_encode());
+        let data: Bytes = DynSolValue::String(amount.to_string()).abi_encode().into();
+        Log {
+            inner: RawLog {
+                address: Address::default(),
+                data: LogData::new_unchecked(vec![topic0, topic1], data),
+            },
+            block_hash: None,
+            block_number: None,
+            block_timestamp: None,
+            transaction_hash: None,
+            transaction_index: None,
+            log_index: None,
+            removed: false,
+        }
+    }
+
+    fn deposit_log_at(sender: Address, amount: &str, block: u64) -> Log {
+        Log {
+            block_number: Some(block),
+            transaction_hash: Some(B256::repeat_byte(block as u8)),
+            log_index: Some(0),
+            ..deposit_log(sender, amount)
+        }
+    }
+
+    /// In-memory queue that refuses the publishes at the given positions.
+    struct FlakyQueue {
+        inner: InMemoryQueue,
+        fail_at: Vec<usize>,
+        published: usize,
+    }
+
+    #[async_trait::async_trait]
+    impl QueueTrait for FlakyQueue {
+        type Consumer = <InMemoryQueue as QueueTrait>::Consumer;
+
+        async fn publish(&mut self, dep: &[u8]) -> Result<(), RelayerError> {
+            let attempt = self.published;
+            self.published += 1;
+            if self.fail_at.contains(&attempt) {
+                return Err(RelayerError::Other(String::from("refused")));
+            }
+            self.inner.publish(dep).await
+        }
+
+        async fn consumer(&mut self) -> Result<Self::Consumer, RelayerError> {
+            self.inner.consumer().await
+        }
+    }
 
     async fn setup_tests() -> (ProviderType, LapinConnection, MockCacheTrait) {
         let asserter = Asserter::new();
         let provider: ProviderType = ProviderBuilder::new().on_mocked_client(asserter);
-        let queue_connection = queue::get_queue_connection(true).await.unwrap();
+        dotenv::dotenv().ok();
+        let config = RelayerConfig::from_toml("", |name| std::env::var(name).ok()).unwrap();
+        let queue_connection =
+            queue::get_queue_connection(&config, QueueTopology::throwaway("test_relayer"))
+                .await
+                .unwrap();
         let cache_connection = MockCacheTrait::new();
         (provider, queue_connection, cache_connection)
     }
@@ -269,6 +814,551 @@ mod tests {
         let res = sub.get_deposits(0, 100).await;
         assert!(res.is_err());
     }
+
+    #[tokio::test]
+    async fn test_work_publishes_to_in_memory_queue() {
+        let sender: Address = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
+            .parse()
+            .unwrap();
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(31337));
+        asserter.push_success(&U64::from(10));
+        asserter.push_success(&vec![deposit_log(sender, "42")]);
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(asserter);
+
+        let key = "relayer:31337:0x0000000000000000000000000000000000000000:Deposited:cursor";
+        let mut cache_connection = MockCacheTrait::new();
+        cache_connection
+            .expect_get_offset()
+            .with(eq(key))
+            .once()
+            .returning(|_| Ok(None));
+        cache_connection
+            .expect_get_offset()
+            .with(eq(LEGACY_CURSOR_KEY))
+            .once()
+            .returning(|_| Ok(None));
+        cache_connection
+            .expect_get_last_offset()
+            .with(eq(key))
+            .once()
+            .returning(|_| Ok(0));
+        cache_connection
+            .expect_set_last_offset()
+            .with(eq(key), eq(10))
+            .once()
+            .returning(|_, _| Ok(()));
+
+        let queue = InMemoryQueue::new();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            queue.clone(),
+            cache_connection,
+            provider,
+        )
+        .await
+        .unwrap();
+        assert_eq!(sub.work().await.unwrap(), 1);
+        assert_eq!(queue.ready_len(), 1);
+
+        let delivery = queue
+            .clone()
+            .consumer()
+            .await
+            .unwrap()
+            .next_delivery()
+            .await;
+        let envelope = Envelope::from_bytes(delivery.unwrap().unwrap().data()).unwrap();
+        assert_eq!(
+            envelope.into_deposit().unwrap(),
+            Deposit {
+                sender,
+                amount: 42,
+                origin: None
+            }
+        );
+    }
+
+    /// The whole flow without brokers: the subscriber publishes a deposit
+    /// into an in-memory queue and the includer mints it on a mocked chain.
+    #[tokio::test]
+    async fn test_deposit_flows_from_subscriber_to_includer() {
+        let sender: Address = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
+            .parse()
+            .unwrap();
+        let source = Asserter::new();
+        source.push_success(&U64::from(10));
+        source.push_success(&vec![deposit_log_at(sender, "42", 5)]);
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(source);
+
+        let queue = InMemoryQueue::new();
+        let mut states = InMemoryStateStore::new();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            queue.clone(),
+            InMemoryCache::new(),
+            provider,
+        )
+        .await
+        .unwrap()
+        .with_chain_id(1)
+        .with_state_store(Some(Box::new(states.clone())));
+        assert_eq!(sub.work().await.unwrap(), 1);
+        assert_eq!(queue.ready_len(), 1);
+
+        let hash = B256::repeat_byte(1);
+        let destination = Asserter::new();
+        destination.push_success(&U64::from(21000));
+        destination.push_success(&json!({
+            "oldestBlock": "0x1",
+            "baseFeePerGas": ["0x1", "0x1"],
+            "gasUsedRatio": [0.5],
+            "reward": [["0x1"]]
+        }));
+        destination.push_success(&U64::from(7));
+        destination.push_success(&U64::from(31337));
+        destination.push_success(&hash);
+        destination.push_success(&json!({
+            "transactionHash": hash,
+            "transactionIndex": "0x0",
+            "blockHash": B256::repeat_byte(2),
+            "blockNumber": "0x64",
+            "from": Address::default(),
+            "to": Address::default(),
+            "contractAddress": null,
+            "gasUsed": "0x5208",
+            "cumulativeGasUsed": "0x5208",
+            "effectiveGasPrice": "0x1",
+            "logsBloom": format!("0x{}", "00".repeat(256)),
+            "type": "0x2",
+            "status": "0x1",
+            "logs": [{
+                "address": Address::default(),
+                "topics": [keccak256("Minted(address,string)")],
+                "data": "0x",
+                "blockHash": B256::repeat_byte(2),
+                "blockNumber": "0x64",
+                "transactionHash": hash,
+                "transactionIndex": "0x0",
+                "logIndex": "0x0",
+                "removed": false
+            }]
+        }));
+        let abi = JsonAbi::parse(["function mint(string amount)"]).unwrap();
+        let mut incl = Includer::with_abi(&test_config(), abi, queue.clone(), Metrics::new())
+            .unwrap()
+            .with_state_store(Some(Box::new(states.clone())));
+        let pk: PrivateKeySigner =
+            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+                .parse()
+                .unwrap();
+        incl.provider = ProviderBuilder::new()
+            .wallet(EthereumWallet::from(pk))
+            .on_mocked_client(destination);
+        incl.contract = ContractInstance::new(
+            *incl.contract.address(),
+            incl.provider.clone(),
+            Interface::new(incl.contract.abi().clone()),
+        );
+
+        let mut consumer = queue.clone().consumer().await.unwrap();
+        assert!(incl.process_deposit(&mut consumer).await.unwrap());
+        assert_eq!(queue.ready_len(), 0);
+        assert_eq!(queue.unacked_len(), 0);
+        assert!(queue.dead_letters().is_empty());
+
+        let deposit = Deposit {
+            sender,
+            amount: 42,
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(5),
+                log_index: 0,
+                block_number: 5,
+            }),
+        };
+        let record = states.get(&deposit.id().unwrap()).await.unwrap().unwrap();
+        let history: Vec<_> = record.history.iter().map(|t| t.state.clone()).collect();
+        assert_eq!(
+            history,
+            vec![
+                DepositState::Observed,
+                DepositState::Published,
+                DepositState::MintSubmitted { tx_hash: hash },
+                DepositState::MintConfirmed { tx_hash: hash },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rescan_leaves_state_alone() {
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(Asserter::new());
+        let mut states = InMemoryStateStore::new();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            InMemoryQueue::new(),
+            InMemoryCache::new(),
+            provider,
+        )
+        .await
+        .unwrap()
+        .with_state_store(Some(Box::new(states.clone())));
+        let deposit = |block: u8| Deposit {
+            sender: Address::default(),
+            amount: 42,
+            origin: Some(DepositOrigin {
+                tx_hash: B256::repeat_byte(block),
+                log_index: 0,
+                block_number: block as u64,
+            }),
+        };
+        let hash = B256::repeat_byte(9);
+        let minting = deposit(6).id().unwrap();
+        for state in [
+            DepositState::Observed,
+            DepositState::Published,
+            DepositState::MintSubmitted { tx_hash: hash },
+        ] {
+            states.transition(&minting, state, "test").await.unwrap();
+        }
+
+        let deposits = [deposit(5), deposit(6)];
+        sub.publish_deposits(&deposits).await.unwrap();
+        let published = states.get(&deposit(5).id().unwrap()).await.unwrap();
+        let rescanned = sub.publish_deposits(&deposits).await.unwrap();
+        assert!(rescanned.is_success());
+
+        // Neither the published deposit nor the one being minted moves.
+        let record = states.get(&deposit(5).id().unwrap()).await.unwrap();
+        assert_eq!(record, published);
+        let record = states.get(&minting).await.unwrap().unwrap();
+        assert_eq!(record.history.len(), 3);
+        assert_eq!(
+            record.state(),
+            Some(&DepositState::MintSubmitted { tx_hash: hash })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_work_stops_cursor_before_failed_publish() {
+        let sender = Address::default();
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(20));
+        asserter.push_success(&vec![
+            deposit_log_at(sender, "1", 5),
+            deposit_log_at(sender, "2", 7),
+            deposit_log_at(sender, "3", 9),
+        ]);
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(asserter);
+
+        let mut cache_connection = MockCacheTrait::new();
+        cache_connection
+            .expect_get_offset()
+            .returning(|_| Ok(Some(2)));
+        cache_connection
+            .expect_get_last_offset()
+            .returning(|_| Ok(2));
+        cache_connection
+            .expect_set_last_offset()
+            .with(
+                eq("relayer:1:0x0000000000000000000000000000000000000000:Deposited:cursor"),
+                eq(6),
+            )
+            .once()
+            .returning(|_, _| Ok(()));
+
+        let queue = InMemoryQueue::new();
+        let flaky = FlakyQueue {
+            inner: queue.clone(),
+            fail_at: vec![1],
+            published: 0,
+        };
+        let mut states = InMemoryStateStore::new();
+        let mut sub = Subscriber::new(Address::default(), flaky, cache_connection, provider)
+            .await
+            .unwrap()
+            .with_chain_id(1)
+            .with_state_store(Some(Box::new(states.clone())));
+        let err = sub.work().await.unwrap_err();
+        // Publishing stops at the failure, so block 9 is not sent now and
+        // again on the rescan.
+        assert!(err.to_string().contains("2 of 3 deposits failed"));
+        assert_eq!(queue.ready_len(), 1);
+        assert_eq!(sub.metrics.get(&DEPOSITS_OBSERVED, &[]), Some(3.0));
+        assert_eq!(sub.metrics.get(&DEPOSITS_PUBLISHED, &[]), Some(1.0));
+        assert_eq!(sub.metrics.get(&QUEUE_PUBLISH_FAILURES, &[]), Some(2.0));
+
+        // The deposits that were not published show as failed until the
+        // rescan publishes them.
+        let id = format!("{}:0", B256::repeat_byte(5));
+        let record = states.get(&id).await.unwrap().unwrap();
+        assert_eq!(record.state(), Some(&DepositState::Published));
+        for block in [7, 9] {
+            let id = format!("{}:0", B256::repeat_byte(block));
+            let record = states.get(&id).await.unwrap().unwrap();
+            assert!(
+                matches!(record.state(), Some(DepositState::Failed { .. })),
+                "block {block}"
+            );
+            assert!(record.state().unwrap().can_move_to(&DepositState::Observed));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backfill_publishes_in_chunks_without_cursor() {
+        let sender: Address = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
+            .parse()
+            .unwrap();
+        let asserter = Asserter::new();
+        asserter.push_success(&vec![deposit_log_at(sender, "1", 5)]);
+        asserter.push_success(&vec![deposit_log_at(sender, "2", BACKFILL_CHUNK + 5)]);
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(asserter);
+
+        // No expectations: the cursor must not be read or written.
+        let cache_connection = MockCacheTrait::new();
+        let queue = InMemoryQueue::new();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            queue.clone(),
+            cache_connection,
+            provider,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sub.backfill(1, BACKFILL_CHUNK + 10).await.unwrap(), 2);
+        assert_eq!(queue.ready_len(), 2);
+        assert!(sub.backfill(10, 9).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backfill_skips_minted_deposits_and_needs_lease() {
+        let sender = Address::default();
+        let asserter = Asserter::new();
+        asserter.push_success(&vec![
+            deposit_log_at(sender, "1", 5),
+            deposit_log_at(sender, "2", 7),
+        ]);
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(asserter);
+        let mut states = InMemoryStateStore::new();
+        let minted = format!("{}:0", B256::repeat_byte(5));
+        let tx_hash = B256::repeat_byte(1);
+        for state in [
+            DepositState::MintSubmitted { tx_hash },
+            DepositState::MintConfirmed { tx_hash },
+        ] {
+            states.transition(&minted, state, "includer").await.unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("subscriber.lock");
+        let mut queue = InMemoryQueue::new();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            queue.clone(),
+            MockCacheTrait::new(),
+            provider,
+        )
+        .await
+        .unwrap()
+        .with_state_store(Some(Box::new(states)))
+        .with_leader_lease(Some(Box::new(FileLease::new(&lock))));
+
+        // A running subscriber holds the lease.
+        let mut running = FileLease::new(&lock);
+        assert!(running.acquire().await.unwrap().is_some());
+        assert!(sub.backfill(1, 10).await.is_err());
+        assert_eq!(queue.ready_len(), 0);
+
+        running.release().await.unwrap();
+        assert_eq!(sub.backfill(1, 10).await.unwrap(), 1);
+        let delivery = queue.consumer().await.unwrap().next_delivery().await;
+        let envelope = Envelope::from_bytes(delivery.unwrap().unwrap().data()).unwrap();
+        assert_eq!(envelope.into_deposit().unwrap().amount, 2);
+        // The lease is released once done.
+        assert!(running.acquire().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_start_block_seeds_empty_cursor_only() {
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(900));
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(asserter);
+        let mut cache = InMemoryCache::new();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            InMemoryQueue::new(),
+            cache.clone(),
+            provider.clone(),
+        )
+        .await
+        .unwrap()
+        .with_chain_id(1)
+        .with_start_block(StartBlock::Latest);
+        let key = sub.cursor_key().await.unwrap();
+        assert_eq!(cache.get_last_offset(&key).await.unwrap(), 900);
+
+        // An existing cursor is left alone, without asking the provider.
+        cache.set_last_offset(&key, 42).await.unwrap();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            InMemoryQueue::new(),
+            cache.clone(),
+            provider.clone(),
+        )
+        .await
+        .unwrap()
+        .with_chain_id(1)
+        .with_start_block(StartBlock::Number(500));
+        sub.cursor_key().await.unwrap();
+        assert_eq!(cache.get_last_offset(&key).await.unwrap(), 42);
+
+        // So is a cursor stored at genesis.
+        cache.set_last_offset(&key, 0).await.unwrap();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            InMemoryQueue::new(),
+            cache.clone(),
+            provider,
+        )
+        .await
+        .unwrap()
+        .with_chain_id(1)
+        .with_start_block(StartBlock::Number(500));
+        sub.cursor_key().await.unwrap();
+        assert_eq!(cache.get_offset(&key).await.unwrap(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_run_backs_off_without_new_blocks_until_shutdown() {
+        let asserter = Asserter::new();
+        asserter.push_success(&U64::from(42));
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(asserter);
+        let mut cache = InMemoryCache::new();
+        let shutdown = Shutdown::new();
+        let health = Health::new();
+        let mut sub = Subscriber::new(
+            Address::default(),
+            InMemoryQueue::new(),
+            cache.clone(),
+            provider,
+        )
+        .await
+        .unwrap()
+        .with_chain_id(1)
+        .with_poll_schedule(PollSchedule::new(
+            Duration::from_secs(60),
+            Duration::from_secs(600),
+        ))
+        .with_shutdown(shutdown.clone())
+        .with_health(health.clone());
+        let key = sub.cursor_key().await.unwrap();
+        cache.set_last_offset(&key, 42).await.unwrap();
+
+        // The only scan finds no new block; the next is a minute away, but
+        // the shutdown cuts the wait short.
+        let handle = tokio::spawn(async move {
+            sub.run().await;
+            sub
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!handle.is_finished());
+        shutdown.trigger();
+        let sub = tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(sub.schedule.until_due() > Duration::from_secs(50));
+        assert_eq!(cache.get_last_offset(&key).await.unwrap(), 42);
+
+        let (alive, body) = health.liveness();
+        assert!(alive);
+        assert_eq!(body["loops"]["subscriber"]["ok"], true);
+        // The mocked provider has no answer left for the probe.
+        let (ready, body) = health.readiness().await;
+        assert!(!ready);
+        assert_eq!(body["checks"]["cache"]["ok"], true);
+        assert_eq!(body["checks"]["lag"]["blocks"], 0);
+        assert_eq!(body["checks"]["source_rpc"]["ok"], false);
+    }
+
+    #[tokio::test]
+    async fn test_only_leader_writes_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("subscriber.lock");
+        let provider: ProviderType = ProviderBuilder::new().on_mocked_client(Asserter::new());
+        let cache = InMemoryCache::new();
+        let new_sub = || {
+            Subscriber::new(
+                Address::default(),
+                InMemoryQueue::new(),
+                cache.clone(),
+                provider.clone(),
+            )
+        };
+        let mut a = new_sub()
+            .await
+            .unwrap()
+            .with_chain_id(1)
+            .with_leader_lease(Some(Box::new(FileLease::new(&lock))));
+        let mut b = new_sub()
+            .await
+            .unwrap()
+            .with_chain_id(1)
+            .with_leader_lease(Some(Box::new(FileLease::new(&lock))));
+        let key = a.cursor_key().await.unwrap();
+
+        assert!(a.ensure_leadership().await);
+        assert!(!b.ensure_leadership().await);
+        a.write_cursor(&key, 10).await.unwrap();
+
+        // `a` stalls and loses the lease; `b` takes over and moves on.
+        a.leader_lease.as_mut().unwrap().release().await.unwrap();
+        assert!(b.ensure_leadership().await);
+        b.write_cursor(&key, 20).await.unwrap();
+
+        // A late write from the old leader is refused.
+        let err = a.write_cursor(&key, 15).await.unwrap_err();
+        assert!(matches!(
+            err,
+            RelayerError::FencedOut {
+                token: 1,
+                current: 2
+            }
+        ));
+        assert_eq!(a.fence, None);
+        let mut cache = cache.clone();
+        assert_eq!(cache.get_last_offset(&key).await.unwrap(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_lease_is_held_while_working() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = dir.path().join("subscriber.lock");
+        let mut lease: Option<Box<dyn LeaderLease>> = Some(Box::new(FileLease::new(&lock)));
+        let fence = lease.as_mut().unwrap().acquire().await.unwrap();
+
+        // Renewals keep the same token, so the scan is never interrupted.
+        let held = tokio::time::timeout(
+            LEASE_RENEW_INTERVAL + Duration::from_millis(500),
+            hold_lease(&mut lease, fence),
+        )
+        .await;
+        assert!(held.is_err());
+
+        // Once the lease changes hands the scan is abandoned.
+        lease.as_mut().unwrap().release().await.unwrap();
+        let mut other = FileLease::new(&lock);
+        assert_eq!(other.acquire().await.unwrap(), Some(2));
+        tokio::time::timeout(
+            LEASE_RENEW_INTERVAL + Duration::from_millis(500),
+            hold_lease(&mut lease, fence),
+        )
+        .await
+        .unwrap();
+
+        let held =
+            tokio::time::timeout(Duration::from_millis(50), hold_lease(&mut None, None)).await;
+        assert!(held.is_err());
+    }
 }
 
 // mod tests {
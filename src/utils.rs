@@ -1,5 +1,6 @@
+use crate::config::RelayerConfig;
 use crate::errors::RelayerError;
-use crate::subscriber::Deposit;
+use crate::subscriber::{Deposit, DepositOrigin};
 use alloy::primitives::Address;
 use alloy::primitives::keccak256;
 use alloy::rpc::types::Log;
@@ -10,7 +11,8 @@ use eyre::Result;
 use serde_json::Value;
 use std::fs;
 use std::str::FromStr;
-use tracing::level_filters::LevelFilter;
+use tracing::debug;
+use tracing_subscriber::EnvFilter;
 use tracing_subscriber::Layer;
 use tracing_subscriber::Registry;
 use tracing_subscriber::fmt;
@@ -32,11 +34,46 @@ impl Deployments {
     }
 }
 
-pub fn setup_logging() {
-    let level = std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into());
-    let level = LevelFilter::from_str(&level).unwrap_or(LevelFilter::DEBUG);
+/// How log lines are written, selected with `log.format`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, carrying the fields of the current span,
+    /// such as the ids of the deposit being handled.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = RelayerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "plain" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(RelayerError::ConfigError(format!(
+                "Unknown log format: {other}"
+            ))),
+        }
+    }
+}
 
-    let fmt_layer = fmt::layer().with_filter(level);
+/// Logs at `log.level`, unless `RUST_LOG` holds directives such as
+/// `relayer=debug,lapin=warn`. Invalid directives are skipped.
+pub fn setup_logging(config: &RelayerConfig) {
+    let level = EnvFilter::builder()
+        .with_default_directive(config.log_level.into())
+        .from_env_lossy();
+    let fmt_layer = match config.log_format {
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_filter(level)
+            .boxed(),
+        LogFormat::Text => fmt::layer().with_filter(level).boxed(),
+    };
 
     let subscriber = Registry::default().with(fmt_layer);
 
@@ -76,7 +113,10 @@ pub async fn push_deposits(
     mut deposits: Vec<Deposit>,
 ) -> Result<Vec<Deposit>, RelayerError> {
     for log in logs {
-        println!("Transfer event: {log:?}");
+        debug!(
+            "Deposit log {:?} in tx {:?}",
+            log.log_index, log.transaction_hash
+        );
         let topics = log.topics();
         let raw_topic = topics.get(1).expect("Expected at least 2 topics");
 
@@ -97,9 +137,16 @@ pub async fn push_deposits(
             _ => return Err(RelayerError::NotString),
         };
 
-        let amount = amount_str.parse::<i32>().unwrap();
+        let amount = amount_str
+            .parse::<i32>()
+            .map_err(|_| RelayerError::InvalidAmount(amount_str.clone()))?;
 
-        deposits.push(Deposit { sender, amount });
+        let origin = DepositOrigin::from_log(&log);
+        deposits.push(Deposit {
+            sender,
+            amount,
+            origin,
+        });
     }
     Ok(deposits)
 }
@@ -114,16 +161,15 @@ pub fn get_dst_contract_addr(addr_path: &str) -> Result<Address, RelayerError> {
     Ok(contract_address.token)
 }
 
+#[cfg(test)]
 mod tests {
-    use std::default;
-
     use alloy::primitives::LogData;
 
     use super::*;
     use crate::errors::RelayerError;
     use alloy::primitives::{Address, B256, Bytes, Log as RawLog};
     use alloy::rpc::types::Log as RpcLog;
-    use alloy_dyn_abi::{DynSolType, DynSolValue};
+    use alloy_dyn_abi::DynSolValue;
 
     #[test]
     fn test_get_src_contract_addr() {
@@ -177,7 +223,7 @@ mod tests {
             "Token":   "0x5FbDB2315678afecb367f032d93F642f64180aa3"
         }
         "#;
-        let json: Value = serde_json::from_str(&json_str).unwrap();
+        let json: Value = serde_json::from_str(json_str).unwrap();
         let res = deployments_from_json(json).unwrap();
         assert_eq!(
             res.deposit.to_string(),
@@ -197,7 +243,7 @@ mod tests {
             "NotToken":   "0x5FbDB2315678afecb367f032d93F642f64180aa3"
         }
         "#;
-        let json: Value = serde_json::from_str(&json_str).unwrap();
+        let json: Value = serde_json::from_str(json_str).unwrap();
         let err = deployments_from_json(json).unwrap_err();
         assert!(matches!(err, RelayerError::Other(_)));
     }
@@ -209,7 +255,7 @@ mod tests {
             "Token":   123
         }
         "#;
-        let json: Value = serde_json::from_str(&json_str).unwrap();
+        let json: Value = serde_json::from_str(json_str).unwrap();
         let err = deployments_from_json(json).unwrap_err();
         assert!(matches!(err, RelayerError::FromHexError(_)));
     }
@@ -330,4 +376,14 @@ mod tests {
         let err = push_deposits(vec![rpc_log], Vec::new()).await.unwrap_err();
         assert!(matches!(err, RelayerError::AbiError(_)));
     }
+
+    #[test]
+    fn test_log_format_from_str() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("Text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!(matches!(
+            "xml".parse::<LogFormat>(),
+            Err(RelayerError::ConfigError(_))
+        ));
+    }
 }
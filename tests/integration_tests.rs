@@ -1,35 +1,40 @@
-use alloy::transports::http::reqwest::Url;
+use alloy::json_abi::JsonAbi;
+use relayer::config::RelayerConfig;
 use relayer::includer;
-use relayer::queue::{QueueTrait, get_queue_connection};
+use relayer::metrics::Metrics;
+use relayer::queue::{QueueTrait, memory::InMemoryQueue};
 use relayer::subscriber::Deposit;
-use relayer::utils::get_dst_contract_addr;
 
 #[tokio::test]
 async fn test_publish_and_consume() {
-    const ADDRESS_PATH: &str = "../project_eth/data/deployments.json";
-    unsafe {
-        std::env::set_var(
-            "PRIVATE_KEY",
-            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
-        );
-    }
-    let mut con = get_queue_connection(true).await.unwrap();
+    let config = RelayerConfig::from_toml(
+        r#"
+        [destination]
+        rpc_url = "http://localhost:8546"
+        contract = "0x5FbDB2315678afecb367f032d93F642f64180aa3"
+
+        [includer]
+        private_key = "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+        "#,
+        |_| None,
+    )
+    .unwrap();
+    let mut con = InMemoryQueue::new();
     let test_deposit = Deposit {
         sender: "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266"
             .parse()
             .unwrap(),
         amount: 42,
+        origin: None,
     };
     let test_item = serde_json::to_vec(&test_deposit).unwrap();
     let resp = con.publish(&test_item).await;
     assert!(resp.is_ok());
     let mut consumer = con.consumer().await.unwrap();
-    let dst_rpc = "http://localhost:8546";
-    let rpc_url_dst: Url = dst_rpc.parse().unwrap();
-    let dst_contract_address = get_dst_contract_addr(ADDRESS_PATH).unwrap();
-    let incl_res = includer::Includer::new(&rpc_url_dst, dst_contract_address, con.clone()).await;
+    let incl_res =
+        includer::Includer::with_abi(&config, JsonAbi::default(), con.clone(), Metrics::new());
     assert!(incl_res.is_ok());
-    let incl = incl_res.unwrap();
+    let mut incl = incl_res.unwrap();
     let res = incl.consume(&mut consumer).await;
     assert!(res.is_ok());
     let tuple = res.unwrap();
@@ -37,4 +42,6 @@ async fn test_publish_and_consume() {
     assert_eq!(received_deposit, test_deposit);
     let res = incl.ack_deposit(delivery).await;
     assert!(res.is_ok());
+    assert_eq!(con.ready_len(), 0);
+    assert_eq!(con.unacked_len(), 0);
 }